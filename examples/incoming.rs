@@ -0,0 +1,45 @@
+//! Records a squelch server to a WAV file using only the library, no cpal.
+//!
+//! ```text
+//! cargo run --example incoming -- 127.0.0.1:1837 out.wav 10
+//! ```
+
+use std::time::{Duration, Instant};
+
+use hound::{WavSpec, WavWriter};
+
+use squelch::{CHANNELS, SAMPLE_RATE, client::Client, fx::FxUnit};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let mut args = std::env::args().skip(1);
+  let address = args.next().ok_or("missing server address")?.parse()?;
+  let output = args.next().unwrap_or_else(|| "incoming.wav".to_owned());
+  let secs: u64 = match args.next() {
+    Some(secs) => secs.parse()?,
+    None => 10,
+  };
+
+  let client = Client::connect(address)?;
+  let incoming = client.incoming(Some(FxUnit::new(false, 1.0, 0.05)))?;
+
+  let spec = WavSpec {
+    channels: CHANNELS,
+    sample_rate: SAMPLE_RATE,
+    bits_per_sample: 32,
+    sample_format: hound::SampleFormat::Float,
+  };
+  let mut writer = WavWriter::create(&output, spec)?;
+
+  println!("Recording {} for {}s to {}", address, secs, output);
+  let deadline = Instant::now() + Duration::from_secs(secs);
+  while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+    if let Ok(samples) = incoming.recv_timeout(left) {
+      for sample in samples {
+        writer.write_sample(sample)?;
+      }
+    }
+  }
+
+  writer.finalize()?;
+  Ok(())
+}
@@ -0,0 +1,76 @@
+//! Runs a squelch server that prints how many packets of each type it has
+//! seen, using the `on_packet` hook.
+//!
+//! ```text
+//! cargo run --example packet_counts
+//! ```
+
+use std::{
+  net::UdpSocket,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::Duration,
+};
+
+use squelch::{
+  Packet,
+  server::{Server, ServerConfig},
+};
+
+#[derive(Default)]
+struct Counts {
+  ping: AtomicU64,
+  audio: AtomicU64,
+  direct: AtomicU64,
+  silence: AtomicU64,
+  /// Level, channel and hello packets.
+  control: AtomicU64,
+}
+
+fn main() -> std::io::Result<()> {
+  let socket = UdpSocket::bind("0.0.0.0:1837")?;
+  let mut server = Server::new(socket, ServerConfig::default());
+
+  // The hook runs on the receive thread, so it only bumps counters; the
+  // printing happens elsewhere.
+  let counts = Arc::new(Counts::default());
+  let hook_counts = counts.clone();
+  server.on_packet(move |_, packet| {
+    let count = match packet {
+      Packet::Ping => &hook_counts.ping,
+      Packet::Audio(_)
+      | Packet::Emergency { .. }
+      | Packet::SeqAudio { .. }
+      | Packet::StampedAudio { .. }
+      | Packet::OpusAudio { .. } => &hook_counts.audio,
+      Packet::DirectAudio { .. } => &hook_counts.direct,
+      Packet::Silence => &hook_counts.silence,
+      Packet::Level(_)
+      | Packet::Channel(_)
+      | Packet::Hello { .. }
+      | Packet::Rejected { .. }
+      | Packet::ServerInfo { .. }
+      | Packet::AcceptOpus
+      | Packet::HelloAck { .. } => &hook_counts.control,
+    };
+    count.fetch_add(1, Ordering::Relaxed);
+  });
+
+  std::thread::spawn(move || {
+    loop {
+      std::thread::sleep(Duration::from_secs(1));
+      println!(
+        "ping: {}, audio: {}, direct: {}, silence: {}, control: {}",
+        counts.ping.load(Ordering::Relaxed),
+        counts.audio.load(Ordering::Relaxed),
+        counts.direct.load(Ordering::Relaxed),
+        counts.silence.load(Ordering::Relaxed),
+        counts.control.load(Ordering::Relaxed),
+      );
+    }
+  });
+
+  server.run()
+}
@@ -1,28 +1,97 @@
 use std::{
   collections::VecDeque,
   net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+  path::PathBuf,
   str::FromStr,
   sync::{
-    Arc,
-    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering},
     mpsc::{self},
   },
-  time::Instant,
+  time::{Duration, Instant},
 };
 
 use clap::Parser;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use eframe::egui::{self, Button, Sense};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use eframe::egui::{self, Button, Color32, RichText, Sense};
 use global_hotkey::{
   GlobalHotKeyEvent, GlobalHotKeyManager,
   hotkey::{Code, HotKey},
 };
+use tracing::{debug, error, info, warn};
 
 use squelch::{
-  MAX_PACKET_SIZE, Packet, TX_BUFFER_SIZE, TxBuffer, WAIT_DURATION, fx::FxUnit,
+  ClientId, KEEPALIVE_INTERVAL, MAX_PACKET_SIZE, PROTOCOL_VERSION, Packet,
+  SAMPLE_RATE, TX_BUFFER_SIZE, TxBuffer, WAIT_DURATION,
+  capture::CaptureWriter,
+  device,
+  dtx::{Dtx, DtxFrame, SID_TIMEOUT},
+  fx::{self, DistortionKind, EqBand, FxConfig, FxUnit, MAX_EQ_BANDS, beep},
+  jitter::JitterBuffer,
+  logging::Verbosity,
   map_would_block,
+  morse::{self, IDENT_TONE_HZ},
+  net,
+  normalize::Normalizer,
+  playback::{PlaybackStats, Speaker, UnderrunFill, build_speaker_stream},
+  plc::Concealer,
+  presets::{DEFAULT_PRESET_DIR, PresetDir},
+  ptt::{Debouncer, TimeoutTimer, Vox},
+  ratelimit::Pacer,
+  seq::Stamper,
+  server::{Server, ServerConfig},
 };
 
+#[cfg(feature = "nr")]
+use squelch::nr::NoiseReducer;
+#[cfg(feature = "opus")]
+use squelch::opus::{OpusDecoder, OpusEncoder};
+
+/// How recently a packet must have arrived to count as connected. The server
+/// answers each keepalive ping, so this is two and a half of them: one lost
+/// reply doesn't make it look gone.
+const CONNECTED_TIMEOUT: Duration =
+  Duration::from_millis(KEEPALIVE_INTERVAL.as_millis() as u64 * 5 / 2);
+
+/// How long `--calibrate` listens for.
+const CALIBRATE_SECS: u64 = 3;
+/// Calibration aims speech at these levels (~-18 dBFS RMS, ~-3 dBFS peak).
+const CALIBRATE_TARGET_RMS: f32 = 0.125;
+const CALIBRATE_TARGET_PEAK: f32 = 0.7;
+
+/// How long the channel must be quiet before an `--ident` goes out.
+const IDENT_HOLDOFF: Duration = Duration::from_secs(1);
+
+/// How often `--target-latency-ms` reports the measured latency.
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Mic buffers allowed to queue behind the send pacer (~46 ms).
+const MAX_OUTGOING: usize = 8;
+
+/// How long the CLIP indicator stays lit after the mic last clipped.
+const CLIP_HOLD: Duration = Duration::from_millis(500);
+
+/// Pitch and length of the beep played when the time-out timer trips.
+const TOT_TONE_HZ: f32 = 1000.0;
+const TOT_TONE_BUFFERS: usize = 40;
+
+/// Length of the `--rx-tone` and `--tx-tone` courtesy tones (~100 ms).
+const COURTESY_TONE_BUFFERS: usize = 17;
+
+/// How long the mic must stay above `--vox-threshold` to key up, so a
+/// click or a cough doesn't.
+const VOX_ATTACK: Duration = Duration::from_millis(30);
+
+/// How long the emergency alert stays up after the last emergency audio.
+const EMERGENCY_HOLD: Duration = Duration::from_secs(3);
+
+/// Undecodable packets in a row, with nothing good between, before we tell
+/// the user the server speaks something else entirely (~0.3 s of audio).
+const MAX_DECODE_ERRORS: usize = 50;
+
+/// How often the stats overlay works out its rates.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Squelch
 #[derive(Debug, Clone, Parser)]
 #[command(version, about, long_about = None)]
@@ -31,54 +100,422 @@ pub struct Cli {
   #[arg(short, long, default_value = None)]
   pub address: Option<SocketAddr>,
 
-  /// Registers a PTT key via key string (see https://docs.rs/global-hotkey/latest/global_hotkey/hotkey/enum.Code.html).
-  #[arg(long)]
-  pub hotkey: Option<String>,
+  /// Runs a server inside the client on localhost that sends you your own
+  /// audio back, to try out transmit, FX and receive without a separate
+  /// server: each transmission plays back when you release PTT. For demos
+  /// and smoke tests, not for real nets.
+  #[arg(long, conflicts_with = "address")]
+  pub local_echo: bool,
+
+  /// Registers a PTT key via key string (see https://docs.rs/global-hotkey/latest/global_hotkey/hotkey/enum.Code.html),
+  /// optionally with modifiers, e.g. `Ctrl+Space`. Repeat for more keys; any
+  /// of them keys up. Works alongside the GUI's PTT button.
+  #[arg(long, value_parser = parse_hotkey)]
+  pub hotkey: Vec<HotKey>,
+
+  /// Channel to join; clients only hear others on the same channel. It can
+  /// be changed while running from the GUI or with the keys below.
+  #[arg(long, default_value_t = 0)]
+  pub channel: u16,
+
+  /// Global hotkey that moves up a channel (same key names as `--hotkey`).
+  #[arg(long, value_parser = parse_hotkey)]
+  pub channel_up_key: Option<HotKey>,
+
+  /// Global hotkey that moves down a channel.
+  #[arg(long, value_parser = parse_hotkey)]
+  pub channel_down_key: Option<HotKey>,
 
   /// Disables effects.
   #[arg(long)]
   pub no_fx: bool,
 
-  /// Threshold of distortion effect.
-  #[arg(short, long, default_value_t = 0.05)]
-  pub distortion: f32,
+  /// Built-in FX preset: default, vhf-air, hf-ssb or cb.
+  #[arg(long, value_parser = parse_preset)]
+  pub preset: Option<FxConfig>,
 
-  /// Gain multiplier for incoming signal.
-  #[arg(short, long, default_value_t = 1.0)]
-  pub gain: f32,
+  /// Loads FX settings from a TOML preset file instead.
+  #[arg(long, conflicts_with = "preset")]
+  pub preset_file: Option<PathBuf>,
+
+  /// Where the window's "Save preset" puts presets, and where it lists them
+  /// from. Without `--preset` or `--preset-file`, the last one picked there
+  /// is loaded at startup.
+  #[arg(long, default_value = DEFAULT_PRESET_DIR)]
+  pub preset_dir: PathBuf,
+
+  /// Threshold of distortion effect (overrides the preset; default 0.05).
+  #[arg(short, long)]
+  pub distortion: Option<f32>,
+
+  /// Distortion model: hard-clip, tanh, bit-crush or downsample (overrides
+  /// the preset; default hard-clip).
+  #[arg(long)]
+  pub distortion_type: Option<DistortionKind>,
+
+  /// Gain multiplier for incoming signal (overrides the preset; default 1.0).
+  #[arg(short, long)]
+  pub gain: Option<f32>,
+
+  /// Level of a faint hiss always added to received audio, like a real
+  /// receiver's, e.g. 0.005 (overrides the preset; default 0, off).
+  #[arg(long)]
+  pub noise_floor: Option<f32>,
+
+  /// Ring-modulate incoming audio with a carrier at this frequency, e.g. 30,
+  /// for a spooky "alien radio" sound (overrides the preset; default off).
+  #[arg(long)]
+  pub ring_mod: Option<f32>,
+
+  /// Cutoff of the low pass on received audio, in Hz; lower sounds more
+  /// muffled (overrides the preset; default 8000).
+  #[arg(long)]
+  pub lowpass: Option<f32>,
+
+  /// Cutoff of the high pass on received audio, in Hz; higher sounds
+  /// thinner. Must be below `--lowpass` (overrides the preset; default 400).
+  #[arg(long)]
+  pub highpass: Option<f32>,
+
+  /// Length of the burst of static after each transmission, in buffers of
+  /// ~6 ms, from 1 to 64 (overrides the preset; default 8).
+  #[arg(long)]
+  pub squelch_tail: Option<usize>,
+
+  /// Adds a peaking EQ band to received audio, as `freq,gain,q`, e.g.
+  /// `2000,3,1` for +3 dB around 2 kHz. Repeat for up to 3 bands; replaces
+  /// the preset's bands.
+  #[arg(long, allow_negative_numbers = true)]
+  pub eq: Vec<EqBand>,
 
   /// Gain multiplier for mic signal.
   #[arg(short, long, default_value_t = 1.0)]
   pub mic_gain: f32,
 
+  /// Keep this many milliseconds of mic audio from before PTT is pressed and
+  /// send it first, so a quick press doesn't clip the first syllable. The
+  /// transmission runs this much behind.
+  #[arg(long, default_value_t = 0)]
+  pub pre_roll_ms: u64,
+
+  /// Plays your own mic back to you at this level while you transmit, e.g.
+  /// 0.3, so you can hear yourself. It's clean, without the radio FX.
+  #[arg(long)]
+  pub sidetone: Option<f32>,
+
+  /// Runs `--sidetone` through the FX too, to hear how you sound on the air.
+  #[arg(long, requires = "sidetone")]
+  pub sidetone_fx: bool,
+
   /// Playback jitter-buffer depth in milliseconds.
   #[arg(long, default_value_t = 20)]
   pub jitter_ms: u64,
 
+  /// Received buffers (~5.8 ms each) to hold back at the start of a
+  /// transmission, so packets that arrive in bursts still play out evenly
+  #[arg(
+    long,
+    default_value_t = 3,
+    value_parser = clap::value_parser!(u64).range(1..=64)
+  )]
+  pub jitter_depth: u64,
+
+  /// Hold playback latency near this many milliseconds, dropping audio that
+  /// builds up beyond it, and report the measured latency periodically.
+  /// Replaces `--jitter-ms`.
+  #[arg(long, conflicts_with = "jitter_ms")]
+  pub target_latency_ms: Option<u64>,
+
+  /// What plays when the speaker runs out of audio partway through a block:
+  /// silence, hold (repeat the last sample) or fade (ramp it to zero). Hold
+  /// and fade can click less than silence.
+  #[arg(long, default_value_t = UnderrunFill::Silence)]
+  pub underrun_fill: UnderrunFill,
+
+  /// When received audio runs out partway through a transmission, fills the
+  /// gap with the last buffer fading out, rather than silence that clicks
+  #[arg(long)]
+  pub plc: bool,
+
+  /// Left/right balance of received audio, from -1.0 (left only) to 1.0
+  /// (right only). Ignored on mono output devices.
+  #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+  pub balance: f32,
+
   /// Audio device period size in frames (0 = backend default). Request a fixed
   /// period (e.g. 441 ≈ 10 ms) for low latency.
   #[arg(long, default_value_t = 0)]
   pub frames: u32,
+
+  /// Voice-operated transmit: key up automatically while you're talking,
+  /// hands-free. Works alongside the PTT button and hotkey. Pair it with
+  /// `--pre-roll-ms` so the first syllable isn't lost.
+  #[arg(long)]
+  pub vox: bool,
+
+  /// Mic RMS level that keys up `--vox`, before `--mic-gain`.
+  #[arg(long, default_value_t = 0.02)]
+  pub vox_threshold: f32,
+
+  /// How long `--vox` keeps transmitting after you stop talking, in
+  /// milliseconds.
+  #[arg(long, default_value_t = 500)]
+  pub vox_hang_ms: u64,
+
+  /// Latching PTT: one press of the hotkey, or click of the PTT button,
+  /// starts transmitting, and the next stops.
+  #[arg(long)]
+  pub ptt_toggle: bool,
+
+  /// Ignore PTT changes within this many milliseconds of the last change.
+  #[arg(long, default_value_t = 50)]
+  pub ptt_debounce_ms: u64,
+
+  /// Append every received datagram, undecoded, to this capture file.
+  #[arg(long)]
+  pub pcap_out: Option<String>,
+
+  /// Ask the OS for UDP receive/send buffers of this many KiB (it may
+  /// clamp the request). Helps avoid drops under bursty traffic.
+  #[arg(long)]
+  pub udp_buffer_kb: Option<usize>,
+
+  /// Send from this local address, e.g. `192.168.1.5:0` to pick the
+  /// interface or `0.0.0.0:40000` for a fixed source port. A fixed port
+  /// keeps the NAT mapping, and any firewall rule for it, stable across
+  /// restarts.
+  #[arg(long)]
+  pub bind: Option<SocketAddr>,
+
+  /// Audio host backend to use, e.g. ASIO or JACK (see `--list-hosts`).
+  #[arg(long)]
+  pub host: Option<String>,
+
+  /// Mic to use, by name, e.g. a USB headset's. Default: the host's default
+  /// input.
+  #[arg(long)]
+  pub input_device: Option<String>,
+
+  /// Speakers to use, by name. Default: the host's default output.
+  #[arg(long)]
+  pub output_device: Option<String>,
+
+  /// Records a few seconds of speech, prints a recommended `--mic-gain` and
+  /// exits.
+  #[arg(long)]
+  pub calibrate: bool,
+
+  /// Adds a "Whisper" button that talks to this client only, by its address
+  /// as the server sees it. Nobody else on the channel hears it.
+  #[arg(long)]
+  pub whisper_to: Option<ClientId>,
+
+  /// Adds an "Emergency" button that talks to every client on every
+  /// channel, after an attention tone. The server has to allow it
+  /// (`--allow-emergency`); otherwise it goes out on our channel as usual.
+  #[arg(long)]
+  pub emergency: bool,
+
+  /// Discontinuous transmission: stop sending while you're silent, and let
+  /// listeners fill the pauses with comfort noise. Saves bandwidth on
+  /// quiet channels.
+  #[arg(long)]
+  pub dtx: bool,
+
+  /// Only open the squelch for received audio whose RMS level is at least
+  /// this, e.g. 0.02, like a real receiver's carrier squelch. Quieter audio
+  /// is muted, and a transmission that stays below it ends in the squelch
+  /// tail as if it had stopped. Default: any audio opens it.
+  #[arg(long)]
+  pub squelch_level: Option<f32>,
+
+  /// Evens out talkers' levels: each received transmission is eased toward
+  /// this RMS level, e.g. 0.1 (-20 dBFS), by up to 12 dB either way, then
+  /// the next one starts over.
+  #[arg(long)]
+  pub normalize: Option<f32>,
+
+  /// Runs the mic through RNNoise before sending, to keep fans, hum and
+  /// keyboard clatter off the net. Adds about 10 ms of delay.
+  #[cfg(feature = "nr")]
+  #[arg(long)]
+  pub noise_reduction: bool,
+
+  /// Automatic gain control: steers the mic toward a steady level
+  /// (-20 dBFS RMS), boosting by up to 20 dB, so you sound the same however
+  /// close you are to the mic. Applied on top of `--mic-gain`.
+  #[arg(long)]
+  pub agc: bool,
+
+  /// Mutes the mic while its RMS level is below this, e.g. 0.01 (-40 dBFS),
+  /// so background noise between words isn't sent. Default: off.
+  #[arg(long)]
+  pub gate_threshold: Option<f32>,
+
+  /// How long the `--gate-threshold` gate takes to open once you start
+  /// talking, in milliseconds.
+  #[arg(long, default_value_t = fx::DEFAULT_GATE_ATTACK.as_millis() as u64)]
+  pub gate_attack_ms: u64,
+
+  /// How long the `--gate-threshold` gate takes to close once you stop
+  /// talking, in milliseconds.
+  #[arg(long, default_value_t = 150)]
+  pub gate_release_ms: u64,
+
+  /// Sends and receives Opus-compressed audio, at a fraction of the
+  /// bandwidth, if the server supports it. Adds up to 20 ms of delay.
+  #[cfg(feature = "opus")]
+  #[arg(long)]
+  pub opus: bool,
+
+  /// Checks the audio devices, FX settings and server connection, prints a
+  /// summary and exits (nonzero if anything failed).
+  #[arg(long)]
+  pub check: bool,
+
+  /// Ask the server to mix us at this level, in percent (up to 200). Unlike
+  /// `--mic-gain`, this is applied by the server after the mic is clamped.
+  #[arg(long)]
+  pub tx_level: Option<u8>,
+
+  /// Plays a short courtesy tone at this many Hz when someone else's
+  /// transmission ends, after the squelch tail. Off by default.
+  #[arg(long)]
+  pub rx_tone: Option<f32>,
+
+  /// Plays a short courtesy tone at this many Hz, locally, when our own
+  /// transmission ends. Pick a different pitch from `--rx-tone` to tell the
+  /// two apart. Off by default.
+  #[arg(long)]
+  pub tx_tone: Option<f32>,
+
+  /// Ends each transmission with a short beep that goes out over the air,
+  /// like a ham or aviation radio's roger beep, so listeners know you're
+  /// done.
+  #[arg(long)]
+  pub roger_beep: bool,
+
+  /// Pitch of the `--roger-beep`, in Hz.
+  #[arg(long, default_value_t = fx::DEFAULT_ROGER_HZ)]
+  pub roger_freq: f32,
+
+  /// Length of the `--roger-beep`, in milliseconds.
+  #[arg(long, default_value_t = fx::DEFAULT_ROGER_LEN.as_millis() as u64)]
+  pub roger_ms: u64,
+
+  /// Time-out timer: cut a transmission after this many seconds and beep.
+  /// PTT must then be released and pressed again to keep talking.
+  #[arg(long)]
+  pub tot_secs: Option<u64>,
+
+  /// Identify the station by sending this callsign in Morse code, at
+  /// startup and then every `--ident-interval`. It waits for the channel to
+  /// be quiet, and pressing PTT cuts it short.
+  #[arg(long)]
+  pub ident: Option<String>,
+
+  /// Seconds between idents.
+  #[arg(long, default_value_t = 600, requires = "ident")]
+  pub ident_interval: u64,
+
+  /// Morse speed of the ident, in words per minute.
+  #[arg(
+    long,
+    default_value_t = 20.0,
+    requires = "ident",
+    value_parser = parse_wpm
+  )]
+  pub ident_wpm: f32,
+
+  /// Runs without a window, e.g. as a background service: talk with
+  /// `--hotkey` and stop with Ctrl+C. Global hotkeys are delivered through a
+  /// window's event loop on macOS and Windows, so without one they only work
+  /// on Linux.
+  #[arg(long)]
+  pub no_gui: bool,
+
+  /// Starts with the network stats overlay open. F3 in the window toggles it.
+  #[arg(long)]
+  pub stats: bool,
+
+  /// Lists the available audio host backends and exits.
+  #[arg(long)]
+  pub list_hosts: bool,
+
+  /// Lists the audio host's input and output devices, for `--input-device`
+  /// and `--output-device`, and exits.
+  #[arg(long)]
+  pub list_devices: bool,
+
+  #[command(flatten)]
+  pub verbosity: Verbosity,
 }
 
 fn main() {
   let args = Cli::parse();
+  args.verbosity.init();
 
-  let address = args.address.unwrap_or_else(|| {
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 1837))
-  });
+  if args.list_hosts {
+    device::list_hosts();
+    return;
+  }
+
+  let address = if args.local_echo {
+    start_local_echo().unwrap_or_else(|e| {
+      error!("Failed to start the local echo server: {e}");
+      std::process::exit(1);
+    })
+  } else {
+    args.address.unwrap_or_else(|| {
+      SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 1837))
+    })
+  };
 
   let err_fn = move |err| {
-    eprintln!("an error occurred on stream: {}", err);
+    error!("an error occurred on stream: {}", err);
   };
 
   let (mic_tx, mic_rx) = mpsc::channel::<Vec<f32>>();
   let (spk_tx, spk_rx) = mpsc::channel::<TxBuffer>();
   let ptt = Arc::new(AtomicBool::new(false));
+  // Set alongside `ptt` while the whisper button is held.
+  let whisper = Arc::new(AtomicBool::new(false));
+  // Likewise for the emergency button.
+  let emergency = Arc::new(AtomicBool::new(false));
+  let channel = Arc::new(AtomicU16::new(args.channel));
+  let status = Arc::new(Status::default());
+
+  let host = device::select_host(args.host.as_deref()).unwrap_or_else(|e| {
+    error!("{e}");
+    std::process::exit(1);
+  });
+  info!("Using audio host: {}", host.id().name());
+
+  if args.list_devices {
+    device::list_devices(&host);
+    return;
+  }
 
-  let host = cpal::default_host();
-  let mic_device = host.default_input_device().unwrap();
-  let spk_device = host.default_output_device().unwrap();
+  if args.check {
+    let ok = run_check(&args, &host, address);
+    std::process::exit(if ok { 0 } else { 1 });
+  }
+  let mic_device =
+    device::select_device(&host, args.input_device.as_deref(), true)
+      .unwrap_or_else(|| {
+        error!("No input device available");
+        std::process::exit(1);
+      });
+  let spk_device =
+    device::select_device(&host, args.output_device.as_deref(), false)
+      .unwrap_or_else(|| {
+        error!("No output device available");
+        std::process::exit(1);
+      });
+  if let Err(e) = device::check_rate(&mic_device, true) {
+    error!("{e}");
+    std::process::exit(1);
+  }
 
   // Request an explicit (optionally small) device period. The backend
   // default period on this machine is ~32 ms, which caps how low playback
@@ -90,176 +527,372 @@ fn main() {
   // rather than panicking.
   let buffer_size = resolve_buffer_size(&mic_device, &spk_device, args.frames);
 
+  // Play on every channel the output device has, rather than assuming mono,
+  // so `--balance` can pan between left and right.
+  let spk_channels = spk_device
+    .default_output_config()
+    .map(|config| config.channels())
+    .unwrap_or(1);
+  // Likewise at its own rate, resampling to it, rather than forcing 44.1 kHz
+  // on a device that may only do 48.
   let spk_config = cpal::StreamConfig {
-    channels: 1,
-    sample_rate: cpal::SampleRate(44100),
+    channels: spk_channels,
+    sample_rate: cpal::SampleRate(device::output_rate(&spk_device)),
     buffer_size,
   };
 
   let mic_config = cpal::StreamConfig {
     channels: 1,
-    sample_rate: cpal::SampleRate(44100),
+    sample_rate: cpal::SampleRate(SAMPLE_RATE),
     buffer_size,
   };
 
-  println!("mic config: {mic_config:?}");
+  debug!("mic config: {mic_config:?}");
 
   let ptt_ref = ptt.clone();
-  let mic_stream = mic_device
-    .build_input_stream(
-      &mic_config,
-      move |data: &[f32], _: &_| {
-        if ptt_ref.load(Ordering::SeqCst) {
-          mic_tx.send(data.to_vec()).unwrap();
-        }
-      },
-      err_fn,
-      None,
+  let pre_roll_len = args.pre_roll_ms as usize * SAMPLE_RATE as usize / 1000;
+  let mut pre_roll = PreRoll::new(pre_roll_len);
+  let mut vox = args.vox.then(|| {
+    Vox::new(
+      args.vox_threshold,
+      VOX_ATTACK,
+      Duration::from_millis(args.vox_hang_ms),
     )
-    .unwrap();
+  });
+  let mic_stream = device::build_input_stream(
+    &mic_device,
+    &mic_config,
+    move |data: &[f32]| {
+      // Only changes are stored, so the PTT button and hotkey still work.
+      if let Some(vox) = vox.as_mut() {
+        let last = vox.state();
+        let state = vox.update(data, Instant::now());
+        if state != last {
+          ptt_ref.store(state, Ordering::SeqCst);
+        }
+      }
+      let transmitting = ptt_ref.load(Ordering::SeqCst);
+      if let Some(samples) = pre_roll.next(data, transmitting) {
+        mic_tx.send(samples).unwrap();
+      }
+    },
+    err_fn,
+  )
+  .unwrap_or_else(|e| {
+    error!("Failed to open the mic: {e}");
+    std::process::exit(1);
+  });
   mic_stream.play().unwrap();
 
-  println!("spk config: {spk_config:?}");
-
-  // Diagnostics: count how often the speaker callback runs short of data.
-  // `underruns`       -> callback had an empty queue (whole block silenced).
-  // `partial_fills`   -> callback had some, but not enough, samples.
-  // `missing_samples` -> total samples we had to zero-fill across all blocks.
-  let underruns = Arc::new(AtomicU64::new(0));
-  let partial_fills = Arc::new(AtomicU64::new(0));
-  let missing_samples = Arc::new(AtomicU64::new(0));
-  let callbacks = Arc::new(AtomicU64::new(0));
-  let queue_len = Arc::new(AtomicU64::new(0));
-
-  let (underruns_cb, partial_cb, missing_cb, callbacks_cb, queue_cb) = (
-    underruns.clone(),
-    partial_fills.clone(),
-    missing_samples.clone(),
-    callbacks.clone(),
-    queue_len.clone(),
-  );
-
-  // Jitter buffer: pre-buffer ~jitter_ms of audio before (re)starting
-  // playback so the consumer block (which is much larger than a single
-  // network chunk) never skates on an empty queue.
-  let target_samples = (args.jitter_ms as usize * 44100) / 1000;
-  // Bound added latency if the sender clock runs slightly fast (drift).
-  let max_samples = target_samples * 4;
-
-  let mut buf = VecDeque::with_capacity(target_samples.max(TX_BUFFER_SIZE));
-  // Start in the "refilling" state so we wait for a healthy backlog.
-  let mut filling = true;
-  let spk_stream = spk_device
-    .build_output_stream(
-      &spk_config,
-      move |data: &mut [f32], _: &_| {
-        spk_rx.try_iter().for_each(|samples| {
-          buf.extend(samples);
-        });
-
-        callbacks_cb.fetch_add(1, Ordering::Relaxed);
+  if args.calibrate {
+    calibrate(&ptt, &mic_rx, args.mic_gain);
+    return;
+  }
 
-        // Drop oldest samples if drift made the backlog grow unbounded.
-        if buf.len() > max_samples {
-          let drop = buf.len() - target_samples;
-          buf.drain(0..drop);
-        }
+  debug!("spk config: {spk_config:?}");
 
-        // While (re)filling, emit silence until the backlog is healthy.
-        // This is what stops the per-block zero-fills (faint pops): we
-        // wait for a cushion instead of dribbling out partial blocks.
-        if filling {
-          if buf.len() >= target_samples {
-            filling = false;
-          } else {
-            for item in data.iter_mut() {
-              *item = 0.0;
-            }
-            queue_cb.store(buf.len() as u64, Ordering::Relaxed);
-            return;
-          }
+  let stats = Arc::new(PlaybackStats::default());
+  let mut speaker = match args.target_latency_ms {
+    Some(target_ms) => {
+      let report_stats = stats.clone();
+      std::thread::spawn(move || {
+        loop {
+          std::thread::sleep(LATENCY_REPORT_INTERVAL);
+          info!(
+            "Playback latency: {} ms queued (target {target_ms} ms)",
+            report_stats.queued().as_millis()
+          );
         }
+      });
+      Speaker::with_target_latency(spk_rx, target_ms, stats.clone())
+    }
+    None => Speaker::new(spk_rx, args.jitter_ms, stats.clone()),
+  };
+  speaker.set_underrun_fill(args.underrun_fill);
+  let spk_stream = build_speaker_stream(
+    &spk_device,
+    &spk_config,
+    speaker,
+    args.balance,
+    err_fn,
+  )
+  .unwrap_or_else(|e| {
+    error!("Failed to open the speaker: {e}");
+    std::process::exit(1);
+  });
+  spk_stream.play().unwrap();
 
-        let take = data.len().min(buf.len());
-
-        buf
-          .iter()
-          .enumerate()
-          .take(take)
-          .for_each(|(i, s)| data[i] = *s);
-        buf.drain(0..take);
-
-        // Couldn't fully satisfy the block: zero the tail, record it, and
-        // drop back into refilling so we rebuild a cushion before resuming
-        // rather than emitting a string of partially-filled blocks.
-        if take < data.len() {
-          if take == 0 {
-            underruns_cb.fetch_add(1, Ordering::Relaxed);
-          } else {
-            partial_cb.fetch_add(1, Ordering::Relaxed);
-          }
-          missing_cb.fetch_add((data.len() - take) as u64, Ordering::Relaxed);
-          for item in data[take..].iter_mut() {
-            *item = 0.0;
-          }
-          filling = true;
-        }
+  let fx_config = load_fx_config(&args);
+  let mut fx_unit = FxUnit::try_new(&fx_config).unwrap_or_else(|e| {
+    error!("Invalid FX settings: {e}");
+    std::process::exit(1);
+  });
+  *status.fx.lock().unwrap() = fx_config.clone();
+  // A unit of its own, so the sidetone never touches the receive chain's
+  // filter state or squelch tail.
+  let mut sidetone_fx =
+    args.sidetone_fx.then(|| FxUnit::from_config(&fx_config));
+  let send_fx = mic_fx(&args);
 
-        queue_cb.store(buf.len() as u64, Ordering::Relaxed);
-      },
-      err_fn,
-      None,
-    )
-    .unwrap();
-  spk_stream.play().unwrap();
+  let mut capture = args
+    .pcap_out
+    .as_ref()
+    .map(|path| CaptureWriter::create(path).unwrap());
 
   let ptt_ref = ptt.clone();
+  let whisper_ref = whisper.clone();
+  let whisper_to = args.whisper_to;
+  let tones = CourtesyTones {
+    rx: args.rx_tone,
+    tx: args.tx_tone,
+  };
+  let emergency_ref = emergency.clone();
+  let status_ref = status.clone();
+  let channel_ref = channel.clone();
+  // The echo queues up in the socket while we transmit, so make room for
+  // more than the OS default holds.
+  let buffer_kb = args
+    .udp_buffer_kb
+    .or(args.local_echo.then_some(LOCAL_ECHO_BUFFER_KB));
+  let socket = net::bind_source(args.bind, buffer_kb).unwrap_or_else(|e| {
+    error!("{e}");
+    std::process::exit(1);
+  });
   std::thread::spawn(move || {
     let mut buf = [0; MAX_PACKET_SIZE];
-    let mut fx_unit = FxUnit::new(args.no_fx, args.gain, args.distortion);
 
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
     socket.set_nonblocking(true).unwrap();
-    map_would_block(
-      socket.send_to(&postcard::to_allocvec(&Packet::Ping).unwrap(), address),
-    )
-    .unwrap();
+    // Everything goes out through here, so the stats overlay counts it.
+    let send = |packet: &Packet| {
+      let bytes = postcard::to_allocvec(packet).unwrap();
+      map_would_block(socket.send_to(&bytes, address)).unwrap();
+      NetStats::count(&status_ref.net.sent, bytes.len());
+    };
+    send(&Packet::Ping);
+    send(&Packet::Hello {
+      version: PROTOCOL_VERSION,
+    });
+    if let Some(level) = args.tx_level {
+      send(&Packet::Level(level));
+    }
+    // Compressed both ways once the server answers in kind.
+    #[cfg(feature = "opus")]
+    if args.opus {
+      send(&Packet::AcceptOpus);
+    }
+    #[cfg(feature = "opus")]
+    let mut opus_rx: Option<OpusDecoder> = None;
 
     let mut last_ptt = false;
     let mut do_squelch = false;
     let mut last_packet = Instant::now();
+    // The latest received buffer is held back one tick so the squelch tail
+    // can be crossfaded into it.
+    let mut held: Option<TxBuffer> = None;
+    // Received audio on its way to `held`, one buffer per `WAIT_DURATION`.
+    let mut jitter = JitterBuffer::new(args.jitter_depth as usize);
+    let mut jitter_pacer = Pacer::new(*WAIT_DURATION);
+    let mut plc = args.plc.then(Concealer::new);
     let mut mic_buf: Vec<f32> = Vec::with_capacity(TX_BUFFER_SIZE);
+    // Leave room for the pre-roll on top of the usual backlog.
+    let mut outgoing =
+      Outgoing::new(MAX_OUTGOING + pre_roll_len.div_ceil(TX_BUFFER_SIZE));
+    // Where the current transmission is going.
+    let mut target = Target::Channel;
+    let mut pacer = Pacer::new(*WAIT_DURATION);
+    // Set once the server stamps its audio, so it understands ours stamped.
+    let mut stamper: Option<Stamper> = None;
+    let mut dtx = args.dtx.then(Dtx::new);
+    let mut mic = Mic {
+      gain: args.mic_gain,
+      #[cfg(feature = "nr")]
+      nr: args.noise_reduction.then(NoiseReducer::new),
+      #[cfg(not(feature = "nr"))]
+      nr: None,
+      fx: send_fx,
+      agc: args.agc,
+    };
+    // Set while a DTX talker is sending silence markers: pauses are filled
+    // with comfort noise instead of ending in a squelch tail.
+    let mut dtx_gap = false;
+    let mut loss = LossMeter::default();
+    let mut decode_errors = 0;
+    let mut normalizer = args.normalize.map(Normalizer::new);
+    let mut clip_light = ClipLight::default();
+    // The server starts everyone on channel 0.
+    let mut joined: u16 = 0;
+    let mut last_audio = Instant::now();
+    let mut comfort_pacer = Pacer::new(*WAIT_DURATION);
+    let mut tot = args
+      .tot_secs
+      .map(|secs| TimeoutTimer::new(Duration::from_secs(secs)));
+    let ident = args
+      .ident
+      .as_deref()
+      .map(|call| morse::ident(call, args.ident_wpm, IDENT_TONE_HZ));
+    let mut next_ident = Instant::now();
+    let mut next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
     loop {
+      // Keep our place on the server while we're only listening.
+      if Instant::now() >= next_keepalive {
+        send(&Packet::Ping);
+        next_keepalive += KEEPALIVE_INTERVAL;
+      }
+
       // If PTT was just released, send white noise.
-      let new_ptt = ptt_ref.load(Ordering::SeqCst);
+      let mut new_ptt = ptt_ref.load(Ordering::SeqCst);
+      let mut clipped = false;
+
+      let channel = channel_ref.load(Ordering::SeqCst);
+      if channel != joined {
+        send(&Packet::Channel(channel));
+        joined = channel;
+        info!("Switched to channel {channel}");
+
+        // Cut off whatever was playing from the old channel.
+        held = None;
+        jitter.clear();
+        if let Some(plc) = plc.as_mut() {
+          plc.reset();
+        }
+        loss = LossMeter::default();
+        do_squelch = false;
+        dtx_gap = false;
+        status_ref.receiving.store(false, Ordering::Relaxed);
+      }
+
+      // Force PTT off when the time-out timer trips. The inputs only store
+      // on a change, so it stays off until the key is released and pressed
+      // again.
+      if let Some(tot) = tot.as_mut() {
+        let now = Instant::now();
+        if tot.update(new_ptt, now) {
+          warn!("Transmit time-out: release PTT to talk again.");
+          ptt_ref.store(false, Ordering::SeqCst);
+          new_ptt = false;
+          tot.update(false, now);
+          status_ref.timed_out.store(true, Ordering::Relaxed);
+        }
+        *status_ref.tot_remaining.lock().unwrap() = tot.remaining(now);
+      }
+
       if !new_ptt && last_ptt {
-        for chunk in fx_unit.squelch() {
+        // Flush what the mic captured before release.
+        mic_buf.extend(mic_rx.try_iter().flatten());
+        let captured = Instant::now();
+        clipped |= flush_mic(
+          &mut outgoing,
+          &mut mic_buf,
+          captured,
+          &mut mic,
+          target,
+          dtx.as_mut(),
+        );
+        #[cfg(feature = "opus")]
+        outgoing.flush_opus(captured);
+        // Like the ident, it goes out whole, past the queue limit.
+        if args.roger_beep {
+          outgoing.queue.extend(
+            mic
+              .fx
+              .roger_beep()
+              .into_iter()
+              .map(|buf| (audio_packet(buf, target), captured)),
+          );
+        }
+
+        for chunk in fx_unit.squelch_with(true) {
+          spk_tx.send(chunk).unwrap();
+        }
+        let timed_out = status_ref.timed_out.load(Ordering::Relaxed);
+        for chunk in tones.at_end(true, timed_out) {
           spk_tx.send(chunk).unwrap();
         }
       }
+      if new_ptt && !last_ptt {
+        status_ref.timed_out.store(false, Ordering::Relaxed);
+        // We stop listening while we talk, which would look like loss.
+        loss = LossMeter::default();
+        target = if emergency_ref.load(Ordering::SeqCst) {
+          Target::Emergency
+        } else {
+          match whisper_to.filter(|_| whisper_ref.load(Ordering::SeqCst)) {
+            Some(to) => Target::Whisper(to),
+            None => Target::Channel,
+          }
+        };
+        dtx = args.dtx.then(Dtx::new);
+        if let Some(fx) = sidetone_fx.as_mut() {
+          fx.reset();
+        }
+        for last in held.take().into_iter().chain(jitter.drain()) {
+          spk_tx.send(last).unwrap();
+        }
+      }
       last_ptt = new_ptt;
 
+      // Queue the ident once it's due and the channel is clear. It goes out
+      // like mic audio, so keying up trims it off the outgoing queue.
+      if let Some(ident) = &ident
+        && Instant::now() >= next_ident
+        && !new_ptt
+        && outgoing.is_empty()
+        && !do_squelch
+        && last_packet.elapsed() >= IDENT_HOLDOFF
+      {
+        info!("Sending ident");
+        let now = Instant::now();
+        outgoing
+          .queue
+          .extend(ident.iter().map(|buf| (Packet::Audio(*buf), now)));
+        next_ident = Instant::now() + Duration::from_secs(args.ident_interval);
+      }
+
+      // Keep draining after release so the flushed tail goes out too.
+      if !outgoing.is_empty() && pacer.ready() {
+        let (packet, captured) = outgoing.queue.pop_front().unwrap();
+        match (packet, stamper.as_mut()) {
+          (Packet::Audio(samples), Some(stamper)) => {
+            let (seq, micros) = stamper.stamp(captured);
+            send(&Packet::StampedAudio {
+              seq,
+              micros,
+              samples,
+            });
+          }
+          (packet, _) => send(&packet),
+        }
+      }
+
+      // Across a DTX pause, wait for the next marker before giving up.
+      let timeout =
+        WAIT_DURATION.mul_f32(if dtx_gap { SID_TIMEOUT as f32 } else { 7.0 });
+
       if ptt_ref.load(Ordering::SeqCst) {
         match mic_rx.try_recv() {
           Ok(new_samples) => {
             mic_buf.extend(new_samples);
+            let captured = Instant::now();
 
             let mut count = 0;
             for chunk in mic_buf.chunks_exact(TX_BUFFER_SIZE) {
-              let mut buf = [0f32; TX_BUFFER_SIZE];
-              buf.copy_from_slice(chunk);
-
-              for s in buf.iter_mut() {
-                *s *= args.mic_gain;
-                *s = s.clamp(-1.0, 1.0);
+              clipped |= queue_mic(
+                &mut outgoing,
+                chunk,
+                captured,
+                &mut mic,
+                target,
+                dtx.as_mut(),
+              );
+              if let Some(level) = args.sidetone {
+                spk_tx
+                  .send(sidetone(
+                    chunk,
+                    args.mic_gain * level,
+                    sidetone_fx.as_mut(),
+                  ))
+                  .unwrap();
               }
-
-              map_would_block(socket.send_to(
-                &postcard::to_allocvec(&Packet::Audio(buf)).unwrap(),
-                address,
-              ))
-              .unwrap();
-
               count += 1;
             }
             mic_buf.drain(0..count * TX_BUFFER_SIZE);
@@ -271,109 +904,1015 @@ fn main() {
             }
           },
         }
-      } else if socket.recv_from(&mut buf).is_ok() {
-        match postcard::from_bytes::<Packet>(&buf) {
-          Ok(packet) => match packet {
-            Packet::Ping => todo!(),
-            Packet::Audio(mut samples) => {
-              last_packet = Instant::now();
-              do_squelch = true;
+      } else if let Ok((size, _)) = socket.recv_from(&mut buf) {
+        NetStats::count(&status_ref.net.received, size);
+        // The client is usually killed rather than shut down, so flush each
+        // record as it's written.
+        if let Some(capture) = capture.as_mut()
+          && let Err(e) =
+            capture.write(&buf[..size]).and_then(|_| capture.flush())
+        {
+          warn!("Failed to write capture: {e}");
+        }
 
-              fx_unit.run(&mut samples);
-              spk_tx.send(samples).unwrap();
+        let packet = Packet::decode(&buf[..size]);
+        // One bad packet is noise; a steady stream of them is a server we
+        // can't talk to, so say so rather than warn about each forever.
+        if packet.is_err() {
+          decode_errors += 1;
+          if decode_errors == MAX_DECODE_ERRORS {
+            let reason = "can't decode anything the server sends; it's \
+                          probably running an incompatible squelch"
+              .to_owned();
+            error!("{reason}");
+            *status_ref.incompatible.lock().unwrap() = Some(reason);
+          }
+        } else {
+          if decode_errors >= MAX_DECODE_ERRORS {
+            info!("Decoding the server's packets again");
+            *status_ref.incompatible.lock().unwrap() = None;
+          }
+          decode_errors = 0;
+        }
+        // Emergency audio plays like any other, with the alarm raised.
+        if let Ok(Some(Packet::Emergency { .. })) = &packet {
+          let mut last = status_ref.emergency.lock().unwrap();
+          if last.is_none_or(|last| last.elapsed() >= EMERGENCY_HOLD) {
+            warn!("Emergency transmission!");
+          }
+          *last = Some(Instant::now());
+        }
+        if let Ok(Some(Packet::StampedAudio { .. })) = &packet
+          && stamper.is_none()
+        {
+          debug!("The server stamps its audio; stamping ours");
+          stamper = Some(Stamper::new());
+        }
+        #[cfg(feature = "opus")]
+        if let Ok(Some(Packet::AcceptOpus)) = &packet
+          && args.opus
+          && opus_rx.is_none()
+        {
+          match (OpusEncoder::new(), OpusDecoder::new()) {
+            (Ok(encoder), Ok(decoder)) => {
+              info!("The server takes Opus; compressing our audio");
+              outgoing.opus = Some(encoder);
+              opus_rx = Some(decoder);
+            }
+            (Err(e), _) | (_, Err(e)) => {
+              warn!("Failed to set up Opus: {e}");
+            }
+          }
+        }
+        // Opus frames decode to any number of buffers, each played like
+        // plain audio.
+        let packets = match packet {
+          #[cfg(feature = "opus")]
+          Ok(Some(Packet::OpusAudio { seq, frame })) => {
+            match opus_rx.as_mut() {
+              Some(decoder) => {
+                if let Err(e) = decoder.decode(seq, &frame) {
+                  warn!("Failed to decode Opus: {e}");
+                }
+                std::iter::from_fn(|| decoder.pop())
+                  .map(|samples| Ok(Some(Packet::Audio(samples))))
+                  .collect()
+              }
+              None => Vec::new(),
+            }
+          }
+          packet => vec![packet],
+        };
+        for packet in packets {
+          let seq = packet
+            .as_ref()
+            .ok()
+            .and_then(Option::as_ref)
+            .and_then(Packet::seq);
+          // Anything the server sends, ping replies included, shows it's
+          // there.
+          if let Ok(Some(_)) = packet {
+            *status_ref.last_packet.lock().unwrap() = Some(Instant::now());
+          }
+          match packet {
+            // Something from a newer server that we can't use.
+            Ok(None) => {}
+            Ok(Some(packet)) => match packet {
+              Packet::Ping
+              | Packet::DirectAudio { .. }
+              | Packet::Level(_)
+              | Packet::Channel(_)
+              | Packet::Hello { .. } => {
+                // Pings are the server answering ours, already counted above,
+                // and direct audio is mixed into our feed rather than
+                // forwarded; ignore both.
+              }
+              // Handled above, if we asked for Opus at all.
+              Packet::AcceptOpus | Packet::OpusAudio { .. } => {}
+              Packet::ServerInfo { fx } => {
+                if fx && !status_ref.server_fx.swap(true, Ordering::Relaxed) {
+                  info!("The server applies FX; turning ours off");
+                  fx_unit = FxUnit::new(true, 1.0, 0.0);
+                }
+              }
+              Packet::HelloAck { version } => {
+                if version < PROTOCOL_VERSION {
+                  info!(
+                    "The server speaks protocol {version}, older than ours \
+                     ({PROTOCOL_VERSION}); newer features are off"
+                  );
+                } else if version > PROTOCOL_VERSION {
+                  warn!(
+                    "The server speaks protocol {version}, newer than ours \
+                     ({PROTOCOL_VERSION}); consider updating"
+                  );
+                }
+                *status_ref.server_version.lock().unwrap() = Some(version);
+                // It can take our audio stamped from the start.
+                let stamped = Packet::StampedAudio {
+                  seq: 0,
+                  micros: 0,
+                  samples: [0.0; TX_BUFFER_SIZE],
+                };
+                if version >= stamped.min_version() && stamper.is_none() {
+                  stamper = Some(Stamper::new());
+                }
+              }
+              Packet::Rejected { reason } => {
+                error!("The server turned us away: {reason}");
+                *status_ref.rejected.lock().unwrap() = Some(reason);
+              }
+              Packet::Silence => {
+                // Only meaningful inside a transmission we're already hearing.
+                if do_squelch {
+                  last_packet = Instant::now();
+                  dtx_gap = true;
+                  loss.pause();
+                }
+              }
+              Packet::Audio(mut samples)
+              | Packet::Emergency { mut samples }
+              | Packet::SeqAudio { mut samples, .. }
+              | Packet::StampedAudio { mut samples, .. } => {
+                loss.audio(Instant::now(), seq);
+                // Below the squelch level counts as no signal: it plays out
+                // the hang time of an open squelch but never opens one.
+                if carrier(&samples, args.squelch_level) {
+                  // A new transmission gets a fresh level, and none of the
+                  // last one's filter ringing.
+                  if !do_squelch {
+                    if let Some(normalizer) = normalizer.as_mut() {
+                      normalizer.reset();
+                    }
+                    fx_unit.reset();
+                  }
+                  last_packet = Instant::now();
+                  last_audio = last_packet;
+                  do_squelch = true;
+                  status_ref.receiving.store(true, Ordering::Relaxed);
+                }
+
+                if do_squelch {
+                  if let Some(normalizer) = normalizer.as_mut() {
+                    normalizer.run(&mut samples);
+                  }
+                  // Once the server runs the FX, ours stay off whatever the
+                  // settings say.
+                  if !status_ref.server_fx.load(Ordering::Relaxed) {
+                    let fx = status_ref.fx.lock().unwrap();
+                    if status_ref.fx_reload.swap(false, Ordering::Relaxed) {
+                      fx_unit = FxUnit::from_config(&fx);
+                    } else {
+                      fx_unit.apply(&fx);
+                    }
+                  }
+                  fx_unit.run(&mut samples);
+                  // Numbered audio is put back in order; a packet that turns
+                  // up after its turn to play would only garble things.
+                  match seq {
+                    Some(seq) if !jitter.push_ordered(seq, samples) => {
+                      debug!("Dropped out-of-order buffer {seq}");
+                    }
+                    Some(_) => {}
+                    None => {
+                      if jitter.push(samples).is_some() {
+                        debug!("Jitter buffer overflowed; dropped a buffer");
+                      }
+                    }
+                  }
+                }
+              }
+            },
+            Err(err) => {
+              if decode_errors < MAX_DECODE_ERRORS {
+                warn!("Failed to decode packet: {err:?}");
+              }
             }
-          },
-          Err(err) => {
-            eprintln!("Failed to decode packet: {err:?}")
           }
         }
-      } else if do_squelch
-        && last_packet.elapsed() >= WAIT_DURATION.mul_f32(7.0)
+      } else if dtx_gap
+        && last_packet.elapsed() < timeout
+        && last_audio.elapsed() >= WAIT_DURATION.mul_f32(2.0)
       {
+        // Finish the speech before the noise starts.
+        for last in held.take().into_iter().chain(jitter.drain()) {
+          spk_tx.send(last).unwrap();
+        }
+        if comfort_pacer.ready() {
+          spk_tx.send(fx_unit.comfort_noise()).unwrap();
+        }
+      } else if do_squelch && last_packet.elapsed() >= timeout {
         do_squelch = false;
+        dtx_gap = false;
+        status_ref.receiving.store(false, Ordering::Relaxed);
+        if let Some((expected, received)) = loss.end() {
+          let net = &status_ref.net;
+          net.audio_expected.fetch_add(expected, Ordering::Relaxed);
+          net.audio_received.fetch_add(received, Ordering::Relaxed);
+        }
 
-        for chunk in fx_unit.squelch() {
+        if let Some(plc) = plc.as_mut() {
+          plc.reset();
+        }
+        // Play out anything still held back, e.g. a transmission shorter
+        // than the jitter buffer.
+        for samples in jitter.drain() {
+          if let Some(last) = held.replace(samples) {
+            spk_tx.send(last).unwrap();
+          }
+        }
+        let tail = match held.take() {
+          Some(mut last) => {
+            let tail = fx_unit.squelch_after(&mut last);
+            spk_tx.send(last).unwrap();
+            tail
+          }
+          None => fx_unit.squelch_with(true),
+        };
+        for chunk in tail {
+          spk_tx.send(chunk).unwrap();
+        }
+        for chunk in tones.at_end(false, false) {
           spk_tx.send(chunk).unwrap();
         }
       }
+
+      if jitter_pacer.ready() {
+        let samples = match jitter.pop_ready() {
+          Some(samples) => {
+            if let Some(plc) = plc.as_mut() {
+              plc.played(&samples);
+            }
+            Some(samples)
+          }
+          // Run dry mid-transmission, rather than in a DTX pause.
+          None if do_squelch && !dtx_gap => {
+            plc.as_mut().and_then(Concealer::conceal)
+          }
+          None => None,
+        };
+        if let Some(samples) = samples
+          && let Some(last) = held.replace(samples)
+        {
+          spk_tx.send(last).unwrap();
+        }
+      }
+      status_ref
+        .jitter_depth
+        .store(jitter.len(), Ordering::Relaxed);
+
+      clip_light.update(clipped, Instant::now(), &status_ref.clipping);
     }
   });
 
-  if let Some(key) = args.hotkey {
-    println!("Using hotkey.");
+  let debounce = Duration::from_millis(args.ptt_debounce_ms);
 
-    let code = Code::from_str(&key).unwrap();
+  // The hotkey is registered here on the main thread, but only listened for
+  // on its own thread, so it works alongside the GUI. Registration has to
+  // stay on the main thread: macOS only delivers hotkey events through the
+  // main thread's event loop, and on Windows they go to the message loop of
+  // the registering thread. eframe runs that loop for us below.
+  let keys = Hotkeys {
+    ptt: args.hotkey.clone(),
+    channel_up: args.channel_up_key,
+    channel_down: args.channel_down_key,
+    ptt_toggle: args.ptt_toggle,
+  };
+  let hotkeys: Vec<HotKey> = keys
+    .ptt
+    .iter()
+    .copied()
+    .chain(keys.channel_up)
+    .chain(keys.channel_down)
+    .collect();
+  let _manager = (!hotkeys.is_empty()).then(|| {
+    debug!("Using hotkeys.");
 
     let manager = GlobalHotKeyManager::new().unwrap();
-    let hotkey = HotKey::new(None, code);
-    manager.register(hotkey).unwrap();
-
-    loop {
-      if let Ok(event) = GlobalHotKeyEvent::receiver().try_recv()
-        && event.id == code as u32
-      {
-        match event.state {
-          global_hotkey::HotKeyState::Pressed => {
-            ptt.store(true, Ordering::SeqCst);
-          }
-          global_hotkey::HotKeyState::Released => {
-            ptt.store(false, Ordering::SeqCst);
-          }
-        }
+    for hotkey in hotkeys {
+      if let Err(e) = manager.register(hotkey) {
+        error!("Failed to register hotkey {hotkey}: {e}");
+        std::process::exit(1);
       }
     }
+
+    let ptt = ptt.clone();
+    let channel = channel.clone();
+    std::thread::spawn(move || listen_hotkeys(keys, &ptt, &channel, debounce));
+    manager
+  });
+
+  if args.no_gui {
+    if args.hotkey.is_empty() && !args.vox {
+      warn!("--no-gui without --hotkey or --vox can only listen");
+    }
+
+    let (stop_tx, stop_rx) = mpsc::channel();
+    ctrlc::set_handler(move || {
+      let _ = stop_tx.send(());
+    })
+    .expect("failed to set Ctrl+C handler");
+    info!("Running without a GUI; press Ctrl+C to exit.");
+
+    let _ = stop_rx.recv();
+    info!("Received Ctrl+C, exiting...");
+    return;
   }
 
-  let ptt_ref = ptt.clone();
+  let app = MyEguiApp {
+    ptt: ptt.clone(),
+    ptt_toggle: args.ptt_toggle,
+    channel,
+    debouncer: Debouncer::new(debounce),
+    whisper,
+    whisper_debouncer: Debouncer::new(debounce),
+    whisper_to: args.whisper_to,
+    emergency: args.emergency.then_some(emergency),
+    emergency_debouncer: Debouncer::new(debounce),
+    presets: Presets::new(
+      PresetDir::new(&args.preset_dir),
+      args.preset.is_none() && args.preset_file.is_none(),
+    ),
+    overlay: StatsOverlay::new(args.stats),
+    playback: stats,
+    status,
+    address,
+  };
   let native_options = eframe::NativeOptions::default();
   eframe::run_native(
     "Squelch",
     native_options,
-    Box::new(|cc| Ok(Box::new(MyEguiApp::new(cc, ptt_ref)))),
+    Box::new(move |_| Ok(Box::new(app))),
   )
   .unwrap();
 }
 
-/// Probe whether the requested fixed device period (`frames`) is accepted by
-/// both the input and output devices. Returns `Fixed(frames)` only if both
-/// accept it; otherwise warns and returns `Default` so we never panic on a
-/// backend that rejects the size (e.g. ALSA via PipeWire returning EINVAL).
-fn resolve_buffer_size(
-  mic_device: &cpal::Device,
-  spk_device: &cpal::Device,
-  frames: u32,
-) -> cpal::BufferSize {
+/// The global hotkeys in use.
+#[derive(Debug, Clone)]
+struct Hotkeys {
+  /// Any of these keys up.
+  ptt: Vec<HotKey>,
+  channel_up: Option<HotKey>,
+  channel_down: Option<HotKey>,
+  /// `--ptt-toggle`: `ptt` flips on each press instead of following the key.
+  ptt_toggle: bool,
+}
+
+/// Drives `ptt` (debounced by `debounce`) and `channel` from the global
+/// hotkeys. A latching PTT key flips `ptt` itself, so it also undoes the
+/// PTT button or a time-out, and ignores presses within `debounce` of the
+/// last.
+fn listen_hotkeys(
+  keys: Hotkeys,
+  ptt: &AtomicBool,
+  channel: &AtomicU16,
+  debounce: Duration,
+) {
+  let is = |key: Option<HotKey>, id: u32| key.is_some_and(|key| key.id() == id);
+  let is_ptt = |id: u32| keys.ptt.iter().any(|key| key.id() == id);
+  let mut debouncer = Debouncer::new(debounce);
+  let mut last_toggle: Option<Instant> = None;
+  // PTT keys held down; PTT stays up until the last is released.
+  let mut held: Vec<u32> = Vec::new();
+  loop {
+    let last = debouncer.state();
+    // Wake up now and then even without events, to apply pending changes.
+    if let Ok(event) =
+      GlobalHotKeyEvent::receiver().recv_timeout(Duration::from_millis(5))
+    {
+      let pressed = event.state == global_hotkey::HotKeyState::Pressed;
+      let now = Instant::now();
+      if is_ptt(event.id) && keys.ptt_toggle {
+        if pressed && last_toggle.is_none_or(|last| now - last >= debounce) {
+          ptt.fetch_xor(true, Ordering::SeqCst);
+          last_toggle = Some(now);
+        }
+      } else if is_ptt(event.id) {
+        held.retain(|&id| id != event.id);
+        if pressed {
+          held.push(event.id);
+        }
+        debouncer.set(!held.is_empty(), now);
+      } else if is(keys.channel_up, event.id) && pressed {
+        let _ = channel.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| {
+          c.checked_add(1)
+        });
+      } else if is(keys.channel_down, event.id) && pressed {
+        let _ = channel.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| {
+          c.checked_sub(1)
+        });
+      }
+    }
+
+    let state = debouncer.poll(Instant::now());
+    if state != last {
+      ptt.store(state, Ordering::SeqCst);
+    }
+  }
+}
+
+/// Where a transmission goes, picked when PTT goes down.
+#[derive(Debug, Clone, Copy)]
+enum Target {
+  Channel,
+  Whisper(ClientId),
+  /// Every channel, if the server allows it.
+  Emergency,
+}
+
+/// Mic audio waiting to be sent, one buffer per `WAIT_DURATION`, each with
+/// when it came off the mic.
+struct Outgoing {
+  queue: VecDeque<(Packet, Instant)>,
+  max: usize,
+  /// Compresses channel audio, once the server takes Opus.
+  #[cfg(feature = "opus")]
+  opus: Option<OpusEncoder>,
+}
+
+impl Outgoing {
+  fn new(max: usize) -> Self {
+    Self {
+      queue: VecDeque::new(),
+      max,
+      #[cfg(feature = "opus")]
+      opus: None,
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.queue.is_empty()
+  }
+
+  /// Drops the oldest buffer once more than `max` are waiting, to keep
+  /// latency bounded.
+  fn push(&mut self, packet: Packet, captured: Instant) {
+    #[cfg(feature = "opus")]
+    let packet = match (packet, self.opus.as_mut()) {
+      (Packet::Audio(samples), Some(encoder)) => {
+        match encoder.encode(&samples) {
+          Ok(Some(packet)) => packet,
+          Ok(None) => return,
+          Err(e) => {
+            warn!("Failed to encode Opus: {e}");
+            return;
+          }
+        }
+      }
+      (packet, _) => packet,
+    };
+    self.queue.push_back((packet, captured));
+    while self.queue.len() > self.max {
+      self.queue.pop_front();
+    }
+  }
+
+  /// Sends the rest of a partial Opus frame at the end of a transmission.
+  #[cfg(feature = "opus")]
+  fn flush_opus(&mut self, captured: Instant) {
+    let Some(encoder) = self.opus.as_mut() else {
+      return;
+    };
+    match encoder.flush() {
+      Ok(Some(packet)) => self.push(packet, captured),
+      Ok(None) => {}
+      Err(e) => warn!("Failed to encode Opus: {e}"),
+    }
+  }
+}
+
+/// Everything the mic goes through before it's sent.
+struct Mic {
+  gain: f32,
+  nr: Option<NoiseReducer>,
+  /// The send side of the FX: the gate, AGC and roger beep.
+  fx: FxUnit,
+  agc: bool,
+}
+
+impl Mic {
+  /// Applies the gain to up to one buffer of mic samples, then denoises,
+  /// gates and levels it if asked. The gate comes before the AGC, so the
+  /// AGC never boosts the noise it closed on. A short final chunk is
+  /// zero-padded to a whole buffer, but the padding doesn't go through the
+  /// gate or AGC, so it can't close one or swing the other. Also returns
+  /// whether any sample had to be clamped.
+  fn process(&mut self, chunk: &[f32]) -> (TxBuffer, bool) {
+    let len = chunk.len();
+    let mut buf = [0f32; TX_BUFFER_SIZE];
+    buf[..len].copy_from_slice(chunk);
+
+    let mut clipped = false;
+    for s in buf[..len].iter_mut() {
+      *s *= self.gain;
+      clipped |= s.abs() > 1.0;
+      *s = s.clamp(-1.0, 1.0);
+    }
+    if let Some(nr) = self.nr.as_mut() {
+      nr.process(&mut buf);
+    }
+    self.fx.run_mic(&mut buf[..len]);
+    if self.agc {
+      self.fx.agc(&mut buf[..len]);
+    }
+
+    (buf, clipped)
+  }
+}
+
+/// A rolling window of the most recent mic audio while PTT is up, bounded to
+/// `--pre-roll-ms`, which goes out ahead of the live audio on key-up.
+struct PreRoll {
+  samples: VecDeque<f32>,
+  len: usize,
+}
+
+impl PreRoll {
+  fn new(len: usize) -> Self {
+    Self {
+      samples: VecDeque::with_capacity(len),
+      len,
+    }
+  }
+
+  /// Takes a block from the mic. While `transmitting`, returns what to send:
+  /// anything held back, then the block. Otherwise keeps the most recent
+  /// samples and returns nothing.
+  fn next(&mut self, data: &[f32], transmitting: bool) -> Option<Vec<f32>> {
+    if transmitting {
+      let mut samples: Vec<f32> = self.samples.drain(..).collect();
+      samples.extend(data);
+      return Some(samples);
+    }
+
+    self.samples.extend(data);
+    if self.samples.len() > self.len {
+      self.samples.drain(..self.samples.len() - self.len);
+    }
+    None
+  }
+}
+
+/// Whether received `samples` open the squelch: with a `--squelch-level`,
+/// only if their RMS level reaches it; without one, always.
+fn carrier(samples: &[f32], level: Option<f32>) -> bool {
+  level.is_none_or(|level| {
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>()
+      / samples.len() as f32)
+      .sqrt();
+    rms >= level
+  })
+}
+
+/// Lights CLIP while the mic is clipping, and for `CLIP_HOLD` after so it
+/// can be seen.
+#[derive(Default)]
+struct ClipLight {
+  last: Option<Instant>,
+}
+
+impl ClipLight {
+  /// Notes whether the mic clipped at `now`, lighting or putting out `lit`.
+  /// Only logs when it starts.
+  fn update(&mut self, clipped: bool, now: Instant, lit: &AtomicBool) {
+    if clipped {
+      if self.last.is_none() {
+        warn!("Mic is clipping; try a lower --mic-gain.");
+      }
+      self.last = Some(now);
+      lit.store(true, Ordering::Relaxed);
+    } else if self
+      .last
+      .is_some_and(|last| now.saturating_duration_since(last) >= CLIP_HOLD)
+    {
+      self.last = None;
+      lit.store(false, Ordering::Relaxed);
+    }
+  }
+}
+
+/// The FX unit the mic goes through, gated as `args` ask, which also makes
+/// the roger beep.
+fn mic_fx(args: &Cli) -> FxUnit {
+  let mut fx = FxUnit::from_config(&FxConfig::default());
+  fx.set_roger_beep(args.roger_freq, Duration::from_millis(args.roger_ms));
+  if let Some(threshold) = args.gate_threshold {
+    fx.set_gate(
+      threshold,
+      Duration::from_millis(args.gate_attack_ms),
+      Duration::from_millis(args.gate_release_ms),
+    );
+  }
+  fx
+}
+
+/// Runs up to one buffer of mic samples, captured at `captured`, through
+/// `mic` and queues it for sending to `to`. With `dtx`, silent buffers become
+/// occasional silence markers (or nothing, when whispering); emergencies
+/// always send audio. Returns whether any sample had to be clamped.
+fn queue_mic(
+  outgoing: &mut Outgoing,
+  chunk: &[f32],
+  captured: Instant,
+  mic: &mut Mic,
+  to: Target,
+  dtx: Option<&mut Dtx>,
+) -> bool {
+  let (buf, clipped) = mic.process(chunk);
+
+  let frame = dtx.map_or(DtxFrame::Speech, |dtx| dtx.next(&buf));
+  let packet = match (frame, to) {
+    (DtxFrame::Speech, _) | (_, Target::Emergency) => audio_packet(buf, to),
+    (DtxFrame::Silence, Target::Channel) => Packet::Silence,
+    (DtxFrame::Silence, Target::Whisper(_)) | (DtxFrame::Skip, _) => {
+      return clipped;
+    }
+  };
+  outgoing.push(packet, captured);
+
+  clipped
+}
+
+/// Queues everything left in `mic_buf` like [`queue_mic`], then empties it.
+/// The final partial buffer is zero-padded rather than dropped, so the end
+/// of the last word isn't cut off. Returns whether any sample had to be
+/// clamped.
+fn flush_mic(
+  outgoing: &mut Outgoing,
+  mic_buf: &mut Vec<f32>,
+  captured: Instant,
+  mic: &mut Mic,
+  to: Target,
+  mut dtx: Option<&mut Dtx>,
+) -> bool {
+  let mut clipped = false;
+  for chunk in mic_buf.chunks(TX_BUFFER_SIZE) {
+    clipped |=
+      queue_mic(outgoing, chunk, captured, mic, to, dtx.as_deref_mut());
+  }
+  mic_buf.clear();
+  clipped
+}
+
+/// The packet that carries `samples` to `to`.
+fn audio_packet(samples: TxBuffer, to: Target) -> Packet {
+  match to {
+    Target::Emergency => Packet::Emergency { samples },
+    Target::Whisper(to) => Packet::DirectAudio { to, samples },
+    Target::Channel => Packet::Audio(samples),
+  }
+}
+
+/// Without the `nr` feature there's no noise reduction to run; this stands in
+/// so the mic path reads the same either way.
+#[cfg(not(feature = "nr"))]
+struct NoiseReducer;
+
+#[cfg(not(feature = "nr"))]
+impl NoiseReducer {
+  fn process(&mut self, _: &mut TxBuffer) {}
+}
+
+/// One buffer of `--sidetone`: the mic at `gain`, run through `fx` if given.
+fn sidetone(chunk: &[f32], gain: f32, fx: Option<&mut FxUnit>) -> TxBuffer {
+  let mut buf = [0f32; TX_BUFFER_SIZE];
+  for (b, s) in buf.iter_mut().zip(chunk) {
+    *b = (s * gain).clamp(-1.0, 1.0);
+  }
+  if let Some(fx) = fx {
+    fx.process_slice(&mut buf[..chunk.len()]);
+  }
+  buf
+}
+
+/// The warning beep for a transmit time-out.
+fn tot_tone() -> Vec<TxBuffer> {
+  beep(TOT_TONE_HZ, TOT_TONE_BUFFERS)
+}
+
+/// The `--rx-tone` and `--tx-tone` courtesy tones, in Hz.
+struct CourtesyTones {
+  rx: Option<f32>,
+  tx: Option<f32>,
+}
+
+impl CourtesyTones {
+  /// The cue played when a transmission ends: for our own, the time-out
+  /// beep if it was cut off, otherwise the tx tone; for someone else's, the
+  /// rx tone. Empty if that tone is off.
+  fn at_end(&self, ours: bool, timed_out: bool) -> Vec<TxBuffer> {
+    if ours && timed_out {
+      return tot_tone();
+    }
+    let hz = if ours { self.tx } else { self.rx };
+    hz.map(|hz| beep(hz, COURTESY_TONE_BUFFERS))
+      .unwrap_or_default()
+  }
+}
+
+/// Records from the mic for a few seconds and suggests a mic gain that puts
+/// speech at a healthy level without hitting the clamp.
+fn calibrate(
+  ptt: &AtomicBool,
+  mic_rx: &mpsc::Receiver<Vec<f32>>,
+  mic_gain: f32,
+) {
+  println!("Calibrating: speak normally for {CALIBRATE_SECS} seconds...");
+
+  // The mic callback only forwards audio while PTT is held.
+  ptt.store(true, Ordering::SeqCst);
+  let deadline = Instant::now() + Duration::from_secs(CALIBRATE_SECS);
+  let mut samples = Vec::new();
+  while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+    if let Ok(data) = mic_rx.recv_timeout(remaining) {
+      samples.extend(data);
+    }
+  }
+  ptt.store(false, Ordering::SeqCst);
+
+  let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+  let rms = (samples.iter().map(|s| s * s).sum::<f32>()
+    / samples.len().max(1) as f32)
+    .sqrt();
+  if peak == 0.0 {
+    error!("No audio captured from the mic; is it muted?");
+    std::process::exit(1);
+  }
+
+  let db = |level: f32| 20.0 * level.log10();
+  println!("Peak: {peak:.3} ({:.1} dBFS)", db(peak));
+  println!("RMS:  {rms:.3} ({:.1} dBFS)", db(rms));
+
+  let gain = (CALIBRATE_TARGET_RMS / rms).min(CALIBRATE_TARGET_PEAK / peak);
+  println!("Recommended: --mic-gain {gain:.2} (currently {mic_gain})");
+}
+
+/// Parses a hotkey: a key name, e.g. `Space` or `F13`, optionally after
+/// modifiers, e.g. `Ctrl+Shift+KeyP`.
+fn parse_hotkey(s: &str) -> Result<HotKey, String> {
+  HotKey::from_str(s).or_else(|e| {
+    // Plain key names the combo parser doesn't know.
+    Code::from_str(s)
+      .map(|code| HotKey::new(None, code))
+      .map_err(|_| e.to_string())
+  })
+}
+
+fn parse_preset(name: &str) -> Result<FxConfig, String> {
+  FxConfig::preset(name).ok_or_else(|| {
+    format!(
+      "unknown preset {name:?} (expected one of: {})",
+      FxConfig::PRESETS.join(", ")
+    )
+  })
+}
+
+fn parse_wpm(s: &str) -> Result<f32, String> {
+  match s.parse::<f32>() {
+    Ok(wpm) if wpm >= 1.0 => Ok(wpm),
+    _ => Err(format!("expected a speed of at least 1 wpm, got {s:?}")),
+  }
+}
+
+/// Resolves the FX settings from the preset (file) and any CLI overrides.
+fn load_fx_config(args: &Cli) -> FxConfig {
+  let mut config = match &args.preset_file {
+    Some(path) => std::fs::read_to_string(path)
+      .map_err(|e| e.to_string())
+      .and_then(|text| toml::from_str(&text).map_err(|e| e.to_string()))
+      .unwrap_or_else(|e| {
+        error!("Failed to load preset file {}: {e}", path.display());
+        std::process::exit(1);
+      }),
+    None => args
+      .preset
+      .clone()
+      .or_else(|| {
+        let presets = PresetDir::new(&args.preset_dir);
+        let name = presets.last()?;
+        presets
+          .load(&name)
+          .inspect(|_| info!("Using preset {name:?}"))
+          .inspect_err(|e| warn!("Failed to load preset {name:?}: {e}"))
+          .ok()
+      })
+      .unwrap_or_default(),
+  };
+
+  if args.no_fx {
+    config.disabled = true;
+  }
+  if let Some(distortion) = args.distortion {
+    config.distortion = distortion;
+  }
+  if let Some(kind) = args.distortion_type {
+    config.distortion_kind = kind;
+  }
+  if let Some(gain) = args.gain {
+    config.signal_gain = gain;
+  }
+  if let Some(floor) = args.noise_floor {
+    config.noise_floor = floor;
+  }
+  if let Some(hz) = args.ring_mod {
+    config.ring_mod_hz = hz;
+  }
+  if let Some(hz) = args.lowpass {
+    config.lowpass_hz = hz;
+  }
+  if let Some(hz) = args.highpass {
+    config.highpass_hz = hz;
+  }
+  if let Some(tail) = args.squelch_tail {
+    config.squelch_tail = tail;
+  }
+  if !args.eq.is_empty() {
+    if args.eq.len() > MAX_EQ_BANDS {
+      error!("At most {MAX_EQ_BANDS} --eq bands are supported");
+      std::process::exit(1);
+    }
+    config.eq = args.eq.clone();
+  }
+
+  config
+}
+
+/// Receive buffer for `--local-echo`, enough for a ~30 s transmission.
+const LOCAL_ECHO_BUFFER_KB: usize = 8192;
+
+/// Starts an in-process server for `--local-echo` and returns its address.
+/// It runs on a background thread, so it goes away with the client.
+fn start_local_echo() -> std::io::Result<SocketAddr> {
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+  let address = socket.local_addr()?;
+  let server = Server::new(
+    socket,
+    ServerConfig {
+      loopback: true,
+      ..Default::default()
+    },
+  );
+  std::thread::spawn(move || {
+    if let Err(e) = server.run() {
+      error!("Local echo server stopped: {e}");
+    }
+  });
+
+  info!("Local echo server on {address}; you'll hear yourself back");
+  Ok(address)
+}
+
+/// How long `--check` waits for the server to answer a ping.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Preflight for `--check`: prints one pass/fail line per check and returns
+/// whether they all passed.
+fn run_check(args: &Cli, host: &cpal::Host, address: SocketAddr) -> bool {
+  let noop_err = |_err| {};
+
+  let input = device::select_device(host, args.input_device.as_deref(), true)
+    .ok_or_else(|| "no input device".to_string())
+    .and_then(|device| {
+      let config = cpal::StreamConfig {
+        channels: 1,
+        sample_rate: cpal::SampleRate(SAMPLE_RATE),
+        buffer_size: cpal::BufferSize::Default,
+      };
+      device::build_input_stream(&device, &config, |_| {}, noop_err)
+        .map(drop)
+        .map_err(|e| e.to_string())?;
+      Ok(device.name().unwrap_or_default())
+    });
+  // Opened just as the speaker is: at the device's own rate, channels and
+  // sample format.
+  let output =
+    device::select_device(host, args.output_device.as_deref(), false)
+      .ok_or_else(|| "no output device".to_string())
+      .and_then(|device| {
+        let config = cpal::StreamConfig {
+          channels: device
+            .default_output_config()
+            .map(|config| config.channels())
+            .unwrap_or(1),
+          sample_rate: cpal::SampleRate(device::output_rate(&device)),
+          buffer_size: cpal::BufferSize::Default,
+        };
+        device::build_output_stream(&device, &config, |_| {}, noop_err)
+          .map(drop)
+          .map_err(|e| e.to_string())?;
+        Ok(format!(
+          "{} at {} Hz",
+          device.name().unwrap_or_default(),
+          config.sample_rate.0
+        ))
+      });
+  let fx = load_fx_config(args).validate().map(|_| "ok".to_string());
+  let server = ping_server(address, args.bind, args.udp_buffer_kb);
+
+  let results = [
+    ("Input device", input),
+    ("Output device", output),
+    ("FX settings", fx),
+    ("Server", server),
+  ];
+  for (name, result) in &results {
+    match result {
+      Ok(detail) => println!("\x1b[32m✔\x1b[0m {name}: {detail}"),
+      Err(e) => println!("\x1b[31m✘\x1b[0m {name}: {e}"),
+    }
+  }
+
+  results.iter().all(|(_, result)| result.is_ok())
+}
+
+/// Pings `address` and waits up to `CHECK_TIMEOUT` for any reply.
+fn ping_server(
+  address: SocketAddr,
+  bind: Option<SocketAddr>,
+  udp_buffer_kb: Option<usize>,
+) -> Result<String, String> {
+  let socket =
+    net::bind_source(bind, udp_buffer_kb).map_err(|e| e.to_string())?;
+  socket
+    .set_read_timeout(Some(CHECK_TIMEOUT))
+    .map_err(|e| e.to_string())?;
+
+  let started = Instant::now();
+  socket
+    .send_to(&postcard::to_allocvec(&Packet::Ping).unwrap(), address)
+    .map_err(|e| e.to_string())?;
+
+  let mut buf = [0; MAX_PACKET_SIZE];
+  match socket.recv_from(&mut buf) {
+    Ok(_) => Ok(format!(
+      "{address} answered in {} ms",
+      started.elapsed().as_millis()
+    )),
+    Err(_) => Err(format!(
+      "no answer from {address} within {}s",
+      CHECK_TIMEOUT.as_secs()
+    )),
+  }
+}
+
+/// Probe whether the requested fixed device period (`frames`) is accepted by
+/// both the input and output devices. Returns `Fixed(frames)` only if both
+/// accept it; otherwise warns and returns `Default` so we never panic on a
+/// backend that rejects the size (e.g. ALSA via PipeWire returning EINVAL).
+fn resolve_buffer_size(
+  mic_device: &cpal::Device,
+  spk_device: &cpal::Device,
+  frames: u32,
+) -> cpal::BufferSize {
   if frames == 0 {
     return cpal::BufferSize::Default;
   }
 
   let cfg = cpal::StreamConfig {
     channels: 1,
-    sample_rate: cpal::SampleRate(44100),
+    sample_rate: cpal::SampleRate(SAMPLE_RATE),
     buffer_size: cpal::BufferSize::Fixed(frames),
   };
   let noop_err = |_err| {};
 
-  let out_probe = spk_device
-    .build_output_stream(&cfg, |_: &mut [f32], _: &_| {}, noop_err, None)
-    .map(drop);
-  let in_probe = mic_device
-    .build_input_stream(&cfg, |_: &[f32], _: &_| {}, noop_err, None)
-    .map(drop);
+  let out_probe =
+    device::build_output_stream(spk_device, &cfg, |_| {}, noop_err).map(drop);
+  let in_probe =
+    device::build_input_stream(mic_device, &cfg, |_| {}, noop_err).map(drop);
 
   if out_probe.is_ok() && in_probe.is_ok() {
-    println!("Using fixed device period of {frames} frames.");
+    debug!("Using fixed device period of {frames} frames.");
     return cpal::BufferSize::Fixed(frames);
   }
 
   if let Err(e) = out_probe {
-    eprintln!(
+    warn!(
       "warning: output device rejected --frames {frames} ({e}); \
        falling back to backend default period"
     );
   }
   if let Err(e) = in_probe {
-    eprintln!(
+    warn!(
       "warning: input device rejected --frames {frames} ({e}); \
        falling back to backend default period"
     );
@@ -381,28 +1920,731 @@ fn resolve_buffer_size(
   cpal::BufferSize::Default
 }
 
+/// State published by the network thread for the GUI.
+#[derive(Default)]
+struct Status {
+  /// A transmission is coming in (cleared when its squelch tail plays).
+  receiving: AtomicBool,
+  last_packet: Mutex<Option<Instant>>,
+  /// Transmit time left before the time-out timer trips, while talking.
+  tot_remaining: Mutex<Option<Duration>>,
+  /// The last transmission was cut off by the time-out timer.
+  timed_out: AtomicBool,
+  /// The mic clipped within the last `CLIP_HOLD`.
+  clipping: AtomicBool,
+  /// Why the server turned us away, if it did.
+  rejected: Mutex<Option<String>>,
+  /// Why we can't make sense of the server, if we can't.
+  incompatible: Mutex<Option<String>>,
+  /// The protocol the server said it speaks, if it's new enough to say.
+  server_version: Mutex<Option<u16>>,
+  /// The server runs the FX, so ours are off.
+  server_fx: AtomicBool,
+  /// When emergency audio last came in.
+  emergency: Mutex<Option<Instant>>,
+  /// The receive FX settings. The network thread takes up any change to the
+  /// live ones (see [`FxUnit::apply`]) before the next buffer it plays.
+  fx: Mutex<FxConfig>,
+  net: NetStats,
+  /// Buffers held in the jitter buffer, gaps included.
+  jitter_depth: AtomicUsize,
+  /// Set when `fx` has been replaced wholesale, e.g. by loading a preset,
+  /// so the network thread rebuilds its unit rather than retuning it.
+  fx_reload: AtomicBool,
+}
+
+/// The window's preset picker and "Save preset" controls.
+struct Presets {
+  dir: PresetDir,
+  names: Vec<String>,
+  /// The preset in use, if it came from `dir`.
+  selected: Option<String>,
+  /// Name to save under.
+  name: String,
+  /// A name that's taken, which the next save overwrites if unchanged.
+  overwrite: Option<String>,
+  message: Option<String>,
+}
+
+impl Presets {
+  /// `from_dir` says whether the FX settings came from the last preset
+  /// picked in `dir`, rather than `--preset` or `--preset-file`.
+  fn new(dir: PresetDir, from_dir: bool) -> Self {
+    let selected = from_dir.then(|| dir.last()).flatten();
+    Self {
+      names: dir.list(),
+      name: selected.clone().unwrap_or_default(),
+      selected,
+      dir,
+      overwrite: None,
+      message: None,
+    }
+  }
+
+  fn show(&mut self, ui: &mut egui::Ui, status: &Status) {
+    ui.horizontal(|ui| {
+      let mut picked = None;
+      egui::ComboBox::from_label("Preset")
+        .selected_text(self.selected.as_deref().unwrap_or("(none)"))
+        .show_ui(ui, |ui| {
+          for name in &self.names {
+            let selected = self.selected.as_ref() == Some(name);
+            if ui.selectable_label(selected, name).clicked() {
+              picked = Some(name.clone());
+            }
+          }
+        });
+      if let Some(name) = picked {
+        self.load(&name, status);
+      }
+      // Pick up presets saved by hand or by another client.
+      if ui.button("Refresh").clicked() {
+        self.names = self.dir.list();
+      }
+    });
+
+    ui.horizontal(|ui| {
+      ui.text_edit_singleline(&mut self.name);
+      let name = self.name.trim().to_owned();
+      let overwrite = self.overwrite.as_ref() == Some(&name);
+      let label = if overwrite {
+        "Overwrite"
+      } else {
+        "Save preset"
+      };
+      if ui.button(label).clicked() {
+        self.save(&name, overwrite, status);
+      }
+    });
+
+    if let Some(message) = &self.message {
+      ui.label(message);
+    }
+  }
+
+  fn load(&mut self, name: &str, status: &Status) {
+    match self.dir.load(name) {
+      Ok(config) => {
+        *status.fx.lock().unwrap() = config;
+        status.fx_reload.store(true, Ordering::Relaxed);
+        self.selected = Some(name.to_owned());
+        self.name = name.to_owned();
+        self.remember(name);
+        self.message = Some(format!("Loaded {name:?}"));
+      }
+      Err(e) => self.message = Some(e),
+    }
+  }
+
+  fn save(&mut self, name: &str, overwrite: bool, status: &Status) {
+    if !overwrite && self.dir.exists(name) {
+      self.overwrite = Some(name.to_owned());
+      self.message = Some(format!("{name:?} already exists"));
+      return;
+    }
+
+    let config = status.fx.lock().unwrap().clone();
+    match self.dir.save(name, &config, overwrite) {
+      Ok(()) => {
+        self.names = self.dir.list();
+        self.selected = Some(name.to_owned());
+        self.remember(name);
+        self.message =
+          Some(format!("Saved {name:?} to {}", self.dir.path().display()));
+      }
+      Err(e) => self.message = Some(e),
+    }
+    self.overwrite = None;
+  }
+
+  /// Makes `name` the preset loaded next time.
+  fn remember(&self, name: &str) {
+    if let Err(e) = self.dir.set_last(name) {
+      warn!("Failed to remember preset {name:?}: {e}");
+    }
+  }
+}
+
+/// Running totals from the network thread, for the stats overlay.
+#[derive(Default)]
+struct NetStats {
+  /// Packets and bytes sent.
+  sent: [AtomicU64; 2],
+  /// Packets and bytes received.
+  received: [AtomicU64; 2],
+  /// Audio packets finished transmissions should have brought, and how many
+  /// they did (see [`LossMeter`]).
+  audio_expected: AtomicU64,
+  audio_received: AtomicU64,
+}
+
+impl NetStats {
+  fn count([packets, bytes]: &[AtomicU64; 2], size: usize) {
+    packets.fetch_add(1, Ordering::Relaxed);
+    bytes.fetch_add(size as u64, Ordering::Relaxed);
+  }
+}
+
+/// Estimates loss on received audio. Numbered audio gives it exactly: a
+/// transmission should bring every number from its first to its last. Older
+/// servers don't number it, but send one buffer per tick while anyone talks,
+/// so a transmission should bring one packet per `WAIT_DURATION` it lasts.
+#[derive(Default)]
+struct LossMeter {
+  first: Option<Instant>,
+  last: Option<Instant>,
+  packets: u64,
+  /// The first and newest sequence numbers, unless some audio had none.
+  seqs: Option<(u32, u32)>,
+  unnumbered: bool,
+  /// DTX pauses are meant to be gaps, so when timing, transmissions with
+  /// them are left out.
+  paused: bool,
+}
+
+impl LossMeter {
+  fn audio(&mut self, now: Instant, seq: Option<u32>) {
+    self.first.get_or_insert(now);
+    self.last = Some(now);
+    self.packets += 1;
+
+    let Some(seq) = seq else {
+      self.unnumbered = true;
+      return;
+    };
+    let (first, newest) = self.seqs.get_or_insert((seq, seq));
+    // Late packets from before the newest don't move it, wrapping included.
+    let ahead = seq.wrapping_sub(*first);
+    if ahead < u32::MAX / 2 && ahead > newest.wrapping_sub(*first) {
+      *newest = seq;
+    }
+  }
+
+  fn pause(&mut self) {
+    self.paused = true;
+  }
+
+  /// Ends the transmission, returning how many packets it should have
+  /// brought and how many it did.
+  fn end(&mut self) -> Option<(u64, u64)> {
+    let meter = std::mem::take(self);
+    if let Some((first, newest)) = meter.seqs
+      && !meter.unnumbered
+    {
+      let expected = newest.wrapping_sub(first) as u64 + 1;
+      return Some((expected.max(meter.packets), meter.packets));
+    }
+    if meter.paused {
+      return None;
+    }
+
+    let length = meter.last? - meter.first?;
+    let expected =
+      (length.as_secs_f64() / WAIT_DURATION.as_secs_f64()).round() as u64 + 1;
+    Some((expected.max(meter.packets), meter.packets))
+  }
+}
+
+/// The stats overlay's figures, worked out every `STATS_INTERVAL`.
+struct StatsOverlay {
+  shown: bool,
+  at: Instant,
+  /// Packets and bytes sent and received, as of `at`.
+  totals: [u64; 4],
+  /// The same, per second.
+  rates: [f64; 4],
+}
+
+impl StatsOverlay {
+  fn new(shown: bool) -> Self {
+    Self {
+      shown,
+      at: Instant::now(),
+      totals: [0; 4],
+      rates: [0.0; 4],
+    }
+  }
+
+  fn update(&mut self, net: &NetStats) {
+    let elapsed = self.at.elapsed();
+    if elapsed < STATS_INTERVAL {
+      return;
+    }
+
+    let totals = [&net.sent, &net.received]
+      .map(|[packets, bytes]| {
+        [packets, bytes].map(|count| count.load(Ordering::Relaxed))
+      })
+      .concat();
+    for ((rate, last), total) in self
+      .rates
+      .iter_mut()
+      .zip(self.totals.iter_mut())
+      .zip(totals)
+    {
+      *rate = (total - *last) as f64 / elapsed.as_secs_f64();
+      *last = total;
+    }
+    self.at = Instant::now();
+  }
+
+  fn show(
+    &self,
+    ctx: &egui::Context,
+    status: &Status,
+    playback: &PlaybackStats,
+  ) {
+    let net = &status.net;
+    let [sent_pps, sent_bps, received_pps, received_bps] = self.rates;
+    let expected = net.audio_expected.load(Ordering::Relaxed);
+    let received = net.audio_received.load(Ordering::Relaxed);
+    let loss = match expected {
+      0 => "n/a".to_owned(),
+      _ => format!(
+        "{:.1}%",
+        100.0 * expected.saturating_sub(received) as f64 / expected as f64
+      ),
+    };
+
+    egui::Window::new("Network (F3)")
+      .resizable(false)
+      .show(ctx, |ui| {
+        ui.monospace(format!(
+          "Out: {sent_pps:>5.0} pkt/s {:>7.1} kbit/s",
+          sent_bps * 8.0 / 1000.0
+        ));
+        ui.monospace(format!(
+          "In:  {received_pps:>5.0} pkt/s {:>7.1} kbit/s",
+          received_bps * 8.0 / 1000.0
+        ));
+        ui.monospace(format!("Loss: {loss} (estimated)"));
+        let depth = status.jitter_depth.load(Ordering::Relaxed);
+        ui.monospace(format!(
+          "Jitter buffer: {} ms",
+          (*WAIT_DURATION * depth as u32).as_millis()
+        ));
+        ui.monospace(format!(
+          "Playback queue: {} ms",
+          playback.queued().as_millis()
+        ));
+        let version = match *status.server_version.lock().unwrap() {
+          Some(version) => version.to_string(),
+          None => "unknown".to_owned(),
+        };
+        ui.monospace(format!("Server protocol: {version}"));
+      });
+  }
+}
+
 struct MyEguiApp {
   ptt: Arc<AtomicBool>,
+  ptt_toggle: bool,
+  channel: Arc<AtomicU16>,
+  debouncer: Debouncer,
+  whisper: Arc<AtomicBool>,
+  whisper_debouncer: Debouncer,
+  whisper_to: Option<ClientId>,
+  /// Only with `--emergency`.
+  emergency: Option<Arc<AtomicBool>>,
+  emergency_debouncer: Debouncer,
+  presets: Presets,
+  overlay: StatsOverlay,
+  playback: Arc<PlaybackStats>,
+  status: Arc<Status>,
+  address: SocketAddr,
+}
+
+/// Draws a lamp-style indicator that lights up in `color` when `on`.
+fn indicator(ui: &mut egui::Ui, label: &str, on: bool, color: Color32) {
+  let color = if on { color } else { Color32::DARK_GRAY };
+  ui.colored_label(color, format!("● {label}"));
 }
 
-impl MyEguiApp {
-  fn new(_: &eframe::CreationContext<'_>, ptt: Arc<AtomicBool>) -> Self {
-    MyEguiApp { ptt }
+/// A hold-to-talk button that keys up with `flag` raised, to pick where the
+/// transmission goes.
+fn talk_button(
+  ui: &mut egui::Ui,
+  button: Button,
+  debouncer: &mut Debouncer,
+  flag: &AtomicBool,
+  ptt: &AtomicBool,
+) {
+  let last = debouncer.state();
+  let response = ui.add(button.sense(Sense::drag()));
+  if response.drag_started() {
+    debouncer.set(true, Instant::now());
+  } else if response.drag_stopped() {
+    debouncer.set(false, Instant::now());
+  }
+
+  // The network thread picks the target when PTT goes down, so raise `flag`
+  // before `ptt` and drop it after.
+  let state = debouncer.poll(Instant::now());
+  if state && !last {
+    flag.store(true, Ordering::SeqCst);
+    ptt.store(true, Ordering::SeqCst);
+  } else if !state && last {
+    ptt.store(false, Ordering::SeqCst);
+    flag.store(false, Ordering::SeqCst);
   }
 }
 
 impl eframe::App for MyEguiApp {
   fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
     egui::CentralPanel::default().show(ctx, |ui| {
-      ui.heading("Hello World!");
-      ui.label(format!("PTT: {}", self.ptt.load(Ordering::SeqCst)));
-
-      let response = ui.add(Button::new("PTT").sense(Sense::drag()));
-      if response.drag_started() {
-        self.ptt.store(true, Ordering::SeqCst);
-      } else if response.drag_stopped() {
-        self.ptt.store(false, Ordering::SeqCst);
+      ui.heading("Squelch");
+      ui.label(format!("Server: {}", self.address));
+
+      if self
+        .status
+        .emergency
+        .lock()
+        .unwrap()
+        .is_some_and(|last| last.elapsed() < EMERGENCY_HOLD)
+      {
+        ui.heading(RichText::new("EMERGENCY").color(Color32::RED).strong());
+      }
+      if let Some(reason) = self.status.rejected.lock().unwrap().as_deref() {
+        ui.colored_label(Color32::RED, format!("Rejected: {reason}"));
+      }
+      if let Some(reason) = self.status.incompatible.lock().unwrap().as_deref()
+      {
+        ui.colored_label(Color32::RED, format!("Incompatible: {reason}"));
+      }
+      let connection = match *self.status.last_packet.lock().unwrap() {
+        Some(last) if last.elapsed() < CONNECTED_TIMEOUT => {
+          "Connected".to_string()
+        }
+        Some(last) => format!(
+          "Reconnecting (last heard {}s ago)",
+          last.elapsed().as_secs()
+        ),
+        None => "Waiting for the server".to_string(),
+      };
+      ui.label(connection);
+
+      ui.horizontal(|ui| {
+        indicator(ui, "TX", self.ptt.load(Ordering::SeqCst), Color32::RED);
+        indicator(
+          ui,
+          "RX",
+          self.status.receiving.load(Ordering::Relaxed),
+          Color32::GREEN,
+        );
+        indicator(
+          ui,
+          "CLIP",
+          self.status.clipping.load(Ordering::Relaxed),
+          Color32::YELLOW,
+        );
+      });
+      if let Some(remaining) = *self.status.tot_remaining.lock().unwrap() {
+        ui.label(format!("TOT: {}s left", remaining.as_secs()));
+      } else if self.status.timed_out.load(Ordering::Relaxed) {
+        ui.colored_label(Color32::YELLOW, "TOT: timed out, release PTT");
+      }
+      ui.horizontal(|ui| {
+        let channel = self.channel.load(Ordering::SeqCst);
+        if ui.button("-").clicked() && channel > 0 {
+          self.channel.store(channel - 1, Ordering::SeqCst);
+        }
+        ui.label(format!("Channel {channel}"));
+        if ui.button("+").clicked() && channel < u16::MAX {
+          self.channel.store(channel + 1, Ordering::SeqCst);
+        }
+      });
+      ui.label(format!(
+        "FX: {}",
+        if self.status.server_fx.load(Ordering::Relaxed) {
+          "on the server"
+        } else if !self.status.fx.lock().unwrap().disabled {
+          "on"
+        } else {
+          "off"
+        }
+      ));
+      ui.add_enabled_ui(!self.status.server_fx.load(Ordering::Relaxed), |ui| {
+        self.presets.show(ui, &self.status);
+      });
+
+      if self.ptt_toggle {
+        // Latched on until clicked again, which the label makes plain.
+        let on = self.ptt.load(Ordering::SeqCst);
+        let label = if on { "PTT (latched on)" } else { "PTT" };
+        if ui.add(Button::new(label).selected(on)).clicked() {
+          self.ptt.store(!on, Ordering::SeqCst);
+        }
+      } else {
+        let last = self.debouncer.state();
+        let response = ui.add(Button::new("PTT").sense(Sense::drag()));
+        if response.drag_started() {
+          self.debouncer.set(true, Instant::now());
+        } else if response.drag_stopped() {
+          self.debouncer.set(false, Instant::now());
+        }
+
+        let state = self.debouncer.poll(Instant::now());
+        if state != last {
+          self.ptt.store(state, Ordering::SeqCst);
+        }
+      }
+
+      if let Some(to) = self.whisper_to {
+        talk_button(
+          ui,
+          Button::new(format!("Whisper to {to}")),
+          &mut self.whisper_debouncer,
+          &self.whisper,
+          &self.ptt,
+        );
+      }
+      if let Some(emergency) = &self.emergency {
+        talk_button(
+          ui,
+          Button::new(RichText::new("Emergency").color(Color32::WHITE))
+            .fill(Color32::DARK_RED),
+          &mut self.emergency_debouncer,
+          emergency,
+          &self.ptt,
+        );
+      }
+
+      // Keep repainting while a change is held back so it's applied even if
+      // the pointer stops moving.
+      if self.debouncer.is_pending()
+        || self.whisper_debouncer.is_pending()
+        || self.emergency_debouncer.is_pending()
+      {
+        ctx.request_repaint();
       }
     });
+
+    if ctx.input(|i| i.key_pressed(egui::Key::F3)) {
+      self.overlay.shown = !self.overlay.shown;
+    }
+    if self.overlay.shown {
+      self.overlay.update(&self.status.net);
+      self.overlay.show(ctx, &self.status, &self.playback);
+    }
+
+    // The network thread updates state behind egui's back, so poll for it.
+    ctx.request_repaint_after(Duration::from_millis(100));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn loss_meter_counts_sequence_gaps() {
+    let now = Instant::now();
+    let mut loss = LossMeter::default();
+    // 10 to 19, with 13 and 14 lost and 17 arriving late.
+    for seq in [10, 11, 12, 15, 16, 18, 17, 19] {
+      loss.audio(now, Some(seq));
+    }
+    // A DTX pause doesn't spoil the count.
+    loss.pause();
+    assert_eq!(loss.end(), Some((10, 8)));
+
+    // Across the wrap.
+    for seq in [u32::MAX - 1, 1] {
+      loss.audio(now, Some(seq));
+    }
+    assert_eq!(loss.end(), Some((4, 2)));
+  }
+
+  #[test]
+  fn releasing_ptt_mid_buffer_sends_the_rest() {
+    let mut outgoing = Outgoing::new(8);
+    let mut mic = Mic {
+      gain: 1.0,
+      nr: None,
+      fx: FxUnit::from_config(&FxConfig::default()),
+      agc: false,
+    };
+    let mut mic_buf = vec![0.25; TX_BUFFER_SIZE + 44];
+    let clipped = flush_mic(
+      &mut outgoing,
+      &mut mic_buf,
+      Instant::now(),
+      &mut mic,
+      Target::Channel,
+      None,
+    );
+    assert!(!clipped);
+    assert!(mic_buf.is_empty());
+
+    let sent: Vec<TxBuffer> = outgoing
+      .queue
+      .iter()
+      .map(|(packet, _)| match packet {
+        Packet::Audio(samples) => *samples,
+        other => panic!("expected audio, got {other:?}"),
+      })
+      .collect();
+    assert_eq!(sent.len(), 2);
+    assert!(sent[0].iter().all(|s| *s == 0.25));
+    assert!(sent[1][..44].iter().all(|s| *s == 0.25));
+    assert!(sent[1][44..].iter().all(|s| *s == 0.0));
+  }
+
+  #[test]
+  fn a_clipping_buffer_lights_clip() {
+    let mut outgoing = Outgoing::new(8);
+    let mut mic = Mic {
+      gain: 4.0,
+      nr: None,
+      fx: FxUnit::from_config(&FxConfig::default()),
+      agc: false,
+    };
+    let lit = AtomicBool::new(false);
+    let mut light = ClipLight::default();
+    let now = Instant::now();
+
+    let quiet = [0.2; TX_BUFFER_SIZE];
+    let clipped =
+      queue_mic(&mut outgoing, &quiet, now, &mut mic, Target::Channel, None);
+    light.update(clipped, now, &lit);
+    assert!(!lit.load(Ordering::Relaxed));
+
+    let loud = [0.3; TX_BUFFER_SIZE];
+    let clipped =
+      queue_mic(&mut outgoing, &loud, now, &mut mic, Target::Channel, None);
+    light.update(clipped, now, &lit);
+    assert!(lit.load(Ordering::Relaxed));
+
+    // It stays lit for a moment, then goes out.
+    light.update(false, now + CLIP_HOLD / 2, &lit);
+    assert!(lit.load(Ordering::Relaxed));
+    light.update(false, now + CLIP_HOLD, &lit);
+    assert!(!lit.load(Ordering::Relaxed));
+  }
+
+  #[test]
+  fn audio_just_before_key_up_is_sent_first() {
+    let mut pre_roll = PreRoll::new(100);
+    for level in [1.0, 2.0, 3.0] {
+      assert_eq!(pre_roll.next(&[level; 64], false), None);
+    }
+
+    // The last 100 samples before key-up lead the first live block.
+    let sent = pre_roll.next(&[4.0; 64], true).unwrap();
+    let expected = [[2.0; 36].as_slice(), &[3.0; 64], &[4.0; 64]].concat();
+    assert_eq!(sent, expected);
+    assert_eq!(pre_roll.next(&[5.0; 64], true), Some(vec![5.0; 64]));
+
+    // Without a pre-roll, nothing is held back.
+    let mut pre_roll = PreRoll::new(0);
+    assert_eq!(pre_roll.next(&[1.0; 64], false), None);
+    assert_eq!(pre_roll.next(&[2.0; 64], true), Some(vec![2.0; 64]));
+  }
+
+  #[test]
+  fn squelch_level_passes_loud_buffers_only() {
+    let tone: Vec<f32> = fx::gen_tone(1000.0, TX_BUFFER_SIZE * 8);
+    // A 0.5 peak tone is about 0.35 RMS; faint static, under 0.01.
+    let loud: Vec<f32> = tone.iter().map(|s| s * 0.5).collect();
+    let quiet: Vec<f32> = fx::white_noise(3, tone.len())
+      .iter()
+      .map(|s| s * 0.01)
+      .collect();
+
+    let mut received = loud
+      .chunks(TX_BUFFER_SIZE)
+      .zip(quiet.chunks(TX_BUFFER_SIZE))
+      .flat_map(|(loud, quiet)| [loud, quiet]);
+    let opened: Vec<bool> = received
+      .clone()
+      .map(|buf| carrier(buf, Some(0.02)))
+      .collect();
+    assert_eq!(opened, [true, false].repeat(8));
+
+    // Without a level, anything opens it, even silence.
+    assert!(received.all(|buf| carrier(buf, None)));
+    assert!(carrier(&[0.0; TX_BUFFER_SIZE], None));
+  }
+
+  #[test]
+  fn local_echo_plays_our_audio_back() {
+    let server = start_local_echo().unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+      .set_read_timeout(Some(Duration::from_millis(500)))
+      .unwrap();
+    let send = |packet: &Packet| {
+      socket
+        .send_to(&postcard::to_allocvec(packet).unwrap(), server)
+        .unwrap();
+    };
+    send(&Packet::Ping);
+    for _ in 0..4 {
+      send(&Packet::Audio([0.25; TX_BUFFER_SIZE]));
+    }
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let heard = (0..20).find_map(|_| {
+      let size = socket.recv(&mut buf).ok()?;
+      match Packet::decode(&buf[..size]) {
+        Ok(Some(Packet::Audio(samples))) => Some(samples),
+        _ => None,
+      }
+    });
+    assert_eq!(heard, Some([0.25; TX_BUFFER_SIZE]));
+  }
+
+  #[test]
+  fn sidetone_is_clean_unless_fx_are_asked_for() {
+    let mic = fx::gen_tone(440.0, TX_BUFFER_SIZE);
+
+    let clean = sidetone(&mic, 0.5, None);
+    for (out, s) in clean.iter().zip(&mic) {
+      assert_eq!(*out, s * 0.5);
+    }
+
+    let mut fx = FxUnit::from_config(&FxConfig::default());
+    let radio = sidetone(&mic, 0.5, Some(&mut fx));
+    assert_ne!(radio, clean);
+    assert!(radio.iter().all(|s| s.abs() <= 1.0));
+
+    // A short chunk is padded with silence either way, FX noise included.
+    let short = sidetone(&mic[..100], 0.5, Some(&mut fx));
+    assert!(short[..100].iter().any(|s| *s != 0.0));
+    assert!(short[100..].iter().all(|s| *s == 0.0));
+  }
+
+  #[test]
+  fn loss_meter_falls_back_to_timing() {
+    let now = Instant::now();
+    let mut loss = LossMeter::default();
+    for tick in [0, 1, 2, 5] {
+      loss.audio(now + *WAIT_DURATION * tick, None);
+    }
+    assert_eq!(loss.end(), Some((6, 4)));
+
+    loss.audio(now, None);
+    loss.pause();
+    assert_eq!(loss.end(), None);
+  }
+
+  #[test]
+  fn each_end_of_transmission_plays_its_own_tone() {
+    let off = CourtesyTones { rx: None, tx: None };
+    assert!(off.at_end(true, false).is_empty());
+    assert!(off.at_end(false, false).is_empty());
+    assert_eq!(off.at_end(true, true), tot_tone());
+
+    let tones = CourtesyTones {
+      rx: Some(600.0),
+      tx: Some(900.0),
+    };
+    let rx = beep(600.0, COURTESY_TONE_BUFFERS);
+    let tx = beep(900.0, COURTESY_TONE_BUFFERS);
+    assert_ne!(rx, tx);
+    assert_eq!(tones.at_end(false, false), rx);
+    assert_eq!(tones.at_end(true, false), tx);
+    // The time-out beep takes the place of ours.
+    assert_eq!(tones.at_end(true, true), tot_tone());
   }
 }
@@ -0,0 +1,260 @@
+use std::{
+  collections::HashMap,
+  net::{SocketAddr, UdpSocket},
+  sync::{Arc, mpsc},
+  time::{Duration, Instant},
+};
+
+use clap::Parser;
+use cpal::traits::{HostTrait, StreamTrait};
+use tracing::{debug, error, info, warn};
+
+use squelch::{
+  KEEPALIVE_INTERVAL, MAX_PACKET_SIZE, PROTOCOL_VERSION, Packet, TxBuffer,
+  WAIT_DURATION, device,
+  dtx::SID_TIMEOUT,
+  fx::FxUnit,
+  logging::Verbosity,
+  playback::{PlaybackStats, Speaker, build_speaker_stream},
+  ratelimit::Pacer,
+  seq::SeqTracker,
+};
+
+/// Listen to a squelch server without transmitting
+#[derive(Debug, Clone, Parser)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+  /// The socket address of the server to listen to.
+  #[arg(short, long, required_unless_present = "scan")]
+  pub address: Option<SocketAddr>,
+
+  /// Scan these servers (comma-separated), like a scanner radio: stop on
+  /// whichever one becomes active, stay on it until it's been quiet for
+  /// `--hang-ms`, then resume scanning.
+  #[arg(long, value_delimiter = ',', conflicts_with = "address")]
+  pub scan: Vec<SocketAddr>,
+
+  /// How long to stay on a channel after its transmission ends when
+  /// scanning, so a reply is heard too.
+  #[arg(long, default_value_t = 2000)]
+  pub hang_ms: u64,
+
+  /// Disables effects.
+  #[arg(long)]
+  pub no_fx: bool,
+
+  /// Threshold of distortion effect.
+  #[arg(short, long, default_value_t = 0.05)]
+  pub distortion: f32,
+
+  /// Gain multiplier for incoming signal.
+  #[arg(short, long, default_value_t = 1.0)]
+  pub gain: f32,
+
+  /// Playback jitter-buffer depth in milliseconds.
+  #[arg(long, default_value_t = 20)]
+  pub jitter_ms: u64,
+
+  #[command(flatten)]
+  pub verbosity: Verbosity,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let args = Cli::parse();
+  args.verbosity.init();
+
+  let err_fn = move |err| {
+    error!("an error occurred on stream: {}", err);
+  };
+
+  let (spk_tx, spk_rx) = mpsc::channel::<TxBuffer>();
+
+  let host = cpal::default_host();
+  let spk_device = host
+    .default_output_device()
+    .ok_or("No default output device")?;
+
+  let spk_config = cpal::StreamConfig {
+    channels: 1,
+    sample_rate: cpal::SampleRate(device::output_rate(&spk_device)),
+    buffer_size: cpal::BufferSize::Default,
+  };
+  debug!("spk config: {spk_config:?}");
+
+  let stats = Arc::new(PlaybackStats::default());
+  let speaker = Speaker::new(spk_rx, args.jitter_ms, stats);
+  let spk_stream =
+    build_speaker_stream(&spk_device, &spk_config, speaker, 0.0, err_fn)?;
+  spk_stream.play()?;
+
+  let socket = UdpSocket::bind("0.0.0.0:0")?;
+  // Wake up regularly even when nothing arrives so the squelch tail can fire.
+  socket.set_read_timeout(Some(*WAIT_DURATION))?;
+  let channels = match args.address {
+    Some(address) => vec![address],
+    None => args.scan.clone(),
+  };
+  for channel in &channels {
+    socket.send_to(&postcard::to_allocvec(&Packet::Ping)?, channel)?;
+    socket.send_to(
+      &postcard::to_allocvec(&Packet::Hello {
+        version: PROTOCOL_VERSION,
+      })?,
+      channel,
+    )?;
+  }
+  let scanning = channels.len() > 1;
+  if scanning {
+    info!("Scanning {} channels...", channels.len());
+  } else {
+    info!("Listening to {}", channels[0]);
+  }
+  let hang = Duration::from_millis(args.hang_ms);
+  // Which channel we've stopped on. Every server streams to us all the
+  // time, so scanning just means taking the first that becomes active and
+  // ignoring the rest while we're on it.
+  let mut active: Option<SocketAddr> = None;
+
+  let mut fx_unit = FxUnit::new(args.no_fx, args.gain, args.distortion);
+  // The server says so again with every ping it answers.
+  let mut server_fx = false;
+  let mut buf = [0; MAX_PACKET_SIZE];
+  let mut do_squelch = false;
+  let mut last_packet = Instant::now();
+  // The latest received buffer is held back one tick so the squelch tail can
+  // be crossfaded into it.
+  let mut held: Option<TxBuffer> = None;
+  // Each server numbers its feed separately.
+  let mut seqs: HashMap<SocketAddr, SeqTracker> = HashMap::new();
+  // Fill DTX pauses with comfort noise rather than silence.
+  let mut dtx_gap = false;
+  let mut last_audio = Instant::now();
+  let mut comfort_pacer = Pacer::new(*WAIT_DURATION);
+  let mut next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
+  loop {
+    // Keep every scanned server sending to us.
+    if Instant::now() >= next_keepalive {
+      for channel in &channels {
+        socket.send_to(&postcard::to_allocvec(&Packet::Ping)?, channel)?;
+      }
+      next_keepalive += KEEPALIVE_INTERVAL;
+    }
+
+    match socket.recv_from(&mut buf) {
+      Ok((_, src))
+        if !channels.contains(&src)
+          || active.is_some_and(|active| active != src) => {}
+      Ok((size, src)) => match Packet::decode(&buf[..size]) {
+        Ok(
+          None
+          | Some(
+            Packet::Ping
+            | Packet::DirectAudio { .. }
+            | Packet::Level(_)
+            | Packet::Channel(_)
+            | Packet::Hello { .. }
+            | Packet::HelloAck { .. }
+            // We never ask for Opus.
+            | Packet::AcceptOpus
+            | Packet::OpusAudio { .. },
+          ),
+        ) => {}
+        // Scanned servers may differ, so keep our own FX for all of them.
+        Ok(Some(Packet::ServerInfo { fx })) => {
+          if fx && !scanning && !server_fx {
+            server_fx = true;
+            info!("{src} applies FX; turning ours off");
+            fx_unit = FxUnit::new(true, args.gain, args.distortion);
+          }
+        }
+        Ok(Some(Packet::Rejected { reason })) => {
+          if !scanning {
+            return Err(format!("{src} rejected us: {reason}").into());
+          }
+          warn!("{src} rejected us: {reason}; scanning the rest");
+        }
+        // A DTX talker pausing: keep the transmission open.
+        Ok(Some(Packet::Silence)) => {
+          if do_squelch {
+            last_packet = Instant::now();
+            dtx_gap = true;
+          }
+        }
+        Ok(Some(
+          Packet::SeqAudio { seq, .. } | Packet::StampedAudio { seq, .. },
+        )) if !seqs.entry(src).or_default().accept(seq) => {
+          debug!("Dropped out-of-order buffer {seq} from {src}");
+        }
+        Ok(Some(
+          Packet::Audio(mut samples)
+          | Packet::Emergency { mut samples }
+          | Packet::SeqAudio { mut samples, .. }
+          | Packet::StampedAudio { mut samples, .. },
+        )) => {
+          if scanning && active.is_none() {
+            info!("Stopped on {src}");
+          }
+          active = Some(src);
+          last_packet = Instant::now();
+          last_audio = last_packet;
+          do_squelch = true;
+
+          fx_unit.run(&mut samples);
+          if let Some(last) = held.replace(samples) {
+            spk_tx.send(last)?;
+          }
+        }
+        Err(err) => warn!("Failed to decode packet: {err:?}"),
+      },
+      Err(e) => {
+        if !matches!(
+          e.kind(),
+          std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ) {
+          return Err(e.into());
+        }
+      }
+    }
+
+    // Across a DTX pause, wait for the next marker before giving up.
+    let timeout =
+      WAIT_DURATION.mul_f32(if dtx_gap { SID_TIMEOUT as f32 } else { 7.0 });
+    if dtx_gap
+      && last_packet.elapsed() < timeout
+      && last_audio.elapsed() >= WAIT_DURATION.mul_f32(2.0)
+    {
+      if let Some(last) = held.take() {
+        spk_tx.send(last)?;
+      }
+      if comfort_pacer.ready() {
+        spk_tx.send(fx_unit.comfort_noise())?;
+      }
+    }
+
+    if do_squelch && last_packet.elapsed() >= timeout {
+      do_squelch = false;
+      dtx_gap = false;
+
+      let tail = match held.take() {
+        Some(mut last) => {
+          let tail = fx_unit.squelch_after(&mut last);
+          spk_tx.send(last)?;
+          tail
+        }
+        None => fx_unit.squelch_with(true),
+      };
+      for chunk in tail {
+        spk_tx.send(chunk)?;
+      }
+    }
+
+    if scanning
+      && active.is_some()
+      && !do_squelch
+      && last_packet.elapsed() >= hang
+    {
+      active = None;
+      info!("Scanning {} channels...", channels.len());
+    }
+  }
+}
@@ -3,14 +3,30 @@ use std::{
   io::BufReader,
   net::{SocketAddr, UdpSocket},
   path::Path,
-  time::Duration,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use clap::Parser;
 use hound::WavReader;
 use minimp3::{Decoder, Frame};
+use tracing::{debug, info};
 
-use squelch::{Packet, TX_BUFFER_SIZE};
+use squelch::{
+  KEEPALIVE_INTERVAL, MAX_PACKET_SIZE, Packet, SAMPLE_RATE, TX_BUFFER_SIZE,
+  capture::CaptureReader,
+  fx::{FxConfig, FxUnit},
+  logging::Verbosity,
+  net,
+};
+
+/// How long the channel must be quiet before a scheduled transmission.
+const BUSY_HOLDOFF: Duration = Duration::from_secs(1);
+
+/// Buffers quieter than this count as a gap between transmissions.
+const GAP_THRESHOLD: f32 = 1e-4;
+/// How long a quiet stretch must last before `--preserve-gaps` stops
+/// transmitting; shorter pauses are part of the speech.
+const MIN_GAP: Duration = Duration::from_millis(250);
 
 /// Play audio file to ham radio server
 #[derive(Debug, Clone, Parser)]
@@ -21,48 +37,151 @@ pub struct Cli {
   pub address: SocketAddr,
 
   /// Path to the audio file (WAV or MP3)
-  #[arg(value_name = "FILE")]
-  pub file: String,
+  #[arg(value_name = "FILE", required_unless_present = "replay")]
+  pub file: Option<String>,
+
+  /// Re-send the datagrams from a raw packet capture (see `--pcap-out`)
+  /// with their original relative timing, instead of playing a file
+  #[arg(long, conflicts_with = "file")]
+  pub replay: Option<String>,
+
+  /// Repeat the file every N seconds, like a beacon, waiting for the channel
+  /// to be clear before each transmission
+  #[arg(long)]
+  pub interval: Option<u64>,
+
+  /// Unkey during long silences in the file (e.g. the gaps `record
+  /// --always-record` fills in), keeping their real length, so each
+  /// transmission in the recording is replayed as a separate one.
+  #[arg(long)]
+  pub preserve_gaps: bool,
+
+  /// Run the file through the FX before sending it, so it goes out already
+  /// sounding like a radio. Takes a preset name (default, vhf-air, hf-ssb or
+  /// cb), or uses the default one if left bare
+  #[arg(
+    long,
+    value_parser = parse_preset,
+    num_args = 0..=1,
+    default_missing_value = "default"
+  )]
+  pub fx: Option<FxConfig>,
+
+  /// Send from this local address, e.g. `192.168.1.5:0` to pick the
+  /// interface or `0.0.0.0:40000` for a fixed source port. A fixed port
+  /// keeps the NAT mapping, and any firewall rule for it, stable across
+  /// restarts
+  #[arg(long)]
+  pub bind: Option<SocketAddr>,
+
+  #[command(flatten)]
+  pub verbosity: Verbosity,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
   let args = Cli::parse();
+  args.verbosity.init();
+
+  if let Some(replay) = &args.replay {
+    return replay_capture(replay, args.address, args.bind);
+  }
 
-  let file_path = Path::new(&args.file);
+  let file = args.file.ok_or("No file to play")?;
+  let file_path = Path::new(&file);
   let extension = file_path
     .extension()
     .and_then(|ext| ext.to_str())
     .ok_or("Unable to determine file extension")?
     .to_lowercase();
 
-  println!("Playing file: {}", args.file);
-  println!("Connecting to server: {}", args.address);
+  info!("Playing file: {}", file);
+  info!("Connecting to server: {}", args.address);
 
-  let socket = UdpSocket::bind("0.0.0.0:0")?;
+  let socket = net::bind_source(args.bind, None)?;
 
   // Send initial ping
   let ping_packet = postcard::to_allocvec(&Packet::Ping)?;
   socket.send_to(&ping_packet, args.address)?;
-  println!("Sent ping to server");
+  debug!("Sent ping to server");
 
-  let samples = match extension.as_str() {
-    "wav" => read_wav_file(&args.file)?,
-    "mp3" => read_mp3_file(&args.file)?,
+  let mut samples = match extension.as_str() {
+    "wav" => read_wav_file(&file)?,
+    "mp3" => read_mp3_file(&file)?,
     _ => return Err(format!("Unsupported file format: {}", extension).into()),
   };
 
-  println!("Loaded {} samples", samples.len());
+  info!("Loaded {} samples", samples.len());
+
+  // The whole file at once, so only the very end is ever padded out.
+  if let Some(config) = &args.fx {
+    FxUnit::try_new(config)?.process_slice(&mut samples);
+  }
+
+  loop {
+    let started = Instant::now();
+    if args.interval.is_some() {
+      wait_for_clear_channel(&socket)?;
+      let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+      info!("[{}] Transmitting beacon: {}", timestamp, file);
+    }
+
+    send_samples(&socket, &samples, args.address, args.preserve_gaps)?;
+
+    match args.interval {
+      Some(interval) => {
+        // Keep pinging between beacons, so the server goes on sending us the
+        // mix and we can tell whether the channel's clear.
+        let next = started + Duration::from_secs(interval);
+        while let Some(wait) = next.checked_duration_since(Instant::now()) {
+          std::thread::sleep(wait.min(KEEPALIVE_INTERVAL));
+          socket.send_to(&ping_packet, args.address)?;
+        }
+      }
+      None => break,
+    }
+  }
+
+  Ok(())
+}
+
+fn parse_preset(name: &str) -> Result<FxConfig, String> {
+  FxConfig::preset(name).ok_or_else(|| {
+    format!(
+      "unknown preset {name:?} (expected one of: {})",
+      FxConfig::PRESETS.join(", ")
+    )
+  })
+}
+
+fn send_samples(
+  socket: &UdpSocket,
+  samples: &[f32],
+  address: SocketAddr,
+  preserve_gaps: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let gap_chunks = (MIN_GAP.as_secs_f32() * SAMPLE_RATE as f32
+    / TX_BUFFER_SIZE as f32) as usize;
+  let mut quiet_chunks = 0;
 
   // Stream audio data in chunks
-  let mut buffer = [0f32; TX_BUFFER_SIZE];
-  for chunk in samples.chunks_exact(TX_BUFFER_SIZE) {
-    // Copy chunk to buffer, padding with zeros if necessary
-    for (i, &sample) in chunk.iter().enumerate() {
-      buffer[i] = sample;
+  for chunk in samples.chunks(TX_BUFFER_SIZE) {
+    // Copy chunk to a fresh buffer so the final partial chunk is padded with
+    // zeros rather than stale samples from the previous one.
+    let mut buffer = [0f32; TX_BUFFER_SIZE];
+    buffer[..chunk.len()].copy_from_slice(chunk);
+
+    if buffer.iter().all(|s| s.abs() < GAP_THRESHOLD) {
+      quiet_chunks += 1;
+    } else {
+      quiet_chunks = 0;
     }
 
-    let audio_packet = postcard::to_allocvec(&Packet::Audio(buffer))?;
-    socket.send_to(&audio_packet, args.address)?;
+    // In a long gap, stay quiet for its length so listeners hear the
+    // transmission end.
+    if !preserve_gaps || quiet_chunks < gap_chunks {
+      let audio_packet = postcard::to_allocvec(&Packet::Audio(buffer))?;
+      socket.send_to(&audio_packet, address)?;
+    }
 
     std::thread::sleep(Duration::from_secs_f32(0.0057));
   }
@@ -70,16 +189,80 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   Ok(())
 }
 
+/// Blocks until no audio has come from the server for `BUSY_HOLDOFF`, so a
+/// scheduled transmission doesn't talk over someone.
+fn wait_for_clear_channel(socket: &UdpSocket) -> std::io::Result<()> {
+  socket.set_nonblocking(true)?;
+
+  let mut buf = [0; MAX_PACKET_SIZE];
+  let mut last_heard: Option<Instant> = None;
+  let mut announced = false;
+  loop {
+    while let Ok((size, _)) = socket.recv_from(&mut buf) {
+      if let Ok(Packet::Audio(_)) = postcard::from_bytes(&buf[..size]) {
+        last_heard = Some(Instant::now());
+      }
+    }
+
+    match last_heard {
+      Some(last) if last.elapsed() < BUSY_HOLDOFF => {
+        if !announced {
+          info!("Channel busy, waiting...");
+          announced = true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+      }
+      _ => break,
+    }
+  }
+
+  socket.set_nonblocking(false)
+}
+
+fn replay_capture(
+  path: &str,
+  address: SocketAddr,
+  bind: Option<SocketAddr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  info!("Replaying capture: {}", path);
+  info!("Connecting to server: {}", address);
+
+  let socket = net::bind_source(bind, None)?;
+  socket.send_to(&postcard::to_allocvec(&Packet::Ping)?, address)?;
+  debug!("Sent ping to server");
+
+  let start = Instant::now();
+  let mut first = None;
+  let mut count = 0u64;
+  for record in CaptureReader::open(path)? {
+    let (offset, datagram) = record?;
+
+    // Sleep until this datagram's original offset from the first one.
+    let offset = offset.saturating_sub(*first.get_or_insert(offset));
+    if let Some(wait) = offset.checked_sub(start.elapsed()) {
+      std::thread::sleep(wait);
+    }
+
+    socket.send_to(&datagram, address)?;
+    count += 1;
+  }
+
+  info!("Replayed {} datagrams", count);
+
+  Ok(())
+}
+
 fn read_wav_file(
   file_path: &str,
 ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
   let mut reader = WavReader::open(file_path)?;
   let spec = reader.spec();
 
-  println!("WAV file info:");
-  println!("  Sample rate: {} Hz", spec.sample_rate);
-  println!("  Channels: {}", spec.channels);
-  println!("  Bits per sample: {}", spec.bits_per_sample);
+  debug!("WAV file info:");
+  debug!("  Sample rate: {} Hz", spec.sample_rate);
+  debug!("  Channels: {}", spec.channels);
+  debug!("  Bits per sample: {}", spec.bits_per_sample);
+  check_rate(file_path, spec.sample_rate)?;
 
   let mut samples = Vec::new();
 
@@ -99,6 +282,14 @@ fn read_wav_file(
             samples.push(sample as f32 / i16::MAX as f32);
           }
         }
+        24 => {
+          // hound hands 24-bit samples back sign-extended in an i32.
+          for sample_result in reader.samples::<i32>() {
+            let sample = sample_result?;
+            // Convert to f32 in range [-1.0, 1.0)
+            samples.push(sample as f32 / (1 << 23) as f32);
+          }
+        }
         32 => {
           for sample_result in reader.samples::<i32>() {
             let sample = sample_result?;
@@ -115,16 +306,7 @@ fn read_wav_file(
     }
   }
 
-  // If stereo, convert to mono by averaging channels
-  if spec.channels == 2 {
-    let mono_samples: Vec<f32> = samples
-      .chunks_exact(2)
-      .map(|pair| (pair[0] + pair[1]) / 2.0)
-      .collect();
-    return Ok(mono_samples);
-  }
-
-  Ok(samples)
+  Ok(downmix(&samples, spec.channels as usize))
 }
 
 fn read_mp3_file(
@@ -134,7 +316,7 @@ fn read_mp3_file(
   let mut decoder = Decoder::new(BufReader::new(file));
   let mut samples = Vec::new();
 
-  println!("MP3 file info:");
+  debug!("MP3 file info:");
 
   loop {
     match decoder.next_frame() {
@@ -145,8 +327,9 @@ fn read_mp3_file(
         ..
       }) => {
         if samples.is_empty() {
-          println!("  Sample rate: {} Hz", sample_rate);
-          println!("  Channels: {}", channels);
+          debug!("  Sample rate: {} Hz", sample_rate);
+          debug!("  Channels: {}", channels);
+          check_rate(file_path, sample_rate as u32)?;
         }
 
         // Convert i16 samples to f32 in range [-1.0, 1.0]
@@ -155,16 +338,7 @@ fn read_mp3_file(
           .map(|&sample| sample as f32 / i16::MAX as f32)
           .collect();
 
-        // If stereo, convert to mono by averaging channels
-        if channels == 2 {
-          let mono_samples: Vec<f32> = frame_samples
-            .chunks_exact(2)
-            .map(|pair| (pair[0] + pair[1]) / 2.0)
-            .collect();
-          samples.extend(mono_samples);
-        } else {
-          samples.extend(frame_samples);
-        }
+        samples.extend(downmix(&frame_samples, channels));
       }
       Err(minimp3::Error::Eof) => break,
       Err(e) => return Err(e.into()),
@@ -173,3 +347,119 @@ fn read_mp3_file(
 
   Ok(samples)
 }
+
+/// Refuses files that aren't at [`SAMPLE_RATE`]: they'd be sent as-is and
+/// play back pitch-shifted and at the wrong speed.
+fn check_rate(
+  file_path: &str,
+  sample_rate: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+  if sample_rate == SAMPLE_RATE {
+    return Ok(());
+  }
+  Err(
+    format!(
+      "{file_path} is {sample_rate} Hz, but squelch streams {SAMPLE_RATE} \
+       Hz; convert it first with `resample {file_path} <OUTPUT> --rate \
+       {SAMPLE_RATE}`"
+    )
+    .into(),
+  )
+}
+
+/// Averages interleaved `channels`-channel audio down to mono.
+fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+  if channels <= 1 {
+    return samples.to_vec();
+  }
+  samples
+    .chunks_exact(channels)
+    .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Sends `samples` to a local socket and returns the buffers it got.
+  fn sent(samples: &[f32], preserve_gaps: bool) -> Vec<[f32; TX_BUFFER_SIZE]> {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = receiver.local_addr().unwrap();
+    send_samples(&sender, samples, address, preserve_gaps).unwrap();
+
+    receiver.set_nonblocking(true).unwrap();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let mut buffers = Vec::new();
+    while let Ok(size) = receiver.recv(&mut buf) {
+      match postcard::from_bytes(&buf[..size]) {
+        Ok(Packet::Audio(samples)) => buffers.push(samples),
+        other => panic!("expected audio, got {other:?}"),
+      }
+    }
+    buffers
+  }
+
+  #[test]
+  fn final_partial_chunk_is_padded_with_silence() {
+    let buffers = sent(&vec![0.5; TX_BUFFER_SIZE * 2 + 44], false);
+    assert_eq!(buffers.len(), 3);
+    assert!(buffers[..2].iter().flatten().all(|s| *s == 0.5));
+    assert!(buffers[2][..44].iter().all(|s| *s == 0.5));
+    assert!(buffers[2][44..].iter().all(|s| *s == 0.0));
+  }
+
+  #[test]
+  fn preserve_gaps_unkeys_for_long_silences_only() {
+    let gap_chunks = (MIN_GAP.as_secs_f32() * SAMPLE_RATE as f32
+      / TX_BUFFER_SIZE as f32) as usize;
+    let loud = vec![0.5; TX_BUFFER_SIZE * 4];
+    let pause = vec![0.0; TX_BUFFER_SIZE * (gap_chunks / 2)];
+    let gap = vec![0.0; TX_BUFFER_SIZE * (gap_chunks + 10)];
+    let samples = [&loud[..], &pause, &loud, &gap, &loud].concat();
+    let chunks = samples.len() / TX_BUFFER_SIZE;
+
+    assert_eq!(sent(&samples, false).len(), chunks);
+
+    // The short pause goes out as part of the speech; the long gap stops
+    // once it's lasted `MIN_GAP`.
+    let buffers = sent(&samples, true);
+    assert_eq!(buffers.len(), chunks - 11);
+    let quiet = |buf: &[f32; TX_BUFFER_SIZE]| buf.iter().all(|s| *s == 0.0);
+    let loud_at = |range: std::ops::Range<usize>| {
+      buffers[range].iter().all(|buf| !quiet(buf))
+    };
+    let pause_end = 8 + gap_chunks / 2;
+    assert!(loud_at(0..4) && loud_at(4 + gap_chunks / 2..pause_end));
+    assert!(
+      buffers[pause_end..pause_end + gap_chunks - 1]
+        .iter()
+        .all(quiet)
+    );
+    assert!(loud_at(pause_end + gap_chunks - 1..buffers.len()));
+  }
+
+  #[test]
+  fn reads_24_bit_wavs_at_full_scale() {
+    let path = std::env::temp_dir()
+      .join(format!("squelch-play-24-bit-{}.wav", std::process::id()));
+    let spec = hound::WavSpec {
+      channels: 1,
+      sample_rate: SAMPLE_RATE,
+      bits_per_sample: 24,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for sample in [0, 1 << 22, -(1 << 22), (1 << 23) - 1, -(1 << 23)] {
+      writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let samples = read_wav_file(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(samples[..3], [0.0, 0.5, -0.5]);
+    assert!(samples[3] < 1.0 && samples[3] > 0.9999);
+    assert_eq!(samples[4], -1.0);
+  }
+}
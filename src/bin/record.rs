@@ -1,119 +1,379 @@
 use std::{
   fs::File,
   io::BufWriter,
-  net::{SocketAddr, UdpSocket},
+  net::SocketAddr,
+  path::Path,
   sync::{
-    Arc, Mutex,
+    Arc,
     atomic::{AtomicBool, Ordering},
     mpsc,
   },
-  time::{SystemTime, UNIX_EPOCH},
+  thread::JoinHandle,
+  time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use clap::Parser;
 use hound::{WavSpec, WavWriter};
+use tracing::{debug, error, info, warn};
 
-use squelch::{MAX_PACKET_SIZE, Packet, TX_BUFFER_SIZE, WAIT_DURATION};
+use squelch::{
+  CHANNELS, KEEPALIVE_INTERVAL, MAX_PACKET_SIZE, PROTOCOL_VERSION, Packet,
+  SAMPLE_RATE, TX_BUFFER_SIZE, WAIT_DURATION, capture::CaptureWriter,
+  logging::Verbosity, net, seq::Timing,
+};
 
 /// Record sound from ham radio server to WAV file
 #[derive(Debug, Clone, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-  /// The socket address of the ham radio server to record from
-  #[arg(short, long)]
-  pub address: SocketAddr,
+  /// The socket address of the ham radio server to record from. Repeat to
+  /// record several servers at once, each to its own file
+  #[arg(short, long, required = true)]
+  pub address: Vec<SocketAddr>,
 
-  /// Output WAV file path (optional - will generate timestamped filename if not provided)
+  /// Output WAV file path (optional - will generate timestamped filename if not provided).
+  /// With several servers, each file gets the server address appended
   #[arg(short, long)]
   pub output: Option<String>,
 
   /// Always record, generating silence when server is not transmitting
   #[arg(long)]
   pub always_record: bool,
+
+  /// Also append every received datagram, undecoded, to this capture file
+  /// (replay it with `play --replay`). With several servers, each capture
+  /// gets the server address appended
+  #[arg(long)]
+  pub pcap_out: Option<String>,
+
+  /// Ask the OS for UDP receive/send buffers of this many KiB (it may
+  /// clamp the request). Helps avoid drops under bursty traffic.
+  #[arg(long)]
+  pub udp_buffer_kb: Option<usize>,
+
+  /// Send from this local address, e.g. `192.168.1.5:0` to pick the
+  /// interface or `0.0.0.0:40000` for a fixed source port. A fixed port
+  /// keeps the NAT mapping, and any firewall rule for it, stable across
+  /// restarts
+  /// (only with a single server, since each one gets its own socket)
+  #[arg(long)]
+  pub bind: Option<SocketAddr>,
+
+  /// Ask the server to timestamp its audio, and log how far apart each
+  /// buffer was sent and how far apart it arrived, in ms. Arrival times are
+  /// only good to about a millisecond
+  #[arg(long)]
+  pub log_timing: bool,
+
+  #[command(flatten)]
+  pub verbosity: Verbosity,
+}
+
+/// One server being recorded.
+struct Recorder {
+  address: SocketAddr,
+  output_path: String,
+  writer: WavWriter<BufWriter<File>>,
+  audio_rx: mpsc::Receiver<Vec<f32>>,
+  udp_thread: JoinHandle<()>,
+  total_samples: u64,
+  last_audio_packet: Instant,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
   let args = Cli::parse();
+  args.verbosity.init();
+  let quiet = args.verbosity.quiet;
+  let multiple = args.address.len() > 1;
 
   // Generate output filename if not provided
-  let output_path = match args.output {
-    Some(path) => path,
+  let output_path = match &args.output {
+    Some(path) => path.clone(),
     None => {
       let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
       format!("recording_{}.wav", timestamp)
     }
   };
 
-  println!("Recording to: {}", output_path);
-  println!("Server address: {}", args.address);
-  println!("Press Ctrl+C to stop recording and save the file...\n");
+  // Set up Ctrl+C handler
+  let running = Arc::new(AtomicBool::new(true));
+  let running_clone = running.clone();
+
+  ctrlc::set_handler(move || {
+    info!("Received Ctrl+C, stopping recording...");
+    running_clone.store(false, Ordering::SeqCst);
+  })?;
+
+  let mut recorders = Vec::with_capacity(args.address.len());
+  for &address in &args.address {
+    let (output_path, pcap_out) = if multiple {
+      (
+        with_address(&output_path, address),
+        args
+          .pcap_out
+          .as_ref()
+          .map(|path| with_address(path, address)),
+      )
+    } else {
+      (output_path.clone(), args.pcap_out.clone())
+    };
+
+    recorders.push(start_recorder(
+      address,
+      output_path,
+      pcap_out.as_deref(),
+      args.bind,
+      args.udp_buffer_kb,
+      args.log_timing,
+      running.clone(),
+    )?);
+  }
+  info!("Press Ctrl+C to stop recording and save the file...");
+
+  info!("Recording started!");
+  if args.always_record {
+    info!(
+      "Always-record mode enabled: will generate silence when server is not transmitting"
+    );
+  }
+
+  let mut last_update = Instant::now();
+
+  // Main loop - process audio data and write to WAV files
+  while running.load(Ordering::SeqCst) {
+    for recorder in recorders.iter_mut() {
+      let mut received_audio = false;
+
+      // Process any pending audio data
+      while let Ok(audio_data) = recorder.audio_rx.try_recv() {
+        received_audio = true;
+        recorder.last_audio_packet = Instant::now();
+
+        if let Err(e) = recorder.write(&audio_data) {
+          error!("Failed to write audio sample: {}", e);
+          running.store(false, Ordering::SeqCst);
+          break;
+        }
+      }
+
+      // If always_record is enabled and we haven't received audio in the expected interval,
+      // generate silence to maintain continuous recording
+      if args.always_record && !received_audio {
+        let elapsed = recorder.last_audio_packet.elapsed();
+        if elapsed >= *WAIT_DURATION {
+          // Generate silence chunks for each missed interval
+          let missed_chunks =
+            (elapsed.as_secs_f64() / WAIT_DURATION.as_secs_f64()) as u64;
+
+          let silence = [0.0f32; TX_BUFFER_SIZE];
+          for _ in 0..missed_chunks {
+            if let Err(e) = recorder.write(&silence) {
+              error!("Failed to write silence sample: {}", e);
+              running.store(false, Ordering::SeqCst);
+              break;
+            }
+          }
+
+          // Update last_audio_packet to account for the silence we just generated
+          let remainder_nanos = elapsed.as_nanos() % WAIT_DURATION.as_nanos();
+          let remainder =
+            std::time::Duration::from_nanos(remainder_nanos as u64);
+          recorder.last_audio_packet = Instant::now()
+            .checked_sub(remainder)
+            .unwrap_or(Instant::now());
+        }
+      }
+    }
+
+    // Print progress every second
+    if !quiet && last_update.elapsed().as_secs() >= 1 {
+      let progress: Vec<String> = recorders
+        .iter()
+        .map(|recorder| {
+          let duration_secs =
+            recorder.total_samples as f64 / SAMPLE_RATE as f64;
+          if multiple {
+            format!(
+              "{}: {:.1}s ({} samples)",
+              recorder.address, duration_secs, recorder.total_samples
+            )
+          } else {
+            format!(
+              "{:.1}s ({} samples)",
+              duration_secs, recorder.total_samples
+            )
+          }
+        })
+        .collect();
+      print!("\rRecording: {}", progress.join(" | "));
+      std::io::Write::flush(&mut std::io::stdout()).unwrap();
+      last_update = Instant::now();
+    }
+
+    // Small delay to prevent busy waiting
+    std::thread::sleep(std::time::Duration::from_millis(10));
+  }
+
+  if !quiet {
+    // End the progress line.
+    println!();
+  }
+  info!("Recording completed!");
+  // Finalize every file, even if an earlier one fails.
+  let mut result = Ok(());
+  for recorder in recorders {
+    let address = recorder.address;
+    if let Err(e) = recorder.finish() {
+      error!("Failed to finish recording of {}: {}", address, e);
+      result = Err(e);
+    }
+  }
+
+  result
+}
+
+/// Inserts `address` before the extension of `path`, so each server gets its
+/// own file: `out.wav` becomes `out_10.0.0.1_1837.wav`.
+fn with_address(path: &str, address: SocketAddr) -> String {
+  let suffix = format!("{}_{}", address.ip(), address.port());
+  let path = Path::new(path);
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  let file_name = match path.extension() {
+    Some(ext) => format!("{}_{}.{}", stem, suffix, ext.to_string_lossy()),
+    None => format!("{}_{}", stem, suffix),
+  };
+  path
+    .with_file_name(file_name)
+    .to_string_lossy()
+    .into_owned()
+}
+
+/// Pings `address` and spawns a thread feeding its audio to a new recorder.
+fn start_recorder(
+  address: SocketAddr,
+  output_path: String,
+  pcap_out: Option<&str>,
+  bind: Option<SocketAddr>,
+  udp_buffer_kb: Option<usize>,
+  log_timing: bool,
+  running: Arc<AtomicBool>,
+) -> Result<Recorder, Box<dyn std::error::Error>> {
+  info!("Recording to: {}", output_path);
+  info!("Server address: {}", address);
 
   // Set up the WAV file writer
   let spec = WavSpec {
-    channels: 1,
-    sample_rate: 44100,
+    channels: CHANNELS,
+    sample_rate: SAMPLE_RATE,
     bits_per_sample: 32, // Using 32-bit float samples
     sample_format: hound::SampleFormat::Float,
   };
 
   let file = File::create(&output_path)?;
-  let wav_writer =
-    Arc::new(Mutex::new(WavWriter::new(BufWriter::new(file), spec)?));
+  let writer = WavWriter::new(BufWriter::new(file), spec)?;
 
   // Set up UDP socket to receive audio from server
-  let socket = UdpSocket::bind("0.0.0.0:0")?;
+  let socket = net::bind_source(bind, udp_buffer_kb)?;
   socket.set_nonblocking(true)?;
 
   // Send initial ping to server to start receiving audio
   let ping_packet = postcard::to_allocvec(&Packet::Ping)?;
-  socket.send_to(&ping_packet, args.address)?;
-  println!("Sent ping to server at {}", args.address);
+  socket.send_to(&ping_packet, address)?;
+  debug!("Sent ping to server at {}", address);
+  if log_timing {
+    let hello = postcard::to_allocvec(&Packet::Hello {
+      version: PROTOCOL_VERSION,
+    })?;
+    socket.send_to(&hello, address)?;
+  }
 
-  println!("Listening for audio packets from server...");
+  debug!("Listening for audio packets from server...");
 
   // Channel for sending audio data from the UDP thread to the main thread
   let (audio_tx, audio_rx) = mpsc::channel::<Vec<f32>>();
 
-  // Set up Ctrl+C handler
-  let running = Arc::new(AtomicBool::new(true));
-  let running_clone = running.clone();
-
-  ctrlc::set_handler(move || {
-    println!("\nReceived Ctrl+C, stopping recording...");
-    running_clone.store(false, Ordering::SeqCst);
-  })?;
+  let mut capture = match pcap_out {
+    Some(path) => {
+      info!("Capturing raw packets to: {}", path);
+      Some(CaptureWriter::create(path)?)
+    }
+    None => None,
+  };
 
   // Spawn UDP receiving thread
-  let running_udp = running.clone();
-  let socket_clone = socket.try_clone()?;
-  std::thread::spawn(move || {
+  let udp_thread = std::thread::spawn(move || {
     let mut buf = [0; MAX_PACKET_SIZE];
+    let mut timing = Timing::new();
+    let mut next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
+
+    while running.load(Ordering::SeqCst) {
+      // Keep the server sending to us through long quiet spells.
+      if Instant::now() >= next_keepalive {
+        if let Err(e) = socket.send_to(&ping_packet, address) {
+          warn!("Failed to ping {}: {}", address, e);
+        }
+        next_keepalive += KEEPALIVE_INTERVAL;
+      }
 
-    while running_udp.load(Ordering::SeqCst) {
-      match socket_clone.recv_from(&mut buf) {
+      match socket.recv_from(&mut buf) {
         Ok((size, _)) => {
+          if let Some(capture) = capture.as_mut()
+            && let Err(e) = capture.write(&buf[..size])
+          {
+            warn!("Failed to write capture: {}", e);
+          }
+
           // Decode the packet
-          match postcard::from_bytes::<Packet>(&buf[..size]) {
-            Ok(packet) => match packet {
-              Packet::Ping => {
-                // Ignore ping packets
-              }
-              Packet::Audio(samples) => {
-                // Send audio samples to main thread
-                if let Err(e) = audio_tx.send(samples.to_vec()) {
-                  eprintln!("Failed to send audio data: {}", e);
-                  break;
+          match Packet::decode(&buf[..size]) {
+            Ok(packet) => {
+              let samples = match packet {
+                Some(
+                  Packet::Audio(samples)
+                  | Packet::Emergency { samples }
+                  | Packet::SeqAudio { samples, .. },
+                ) => Some(samples),
+                Some(Packet::StampedAudio {
+                  seq,
+                  micros,
+                  samples,
+                }) => {
+                  if let Some((sent, arrived)) =
+                    timing.delta(micros, Instant::now())
+                  {
+                    info!(
+                      "{}: buffer {} sent {:.2} ms after the last, \
+                       arrived {:.2} ms after",
+                      address,
+                      seq,
+                      sent as f64 / 1000.0,
+                      arrived as f64 / 1000.0
+                    );
+                  }
+                  Some(samples)
+                }
+                _ => {
+                  // Ignore pings, DTX markers and anything newer; direct
+                  // audio is mixed in by the server
+                  None
                 }
+              };
+
+              // Send audio samples to main thread
+              if let Some(samples) = samples
+                && let Err(e) = audio_tx.send(samples.to_vec())
+              {
+                error!("Failed to send audio data: {}", e);
+                break;
               }
-            },
+            }
             Err(err) => {
-              eprintln!("Failed to decode packet: {:?}", err);
+              warn!("Failed to decode packet: {:?}", err);
             }
           }
         }
         Err(e) => {
           if e.kind() != std::io::ErrorKind::WouldBlock {
-            eprintln!("UDP receive error: {}", e);
+            warn!("UDP receive error: {}", e);
           }
         }
       }
@@ -121,122 +381,61 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
       // Small delay to prevent busy waiting
       std::thread::sleep(std::time::Duration::from_millis(1));
     }
-  });
 
-  println!("Recording started!");
-  if args.always_record {
-    println!(
-      "Always-record mode enabled: will generate silence when server is not transmitting"
-    );
-  }
+    if let Some(capture) = capture.as_mut()
+      && let Err(e) = capture.flush()
+    {
+      warn!("Failed to flush capture: {}", e);
+    }
+  });
 
-  let mut total_samples = 0u64;
-  let mut last_update = std::time::Instant::now();
-  let mut last_audio_packet = std::time::Instant::now();
+  Ok(Recorder {
+    address,
+    output_path,
+    writer,
+    audio_rx,
+    udp_thread,
+    total_samples: 0,
+    last_audio_packet: Instant::now(),
+  })
+}
 
-  // Main loop - process audio data and write to WAV file
-  while running.load(Ordering::SeqCst) {
-    let mut received_audio = false;
+impl Recorder {
+  fn write(&mut self, samples: &[f32]) -> hound::Result<()> {
+    for &sample in samples {
+      self.writer.write_sample(sample)?;
+    }
+    self.total_samples += samples.len() as u64;
+    Ok(())
+  }
 
-    // Process any pending audio data
-    while let Ok(audio_data) = audio_rx.try_recv() {
-      received_audio = true;
-      last_audio_packet = std::time::Instant::now();
+  /// Waits for the UDP thread, writes any audio still queued and finalizes
+  /// the WAV file.
+  fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+    // The UDP thread exits once `running` is cleared; wait for it so the
+    // capture file is flushed.
+    self.udp_thread.join().map_err(|_| "UDP thread panicked")?;
 
-      let mut writer = wav_writer.lock().unwrap();
+    // Process any remaining audio data
+    while let Ok(audio_data) = self.audio_rx.try_recv() {
       for &sample in &audio_data {
-        if let Err(e) = writer.write_sample(sample) {
-          eprintln!("Failed to write audio sample: {}", e);
-          running.store(false, Ordering::SeqCst);
+        if let Err(e) = self.writer.write_sample(sample) {
+          error!("Failed to write final audio sample: {}", e);
           break;
         }
       }
-      total_samples += audio_data.len() as u64;
-
-      // Print progress every second
-      if last_update.elapsed().as_secs() >= 1 {
-        let duration_secs = total_samples as f64 / 44100.0;
-        print!(
-          "\rRecording: {:.1}s ({} samples)",
-          duration_secs, total_samples
-        );
-        std::io::Write::flush(&mut std::io::stdout()).unwrap();
-        last_update = std::time::Instant::now();
-      }
+      self.total_samples += audio_data.len() as u64;
     }
 
-    // If always_record is enabled and we haven't received audio in the expected interval,
-    // generate silence to maintain continuous recording
-    if args.always_record && !received_audio {
-      let elapsed = last_audio_packet.elapsed();
-      if elapsed >= *WAIT_DURATION {
-        // Generate silence chunks for each missed interval
-        let mut writer = wav_writer.lock().unwrap();
-        let missed_chunks =
-          (elapsed.as_secs_f64() / WAIT_DURATION.as_secs_f64()) as u64;
-
-        for _ in 0..missed_chunks {
-          let silence = vec![0.0f32; TX_BUFFER_SIZE];
-          for &sample in &silence {
-            if let Err(e) = writer.write_sample(sample) {
-              eprintln!("Failed to write silence sample: {}", e);
-              running.store(false, Ordering::SeqCst);
-              break;
-            }
-          }
-          total_samples += silence.len() as u64;
-        }
+    // Finalize the WAV file
+    self.writer.finalize()?;
 
-        // Update last_audio_packet to account for the silence we just generated
-        let remainder_nanos = elapsed.as_nanos() % WAIT_DURATION.as_nanos();
-        let remainder = std::time::Duration::from_nanos(remainder_nanos as u64);
-        last_audio_packet = std::time::Instant::now()
-          .checked_sub(remainder)
-          .unwrap_or(std::time::Instant::now());
-
-        // Print progress every second
-        if last_update.elapsed().as_secs() >= 1 {
-          let duration_secs = total_samples as f64 / 44100.0;
-          print!(
-            "\rRecording: {:.1}s ({} samples)",
-            duration_secs, total_samples
-          );
-          std::io::Write::flush(&mut std::io::stdout()).unwrap();
-          last_update = std::time::Instant::now();
-        }
-      }
-    }
+    let final_duration = self.total_samples as f64 / SAMPLE_RATE as f64;
+    info!("Server: {}", self.address);
+    info!("Total samples: {}", self.total_samples);
+    info!("Duration: {:.2} seconds", final_duration);
+    info!("File saved: {}", self.output_path);
 
-    // Small delay to prevent busy waiting
-    std::thread::sleep(std::time::Duration::from_millis(10));
+    Ok(())
   }
-
-  // Signal UDP thread to stop (it will exit when running becomes false)
-
-  // Process any remaining audio data
-  while let Ok(audio_data) = audio_rx.try_recv() {
-    let mut writer = wav_writer.lock().unwrap();
-    for &sample in &audio_data {
-      if let Err(e) = writer.write_sample(sample) {
-        eprintln!("Failed to write final audio sample: {}", e);
-        break;
-      }
-    }
-    total_samples += audio_data.len() as u64;
-  }
-
-  // Finalize the WAV file
-  let writer = Arc::try_unwrap(wav_writer)
-    .map_err(|_| "Failed to unwrap Arc")?
-    .into_inner()
-    .map_err(|_| "Failed to unwrap Mutex")?;
-  writer.finalize()?;
-
-  let final_duration = total_samples as f64 / 44100.0;
-  println!("\n\nRecording completed!");
-  println!("Total samples: {}", total_samples);
-  println!("Duration: {:.2} seconds", final_duration);
-  println!("File saved: {}", output_path);
-
-  Ok(())
 }
@@ -0,0 +1,168 @@
+use std::{
+  net::{SocketAddr, UdpSocket},
+  time::{Duration, Instant},
+};
+
+use clap::Parser;
+use tracing::{info, warn};
+
+use squelch::{
+  KEEPALIVE_INTERVAL, MAX_PACKET_SIZE, Packet, WAIT_DURATION, fx::FxUnit,
+  logging::Verbosity,
+};
+
+/// Link two squelch servers, relaying audio between them in both directions
+#[derive(Debug, Clone, Parser)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+  /// The first server to link.
+  #[arg(long)]
+  pub from: SocketAddr,
+
+  /// The second server to link.
+  #[arg(long)]
+  pub to: SocketAddr,
+
+  /// Disables effects.
+  #[arg(long)]
+  pub no_fx: bool,
+
+  /// Threshold of distortion effect.
+  #[arg(short, long, default_value_t = 0.05)]
+  pub distortion: f32,
+
+  /// Gain multiplier for relayed signal.
+  #[arg(short, long, default_value_t = 1.0)]
+  pub gain: f32,
+
+  /// How long a direction stays keyed after its last packet, in
+  /// milliseconds. Audio from the other side is ignored meanwhile, so the
+  /// repeater never relays its own transmission or squelch tail back.
+  #[arg(long, default_value_t = 500)]
+  pub hang_ms: u64,
+
+  #[command(flatten)]
+  pub verbosity: Verbosity,
+}
+
+/// One linked server.
+struct Side {
+  name: &'static str,
+  address: SocketAddr,
+  socket: UdpSocket,
+  /// FX applied to audio relayed *from* this side.
+  fx_unit: FxUnit,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let args = Cli::parse();
+  args.verbosity.init();
+  let hang = Duration::from_millis(args.hang_ms);
+
+  let mut sides =
+    [("from", args.from), ("to", args.to)].map(|(name, address)| {
+      let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+      socket.set_nonblocking(true).unwrap();
+      Side {
+        name,
+        address,
+        socket,
+        fx_unit: FxUnit::new(args.no_fx, args.gain, args.distortion),
+      }
+    });
+
+  for side in sides.iter() {
+    side
+      .socket
+      .send_to(&postcard::to_allocvec(&Packet::Ping)?, side.address)?;
+    info!("Linked {} server: {}", side.name, side.address);
+  }
+
+  // Which side currently owns the link, and when we last heard from it.
+  let mut keyed: Option<(usize, Instant)> = None;
+  let mut tail_sent = true;
+  let mut buf = [0; MAX_PACKET_SIZE];
+  let mut next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
+  loop {
+    let mut received = false;
+
+    // Stay joined to both servers while the link is quiet.
+    if Instant::now() >= next_keepalive {
+      for side in sides.iter() {
+        side
+          .socket
+          .send_to(&postcard::to_allocvec(&Packet::Ping)?, side.address)?;
+      }
+      next_keepalive += KEEPALIVE_INTERVAL;
+    }
+
+    for src in 0..sides.len() {
+      let dst = 1 - src;
+      let size = match sides[src].socket.recv_from(&mut buf) {
+        Ok((size, _)) => size,
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+        Err(e) => return Err(e.into()),
+      };
+      received = true;
+
+      let mut samples = match Packet::decode(&buf[..size]) {
+        // An emergency is passed on as ordinary audio; the far server
+        // decides for itself whether to let one through.
+        Ok(Some(Packet::Audio(samples) | Packet::Emergency { samples })) => {
+          samples
+        }
+        Ok(_) => {
+          continue;
+        }
+        Err(err) => {
+          warn!("Failed to decode packet: {err:?}");
+          continue;
+        }
+      };
+
+      // Half-duplex: while the other side holds the link, drop this side's
+      // audio (it's most likely our own relay echoing back).
+      if let Some((owner, _)) = keyed
+        && owner != src
+      {
+        continue;
+      }
+
+      if keyed.is_none() {
+        info!("Relaying {} -> {}", sides[src].name, sides[dst].name);
+      }
+      keyed = Some((src, Instant::now()));
+      tail_sent = false;
+
+      sides[src].fx_unit.run(&mut samples);
+      sides[dst].socket.send_to(
+        &postcard::to_allocvec(&Packet::Audio(samples))?,
+        sides[dst].address,
+      )?;
+    }
+
+    if let Some((owner, last)) = keyed {
+      let dst = 1 - owner;
+
+      if !tail_sent && last.elapsed() >= WAIT_DURATION.mul_f32(7.0) {
+        tail_sent = true;
+        for chunk in sides[owner].fx_unit.squelch_with(true) {
+          sides[dst].socket.send_to(
+            &postcard::to_allocvec(&Packet::Audio(chunk))?,
+            sides[dst].address,
+          )?;
+        }
+      }
+
+      if last.elapsed() >= hang {
+        keyed = None;
+        info!("Link idle");
+      }
+    }
+
+    if !received {
+      // Small delay to prevent busy waiting
+      std::thread::sleep(Duration::from_millis(1));
+    }
+  }
+}
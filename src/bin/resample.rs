@@ -5,6 +5,9 @@ use audioadapter_buffers::direct::SequentialSliceOfVecs;
 use clap::Parser;
 use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
 use rubato::{Fft, FixedSync, Resampler};
+use tracing::info;
+
+use squelch::logging::Verbosity;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -32,10 +35,14 @@ struct Args {
   /// Hard-clip output samples to [-1.0, 1.0]
   #[arg(long, default_value_t = true)]
   clip: bool,
+
+  #[command(flatten)]
+  verbosity: Verbosity,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
   let args = Args::parse();
+  args.verbosity.init();
 
   let mut reader = WavReader::open(&args.input)?;
   let spec = reader.spec();
@@ -55,7 +62,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     return Err("sub_chunks must be > 0".into());
   }
 
-  eprintln!(
+  info!(
     "Reading {:?} ({} Hz, {} ch, {:?} {}-bit)",
     args.input, src_rate, channels, spec.sample_format, spec.bits_per_sample
   );
@@ -106,7 +113,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     );
   }
 
-  eprintln!(
+  info!(
     "Resampling {} frames/channel: {} Hz -> {} Hz",
     input_len, src_rate, args.rate
   );
@@ -138,7 +145,7 @@ fn main() -> Result<(), Box<dyn Error>> {
   let (_nbr_in, nbr_out) =
     resampler.process_all_into_buffer(&input, &mut output, input_len, None)?;
 
-  eprintln!(
+  info!(
     "Writing {:?} ({} Hz, {} ch, {} frames/channel)",
     args.output, args.rate, channels, nbr_out
   );
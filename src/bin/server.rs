@@ -1,104 +1,316 @@
 use std::{
-  collections::{HashMap, VecDeque},
-  net::{SocketAddr, UdpSocket},
-  sync::mpsc::channel,
-  time::Instant,
+  fs::File,
+  io::{self, BufWriter},
+  net::{IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::SystemTime,
 };
 
+use clap::Parser;
+use hound::{WavSpec, WavWriter};
+use ipnet::IpNet;
 use squelch::{
-  MAX_PACKET_SIZE, Packet, TX_BUFFER_SIZE, TxBuffer, WAIT_DURATION,
+  CHANNELS, SAMPLE_RATE,
+  fx::FxConfig,
+  logging::Verbosity,
+  mix::{Attenuation, MixMode, MixPolicy},
+  net,
+  server::{Server, ServerConfig},
+  transmissions::{self, TransmissionLog},
 };
+use tracing::{error, info};
 
-/// Maximum number of buffered audio chunks to retain per client before
-/// dropping the oldest.
-const MAX_CLIENT_BACKLOG: usize = 8;
+/// Squelch server
+#[derive(Debug, Clone, Parser)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+  /// Maximum packets per second accepted from a single client (0 = no limit).
+  /// Real-time audio is ~172 packets/sec.
+  #[arg(long, default_value_t = 200.0)]
+  pub max_pps: f64,
+
+  /// Number of packets a client may burst above `--max-pps`.
+  #[arg(long, default_value_t = 32.0)]
+  pub burst: f64,
+
+  /// Only accept clients from these IPs or CIDR ranges (repeatable). Accepts
+  /// everyone when empty.
+  #[arg(long, value_parser = parse_ip_net)]
+  pub allow: Vec<IpNet>,
+
+  /// Reject clients from these IPs or CIDR ranges (repeatable). Takes
+  /// precedence over `--allow`.
+  #[arg(long, value_parser = parse_ip_net)]
+  pub block: Vec<IpNet>,
+
+  /// How to scale the mix by the number of simultaneous talkers: none, sqrt
+  /// or linear.
+  #[arg(long, default_value_t = Attenuation::None)]
+  pub attenuation: Attenuation,
+
+  /// Mixing shorthand: sum, average, or auto (divide by the talkers with
+  /// non-silent audio this tick). Overrides `--attenuation`.
+  #[arg(long, conflicts_with = "attenuation")]
+  pub mix_mode: Option<MixMode>,
+
+  /// Extra attenuation in dB applied to the mix before clamping.
+  #[arg(long, default_value_t = 0.0)]
+  pub headroom_db: f32,
+
+  /// Ask the OS for UDP receive/send buffers of this many KiB (it may
+  /// clamp the request). Helps avoid drops under bursty traffic.
+  #[arg(long)]
+  pub udp_buffer_kb: Option<usize>,
+
+  /// Always send the mix to this address (repeatable), without waiting for
+  /// it to ping. Clients are normally dynamic peers that join by pinging;
+  /// static peers suit fixed links to another server or a recorder.
+  #[arg(long)]
+  pub peer: Vec<SocketAddr>,
+
+  /// Turn away clients past this many, telling them the server is full (0
+  /// = no limit). Static `--peer`s don't count.
+  #[arg(long, default_value_t = 0)]
+  pub max_clients: usize,
+
+  /// Apply the FX here, to each client's mix, instead of in each client, so
+  /// everyone hears the same channel sound. Takes a preset name (default,
+  /// vhf-air, hf-ssb or cb), or uses the default one if left bare. Clients
+  /// turn their own FX off.
+  #[arg(
+    long,
+    value_parser = parse_preset,
+    num_args = 0..=1,
+    default_missing_value = "default"
+  )]
+  pub server_fx: Option<FxConfig>,
+
+  /// Diagnostic mode: instead of mixing, send every connected client a
+  /// steady tone at this many Hz, e.g. 1000, to check a receive path, FX
+  /// and squelch without anyone else on. Clients' own audio is ignored.
+  #[arg(
+    long,
+    conflicts_with = "server_fx",
+    value_parser = clap::value_parser!(u32).range(1..22050)
+  )]
+  pub test_tone: Option<u32>,
+
+  /// Let clients make emergency transmissions, which every client hears on
+  /// every channel after an attention tone. Without this they're mixed
+  /// into the sender's channel like any other audio.
+  #[arg(long)]
+  pub allow_emergency: bool,
+
+  /// Record the channel to this WAV file, with a log of each transmission
+  /// (client, start, end, duration and peak level) written next to it as
+  /// JSON when the server is stopped with Ctrl+C.
+  #[arg(long)]
+  pub record: Option<PathBuf>,
+
+  /// Serve clients from a single tokio task instead of a receive thread and
+  /// a mixer thread.
+  #[cfg(feature = "async")]
+  #[arg(long = "async")]
+  pub async_io: bool,
+
+  /// Serve Prometheus metrics over HTTP on this address.
+  #[cfg(feature = "metrics")]
+  #[arg(long)]
+  pub metrics_addr: Option<SocketAddr>,
+
+  #[command(flatten)]
+  pub verbosity: Verbosity,
+}
+
+/// Parses either a CIDR range (`10.0.0.0/8`) or a plain IP (`10.0.0.1`).
+fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+  s.parse::<IpNet>()
+    .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+    .map_err(|_| format!("invalid IP address or CIDR range: {s}"))
+}
+
+fn parse_preset(name: &str) -> Result<FxConfig, String> {
+  FxConfig::preset(name).ok_or_else(|| {
+    format!(
+      "unknown preset {name:?} (expected one of: {})",
+      FxConfig::PRESETS.join(", ")
+    )
+  })
+}
+
+/// Whether a client IP passes the `--allow`/`--block` lists.
+fn is_allowed(args: &Cli, ip: IpAddr) -> bool {
+  if args.block.iter().any(|net| net.contains(&ip)) {
+    return false;
+  }
+
+  args.allow.is_empty() || args.allow.iter().any(|net| net.contains(&ip))
+}
+
+/// Records the mix to `path` and logs transmissions, writing both out on
+/// Ctrl+C.
+fn record(server: &mut Server, path: &Path) -> io::Result<()> {
+  let spec = WavSpec {
+    channels: CHANNELS,
+    sample_rate: SAMPLE_RATE,
+    bits_per_sample: 32,
+    sample_format: hound::SampleFormat::Float,
+  };
+  let writer = WavWriter::create(path, spec).map_err(io::Error::other)?;
+  let writer: Arc<Mutex<Option<WavWriter<BufWriter<File>>>>> =
+    Arc::new(Mutex::new(Some(writer)));
+  let log = Arc::new(Mutex::new(TransmissionLog::new(
+    transmissions::default_gap(),
+  )));
+
+  let mix_writer = writer.clone();
+  server.on_mix(move |mix| {
+    let mut writer = mix_writer.lock().unwrap();
+    if let Some(w) = writer.as_mut()
+      && let Err(e) = mix.iter().try_for_each(|s| w.write_sample(*s))
+    {
+      error!("Stopped recording: {e}");
+      *writer = None;
+    }
+  });
+
+  let packet_log = log.clone();
+  server.on_packet(move |src, packet| {
+    packet_log
+      .lock()
+      .unwrap()
+      .record(src, packet, SystemTime::now());
+  });
+
+  let json_path = path.with_extension("json");
+  ctrlc::set_handler(move || {
+    info!("Received Ctrl+C, saving recording...");
+    if let Some(writer) = writer.lock().unwrap().take()
+      && let Err(e) = writer.finalize()
+    {
+      error!("Failed to finalize recording: {e}");
+    }
+
+    let log = log.lock().unwrap().clone();
+    let origin = log.started();
+    let transmissions = log.finish();
+    match std::fs::write(
+      &json_path,
+      transmissions::to_json(&transmissions, origin),
+    ) {
+      Ok(()) => info!(
+        "Logged {} transmissions to {}",
+        transmissions.len(),
+        json_path.display()
+      ),
+      Err(e) => error!("Failed to write {}: {e}", json_path.display()),
+    }
+    std::process::exit(0);
+  })
+  .map_err(io::Error::other)
+}
 
 fn main() -> std::io::Result<()> {
-  let socket = UdpSocket::bind("0.0.0.0:1837")?;
+  let args = Cli::parse();
+  args.verbosity.init();
+
+  let socket =
+    net::bind_udp(SocketAddr::from(([0, 0, 0, 0], 1837)), args.udp_buffer_kb)?;
   socket
     .set_broadcast(true)
     .expect("set_broadcast to true should succeed");
 
-  let (audio_tx, audio_rx) = channel::<(SocketAddr, TxBuffer)>();
-  let (ping_tx, ping_rx) = channel::<SocketAddr>();
-
-  let cloned_socket = socket.try_clone().unwrap();
-  std::thread::spawn(move || {
-    let mut last_sent = Instant::now();
-    let mut client_chunks: HashMap<SocketAddr, VecDeque<TxBuffer>> =
-      HashMap::new();
-
-    let mut current_chunks: Vec<(SocketAddr, TxBuffer)> = Vec::new();
-    let mut buf = [0f32; TX_BUFFER_SIZE];
-    loop {
-      while let Ok(src) = ping_rx.try_recv() {
-        client_chunks.entry(src).or_default();
-        println!("Now {} clients", client_chunks.len());
-      }
-
-      while let Ok((src, bytes)) = audio_rx.try_recv() {
-        client_chunks
-          .entry(src)
-          .and_modify(|e| e.push_back(bytes))
-          .or_insert_with(|| VecDeque::from_iter([bytes]));
-      }
-
-      if last_sent.elapsed() > *WAIT_DURATION {
-        current_chunks.clear();
-
-        for (src, chunks) in client_chunks.iter_mut() {
-          while chunks.len() > MAX_CLIENT_BACKLOG {
-            chunks.pop_front();
-          }
-
-          if let Some(samples) = chunks.pop_front() {
-            current_chunks.push((*src, samples));
-          }
-        }
-
-        for (client, _) in client_chunks.iter() {
-          buf.fill(0.0);
-
-          for (src, samples) in current_chunks.iter() {
-            if src != client {
-              for (b, s) in buf.iter_mut().zip(samples.iter()) {
-                *b += s;
-                *b = b.clamp(-1.0, 1.0);
-              }
-            }
-          }
-
-          if buf.iter().any(|a| *a != 0.0) {
-            cloned_socket
-              .send_to(
-                &postcard::to_allocvec(&Packet::Audio(buf)).unwrap(),
-                client,
-              )
-              .unwrap();
-          }
-        }
-
-        last_sent += *WAIT_DURATION;
-        if last_sent.elapsed() > *WAIT_DURATION * 4 {
-          last_sent = Instant::now();
-        }
-      }
-    }
-  });
+  let policy = match args.mix_mode {
+    Some(mode) => mode.policy(args.headroom_db),
+    None => MixPolicy {
+      attenuation: args.attenuation,
+      headroom_db: args.headroom_db,
+      active_only: false,
+    },
+  };
 
-  let mut buf = [0; MAX_PACKET_SIZE];
-  loop {
-    let (_, src) = socket.recv_from(&mut buf)?;
-    match postcard::from_bytes::<Packet>(&buf) {
-      Ok(packet) => match packet {
-        Packet::Ping => {
-          ping_tx.send(src).unwrap();
-        }
-        Packet::Audio(bytes) => {
-          audio_tx.send((src, bytes)).unwrap();
-        }
-      },
-      Err(err) => eprintln!("Error decoding packet: {err:?}"),
-    }
+  let mut server = Server::new(
+    socket,
+    ServerConfig {
+      max_pps: args.max_pps,
+      burst: args.burst,
+      policy,
+      peers: args.peer.clone(),
+      max_clients: args.max_clients,
+      fx: args.server_fx.clone(),
+      loopback: false,
+      test_tone: args.test_tone,
+      allow_emergency: args.allow_emergency,
+    },
+  );
+
+  #[cfg(feature = "metrics")]
+  if let Some(addr) = args.metrics_addr {
+    server.metrics().serve(addr)?;
+    info!("Serving metrics on http://{addr}/metrics");
+  }
+
+  if let Some(path) = &args.record {
+    record(&mut server, path)?;
+    info!("Recording to {}", path.display());
+  }
+
+  #[cfg(feature = "async")]
+  let async_io = args.async_io;
+
+  server.admit(move |ip| is_allowed(&args, ip));
+
+  #[cfg(feature = "async")]
+  if async_io {
+    return tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()?
+      .block_on(server.run_async());
+  }
+
+  server.run()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cli(args: &[&str]) -> Cli {
+    Cli::parse_from(std::iter::once("server").chain(args.iter().copied()))
+  }
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn everyone_is_allowed_without_lists() {
+    let args = cli(&[]);
+    assert!(is_allowed(&args, ip("10.0.0.1")));
+    assert!(is_allowed(&args, ip("::1")));
+  }
+
+  #[test]
+  fn allow_list_admits_only_its_ranges() {
+    let args = cli(&["--allow", "10.0.0.0/8", "--allow", "192.168.1.7"]);
+    assert!(is_allowed(&args, ip("10.1.2.3")));
+    assert!(is_allowed(&args, ip("192.168.1.7")));
+    assert!(!is_allowed(&args, ip("192.168.1.8")));
+    assert!(!is_allowed(&args, ip("172.16.0.1")));
+  }
+
+  #[test]
+  fn block_list_rejects_its_ranges() {
+    let args = cli(&["--block", "172.16.0.0/12"]);
+    assert!(!is_allowed(&args, ip("172.16.5.5")));
+    assert!(is_allowed(&args, ip("10.0.0.1")));
+  }
+
+  #[test]
+  fn block_takes_precedence_over_allow() {
+    let args = cli(&["--allow", "10.0.0.0/8", "--block", "10.0.0.5"]);
+    assert!(is_allowed(&args, ip("10.0.0.4")));
+    assert!(!is_allowed(&args, ip("10.0.0.5")));
   }
 }
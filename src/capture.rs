@@ -0,0 +1,89 @@
+//! Raw packet captures.
+//!
+//! A capture file is a flat sequence of records, one per datagram, with no
+//! header:
+//!
+//! ```text
+//! u64 LE  microseconds since the capture started
+//! u32 LE  datagram length in bytes
+//! [u8]    the datagram, exactly as received
+//! ```
+//!
+//! Datagrams are stored undecoded, so pings and malformed packets are kept
+//! alongside audio.
+
+use std::{
+  fs::{File, OpenOptions},
+  io::{self, BufReader, BufWriter, Read, Write},
+  path::Path,
+  time::{Duration, Instant},
+};
+
+pub struct CaptureWriter {
+  writer: BufWriter<File>,
+  start: Instant,
+}
+
+impl CaptureWriter {
+  /// Opens `path` for appending, creating it if needed.
+  pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Self {
+      writer: BufWriter::new(file),
+      start: Instant::now(),
+    })
+  }
+
+  pub fn write(&mut self, datagram: &[u8]) -> io::Result<()> {
+    let offset = self.start.elapsed().as_micros() as u64;
+    self.writer.write_all(&offset.to_le_bytes())?;
+    self
+      .writer
+      .write_all(&(datagram.len() as u32).to_le_bytes())?;
+    self.writer.write_all(datagram)
+  }
+
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.writer.flush()
+  }
+}
+
+pub struct CaptureReader {
+  reader: BufReader<File>,
+}
+
+impl CaptureReader {
+  pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+    Ok(Self {
+      reader: BufReader::new(File::open(path)?),
+    })
+  }
+
+  fn read_record(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+    let mut offset = [0u8; 8];
+    match self.reader.read_exact(&mut offset) {
+      Ok(()) => {}
+      Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(e) => return Err(e),
+    }
+
+    let mut len = [0u8; 4];
+    self.reader.read_exact(&mut len)?;
+    let mut datagram = vec![0u8; u32::from_le_bytes(len) as usize];
+    self.reader.read_exact(&mut datagram)?;
+
+    Ok(Some((
+      Duration::from_micros(u64::from_le_bytes(offset)),
+      datagram,
+    )))
+  }
+}
+
+impl Iterator for CaptureReader {
+  /// The datagram and its offset from the start of the capture.
+  type Item = io::Result<(Duration, Vec<u8>)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.read_record().transpose()
+  }
+}
@@ -0,0 +1,138 @@
+use std::{
+  io,
+  net::{SocketAddr, UdpSocket},
+  sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TrySendError},
+  thread,
+};
+
+use tracing::warn;
+
+use crate::{
+  KEEPALIVE_INTERVAL, MAX_PACKET_SIZE, PROTOCOL_VERSION, Packet, TxBuffer,
+  fx::FxUnit, seq::SeqTracker,
+};
+
+/// How many received buffers [`Client::incoming`] queues for a slow consumer
+/// before it starts dropping them (~185 ms).
+pub const INCOMING_BACKLOG: usize = 32;
+
+/// A connection to a squelch server, for embedding squelch in another app
+/// without going through cpal.
+pub struct Client {
+  socket: UdpSocket,
+  address: SocketAddr,
+  // Dropped with the client, which stops the keepalive thread.
+  _keepalive: Sender<()>,
+}
+
+impl Client {
+  /// Binds a local socket and pings `address` so the server starts sending
+  /// us audio, then says which protocol version we speak. Pings again every
+  /// [`KEEPALIVE_INTERVAL`] for as long as the client lives.
+  pub fn connect(address: SocketAddr) -> io::Result<Self> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let ping =
+      postcard::to_allocvec(&Packet::Ping).map_err(io::Error::other)?;
+    socket.send_to(&ping, address)?;
+    let hello = postcard::to_allocvec(&Packet::Hello {
+      version: PROTOCOL_VERSION,
+    })
+    .map_err(io::Error::other)?;
+    socket.send_to(&hello, address)?;
+
+    let (keepalive, stop) = mpsc::channel::<()>();
+    let pinger = socket.try_clone()?;
+    thread::spawn(move || {
+      while let Err(RecvTimeoutError::Timeout) =
+        stop.recv_timeout(KEEPALIVE_INTERVAL)
+      {
+        if let Err(e) = pinger.send_to(&ping, address) {
+          warn!("Failed to ping {address}: {e}");
+        }
+      }
+    });
+
+    Ok(Self {
+      socket,
+      address,
+      _keepalive: keepalive,
+    })
+  }
+
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  /// Transmits one buffer of audio.
+  pub fn send(&self, samples: TxBuffer) -> io::Result<()> {
+    let packet = postcard::to_allocvec(&Packet::Audio(samples))
+      .map_err(io::Error::other)?;
+    self.socket.send_to(&packet, self.address)?;
+    Ok(())
+  }
+
+  /// Spawns a thread that decodes every audio packet from the server and
+  /// sends it down the returned channel, run through `fx` first if one is
+  /// given (pass `None` for raw audio).
+  ///
+  /// The channel holds at most [`INCOMING_BACKLOG`] buffers. Audio is
+  /// real-time, so if the consumer falls further behind than that, newly
+  /// received buffers are dropped rather than blocking the socket or growing
+  /// without bound. The thread exits once the receiver is dropped and the
+  /// next packet arrives.
+  pub fn incoming(
+    &self,
+    mut fx: Option<FxUnit>,
+  ) -> io::Result<Receiver<TxBuffer>> {
+    let socket = self.socket.try_clone()?;
+    let (tx, rx) = mpsc::sync_channel(INCOMING_BACKLOG);
+
+    thread::spawn(move || {
+      let mut buf = [0; MAX_PACKET_SIZE];
+      let mut seqs = SeqTracker::new();
+      loop {
+        let size = match socket.recv_from(&mut buf) {
+          Ok((size, _)) => size,
+          Err(e) => {
+            warn!("UDP receive error: {}", e);
+            continue;
+          }
+        };
+
+        let mut samples = match Packet::decode(&buf[..size]) {
+          Ok(Some(Packet::Audio(samples) | Packet::Emergency { samples })) => {
+            samples
+          }
+          // Late buffers are dropped rather than played out of order.
+          Ok(Some(
+            Packet::SeqAudio { seq, samples }
+            | Packet::StampedAudio { seq, samples, .. },
+          )) => {
+            if !seqs.accept(seq) {
+              continue;
+            }
+            samples
+          }
+          Ok(_) => {
+            continue;
+          }
+          Err(err) => {
+            warn!("Failed to decode packet: {err:?}");
+            continue;
+          }
+        };
+
+        if let Some(fx) = fx.as_mut() {
+          fx.run(&mut samples);
+        }
+
+        match tx.try_send(samples) {
+          Ok(()) | Err(TrySendError::Full(_)) => {}
+          Err(TrySendError::Disconnected(_)) => break,
+        }
+      }
+    });
+
+    Ok(rx)
+  }
+}
@@ -0,0 +1,338 @@
+use cpal::{
+  BuildStreamError, FromSample, I24, SampleFormat, SizedSample,
+  traits::{DeviceTrait, HostTrait},
+};
+use tracing::{debug, warn};
+
+use crate::SAMPLE_RATE;
+
+/// Picks the cpal host backend by name (case-insensitive), or the platform
+/// default when `name` is `None`.
+pub fn select_host(name: Option<&str>) -> Result<cpal::Host, String> {
+  let Some(name) = name else {
+    return Ok(cpal::default_host());
+  };
+
+  let available = cpal::available_hosts();
+  let id = available
+    .iter()
+    .find(|id| id.name().eq_ignore_ascii_case(name))
+    .ok_or_else(|| {
+      let names: Vec<_> = available.iter().map(|id| id.name()).collect();
+      format!(
+        "unknown audio host {name:?} (available: {})",
+        names.join(", ")
+      )
+    })?;
+
+  cpal::host_from_id(*id).map_err(|e| format!("{}: {e}", id.name()))
+}
+
+/// Prints every host backend compiled in, marking the default.
+pub fn list_hosts() {
+  let default = cpal::default_host().id();
+  println!("Available audio hosts:");
+  for id in cpal::available_hosts() {
+    let marker = if id == default { " (default)" } else { "" };
+    println!("  {}{marker}", id.name());
+  }
+}
+
+/// Prints `host`'s input and output devices, marking the defaults, for
+/// `--input-device` and `--output-device`.
+pub fn list_devices(host: &cpal::Host) {
+  for input in [true, false] {
+    let (kind, default, devices) = if input {
+      (
+        "input",
+        host.default_input_device(),
+        host.input_devices().map(Iterator::collect::<Vec<_>>),
+      )
+    } else {
+      (
+        "output",
+        host.default_output_device(),
+        host.output_devices().map(Iterator::collect::<Vec<_>>),
+      )
+    };
+    let default = default.and_then(|device| device.name().ok());
+
+    println!("Available {kind} devices:");
+    match devices {
+      Ok(devices) if devices.is_empty() => println!("  (none)"),
+      Ok(devices) => {
+        for name in devices.iter().filter_map(|device| device.name().ok()) {
+          let marker = if default.as_ref() == Some(&name) {
+            " (default)"
+          } else {
+            ""
+          };
+          println!("  {name}{marker}");
+        }
+      }
+      Err(e) => println!("  (couldn't list them: {e})"),
+    }
+  }
+}
+
+/// Picks `host`'s input (or output) device called `name`, ignoring case, or
+/// its default when `name` is `None`. An unknown name falls back to the
+/// default too, with a warning that lists the devices there are.
+pub fn select_device(
+  host: &cpal::Host,
+  name: Option<&str>,
+  input: bool,
+) -> Option<cpal::Device> {
+  let default = || {
+    if input {
+      host.default_input_device()
+    } else {
+      host.default_output_device()
+    }
+  };
+  let Some(name) = name else {
+    return default();
+  };
+
+  let devices: Vec<_> = if input {
+    host.input_devices().map(Iterator::collect)
+  } else {
+    host.output_devices().map(Iterator::collect)
+  }
+  .unwrap_or_default();
+  if let Some(device) = devices.iter().find(|device| {
+    device
+      .name()
+      .is_ok_and(|device| device.eq_ignore_ascii_case(name))
+  }) {
+    return Some(device.clone());
+  }
+
+  let names: Vec<_> = devices.iter().filter_map(|d| d.name().ok()).collect();
+  let kind = if input { "input" } else { "output" };
+  warn!(
+    "No {kind} device named {name:?}; using the default (available: {})",
+    names.join(", ")
+  );
+  default()
+}
+
+/// The rate to run `device`'s output stream at: its own default, which the
+/// speaker resamples to (see [`crate::playback::build_speaker_stream`]),
+/// rather than forcing [`SAMPLE_RATE`] on it.
+pub fn output_rate(device: &cpal::Device) -> u32 {
+  device
+    .default_output_config()
+    .map(|config| config.sample_rate().0)
+    .unwrap_or(SAMPLE_RATE)
+}
+
+/// Runs `$build::<T>(args)` for the sample type `T` of `$format`, or fails
+/// for a format cpal has added since.
+macro_rules! for_sample_format {
+  ($format:expr, $build:ident($($arg:expr),* $(,)?)) => {
+    match $format {
+      SampleFormat::I8 => $build::<i8>($($arg),*),
+      SampleFormat::I16 => $build::<i16>($($arg),*),
+      SampleFormat::I24 => $build::<I24>($($arg),*),
+      SampleFormat::I32 => $build::<i32>($($arg),*),
+      SampleFormat::I64 => $build::<i64>($($arg),*),
+      SampleFormat::U8 => $build::<u8>($($arg),*),
+      SampleFormat::U16 => $build::<u16>($($arg),*),
+      SampleFormat::U32 => $build::<u32>($($arg),*),
+      SampleFormat::U64 => $build::<u64>($($arg),*),
+      SampleFormat::F32 => $build::<f32>($($arg),*),
+      SampleFormat::F64 => $build::<f64>($($arg),*),
+      _ => Err(BuildStreamError::StreamConfigNotSupported),
+    }
+  };
+}
+
+/// Builds an input stream in `device`'s native sample format, handing
+/// `on_data` each block converted to f32. Not every mic takes F32; many
+/// only do I16.
+pub fn build_input_stream(
+  device: &cpal::Device,
+  config: &cpal::StreamConfig,
+  on_data: impl FnMut(&[f32]) + Send + 'static,
+  err_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError> {
+  let format = device
+    .default_input_config()
+    .map(|config| config.sample_format())
+    .unwrap_or(SampleFormat::F32);
+  debug!("mic sample format: {format:?}");
+  for_sample_format!(format, input_stream(device, config, on_data, err_fn))
+}
+
+/// Builds an output stream in `device`'s native sample format, running
+/// `fill` on an f32 buffer and converting it into the device's.
+pub fn build_output_stream(
+  device: &cpal::Device,
+  config: &cpal::StreamConfig,
+  fill: impl FnMut(&mut [f32]) + Send + 'static,
+  err_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError> {
+  let format = device
+    .default_output_config()
+    .map(|config| config.sample_format())
+    .unwrap_or(SampleFormat::F32);
+  debug!("spk sample format: {format:?}");
+  for_sample_format!(format, output_stream(device, config, fill, err_fn))
+}
+
+fn input_stream<T>(
+  device: &cpal::Device,
+  config: &cpal::StreamConfig,
+  mut on_data: impl FnMut(&[f32]) + Send + 'static,
+  err_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError>
+where
+  T: SizedSample,
+  f32: FromSample<T>,
+{
+  let mut scratch: Vec<f32> = Vec::new();
+  device.build_input_stream(
+    config,
+    move |data: &[T], _: &_| {
+      convert_in(data, &mut scratch);
+      on_data(&scratch);
+    },
+    err_fn,
+    None,
+  )
+}
+
+fn output_stream<T>(
+  device: &cpal::Device,
+  config: &cpal::StreamConfig,
+  mut fill: impl FnMut(&mut [f32]) + Send + 'static,
+  err_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError>
+where
+  T: SizedSample + FromSample<f32>,
+{
+  let mut scratch: Vec<f32> = Vec::new();
+  device.build_output_stream(
+    config,
+    move |data: &mut [T], _: &_| {
+      scratch.resize(data.len(), 0.0);
+      fill(&mut scratch);
+      convert_out(&scratch, data);
+    },
+    err_fn,
+    None,
+  )
+}
+
+/// Converts a block from the mic's sample format to f32, into `out`.
+fn convert_in<T>(data: &[T], out: &mut Vec<f32>)
+where
+  T: SizedSample,
+  f32: FromSample<T>,
+{
+  out.clear();
+  out.extend(data.iter().map(|s| s.to_sample::<f32>()));
+}
+
+/// Converts f32 `samples` to the speaker's sample format, into `out`. cpal's
+/// conversions take -1.0 up to but not including +1.0, and +1.0 itself
+/// overflows I24 (which has no saturating cast) into a full-scale negative
+/// click, so integer formats are clamped just below it.
+fn convert_out<T: SizedSample + FromSample<f32>>(
+  samples: &[f32],
+  out: &mut [T],
+) {
+  let top = if T::FORMAT.is_float() {
+    1.0
+  } else {
+    1.0 - f32::EPSILON / 2.0
+  };
+  for (out, s) in out.iter_mut().zip(samples) {
+    *out = T::from_sample(s.clamp(-1.0, top));
+  }
+}
+
+/// Checks that `device` can run at [`SAMPLE_RATE`]. Nothing resamples between
+/// a mic and the wire, so it's better to stop with a clear error up front
+/// than to fail with a vague backend one, or play at the wrong pitch. Output
+/// devices don't need this; see [`output_rate`].
+pub fn check_rate(device: &cpal::Device, input: bool) -> Result<(), String> {
+  let name = device.name().unwrap_or_else(|_| "audio device".to_owned());
+  let configs: Vec<_> = if input {
+    device.supported_input_configs().map(Iterator::collect)
+  } else {
+    device.supported_output_configs().map(Iterator::collect)
+  }
+  .map_err(|e| format!("{name}: {e}"))?;
+
+  let rate = cpal::SampleRate(SAMPLE_RATE);
+  if configs
+    .iter()
+    .any(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+  {
+    return Ok(());
+  }
+
+  let rates: Vec<_> = configs
+    .iter()
+    .map(|c| match (c.min_sample_rate().0, c.max_sample_rate().0) {
+      (min, max) if min == max => format!("{min} Hz"),
+      (min, max) => format!("{min}-{max} Hz"),
+    })
+    .collect();
+  Err(format!(
+    "{name} doesn't support {SAMPLE_RATE} Hz (only {}); set it to \
+     {SAMPLE_RATE} Hz in your sound settings or pick another device",
+    rates.join(", ")
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Converts `samples` out to `T` and back.
+  fn round_trip<T: SizedSample + FromSample<f32>>(
+    samples: &[f32],
+  ) -> (Vec<T>, Vec<f32>)
+  where
+    f32: FromSample<T>,
+  {
+    let mut out = vec![T::EQUILIBRIUM; samples.len()];
+    convert_out(samples, &mut out);
+    let mut back = Vec::new();
+    convert_in(&out, &mut back);
+    (out, back)
+  }
+
+  /// Checks that full scale reaches `min` and `max`, silence `mid`, and that
+  /// a round trip is within `lsb`.
+  fn check<T>(min: T, mid: T, max: T, lsb: f32)
+  where
+    T: SizedSample + FromSample<f32> + std::fmt::Debug,
+    f32: FromSample<T>,
+  {
+    let (out, _) = round_trip::<T>(&[-1.0, 0.0, 1.0]);
+    assert_eq!(out, [min, mid, max], "{}", T::FORMAT);
+
+    let ramp: Vec<f32> = (-1000..=1000).map(|i| i as f32 / 1000.0).collect();
+    let (_, back) = round_trip::<T>(&ramp);
+    for (s, back) in ramp.iter().zip(&back) {
+      assert!(
+        (s - back).abs() <= lsb,
+        "{}: {s} came back {back}",
+        T::FORMAT
+      );
+    }
+  }
+
+  #[test]
+  fn each_format_converts_at_full_scale_and_back() {
+    check(i16::MIN, 0, i16::MAX, 1.0 / 32_768.0);
+    check(u16::MIN, 32_768, u16::MAX, 1.0 / 32_768.0);
+    let i24 = |i| I24::new(i).unwrap();
+    check(i24(-8_388_608), i24(0), i24(8_388_607), 1.0 / 8_388_608.0);
+    check(-1.0, 0.0, 1.0, 0.0);
+  }
+}
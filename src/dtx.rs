@@ -0,0 +1,119 @@
+//! Discontinuous transmission.
+//!
+//! With DTX on, the client stops sending audio while the talker is silent
+//! and sends a [`Packet::Silence`](crate::Packet::Silence) marker every
+//! [`SID_INTERVAL`] buffers instead. Receivers treat the markers as "still
+//! transmitting": rather than playing the squelch tail when audio stops,
+//! they fill the gap with comfort noise until audio resumes or the markers
+//! stop too. The markers aren't numbered, so gaps are detected by time.
+
+use crate::TxBuffer;
+
+/// RMS below which a buffer counts as silence (~-40 dBFS).
+pub const DTX_THRESHOLD: f32 = 0.01;
+/// Buffers still sent after speech drops below the threshold, so word
+/// endings aren't clipped (~46 ms).
+pub const DTX_HANGOVER: usize = 8;
+/// How often a silence marker goes out while silent, in buffers (~93 ms).
+pub const SID_INTERVAL: usize = 16;
+/// How many buffers a receiver waits without hearing anything before ending
+/// a transmission that's in a DTX pause: long enough for the next marker,
+/// with one lost marker to spare.
+pub const SID_TIMEOUT: usize = 2 * SID_INTERVAL + 1;
+
+/// What to do with one outgoing buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtxFrame {
+  Speech,
+  Silence,
+  Skip,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Dtx {
+  hangover: usize,
+  silent: usize,
+}
+
+impl Dtx {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn next(&mut self, samples: &TxBuffer) -> DtxFrame {
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>()
+      / samples.len() as f32)
+      .sqrt();
+
+    if rms >= DTX_THRESHOLD {
+      self.hangover = DTX_HANGOVER;
+      self.silent = 0;
+      return DtxFrame::Speech;
+    }
+
+    if self.hangover > 0 {
+      self.hangover -= 1;
+      return DtxFrame::Speech;
+    }
+
+    let frame = if self.silent.is_multiple_of(SID_INTERVAL) {
+      DtxFrame::Silence
+    } else {
+      DtxFrame::Skip
+    };
+    self.silent += 1;
+    frame
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::iter::repeat_n;
+
+  use super::*;
+  use crate::{
+    TX_BUFFER_SIZE,
+    fx::{COMFORT_NOISE_LEVEL, FxUnit},
+  };
+
+  #[test]
+  fn markers_keep_a_pause_open_until_speech_resumes() {
+    let mut dtx = Dtx::new();
+    let loud = [0.5; TX_BUFFER_SIZE];
+    let quiet = [0.001; TX_BUFFER_SIZE];
+    let input = repeat_n(&loud, 10)
+      .chain(repeat_n(&quiet, 200))
+      .chain(repeat_n(&loud, 10));
+    let frames: Vec<DtxFrame> = input.map(|buf| dtx.next(buf)).collect();
+
+    // The speech, and its hangover, go out whole.
+    assert!(
+      frames[..10 + DTX_HANGOVER]
+        .iter()
+        .all(|f| *f == DtxFrame::Speech)
+    );
+    assert_eq!(frames[10 + DTX_HANGOVER], DtxFrame::Silence);
+    assert!(frames[210..].iter().all(|f| *f == DtxFrame::Speech));
+
+    // Most of the pause isn't sent...
+    let sent: Vec<usize> = (0..frames.len())
+      .filter(|i| frames[*i] != DtxFrame::Skip)
+      .collect();
+    assert!(sent.len() < 50);
+
+    // ...but a receiver never goes long enough without a packet to end the
+    // transmission, even if it loses a marker.
+    let longest = sent.windows(2).map(|w| w[1] - w[0]).max().unwrap();
+    assert_eq!(longest, SID_INTERVAL);
+    assert!(2 * longest < SID_TIMEOUT);
+  }
+
+  #[test]
+  fn comfort_noise_is_quiet_static() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    let noise: Vec<f32> =
+      (0..SID_INTERVAL).flat_map(|_| fx.comfort_noise()).collect();
+    let peak = noise.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    assert!(peak > 0.0 && peak <= COMFORT_NOISE_LEVEL);
+  }
+}
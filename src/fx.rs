@@ -1,81 +1,786 @@
+use std::{fmt, ops::RangeInclusive, str::FromStr, time::Duration};
+
 use biquad::{
   Biquad, Coefficients, DirectForm1, Q_BUTTERWORTH_F32, ToHertz, Type,
 };
 use noise::{Fbm, NoiseFn, Simplex};
+use serde::{Deserialize, Serialize};
+
+use crate::{SAMPLE_RATE, TX_BUFFER_SIZE, TxBuffer};
+
+/// Level of the comfort noise played through DTX gaps.
+pub const COMFORT_NOISE_LEVEL: f32 = 0.02;
+
+/// Length of the fades around the squelch tail (~3 ms).
+pub const CROSSFADE_LEN: usize = 128;
+
+/// Corner of the DC blocker at the end of the chain, well below speech.
+const DC_BLOCK_HZ: f32 = 20.0;
+
+/// Squelch tail lengths [`FxConfig`] accepts, in buffers (~6 ms to ~370 ms).
+pub const SQUELCH_TAIL_RANGE: RangeInclusive<usize> = 1..=64;
+
+/// How far the noise floor advances through the noise field per sample at
+/// 44.1 kHz; high enough that it's a hiss rather than a rumble.
+const FLOOR_STEP: f64 = 0.3;
+
+/// Smallest distortion threshold [`FxUnit`] accepts. The distortion stages
+/// divide by the threshold, so zero (or a negative/NaN value from a preset
+/// file) would fill the output with inf/NaN.
+pub const MIN_DISTORTION: f32 = 1e-4;
+
+/// A sensible opening time for the mic gate: quick enough not to clip the
+/// first syllable.
+pub const DEFAULT_GATE_ATTACK: Duration = Duration::from_millis(1);
+
+/// The level [`FxUnit::agc`] steers the mic toward: a comfortable speech
+/// level (-20 dBFS RMS).
+pub const AGC_TARGET_RMS: f32 = 0.1;
+/// RMS below which a buffer is a pause, not speech, and the AGC's gain holds
+/// (~-46 dBFS), so silence isn't pulled up into audible hiss.
+const AGC_SPEECH_THRESHOLD: f32 = 0.005;
+/// The most the AGC will boost by (+20 dB), so noise isn't amplified to full
+/// level; it may cut by as much as it likes.
+const AGC_MAX_GAIN: f32 = 10.0;
+/// How quickly the AGC's gain comes down, and goes back up.
+const AGC_ATTACK: Duration = Duration::from_millis(10);
+const AGC_RELEASE: Duration = Duration::from_secs(1);
+
+/// Pitch and length of [`FxUnit::roger_beep`] until set otherwise.
+pub const DEFAULT_ROGER_HZ: f32 = 1000.0;
+pub const DEFAULT_ROGER_LEN: Duration = Duration::from_millis(150);
+
+/// The distortion model applied by [`FxUnit::run`]. Each one is driven by
+/// [`FxConfig::distortion`]; lower values are harsher.
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum DistortionKind {
+  /// Clamps to ±`distortion`, then applies makeup gain.
+  #[default]
+  HardClip,
+  /// Smooth `tanh` saturation with the same makeup gain as `HardClip`.
+  Tanh,
+  /// Quantizes to steps of `distortion`, like a low bit-depth converter.
+  BitCrush,
+  /// Holds every sample for `0.2 / distortion` samples, aliasing like a low
+  /// sample rate.
+  Downsample,
+}
+
+impl FromStr for DistortionKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "hard-clip" => Ok(Self::HardClip),
+      "tanh" => Ok(Self::Tanh),
+      "bit-crush" => Ok(Self::BitCrush),
+      "downsample" => Ok(Self::Downsample),
+      _ => Err(format!(
+        "unknown distortion type {s:?} (expected hard-clip, tanh, \
+         bit-crush or downsample)"
+      )),
+    }
+  }
+}
+
+impl fmt::Display for DistortionKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::HardClip => write!(f, "hard-clip"),
+      Self::Tanh => write!(f, "tanh"),
+      Self::BitCrush => write!(f, "bit-crush"),
+      Self::Downsample => write!(f, "downsample"),
+    }
+  }
+}
+
+/// How [`FxUnit`] narrows received audio down to a radio's voice band.
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterMode {
+  /// A low pass at [`FxConfig::lowpass_hz`] then a high pass at
+  /// [`FxConfig::highpass_hz`]. Each edge is placed on its own and rolls off
+  /// steeply, for a flat band with a sharp cut either side.
+  #[default]
+  Cascade,
+  /// A single band pass centred on [`FxConfig::bandpass_hz`], as wide as
+  /// [`FxConfig::bandpass_q`] makes it. Its skirts are gentler and its top is
+  /// rounded rather than flat, which sounds smoother and a little more
+  /// "boxy", but the band can't be made flat and wide at once.
+  BandPass,
+}
+
+/// Most bands a receive equalizer may have.
+pub const MAX_EQ_BANDS: usize = 3;
+
+/// One peaking band of the receive equalizer, e.g. a few dB around 2 kHz
+/// to bring out consonants.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EqBand {
+  pub freq_hz: f32,
+  /// Boost at `freq_hz` in dB; negative cuts.
+  pub gain_db: f32,
+  /// How narrow the band is; around 1 is a gentle, broad bump.
+  pub q: f32,
+}
+
+impl EqBand {
+  fn check(&self) -> Result<(), String> {
+    if !(self.freq_hz > 0.0 && self.freq_hz < 22050.0) {
+      return Err(format!(
+        "EQ frequency must be between 0 and 22050 Hz (got {})",
+        self.freq_hz
+      ));
+    }
+    if self.q.is_nan() || self.q <= 0.0 {
+      return Err(format!("EQ Q must be above 0 (got {})", self.q));
+    }
+    if !self.gain_db.is_finite() {
+      return Err(format!("EQ gain must be a number (got {})", self.gain_db));
+    }
+    Ok(())
+  }
+}
+
+/// Parses `freq,gain,q`, e.g. `2000,3,1.0`.
+impl FromStr for EqBand {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let fields: Vec<f32> = s
+      .split(',')
+      .map(|field| field.trim().parse::<f32>())
+      .collect::<Result<_, _>>()
+      .map_err(|e| format!("invalid EQ band {s:?}: {e}"))?;
+    let [freq_hz, gain_db, q] = fields[..] else {
+      return Err(format!("invalid EQ band {s:?} (expected freq,gain,q)"));
+    };
+
+    let band = Self {
+      freq_hz,
+      gain_db,
+      q,
+    };
+    band.check()?;
+    Ok(band)
+  }
+}
+
+/// Every tunable of an [`FxUnit`], so a "radio preset" can be saved, loaded
+/// and shared.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FxConfig {
+  pub disabled: bool,
+
+  pub signal_gain: f32,
+  /// Clipping threshold of the distortion stage; lower is harsher.
+  pub distortion: f32,
+  pub distortion_kind: DistortionKind,
+
+  pub filter: FilterMode,
+  /// Cutoffs for [`FilterMode::Cascade`].
+  pub lowpass_hz: f32,
+  pub highpass_hz: f32,
+  /// Centre and Q for [`FilterMode::BandPass`]; a lower Q is a wider band.
+  pub bandpass_hz: f32,
+  pub bandpass_q: f32,
+
+  pub noise_seed: u32,
+  /// Level of the static mixed into received audio.
+  pub noise_level: f32,
+  /// How far the noise field advances per sample; higher is hissier.
+  pub noise_step: f64,
+  /// Level of the static burst played when a transmission ends.
+  pub squelch_level: f32,
+  pub squelch_step: f64,
+  /// Length of the squelch tail, in buffers, within
+  /// [`SQUELCH_TAIL_RANGE`].
+  pub squelch_tail: usize,
+  /// Level of a faint hiss always added to received audio and comfort
+  /// noise, like a real receiver's, even with FX disabled. 0 turns it off.
+  pub noise_floor: f32,
+  /// Frequency of a ring modulator's carrier, for the "alien radio" sound.
+  /// 0 bypasses it.
+  pub ring_mod_hz: f32,
+  /// Peaking bands applied after the low and high pass (or band pass), up to
+  /// [`MAX_EQ_BANDS`].
+  pub eq: Vec<EqBand>,
+}
+
+impl Default for FxConfig {
+  fn default() -> Self {
+    Self {
+      disabled: false,
+      signal_gain: 1.0,
+      distortion: 0.05,
+      distortion_kind: DistortionKind::HardClip,
+      filter: FilterMode::Cascade,
+      lowpass_hz: 8000.0,
+      highpass_hz: 400.0,
+      bandpass_hz: 1000.0,
+      bandpass_q: 0.7,
+      noise_seed: 0,
+      noise_level: 0.3,
+      noise_step: 0.005,
+      squelch_level: 0.1,
+      squelch_step: 0.03,
+      squelch_tail: 8,
+      noise_floor: 0.0,
+      ring_mod_hz: 0.0,
+      eq: Vec::new(),
+    }
+  }
+}
+
+impl FxConfig {
+  /// Starts an [`FxConfigBuilder`] from the defaults.
+  pub fn builder() -> FxConfigBuilder {
+    FxConfigBuilder::default()
+  }
+
+  /// Names accepted by [`FxConfig::preset`].
+  pub const PRESETS: &[&str] = &["default", "vhf-air", "hf-ssb", "cb"];
+
+  /// Checks the parameters make sense together, e.g. for a preset file.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.distortion.is_nan() || self.distortion <= 0.0 {
+      return Err(format!(
+        "distortion must be above 0 (got {})",
+        self.distortion
+      ));
+    }
+    if !(self.highpass_hz > 0.0 && self.highpass_hz < self.lowpass_hz) {
+      return Err(format!(
+        "highpass ({} Hz) must be above 0 and below lowpass ({} Hz)",
+        self.highpass_hz, self.lowpass_hz
+      ));
+    }
+    if !(self.bandpass_hz > 0.0 && self.bandpass_hz < 22050.0) {
+      return Err(format!(
+        "band pass centre must be between 0 and 22050 Hz (got {})",
+        self.bandpass_hz
+      ));
+    }
+    if self.bandpass_q.is_nan() || self.bandpass_q <= 0.0 {
+      return Err(format!(
+        "band pass Q must be above 0 (got {})",
+        self.bandpass_q
+      ));
+    }
+    if !(0.0..22050.0).contains(&self.ring_mod_hz) {
+      return Err(format!(
+        "ring mod must be between 0 and 22050 Hz (got {})",
+        self.ring_mod_hz
+      ));
+    }
+    if !(0.0..=1.0).contains(&self.noise_floor) {
+      return Err(format!(
+        "noise floor must be between 0 and 1 (got {})",
+        self.noise_floor
+      ));
+    }
+    if !SQUELCH_TAIL_RANGE.contains(&self.squelch_tail) {
+      return Err(format!(
+        "squelch tail must be {} to {} buffers (got {})",
+        SQUELCH_TAIL_RANGE.start(),
+        SQUELCH_TAIL_RANGE.end(),
+        self.squelch_tail
+      ));
+    }
+    if self.eq.len() > MAX_EQ_BANDS {
+      return Err(format!(
+        "at most {MAX_EQ_BANDS} EQ bands are supported (got {})",
+        self.eq.len()
+      ));
+    }
+    for band in &self.eq {
+      band.check()?;
+    }
+    if self.lowpass_hz.is_nan() || self.lowpass_hz >= 22050.0 {
+      return Err(format!(
+        "lowpass ({} Hz) must be below the Nyquist frequency (22050 Hz)",
+        self.lowpass_hz
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// Looks up a built-in preset by name.
+  pub fn preset(name: &str) -> Option<Self> {
+    let default = Self::default();
+    match name {
+      "default" => Some(default),
+      // AM airband: narrow voice band, light static.
+      "vhf-air" => Some(Self {
+        distortion: 0.08,
+        lowpass_hz: 3400.0,
+        highpass_hz: 300.0,
+        noise_level: 0.2,
+        ..default
+      }),
+      // Shortwave sideband: very narrow and buried in hiss.
+      "hf-ssb" => Some(Self {
+        distortion: 0.1,
+        lowpass_hz: 2700.0,
+        highpass_hz: 300.0,
+        noise_level: 0.5,
+        noise_step: 0.02,
+        squelch_level: 0.2,
+        ..default
+      }),
+      // Citizens band: overdriven and crunchy.
+      "cb" => Some(Self {
+        distortion: 0.03,
+        lowpass_hz: 3000.0,
+        highpass_hz: 300.0,
+        noise_level: 0.35,
+        ..default
+      }),
+      _ => None,
+    }
+  }
+}
+
+/// Builds an [`FxConfig`] one setting at a time, checking the result.
+/// Each method sets the field of the same name; anything left alone keeps
+/// its default, or the preset's when started from one.
+///
+/// ```
+/// use squelch::fx::{DistortionKind, FxConfig, FxConfigBuilder};
+///
+/// let config = FxConfig::builder()
+///   .distortion(0.08)
+///   .distortion_kind(DistortionKind::Tanh)
+///   .cutoffs(3400.0, 300.0)
+///   .build()
+///   .unwrap();
+/// assert_eq!(config.lowpass_hz, 3400.0);
+///
+/// // Starting from a preset.
+/// let quieter = FxConfigBuilder::from(FxConfig::preset("cb").unwrap())
+///   .noise_level(0.1)
+///   .build()
+///   .unwrap();
+/// assert_eq!(quieter.distortion, 0.03);
+///
+/// assert!(FxConfig::builder().cutoffs(300.0, 3400.0).build().is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FxConfigBuilder {
+  config: FxConfig,
+}
+
+impl From<FxConfig> for FxConfigBuilder {
+  fn from(config: FxConfig) -> Self {
+    Self { config }
+  }
+}
+
+impl FxConfigBuilder {
+  pub fn disabled(mut self, disabled: bool) -> Self {
+    self.config.disabled = disabled;
+    self
+  }
+
+  pub fn signal_gain(mut self, signal_gain: f32) -> Self {
+    self.config.signal_gain = signal_gain;
+    self
+  }
+
+  pub fn distortion(mut self, distortion: f32) -> Self {
+    self.config.distortion = distortion;
+    self
+  }
+
+  pub fn distortion_kind(mut self, kind: DistortionKind) -> Self {
+    self.config.distortion_kind = kind;
+    self
+  }
+
+  pub fn filter(mut self, filter: FilterMode) -> Self {
+    self.config.filter = filter;
+    self
+  }
+
+  /// The low and high pass cutoffs, which must be set together to stay in
+  /// order.
+  pub fn cutoffs(mut self, lowpass_hz: f32, highpass_hz: f32) -> Self {
+    self.config.lowpass_hz = lowpass_hz;
+    self.config.highpass_hz = highpass_hz;
+    self
+  }
+
+  /// Switches to [`FilterMode::BandPass`] around `hz`.
+  pub fn bandpass(mut self, hz: f32, q: f32) -> Self {
+    self.config.filter = FilterMode::BandPass;
+    self.config.bandpass_hz = hz;
+    self.config.bandpass_q = q;
+    self
+  }
+
+  pub fn noise_seed(mut self, seed: u32) -> Self {
+    self.config.noise_seed = seed;
+    self
+  }
 
-use crate::{TX_BUFFER_SIZE, TxBuffer};
+  pub fn noise_level(mut self, level: f32) -> Self {
+    self.config.noise_level = level;
+    self
+  }
+
+  pub fn noise_step(mut self, step: f64) -> Self {
+    self.config.noise_step = step;
+    self
+  }
+
+  pub fn squelch_level(mut self, level: f32) -> Self {
+    self.config.squelch_level = level;
+    self
+  }
+
+  pub fn squelch_step(mut self, step: f64) -> Self {
+    self.config.squelch_step = step;
+    self
+  }
+
+  pub fn squelch_tail(mut self, buffers: usize) -> Self {
+    self.config.squelch_tail = buffers;
+    self
+  }
+
+  pub fn noise_floor(mut self, level: f32) -> Self {
+    self.config.noise_floor = level;
+    self
+  }
+
+  pub fn ring_mod_hz(mut self, hz: f32) -> Self {
+    self.config.ring_mod_hz = hz;
+    self
+  }
+
+  /// Adds a band to the receive equalizer.
+  pub fn eq_band(mut self, band: EqBand) -> Self {
+    self.config.eq.push(band);
+    self
+  }
+
+  /// The finished config, if [`FxConfig::validate`] accepts it.
+  pub fn build(self) -> Result<FxConfig, String> {
+    self.config.validate()?;
+    Ok(self.config)
+  }
+}
+
+/// The mic gate's settings, and how far open it is.
+#[derive(Debug, Clone)]
+struct Gate {
+  threshold: f32,
+  gain: f32,
+  /// How far the gain moves toward open, and toward closed, each sample.
+  attack: f32,
+  release: f32,
+}
 
 #[derive(Debug, Clone)]
 pub struct FxUnit {
   disabled: bool,
+  sample_rate: u32,
 
   noiser: Fbm<Simplex>,
   noise_idx: f64,
+  noise_level: f32,
+  noise_step: f64,
+  squelch_level: f32,
+  squelch_step: f64,
+  squelch_tail: usize,
 
   lowpass: DirectForm1<f32>,
   highpass: DirectForm1<f32>,
+  /// The cutoffs the filters are set to, so they're only recomputed when
+  /// [`set_cutoffs`](FxUnit::set_cutoffs) actually changes them.
+  cutoffs: (f32, f32),
+  /// Replaces the low and high pass, with [`FilterMode::BandPass`].
+  bandpass: Option<DirectForm1<f32>>,
+  eq: Vec<DirectForm1<f32>>,
+  /// Pole of the DC blocker, and its last input and output.
+  dc_pole: f32,
+  dc_state: (f32, f32),
 
   signal_gain: f32,
   distortion: f32,
+  distortion_kind: DistortionKind,
+  /// Sample being held by [`DistortionKind::Downsample`], and for how many
+  /// more samples.
+  hold: (f32, usize),
+  /// The last buffer's worth of samples through [`run`](Self::run) or
+  /// [`process_slice`](Self::process_slice), for the fade tail.
+  last: TxBuffer,
+
+  noise_floor: f32,
+  floor_idx: f64,
+  floor_step: f64,
+
+  ring_mod_hz: f32,
+  /// Carrier phase in radians, carried across buffers so it doesn't click.
+  ring_phase: f32,
+
+  /// Set with [`set_gate`](Self::set_gate), for [`run_mic`](Self::run_mic).
+  gate: Option<Gate>,
+  /// The gain [`agc`](Self::agc) has settled on, and how far it moves toward
+  /// its goal each sample, going down and up.
+  agc_gain: f32,
+  agc_attack: f32,
+  agc_release: f32,
+
+  /// Pitch of [`roger_beep`](Self::roger_beep), and its length in buffers.
+  roger: (f32, usize),
 }
 
 impl FxUnit {
   pub fn new(disabled: bool, signal_gain: f32, distortion: f32) -> Self {
-    let noise_idx = 0.0f64;
-    let noiser: Fbm<noise::Simplex> = noise::Fbm::new(0);
-
-    let fs = 44100.hz();
+    Self::from_config(&FxConfig {
+      disabled,
+      signal_gain,
+      distortion,
+      ..Default::default()
+    })
+  }
 
-    let f0 = 8000.hz();
-    let coeffs = Coefficients::<f32>::from_params(
-      Type::LowPass,
-      fs,
-      f0,
-      Q_BUTTERWORTH_F32,
+  /// Like [`new`](Self::new), for audio at `rate` Hz rather than 44.1 kHz,
+  /// e.g. straight from a 48 kHz device or for offline processing.
+  pub fn with_sample_rate(
+    rate: u32,
+    disabled: bool,
+    signal_gain: f32,
+    distortion: f32,
+  ) -> Self {
+    Self::from_config_at(
+      &FxConfig {
+        disabled,
+        signal_gain,
+        distortion,
+        ..Default::default()
+      },
+      rate,
     )
-    .unwrap();
-    let lowpass = DirectForm1::<f32>::new(coeffs);
+  }
+
+  pub fn from_config(config: &FxConfig) -> Self {
+    Self::from_config_at(config, SAMPLE_RATE)
+  }
+
+  /// Like [`from_config`](Self::from_config), but checks `config` first
+  /// (see [`FxConfig::validate`]), so bad cutoffs from the user are an error
+  /// rather than a panic while designing the filters.
+  pub fn try_new(config: &FxConfig) -> Result<Self, String> {
+    config.validate()?;
+    Ok(Self::from_config(config))
+  }
 
-    let f0 = 400.hz();
-    let coeffs = Coefficients::<f32>::from_params(
+  /// Like [`from_config`](Self::from_config), for audio at `rate` Hz. The
+  /// filters are designed for that rate, and the noise moves through its
+  /// field at the same speed per second, so it sounds the same as at
+  /// 44.1 kHz. Frequencies the filters can't reach at `rate`, at or above
+  /// half of it, are pulled just below instead, so any valid config works
+  /// at any rate.
+  pub fn from_config_at(config: &FxConfig, rate: u32) -> Self {
+    let noise_idx = 0.0f64;
+    let noiser: Fbm<noise::Simplex> = noise::Fbm::new(config.noise_seed);
+    // The noise steps are per sample at 44.1 kHz.
+    let step_scale = SAMPLE_RATE as f64 / rate as f64;
+
+    let lowpass = DirectForm1::<f32>::new(cutoff_coeffs(
+      Type::LowPass,
+      config.lowpass_hz,
+      rate,
+    ));
+    let highpass = DirectForm1::<f32>::new(cutoff_coeffs(
       Type::HighPass,
-      fs,
-      f0,
-      Q_BUTTERWORTH_F32,
-    )
-    .unwrap();
-    let highpass = DirectForm1::<f32>::new(coeffs);
+      config.highpass_hz,
+      rate,
+    ));
+
+    let bandpass = (config.filter == FilterMode::BandPass).then(|| {
+      DirectForm1::<f32>::new(coeffs(
+        Type::BandPass,
+        config.bandpass_hz,
+        config.bandpass_q,
+        rate,
+      ))
+    });
+
+    let eq = config
+      .eq
+      .iter()
+      .map(|band| {
+        DirectForm1::<f32>::new(coeffs(
+          Type::PeakingEQ(band.gain_db),
+          band.freq_hz,
+          band.q,
+          rate,
+        ))
+      })
+      .collect();
 
     Self {
-      disabled,
+      disabled: config.disabled,
+      sample_rate: rate,
       noiser,
       noise_idx,
+      noise_level: config.noise_level,
+      noise_step: config.noise_step * step_scale,
+      squelch_level: config.squelch_level,
+      squelch_step: config.squelch_step * step_scale,
+      squelch_tail: config.squelch_tail.max(1),
       lowpass,
       highpass,
-      signal_gain,
-      distortion,
+      cutoffs: (config.lowpass_hz, config.highpass_hz),
+      bandpass,
+      eq,
+      dc_pole: 1.0 - std::f32::consts::TAU * DC_BLOCK_HZ / rate as f32,
+      dc_state: (0.0, 0.0),
+      signal_gain: config.signal_gain,
+      // `f32::max` also maps NaN to the minimum.
+      distortion: config.distortion.max(MIN_DISTORTION),
+      distortion_kind: config.distortion_kind,
+      hold: (0.0, 0),
+      last: [0.0; TX_BUFFER_SIZE],
+      noise_floor: config.noise_floor,
+      floor_idx: 0.0,
+      floor_step: FLOOR_STEP * step_scale,
+      ring_mod_hz: config.ring_mod_hz,
+      ring_phase: 0.0,
+      gate: None,
+      agc_gain: 1.0,
+      agc_attack: ramp_coeff(AGC_ATTACK, rate),
+      agc_release: ramp_coeff(AGC_RELEASE, rate),
+      roger: (DEFAULT_ROGER_HZ, buffers_in(DEFAULT_ROGER_LEN)),
+    }
+  }
+
+  pub fn set_gain(&mut self, signal_gain: f32) {
+    self.signal_gain = signal_gain;
+  }
+
+  pub fn set_distortion(&mut self, distortion: f32) {
+    self.distortion = distortion.max(MIN_DISTORTION);
+  }
+
+  pub fn set_disabled(&mut self, disabled: bool) {
+    self.disabled = disabled;
+  }
+
+  /// Moves the low and high pass filters. Their state carries over, so this
+  /// doesn't click mid-transmission.
+  pub fn set_cutoffs(&mut self, lowpass_hz: f32, highpass_hz: f32) {
+    let (lowpass, highpass) = self.cutoffs;
+    if lowpass_hz != lowpass {
+      self.lowpass.update_coefficients(cutoff_coeffs(
+        Type::LowPass,
+        lowpass_hz,
+        self.sample_rate,
+      ));
+    }
+    if highpass_hz != highpass {
+      self.highpass.update_coefficients(cutoff_coeffs(
+        Type::HighPass,
+        highpass_hz,
+        self.sample_rate,
+      ));
     }
+    self.cutoffs = (lowpass_hz, highpass_hz);
+  }
+
+  /// Gates [`run_mic`](Self::run_mic): it mutes while the mic's RMS stays
+  /// below `threshold`, e.g. 0.01 (-40 dBFS), fading in over `attack` (see
+  /// [`DEFAULT_GATE_ATTACK`]) and out over `release`.
+  pub fn set_gate(
+    &mut self,
+    threshold: f32,
+    attack: Duration,
+    release: Duration,
+  ) {
+    self.gate = Some(Gate {
+      threshold,
+      gain: 0.0,
+      attack: ramp_coeff(attack, self.sample_rate),
+      release: ramp_coeff(release, self.sample_rate),
+    });
+  }
+
+  /// Sets the pitch and length of [`roger_beep`](Self::roger_beep). The
+  /// length is rounded to whole buffers, at least one.
+  pub fn set_roger_beep(&mut self, freq_hz: f32, len: Duration) {
+    self.roger = (freq_hz, buffers_in(len));
+  }
+
+  /// Takes up the parts of `config` that can change while running: gain,
+  /// distortion, on/off and cutoffs. Everything else stays as the unit was
+  /// built.
+  pub fn apply(&mut self, config: &FxConfig) {
+    self.set_gain(config.signal_gain);
+    self.set_distortion(config.distortion);
+    self.set_disabled(config.disabled);
+    self.set_cutoffs(config.lowpass_hz, config.highpass_hz);
+  }
+
+  /// Clears what's left of the last transmission: filter state, held
+  /// samples and the noise position, so a new one doesn't start with the
+  /// old one still ringing. Settings are kept.
+  pub fn reset(&mut self) {
+    self.lowpass.reset_state();
+    self.highpass.reset_state();
+    if let Some(bandpass) = self.bandpass.as_mut() {
+      bandpass.reset_state();
+    }
+    for band in self.eq.iter_mut() {
+      band.reset_state();
+    }
+    self.dc_state = (0.0, 0.0);
+    self.noise_idx = 0.0;
+    self.hold = (0.0, 0);
+    self.last = [0.0; TX_BUFFER_SIZE];
+    self.ring_phase = 0.0;
   }
 
   pub fn run(&mut self, samples: &mut TxBuffer) {
-    if !self.disabled {
-      let mut noise = [0f32; TX_BUFFER_SIZE];
-      for s in noise.iter_mut() {
-        *s = self.noiser.get([self.noise_idx, self.noise_idx]) as f32;
-        self.noise_idx += 0.005;
-      }
+    self.process_slice(samples);
+  }
 
-      for (s, n) in samples.iter_mut().zip(noise.iter()) {
-        *s =
-          s.clamp(-self.distortion, self.distortion) * (0.4 / self.distortion);
+  /// Like [`run`](Self::run), for any number of samples. The noise, filters
+  /// and everything else carry on from one call to the next, so a stream
+  /// can be fed in whatever lengths it arrives in.
+  pub fn process_slice(&mut self, samples: &mut [f32]) {
+    if !self.disabled {
+      self.ring_mod(samples);
+      self.distort(samples);
+      for s in samples.iter_mut() {
+        let noise = self.noiser.get([self.noise_idx, self.noise_idx]) as f32;
+        self.noise_idx += self.noise_step;
         *s *= self.signal_gain;
-        *s += n * 0.3;
+        *s += noise * self.noise_level;
         *s = s.clamp(-1.0, 1.0);
       }
 
       for s in samples.iter_mut() {
-        *s = self.lowpass.run(*s);
-        *s = self.highpass.run(*s);
+        match self.bandpass.as_mut() {
+          Some(bandpass) => *s = bandpass.run(*s),
+          None => {
+            *s = self.lowpass.run(*s);
+            *s = self.highpass.run(*s);
+          }
+        }
+        for band in self.eq.iter_mut() {
+          *s = band.run(*s);
+        }
+        *s = self.block_dc(*s);
       }
     } else {
       for s in samples.iter_mut() {
@@ -83,25 +788,1054 @@ impl FxUnit {
         *s = s.clamp(-1.0, 1.0);
       }
     }
+
+    self.add_floor(samples);
+    self.remember(samples);
   }
 
-  pub fn squelch(&mut self) -> Vec<TxBuffer> {
-    let length = 8;
-    let mut chunks = Vec::with_capacity(length);
-    if !self.disabled {
-      for _ in 0..length {
-        let mut noise_buf = [0f32; TX_BUFFER_SIZE];
-        for sample in noise_buf.iter_mut() {
-          *sample =
-            self.noiser.get([self.noise_idx, self.noise_idx]) as f32 * 0.1;
-          self.noise_idx += 0.03;
+  /// Runs the mic through the gate, if one is set, before it's sent. Between
+  /// words a mic still picks up the room: fans, hiss, keyboards. The gate
+  /// mutes that, so it isn't summed into everyone's mix, opening quickly
+  /// when speech starts and closing slowly so word endings aren't chopped.
+  pub fn run_mic(&mut self, samples: &mut [f32]) {
+    let Some(gate) = self.gate.as_mut() else {
+      return;
+    };
+    let (goal, coeff) = if rms(samples) >= gate.threshold {
+      (1.0, gate.attack)
+    } else {
+      (0.0, gate.release)
+    };
+    for s in samples.iter_mut() {
+      gate.gain += (goal - gate.gain) * coeff;
+      *s *= gate.gain;
+    }
+  }
+
+  /// Automatic gain control for the mic. `--mic-gain` is fixed, so a quiet
+  /// talker stays quiet however far they are from the mic; this follows the
+  /// level and steers the gain toward [`AGC_TARGET_RMS`], boosting by at
+  /// most 20 dB. It backs off quickly when the talker gets louder, so peaks
+  /// don't blast, but recovers slowly when they get quieter, so the gain
+  /// doesn't pump between syllables.
+  pub fn agc(&mut self, samples: &mut [f32]) {
+    let rms = rms(samples);
+    let goal = if rms >= AGC_SPEECH_THRESHOLD {
+      (AGC_TARGET_RMS / rms).min(AGC_MAX_GAIN)
+    } else {
+      self.agc_gain
+    };
+    let coeff = if goal < self.agc_gain {
+      self.agc_attack
+    } else {
+      self.agc_release
+    };
+    for s in samples.iter_mut() {
+      self.agc_gain += (goal - self.agc_gain) * coeff;
+      *s = (*s * self.agc_gain).clamp(-1.0, 1.0);
+    }
+  }
+
+  /// The roger beep that ends a transmission on many ham and aviation radios,
+  /// so listeners know the talker is done. It's sent as it is, at 44.1 kHz,
+  /// and goes through the listeners' FX like the voice before it.
+  pub fn roger_beep(&mut self) -> Vec<TxBuffer> {
+    let (freq_hz, buffers) = self.roger;
+    beep(freq_hz, buffers)
+  }
+
+  /// Keeps the last buffer's worth of output for the fade tail.
+  fn remember(&mut self, samples: &[f32]) {
+    let len = samples.len();
+    if len >= TX_BUFFER_SIZE {
+      self.last.copy_from_slice(&samples[len - TX_BUFFER_SIZE..]);
+    } else {
+      self.last.rotate_left(len);
+      self.last[TX_BUFFER_SIZE - len..].copy_from_slice(samples);
+    }
+  }
+
+  /// A one-pole high pass at [`DC_BLOCK_HZ`], taking out any offset the
+  /// distortion and clamping leave behind. Its output is flushed to zero
+  /// once it decays to denormal range, where it would crawl.
+  fn block_dc(&mut self, s: f32) -> f32 {
+    let (x1, y1) = self.dc_state;
+    let mut y = s - x1 + self.dc_pole * y1;
+    if !y.is_normal() {
+      y = 0.0;
+    }
+    self.dc_state = (s, y);
+    y
+  }
+
+  fn distort(&mut self, samples: &mut [f32]) {
+    let d = self.distortion;
+    match self.distortion_kind {
+      DistortionKind::HardClip => {
+        for s in samples.iter_mut() {
+          *s = s.clamp(-d, d) * (0.4 / d);
+        }
+      }
+      DistortionKind::Tanh => {
+        for s in samples.iter_mut() {
+          *s = (*s / d).tanh() * 0.4;
+        }
+      }
+      DistortionKind::BitCrush => {
+        for s in samples.iter_mut() {
+          *s = (*s / d).round() * d;
         }
+      }
+      DistortionKind::Downsample => {
+        let factor = ((0.2 / d).round() as usize).max(1);
+        for s in samples.iter_mut() {
+          let (held, left) = &mut self.hold;
+          if *left == 0 {
+            *held = *s;
+            *left = factor;
+          }
+          *left -= 1;
+          *s = *held;
+        }
+      }
+    }
+  }
+
+  /// The squelch tail: a burst of filtered static, fading out at the end.
+  /// With FX disabled there's no static, so this is empty.
+  pub fn squelch(&mut self) -> Vec<TxBuffer> {
+    self.squelch_with(false)
+  }
 
-        self.run(&mut noise_buf);
-        chunks.push(noise_buf);
+  /// Like [`squelch`](Self::squelch), but with FX disabled and `fade` set,
+  /// returns a single buffer that replays the last one [`run`](Self::run)
+  /// while fading it to silence, so `--no-fx` audio tails off instead of
+  /// stopping dead. With FX enabled, `fade` makes no difference.
+  pub fn squelch_with(&mut self, fade: bool) -> Vec<TxBuffer> {
+    if self.disabled {
+      if !fade {
+        return Vec::new();
       }
+
+      let mut tail = self.last;
+      fade_out(&mut tail);
+      self.last = [0.0; TX_BUFFER_SIZE];
+      return vec![tail];
+    }
+
+    let mut chunks = Vec::with_capacity(self.squelch_tail);
+    for _ in 0..self.squelch_tail {
+      let mut noise_buf = [0f32; TX_BUFFER_SIZE];
+      for sample in noise_buf.iter_mut() {
+        *sample = self.noiser.get([self.noise_idx, self.noise_idx]) as f32
+          * self.squelch_level;
+        self.noise_idx += self.squelch_step;
+      }
+
+      self.run(&mut noise_buf);
+      chunks.push(noise_buf);
+    }
+
+    if let Some(last) = chunks.last_mut() {
+      fade_out(&mut last[TX_BUFFER_SIZE - CROSSFADE_LEN..]);
     }
 
     chunks
   }
+
+  /// One buffer of quiet static to fill a DTX gap, so the channel doesn't
+  /// sound dead while the talker pauses.
+  pub fn comfort_noise(&mut self) -> TxBuffer {
+    let mut buf = [0f32; TX_BUFFER_SIZE];
+    for sample in buf.iter_mut() {
+      *sample = self.noiser.get([self.noise_idx, self.noise_idx]) as f32
+        * COMFORT_NOISE_LEVEL;
+      self.noise_idx += self.noise_step;
+    }
+    self.add_floor(&mut buf);
+    buf
+  }
+
+  /// Multiplies the signal by a sine carrier, shifting every frequency up
+  /// and down by `ring_mod_hz`.
+  fn ring_mod(&mut self, samples: &mut [f32]) {
+    if self.ring_mod_hz <= 0.0 {
+      return;
+    }
+
+    let step =
+      std::f32::consts::TAU * self.ring_mod_hz / self.sample_rate as f32;
+    for s in samples.iter_mut() {
+      *s *= self.ring_phase.sin();
+      self.ring_phase = (self.ring_phase + step) % std::f32::consts::TAU;
+    }
+  }
+
+  /// Adds the noise floor. It's drawn from its own track through the noise
+  /// field, so it's as repeatable as the rest of the noise for a given seed
+  /// without tracking the static.
+  fn add_floor(&mut self, samples: &mut [f32]) {
+    if self.noise_floor <= 0.0 {
+      return;
+    }
+
+    for s in samples.iter_mut() {
+      *s += self.noiser.get([self.floor_idx, -self.floor_idx]) as f32
+        * self.noise_floor;
+      *s = s.clamp(-1.0, 1.0);
+      self.floor_idx += self.floor_step;
+    }
+  }
+
+  /// Like [`squelch_with(true)`](Self::squelch_with), but crossfades the
+  /// start of the tail into the end of `last`, the final received buffer
+  /// (which must not have been played yet), so the switch from audio to
+  /// static doesn't pop.
+  pub fn squelch_after(&mut self, last: &mut TxBuffer) -> Vec<TxBuffer> {
+    let chunks = self.squelch_with(true);
+    let Some(first) = chunks.first() else {
+      fade_out(&mut last[TX_BUFFER_SIZE - CROSSFADE_LEN..]);
+      return chunks;
+    };
+    crossfade(last, first, CROSSFADE_LEN);
+
+    // The first `CROSSFADE_LEN` samples now live inside `last`, so shift the
+    // rest of the tail down and pad the end with silence.
+    let rest: Vec<f32> = chunks
+      .iter()
+      .flatten()
+      .skip(CROSSFADE_LEN)
+      .copied()
+      .collect();
+    rest
+      .chunks(TX_BUFFER_SIZE)
+      .map(|chunk| {
+        let mut buf = [0f32; TX_BUFFER_SIZE];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        buf
+      })
+      .collect()
+  }
+}
+
+/// Crossfades the last `len` samples of `from` into the first `len` samples
+/// of `to`, which is assumed to follow it.
+pub fn crossfade(from: &mut TxBuffer, to: &TxBuffer, len: usize) {
+  let len = len.min(TX_BUFFER_SIZE);
+  let start = TX_BUFFER_SIZE - len;
+  for (i, (a, b)) in from[start..].iter_mut().zip(to.iter()).enumerate() {
+    let t = (i + 1) as f32 / (len + 1) as f32;
+    *a = *a * (1.0 - t) + b * t;
+  }
+}
+
+/// Linearly fades `samples` out to silence.
+pub fn fade_out(samples: &mut [f32]) {
+  let len = samples.len();
+  for (i, s) in samples.iter_mut().enumerate() {
+    *s *= 1.0 - (i + 1) as f32 / len as f32;
+  }
+}
+
+/// Butterworth low or high pass coefficients for `hz` at `rate`.
+fn cutoff_coeffs(filter: Type<f32>, hz: f32, rate: u32) -> Coefficients<f32> {
+  coeffs(filter, hz, Q_BUTTERWORTH_F32, rate)
+}
+
+/// Coefficients for `filter` at `hz` and `q`, at `rate`. `hz` is kept
+/// within 0 and just under half of `rate`, where the filter can be built;
+/// `q` must be above 0, as [`FxConfig::validate`] checks.
+fn coeffs(filter: Type<f32>, hz: f32, q: f32, rate: u32) -> Coefficients<f32> {
+  let hz = hz.clamp(0.0, rate as f32 / 2.0 * 0.99);
+  Coefficients::<f32>::from_params(filter, rate.hz(), hz.hz(), q)
+    .expect("Q must be above 0")
+}
+
+/// A quarter-scale beep at `freq_hz`, `buffers` long at 44.1 kHz, for cues
+/// like the roger beep and courtesy tones.
+pub fn beep(freq_hz: f32, buffers: usize) -> Vec<TxBuffer> {
+  let mut tone = gen_tone(freq_hz, buffers * TX_BUFFER_SIZE);
+  // Tail off rather than stop mid-cycle, which clicks.
+  let len = tone.len();
+  fade_out(&mut tone[len.saturating_sub(CROSSFADE_LEN)..]);
+  tone
+    .chunks_exact(TX_BUFFER_SIZE)
+    .map(|chunk| {
+      let mut buf = [0f32; TX_BUFFER_SIZE];
+      for (b, s) in buf.iter_mut().zip(chunk) {
+        *b = s * 0.25;
+      }
+      buf
+    })
+    .collect()
+}
+
+/// How many whole buffers at 44.1 kHz `len` rounds to, at least one.
+fn buffers_in(len: Duration) -> usize {
+  let samples = len.as_secs_f64() * SAMPLE_RATE as f64;
+  ((samples / TX_BUFFER_SIZE as f64).round() as usize).max(1)
+}
+
+/// The per-sample step of a one-pole ramp with time constant `time` at
+/// `rate`; an instant one jumps straight there.
+fn ramp_coeff(time: Duration, rate: u32) -> f32 {
+  let samples = time.as_secs_f32() * rate as f32;
+  if samples <= 0.0 {
+    1.0
+  } else {
+    1.0 - (-1.0 / samples).exp()
+  }
+}
+
+/// Root mean square of `samples`; 0 for none.
+fn rms(samples: &[f32]) -> f32 {
+  if samples.is_empty() {
+    return 0.0;
+  }
+  (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// A full-scale (peak 1.0) sine at `freq_hz`, `samples` long at 44.1 kHz.
+///
+/// ```
+/// use squelch::fx::gen_tone;
+///
+/// // A quarter cycle in, a 441 Hz tone is at its peak.
+/// let tone = gen_tone(441.0, 100);
+/// assert_eq!(tone.len(), 100);
+/// assert_eq!(tone[0], 0.0);
+/// assert!((tone[25] - 1.0).abs() < 1e-6);
+/// assert!(tone.iter().all(|s| s.abs() <= 1.0));
+/// ```
+pub fn gen_tone(freq_hz: f32, samples: usize) -> Vec<f32> {
+  let step = std::f32::consts::TAU * freq_hz / SAMPLE_RATE as f32;
+  (0..samples).map(|i| (i as f32 * step).sin()).collect()
+}
+
+/// A full-scale sine sweeping exponentially from `start_hz` to `end_hz` over
+/// `samples` at 44.1 kHz, so each octave gets the same time.
+///
+/// ```
+/// use squelch::fx::gen_sweep;
+///
+/// let sweep = gen_sweep(100.0, 10_000.0, 44_100);
+/// assert_eq!(sweep.len(), 44_100);
+/// let peak = sweep.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+/// assert!(peak > 0.999 && peak <= 1.0);
+/// ```
+pub fn gen_sweep(start_hz: f32, end_hz: f32, samples: usize) -> Vec<f32> {
+  let ratio = end_hz / start_hz;
+  let mut phase = 0f32;
+  (0..samples)
+    .map(|i| {
+      let t = i as f32 / samples.max(1) as f32;
+      let s = phase.sin();
+      phase +=
+        std::f32::consts::TAU * start_hz * ratio.powf(t) / SAMPLE_RATE as f32;
+      phase %= std::f32::consts::TAU;
+      s
+    })
+    .collect()
+}
+
+/// Uniform white noise in [-1.0, 1.0), `samples` long. The same `seed` always
+/// gives the same noise.
+///
+/// ```
+/// use squelch::fx::white_noise;
+///
+/// let noise = white_noise(7, 10_000);
+/// assert_eq!(noise.len(), 10_000);
+/// assert!(noise.iter().all(|s| (-1.0..1.0).contains(s)));
+/// // Uniform, so its RMS is about 1/√3.
+/// let rms = (noise.iter().map(|s| s * s).sum::<f32>() / 10_000.0).sqrt();
+/// assert!((rms - 0.577).abs() < 0.02);
+/// assert_eq!(noise, white_noise(7, 10_000));
+/// ```
+pub fn white_noise(seed: u32, samples: usize) -> Vec<f32> {
+  // xorshift32: plenty for test signals, and it never repeats within any
+  // length we'd generate. Zero is its one fixed point, so avoid it.
+  let mut state = seed.max(1);
+  (0..samples)
+    .map(|_| {
+      state ^= state << 13;
+      state ^= state >> 17;
+      state ^= state << 5;
+      state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    })
+    .collect()
+}
+
+/// Pink (-3 dB/octave) noise, `samples` long, clamped to ±1.0. It's
+/// [`white_noise`] through Paul Kellet's economy pink filter.
+///
+/// ```
+/// use squelch::fx::pink_noise;
+///
+/// let noise = pink_noise(7, 10_000);
+/// assert_eq!(noise.len(), 10_000);
+/// assert!(noise.iter().all(|s| s.abs() <= 1.0));
+/// let rms = (noise.iter().map(|s| s * s).sum::<f32>() / 10_000.0).sqrt();
+/// assert!(rms > 0.05 && rms < 0.5);
+/// ```
+pub fn pink_noise(seed: u32, samples: usize) -> Vec<f32> {
+  let (mut b0, mut b1, mut b2) = (0f32, 0f32, 0f32);
+  white_noise(seed, samples)
+    .into_iter()
+    .map(|white| {
+      b0 = 0.99765 * b0 + white * 0.0990460;
+      b1 = 0.96300 * b1 + white * 0.2965164;
+      b2 = 0.57000 * b2 + white * 1.0526913;
+      ((b0 + b1 + b2 + white * 0.1848) * 0.15).clamp(-1.0, 1.0)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The amplitude of the `hz` component of `samples` at 44.1 kHz.
+  fn level_at(samples: &[f32], hz: f32) -> f32 {
+    level_at_rate(samples, hz, SAMPLE_RATE)
+  }
+
+  /// Like [`level_at`], for `samples` at `rate`.
+  fn level_at_rate(samples: &[f32], hz: f32, rate: u32) -> f32 {
+    let step = std::f32::consts::TAU * hz / rate as f32;
+    let (re, im) =
+      samples
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(re, im), (i, s)| {
+          let phase = i as f32 * step;
+          (re + s * phase.cos(), im + s * phase.sin())
+        });
+    2.0 * (re * re + im * im).sqrt() / samples.len() as f32
+  }
+
+  #[test]
+  fn no_fx_squelch_tail_fades_the_last_buffer() {
+    let mut fx = FxUnit::new(true, 1.0, 0.05);
+    let mut buf = [0.5; TX_BUFFER_SIZE];
+    fx.run(&mut buf);
+    assert!(fx.squelch_with(false).is_empty());
+
+    let tail = fx.squelch_with(true);
+    assert_eq!(tail.len(), 1);
+    assert!((tail[0][0] - 0.5).abs() < 0.01);
+    assert_eq!(tail[0][TX_BUFFER_SIZE - 1], 0.0);
+    assert!(tail[0].windows(2).all(|w| w[1] <= w[0]));
+  }
+
+  #[test]
+  fn squelch_tail_is_static_that_fades_out() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    for fade in [false, true] {
+      let tail = fx.squelch_with(fade);
+      assert_eq!(tail.len(), FxConfig::default().squelch_tail);
+      assert!(tail[0].iter().any(|s| *s != 0.0));
+      assert_eq!(tail.last().unwrap()[TX_BUFFER_SIZE - 1], 0.0);
+    }
+  }
+
+  #[test]
+  fn builder_sets_fields_and_validates() {
+    let band = EqBand {
+      freq_hz: 2000.0,
+      gain_db: 3.0,
+      q: 1.0,
+    };
+    let config = FxConfig::builder()
+      .signal_gain(2.0)
+      .bandpass(1500.0, 1.2)
+      .squelch_tail(4)
+      .eq_band(band)
+      .build()
+      .unwrap();
+    assert_eq!(
+      config,
+      FxConfig {
+        signal_gain: 2.0,
+        filter: FilterMode::BandPass,
+        bandpass_hz: 1500.0,
+        bandpass_q: 1.2,
+        squelch_tail: 4,
+        eq: vec![band],
+        ..Default::default()
+      }
+    );
+
+    assert!(FxConfig::builder().distortion(0.0).build().is_err());
+    assert!(FxConfig::builder().squelch_tail(0).build().is_err());
+    let too_many = (0..=MAX_EQ_BANDS)
+      .fold(FxConfig::builder(), |builder, _| builder.eq_band(band));
+    assert!(too_many.build().is_err());
+  }
+
+  #[test]
+  fn gate_silences_a_quiet_sine_and_passes_speech() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    fx.set_gate(0.01, DEFAULT_GATE_ATTACK, Duration::from_millis(10));
+
+    // Well below the threshold: -46 dBFS RMS.
+    let quiet: Vec<f32> = gen_tone(440.0, 20 * TX_BUFFER_SIZE)
+      .iter()
+      .map(|s| s * 0.007)
+      .collect();
+    for chunk in quiet.chunks(TX_BUFFER_SIZE) {
+      let mut buf = chunk.to_vec();
+      fx.run_mic(&mut buf);
+      assert!(buf.iter().all(|s| *s == 0.0));
+    }
+
+    // Speech opens it within the attack, and it closes again after.
+    let mut loud = gen_tone(440.0, 2 * TX_BUFFER_SIZE);
+    fx.run_mic(&mut loud);
+    let tail = &loud[TX_BUFFER_SIZE..];
+    assert!((rms(tail) - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+
+    let mut closing = Vec::new();
+    for chunk in quiet.chunks(TX_BUFFER_SIZE) {
+      let mut buf = chunk.to_vec();
+      fx.run_mic(&mut buf);
+      closing.push(rms(&buf));
+    }
+    assert!(closing[0] > 1e-4);
+    assert!(*closing.last().unwrap() < 1e-6);
+  }
+
+  #[test]
+  fn no_gate_leaves_the_mic_alone() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    let mut buf = [0.001; TX_BUFFER_SIZE];
+    fx.run_mic(&mut buf);
+    assert_eq!(buf, [0.001; TX_BUFFER_SIZE]);
+  }
+
+  #[test]
+  fn agc_brings_quiet_and_loud_talkers_to_the_same_level() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    let tone = gen_tone(300.0, 3 * SAMPLE_RATE as usize);
+    let mut levels = Vec::new();
+    // Three seconds quiet (-33 dBFS RMS), then three loud (-9 dBFS RMS).
+    for scale in [0.03, 0.5] {
+      let mut segment: Vec<f32> = tone.iter().map(|s| s * scale).collect();
+      for chunk in segment.chunks_mut(TX_BUFFER_SIZE) {
+        fx.agc(chunk);
+      }
+      // How it ends up, once the gain has settled.
+      levels.push(rms(&segment[segment.len() - SAMPLE_RATE as usize..]));
+    }
+
+    for level in levels {
+      assert!((level - AGC_TARGET_RMS).abs() < 0.01, "{level}");
+    }
+  }
+
+  #[test]
+  fn agc_holds_its_gain_through_silence() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    let mut quiet = [0.001; TX_BUFFER_SIZE];
+    for _ in 0..1000 {
+      fx.agc(&mut quiet);
+    }
+    assert_eq!(quiet, [0.001; TX_BUFFER_SIZE]);
+  }
+
+  #[test]
+  fn roger_beep_is_a_tone_at_the_requested_pitch() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    fx.set_roger_beep(1200.0, Duration::from_millis(100));
+    let beep: Vec<f32> = fx.roger_beep().concat();
+    assert_eq!(beep.len(), 17 * TX_BUFFER_SIZE);
+
+    assert!((level_at(&beep, 1200.0) - 0.25).abs() < 0.01);
+    for off in [1000.0, 1100.0, 1300.0, 2400.0] {
+      assert!(level_at(&beep, off) < 0.01, "{off} Hz");
+    }
+    assert_eq!(*beep.last().unwrap(), 0.0);
+  }
+
+  #[test]
+  fn process_slice_in_odd_lengths_matches_whole_buffers() {
+    let config = FxConfig {
+      distortion_kind: DistortionKind::Downsample,
+      ring_mod_hz: 30.0,
+      noise_floor: 0.01,
+      ..Default::default()
+    };
+    let input = gen_sweep(100.0, 8000.0, 8 * TX_BUFFER_SIZE);
+
+    let mut whole = FxUnit::from_config(&config);
+    let mut expected = Vec::new();
+    for chunk in input.chunks(TX_BUFFER_SIZE) {
+      let mut buf: TxBuffer = chunk.try_into().unwrap();
+      whole.run(&mut buf);
+      expected.extend(buf);
+    }
+
+    let mut sliced = FxUnit::from_config(&config);
+    let mut output = input.clone();
+    let mut rest = output.as_mut_slice();
+    for len in [1, 100, 37, 256, 511, 3].into_iter().cycle() {
+      let (head, tail) = rest.split_at_mut(len.min(rest.len()));
+      sliced.process_slice(head);
+      rest = tail;
+      if rest.is_empty() {
+        break;
+      }
+    }
+
+    assert_eq!(output, expected);
+    // The fade tail picks up the last samples wherever they came from.
+    assert_eq!(sliced.squelch_with(true), whole.squelch_with(true));
+  }
+
+  #[test]
+  fn cutoffs_above_nyquist_are_pulled_below_it() {
+    let config = FxConfig {
+      filter: FilterMode::BandPass,
+      bandpass_hz: 6000.0,
+      eq: vec![EqBand {
+        freq_hz: 5000.0,
+        gain_db: 6.0,
+        q: 1.0,
+      }],
+      ..Default::default()
+    };
+    // The default 8 kHz low pass, and the rest, are out of reach at 8 kHz.
+    let mut fx = FxUnit::from_config_at(&config, 8000);
+    fx.set_cutoffs(44_100.0, 4000.0);
+
+    let mut buf: TxBuffer =
+      gen_tone(1000.0, TX_BUFFER_SIZE).try_into().unwrap();
+    fx.run(&mut buf);
+    assert!(buf.iter().all(|s| s.is_finite()));
+    assert!(buf.iter().any(|s| *s != 0.0));
+  }
+
+  #[test]
+  fn cutoffs_are_the_same_at_any_rate() {
+    let config = FxConfig {
+      // Next to no distortion or noise, to hear the filters alone.
+      distortion: MIN_DISTORTION,
+      distortion_kind: DistortionKind::BitCrush,
+      noise_level: 0.0,
+      lowpass_hz: 3000.0,
+      highpass_hz: 300.0,
+      ..Default::default()
+    };
+    let through = |hz: f32, rate: u32| {
+      let step = std::f32::consts::TAU * hz / rate as f32;
+      let mut tone: Vec<f32> =
+        (0..rate).map(|i| (i as f32 * step).sin()).collect();
+      FxUnit::from_config_at(&config, rate).process_slice(&mut tone);
+      // Once the filters have settled.
+      level_at_rate(&tone[rate as usize / 2..], hz, rate)
+    };
+
+    let probes = [30.0, 500.0, 1000.0, 3000.0, 6000.0];
+    let at_44k: Vec<f32> =
+      probes.iter().map(|hz| through(*hz, 44_100)).collect();
+    let at_48k: Vec<f32> =
+      probes.iter().map(|hz| through(*hz, 48_000)).collect();
+    for (hz, (a, b)) in probes.iter().zip(at_44k.iter().zip(&at_48k)) {
+      assert!((a - b).abs() < 0.01, "{hz} Hz: {a} vs {b}");
+    }
+    // And the filters really are doing something.
+    assert!(at_44k[0] < at_44k[1] / 2.0);
+    assert!(at_44k[4] < at_44k[1] / 2.0);
+  }
+
+  #[test]
+  fn band_pass_is_rounder_and_gentler_than_the_cascade() {
+    // An octave apart, from 31.25 Hz to 16 kHz.
+    let probes: Vec<f32> = (0..10).map(|i| 31.25 * 2f32.powi(i)).collect();
+    let response = |filter| {
+      let config = FxConfig {
+        distortion: MIN_DISTORTION,
+        distortion_kind: DistortionKind::BitCrush,
+        noise_level: 0.0,
+        filter,
+        lowpass_hz: 3000.0,
+        highpass_hz: 300.0,
+        ..Default::default()
+      };
+      let levels: Vec<f32> = probes
+        .iter()
+        .map(|hz| {
+          let mut tone = gen_tone(*hz, SAMPLE_RATE as usize / 2);
+          FxUnit::from_config(&config).process_slice(&mut tone);
+          level_at(&tone[SAMPLE_RATE as usize / 4..], *hz)
+        })
+        .collect();
+      // Relative to its own peak, to compare shapes.
+      let peak = levels.iter().copied().fold(0.0, f32::max);
+      levels.iter().map(|l| l / peak).collect::<Vec<f32>>()
+    };
+    let cascade = response(FilterMode::Cascade);
+    let band_pass = response(FilterMode::BandPass);
+
+    // The cascade is flat across several octaves; the band pass peaks.
+    let top = |levels: &[f32]| levels.iter().filter(|l| **l > 0.9).count();
+    assert!(top(&cascade) >= 3, "{cascade:?}");
+    assert_eq!(top(&band_pass), 1, "{band_pass:?}");
+
+    // Both cut the ends, but an octave further out the cascade has fallen
+    // by far more.
+    let falloff =
+      |levels: &[f32], near: usize, far: usize| levels[near] / levels[far];
+    for (near, far) in [(1, 0), (7, 8)] {
+      let (steep, gentle) =
+        (falloff(&cascade, near, far), falloff(&band_pass, near, far));
+      assert!(steep > 3.0, "{cascade:?}");
+      assert!(gentle < 3.0 && gentle > 1.5, "{band_pass:?}");
+    }
+  }
+
+  #[test]
+  fn dc_block_centres_a_biased_signal_without_denormals() {
+    let mut fx = FxUnit::from_config(&FxConfig::default());
+    let biased: Vec<f32> = gen_tone(440.0, 2 * SAMPLE_RATE as usize)
+      .iter()
+      .map(|s| s * 0.3 + 0.2)
+      .collect();
+    let out: Vec<f32> = biased.iter().map(|s| fx.block_dc(*s)).collect();
+
+    let mean =
+      |samples: &[f32]| samples.iter().sum::<f32>() / samples.len() as f32;
+    let last_second = &out[out.len() - SAMPLE_RATE as usize..];
+    assert!((mean(&biased) - 0.2).abs() < 0.001);
+    assert!(mean(last_second).abs() < 0.001, "{}", mean(last_second));
+    // The tone itself goes through untouched.
+    assert!((level_at(last_second, 440.0) - 0.3).abs() < 0.01);
+
+    // Decaying after the signal stops, it goes to zero, not denormal.
+    for _ in 0..SAMPLE_RATE {
+      let s = fx.block_dc(0.0);
+      assert!(s == 0.0 || s.is_normal(), "{s:e}");
+    }
+    assert_eq!(fx.block_dc(0.0), 0.0);
+  }
+
+  #[test]
+  fn each_distortion_model_shapes_a_tone() {
+    let tone: Vec<f32> =
+      gen_tone(440.0, 1000).iter().map(|s| s * 0.5).collect();
+    let distorted = |kind| {
+      let mut fx = FxUnit::from_config(&FxConfig {
+        distortion: 0.1,
+        distortion_kind: kind,
+        ..Default::default()
+      });
+      let mut out = tone.clone();
+      fx.distort(&mut out);
+      out
+    };
+    let peak =
+      |samples: &[f32]| samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+
+    // Flat tops at the threshold, made up to 0.4.
+    let clipped = distorted(DistortionKind::HardClip);
+    assert!((peak(&clipped) - 0.4).abs() < 1e-6);
+    let flat = clipped.iter().filter(|s| (s.abs() - 0.4).abs() < 1e-6);
+    assert!(flat.count() > 500);
+
+    // Rounded tops: just short of 0.4, reached gradually.
+    let saturated = distorted(DistortionKind::Tanh);
+    assert!(peak(&saturated) < 0.4 && peak(&saturated) > 0.399);
+    let flat = saturated.iter().filter(|s| (s.abs() - 0.4).abs() < 1e-6);
+    assert_eq!(flat.count(), 0);
+
+    // Only whole steps of 0.1, from -0.5 to 0.5.
+    let crushed = distorted(DistortionKind::BitCrush);
+    assert!(crushed.iter().all(|s| {
+      let steps = s / 0.1;
+      (steps - steps.round()).abs() < 1e-4 && steps.abs() <= 5.001
+    }));
+
+    // Every sample held for 0.2 / 0.1 = 2.
+    let held = distorted(DistortionKind::Downsample);
+    for (pair, input) in held.chunks(2).zip(tone.chunks(2)) {
+      assert_eq!(pair, [input[0], input[0]]);
+    }
+  }
+
+  #[test]
+  fn crossfade_meets_the_next_buffer_without_a_jump() {
+    let mut from = [1.0; TX_BUFFER_SIZE];
+    let to = [0.0; TX_BUFFER_SIZE];
+    crossfade(&mut from, &to, 8);
+
+    // The last 8 samples step down evenly, landing one step above `to`.
+    let step = 1.0 / 9.0;
+    let joined: Vec<f32> = from.iter().chain(&to[..1]).copied().collect();
+    assert!(
+      joined
+        .windows(2)
+        .all(|w| (w[0] - w[1]).abs() <= step + 1e-6)
+    );
+    assert_eq!(from[TX_BUFFER_SIZE - 9], 1.0);
+  }
+
+  #[test]
+  fn squelch_after_joins_audio_and_static_smoothly() {
+    let mut fx = FxUnit::new(false, 1.0, 0.05);
+    let mut last = [0.5; TX_BUFFER_SIZE];
+    fx.run(&mut last);
+    let before = last;
+    let tail = fx.squelch_after(&mut last);
+
+    // Untouched up to the crossfade, then no bigger a jump across the
+    // boundary than the static makes on its own.
+    let start = TX_BUFFER_SIZE - CROSSFADE_LEN;
+    assert_eq!(last[..start], before[..start]);
+    let biggest_jump = |samples: &[f32]| {
+      samples
+        .windows(2)
+        .fold(0f32, |jump, w| jump.max((w[1] - w[0]).abs()))
+    };
+    let joined: Vec<f32> = last[start - 1..]
+      .iter()
+      .chain(&tail[0][..CROSSFADE_LEN])
+      .copied()
+      .collect();
+    let static_jump = biggest_jump(&tail[0]).max(biggest_jump(&before));
+    assert!(biggest_jump(&joined) <= static_jump + 1e-6);
+
+    // And the tail still ends in silence.
+    assert_eq!(tail.last().unwrap()[TX_BUFFER_SIZE - 1], 0.0);
+  }
+
+  #[test]
+  fn nan_cutoffs_are_refused() {
+    for config in [
+      FxConfig {
+        lowpass_hz: f32::NAN,
+        ..Default::default()
+      },
+      FxConfig {
+        highpass_hz: f32::NAN,
+        ..Default::default()
+      },
+    ] {
+      assert!(FxUnit::try_new(&config).is_err(), "{config:?}");
+    }
+  }
+
+  #[test]
+  fn zero_distortion_is_refused_or_kept_finite() {
+    let config = FxConfig {
+      distortion: 0.0,
+      ..Default::default()
+    };
+    assert!(FxUnit::try_new(&config).is_err());
+
+    for kind in [
+      DistortionKind::HardClip,
+      DistortionKind::Tanh,
+      DistortionKind::BitCrush,
+      DistortionKind::Downsample,
+    ] {
+      let mut from_config = FxUnit::from_config(&FxConfig {
+        distortion_kind: kind,
+        ..config.clone()
+      });
+      let mut set = FxUnit::from_config(&FxConfig {
+        distortion_kind: kind,
+        ..Default::default()
+      });
+      set.set_distortion(0.0);
+
+      for fx in [&mut from_config, &mut set] {
+        let mut out = gen_tone(440.0, TX_BUFFER_SIZE * 4);
+        fx.process_slice(&mut out);
+        out.extend(fx.squelch().into_iter().flatten());
+        assert!(out.iter().all(|s| s.is_finite()), "{kind}");
+      }
+    }
+  }
+
+  #[test]
+  fn reset_stops_a_loud_burst_ringing_into_silence() {
+    let config = FxConfig {
+      noise_level: 0.0,
+      ..Default::default()
+    };
+    let after_burst = |reset: bool| {
+      let mut fx = FxUnit::from_config(&config);
+      // Stopping mid-cycle, as a transmission does.
+      let mut burst = gen_tone(440.0, TX_BUFFER_SIZE * 20 + 30);
+      fx.process_slice(&mut burst);
+      if reset {
+        fx.reset();
+      }
+      let mut silence = [0.0; TX_BUFFER_SIZE];
+      fx.run(&mut silence);
+      silence.iter().fold(0f32, |peak, s| peak.max(s.abs()))
+    };
+
+    // Left alone, the filters are still ringing from the burst.
+    assert!(after_burst(false) > 0.01);
+    assert!(after_burst(true) < 1e-6);
+  }
+
+  #[test]
+  fn setters_match_the_config_and_change_later_output() {
+    type Setter = fn(&mut FxUnit);
+    let cases: [(Setter, FxConfig); 4] = [
+      (
+        |fx| fx.set_gain(0.5),
+        FxConfig {
+          signal_gain: 0.5,
+          ..Default::default()
+        },
+      ),
+      (
+        |fx| fx.set_distortion(0.5),
+        FxConfig {
+          distortion: 0.5,
+          ..Default::default()
+        },
+      ),
+      (
+        |fx| fx.set_disabled(true),
+        FxConfig {
+          disabled: true,
+          ..Default::default()
+        },
+      ),
+      (
+        |fx| fx.set_cutoffs(3000.0, 300.0),
+        FxConfig {
+          lowpass_hz: 3000.0,
+          highpass_hz: 300.0,
+          ..Default::default()
+        },
+      ),
+    ];
+    let tone = gen_tone(440.0, TX_BUFFER_SIZE * 4);
+    let run = |fx: &mut FxUnit| {
+      let mut out = tone.clone();
+      fx.process_slice(&mut out);
+      out
+    };
+
+    for (i, (set, config)) in cases.into_iter().enumerate() {
+      // Set on a fresh unit, it's as if the unit had been built that way.
+      let mut fresh = FxUnit::from_config(&FxConfig::default());
+      set(&mut fresh);
+      assert_eq!(run(&mut fresh), run(&mut FxUnit::from_config(&config)));
+
+      // Set mid-stream, it takes effect from the next samples on.
+      let mut kept = FxUnit::from_config(&FxConfig::default());
+      let mut changed = FxUnit::from_config(&FxConfig::default());
+      assert_eq!(run(&mut kept), run(&mut changed));
+      set(&mut changed);
+      assert_ne!(run(&mut kept), run(&mut changed), "setter {i}");
+    }
+  }
+
+  #[test]
+  fn noise_floor_adds_faint_repeatable_hiss() {
+    let floor = |noise_floor, noise_seed| {
+      let mut fx = FxUnit::from_config(&FxConfig {
+        disabled: true,
+        noise_floor,
+        noise_seed,
+        ..Default::default()
+      });
+      let mut out = vec![0.0; TX_BUFFER_SIZE * 16];
+      fx.process_slice(&mut out);
+      out
+    };
+
+    let hiss = floor(0.01, 7);
+    let peak = hiss.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    assert!(peak <= 0.01 && rms(&hiss) > 0.001, "{peak}, {}", rms(&hiss));
+
+    // Scales with the level, and is the same for the same seed.
+    let louder = floor(0.02, 7);
+    for (a, b) in hiss.iter().zip(&louder) {
+      assert!((a * 2.0 - b).abs() < 1e-6);
+    }
+    assert_ne!(hiss, floor(0.01, 8));
+    assert!(floor(0.0, 7).iter().all(|s| *s == 0.0));
+  }
+
+  #[test]
+  fn ring_mod_shifts_a_tone_both_ways() {
+    let mut fx = FxUnit::from_config(&FxConfig {
+      ring_mod_hz: 300.0,
+      ..Default::default()
+    });
+    let tone = gen_tone(1000.0, SAMPLE_RATE as usize / 10);
+    let mut shifted = tone.clone();
+    // In two uneven parts, as the carrier carries on between calls.
+    let (first, rest) = shifted.split_at_mut(1000);
+    fx.ring_mod(first);
+    fx.ring_mod(rest);
+
+    assert!(level_at(&tone, 1000.0) > 0.99);
+    assert!(level_at(&shifted, 1000.0) < 0.01);
+    for hz in [700.0, 1300.0] {
+      let level = level_at(&shifted, hz);
+      assert!((level - 0.5).abs() < 0.01, "{hz} Hz: {level}");
+    }
+  }
+
+  #[test]
+  fn eq_band_boosts_or_cuts_around_its_frequency_only() {
+    let response = |gain_db| {
+      let config = FxConfig {
+        distortion: 1.0,
+        noise_level: 0.0,
+        ..Default::default()
+      };
+      let band = EqBand {
+        freq_hz: 2000.0,
+        gain_db,
+        q: 1.0,
+      };
+      (1..=40)
+        .map(|step| {
+          let hz = step as f32 * 100.0;
+          let mut plain = FxUnit::from_config(&config);
+          let mut eq = FxUnit::from_config(&FxConfig {
+            eq: vec![band],
+            ..config.clone()
+          });
+          let tone: Vec<f32> = gen_tone(hz, SAMPLE_RATE as usize / 5)
+            .iter()
+            .map(|s| s * 0.1)
+            .collect();
+          let (mut a, mut b) = (tone.clone(), tone);
+          plain.process_slice(&mut a);
+          eq.process_slice(&mut b);
+          // Past the filters' settling.
+          let settled = SAMPLE_RATE as usize / 10;
+          level_at(&b[settled..], hz) / level_at(&a[settled..], hz)
+        })
+        .collect::<Vec<f32>>()
+    };
+
+    // 6 dB is twice the amplitude at the peak, and nothing else is touched
+    // more than that or the other way.
+    let boost = response(6.0);
+    let peak = boost.iter().copied().fold(0.0, f32::max);
+    assert!((peak - 2.0).abs() < 0.05, "peak {peak}");
+    assert!(boost.iter().all(|g| (0.99..=2.0).contains(g)), "{boost:?}");
+    // Away from the band it falls back to unity.
+    assert!(boost[39] < 1.05, "{boost:?}");
+    let at = boost.iter().position(|g| *g == peak).unwrap();
+    assert!(boost[..at].windows(2).all(|w| w[0] <= w[1]), "{boost:?}");
+    assert!(boost[at..].windows(2).all(|w| w[0] >= w[1]), "{boost:?}");
+
+    // A cut mirrors it.
+    let cut = response(-6.0);
+    let dip = cut.iter().copied().fold(1.0, f32::min);
+    assert!((dip - 0.5).abs() < 0.02, "dip {dip}");
+    assert!(cut.iter().all(|g| (0.5..=1.01).contains(g)), "{cut:?}");
+  }
 }
@@ -1,24 +1,265 @@
+//! A jitter buffer for whole network buffers: it holds back the first few of
+//! a stream so that packets arriving in bursts still play out evenly.
+//!
+//! Numbered packets can instead go in with
+//! [`push_ordered`](JitterBuffer::push_ordered), which puts them back in
+//! order, and come out with [`pop_ready`](JitterBuffer::pop_ready), which
+//! waits a little for any that are missing. The client takes both kinds, so
+//! it always plays out with `pop_ready`.
+
+use std::collections::VecDeque;
+
+use crate::seq::MAX_REORDER;
+
 pub struct JitterBuffer<T> {
-  buffer: Vec<T>,
+  /// Oldest first. Only `push_ordered` leaves gaps, for packets that haven't
+  /// arrived yet.
+  buffer: VecDeque<Option<T>>,
   capacity: usize,
+  /// Set once the buffer has filled, until it next runs dry.
+  draining: bool,
+  /// The sequence number of the front slot, once `push_ordered` is used.
+  next_seq: Option<u32>,
 }
 
 impl<T> JitterBuffer<T> {
   pub fn new(capacity: usize) -> Self {
     Self {
-      buffer: Vec::with_capacity(capacity),
+      buffer: VecDeque::with_capacity(capacity),
       capacity,
+      draining: false,
+      next_seq: None,
+    }
+  }
+
+  /// Slots held, counting gaps left for packets still to come.
+  pub fn len(&self) -> usize {
+    self.buffer.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.buffer.is_empty()
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.buffer.len() >= self.capacity
+  }
+
+  /// Adds `value`, dropping and returning the oldest item if the buffer was
+  /// already full, so a sender that runs fast can't build up latency.
+  ///
+  /// An unnumbered value has no place among numbered ones, so it goes at the
+  /// back and any gaps still waiting for late packets are given up on.
+  pub fn push(&mut self, value: T) -> Option<T> {
+    if self.next_seq.take().is_some() {
+      self.buffer.retain(Option::is_some);
+    }
+    let dropped = if self.is_full() { self.advance() } else { None };
+    self.buffer.push_back(Some(value));
+    if self.is_full() {
+      self.draining = true;
+    }
+    dropped
+  }
+
+  /// The oldest item, once the buffer has filled. Nothing comes out until
+  /// it does, and again after it runs dry, until it's refilled.
+  pub fn pop(&mut self) -> Option<T> {
+    if !self.draining {
+      return None;
+    }
+    let mut value = None;
+    while value.is_none() && !self.buffer.is_empty() {
+      value = self.advance();
+    }
+    if self.buffer.is_empty() {
+      self.draining = false;
+    }
+    value
+  }
+
+  /// Puts `value`, numbered `seq`, in its place in the sequence, returning
+  /// whether it was kept. Duplicates, and packets from before the playout
+  /// position, are dropped, allowing for wraparound. One too far ahead to
+  /// fit moves the playout position up to make room, giving up on whatever
+  /// it skips. The first of a stream goes in behind anything already held.
+  pub fn push_ordered(&mut self, seq: u32, value: T) -> bool {
+    let held = self.buffer.len() as u32;
+    let next = *self.next_seq.get_or_insert(seq.wrapping_sub(held));
+    let behind = next.wrapping_sub(seq);
+    if behind != 0 && behind <= u32::MAX / 2 {
+      if behind <= MAX_REORDER {
+        return false;
+      }
+      // Too far back to be late: the sender started over.
+      self.clear();
+      self.next_seq = Some(seq);
+    }
+
+    let mut offset = seq.wrapping_sub(self.next_seq.unwrap_or(seq)) as usize;
+    if offset >= self.capacity {
+      let skip = offset + 1 - self.capacity.max(1);
+      self.buffer.drain(..skip.min(self.buffer.len()));
+      self.next_seq = self.next_seq.map(|next| next.wrapping_add(skip as u32));
+      offset -= skip;
+    }
+
+    if self.buffer.len() <= offset {
+      self.buffer.resize_with(offset + 1, || None);
+    }
+    let slot = &mut self.buffer[offset];
+    if slot.is_some() {
+      return false;
+    }
+    *slot = Some(value);
+    if self.is_full() {
+      self.draining = true;
     }
+    true
   }
 
-  pub fn push_and_drain(&mut self, value: T) -> Option<Vec<T>> {
-    if self.buffer.len() >= self.capacity {
-      let items: Vec<_> = self.buffer.drain(..).collect();
-      self.buffer.push(value);
-      Some(items)
-    } else {
-      self.buffer.push(value);
-      None
+  /// The next packet in sequence, held back like [`pop`](Self::pop) until
+  /// the buffer has filled. A gap stalls playout until it's filled, or
+  /// until the buffer fills up behind it, when the missing packet is taken
+  /// as lost and skipped. Without gaps it's the same as `pop`.
+  pub fn pop_ready(&mut self) -> Option<T> {
+    if !self.draining {
+      return None;
     }
+    while matches!(self.buffer.front(), Some(None)) && self.is_full() {
+      self.advance();
+    }
+    let value = match self.buffer.front() {
+      Some(Some(_)) => self.advance(),
+      _ => None,
+    };
+    if self.buffer.is_empty() {
+      self.draining = false;
+    }
+    value
+  }
+
+  /// Everything buffered, oldest first, full or not, e.g. to play out the
+  /// end of a stream.
+  pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+    self.draining = false;
+    let len = self.buffer.len() as u32;
+    self.next_seq = self.next_seq.map(|next| next.wrapping_add(len));
+    self.buffer.drain(..).flatten()
+  }
+
+  /// Empties the buffer and forgets the sequence, e.g. for a new stream.
+  pub fn clear(&mut self) {
+    self.draining = false;
+    self.next_seq = None;
+    self.buffer.clear();
+  }
+
+  /// Takes the front slot, moving the playout position past it.
+  fn advance(&mut self) -> Option<T> {
+    let slot = self.buffer.pop_front()?;
+    self.next_seq = self.next_seq.map(|next| next.wrapping_add(1));
+    slot
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn holds_back_until_full() {
+    let mut jitter = JitterBuffer::new(3);
+    jitter.push(1);
+    jitter.push(2);
+    assert_eq!(jitter.pop(), None);
+    assert_eq!(jitter.len(), 2);
+
+    jitter.push(3);
+    assert_eq!(jitter.pop(), Some(1));
+    assert_eq!(jitter.pop(), Some(2));
+  }
+
+  #[test]
+  fn refills_after_an_underrun() {
+    let mut jitter = JitterBuffer::new(2);
+    jitter.push(1);
+    jitter.push(2);
+    assert_eq!(jitter.pop(), Some(1));
+    assert_eq!(jitter.pop(), Some(2));
+    assert_eq!(jitter.pop(), None);
+
+    // Run dry, it waits for the full depth again before resuming.
+    jitter.push(3);
+    assert_eq!(jitter.pop(), None);
+    jitter.push(4);
+    assert_eq!(jitter.pop(), Some(3));
+    assert_eq!(jitter.pop(), Some(4));
+  }
+
+  #[test]
+  fn overflow_drops_the_oldest() {
+    let mut jitter = JitterBuffer::new(2);
+    assert_eq!(jitter.push(1), None);
+    assert_eq!(jitter.push(2), None);
+    assert_eq!(jitter.push(3), Some(1));
+    assert_eq!(jitter.len(), 2);
+    assert_eq!(jitter.drain().collect::<Vec<_>>(), [2, 3]);
+    assert!(jitter.is_empty());
+  }
+
+  #[test]
+  fn puts_numbered_packets_back_in_order() {
+    let mut jitter = JitterBuffer::new(3);
+    assert!(jitter.push_ordered(10, 'a'));
+    assert!(jitter.push_ordered(12, 'c'));
+    assert_eq!(jitter.pop_ready(), Some('a'));
+    // 11 is late, and playout waits for it.
+    assert_eq!(jitter.pop_ready(), None);
+    assert!(jitter.push_ordered(11, 'b'));
+    // Duplicates and packets already played past are dropped.
+    assert!(!jitter.push_ordered(11, 'x'));
+    assert!(!jitter.push_ordered(10, 'x'));
+    assert_eq!(jitter.pop_ready(), Some('b'));
+    assert_eq!(jitter.pop_ready(), Some('c'));
+    assert_eq!(jitter.pop_ready(), None);
+  }
+
+  #[test]
+  fn skips_a_lost_packet_once_full() {
+    let mut jitter = JitterBuffer::new(3);
+    jitter.push_ordered(0, 0);
+    jitter.push_ordered(2, 2);
+    assert_eq!(jitter.pop_ready(), Some(0));
+    assert_eq!(jitter.pop_ready(), None);
+    // 1 never comes, and the buffer fills up behind it.
+    jitter.push_ordered(3, 3);
+    jitter.push_ordered(4, 4);
+    assert_eq!(jitter.pop_ready(), Some(2));
+  }
+
+  #[test]
+  fn reorders_across_wraparound() {
+    let mut jitter = JitterBuffer::new(4);
+    for seq in [u32::MAX - 1, 0, u32::MAX, 1] {
+      assert!(jitter.push_ordered(seq, seq));
+    }
+    let played: Vec<u32> = std::iter::from_fn(|| jitter.pop_ready()).collect();
+    assert_eq!(played, [u32::MAX - 1, u32::MAX, 0, 1]);
+    assert!(!jitter.push_ordered(u32::MAX, 0));
+    assert!(jitter.push_ordered(2, 2));
+  }
+
+  #[test]
+  fn mixes_numbered_and_unnumbered_packets() {
+    let mut jitter = JitterBuffer::new(4);
+    jitter.push_ordered(5, 5);
+    jitter.push_ordered(7, 7);
+    // An unnumbered packet gives up waiting for 6, and the next numbered
+    // one goes in behind it.
+    jitter.push(100);
+    jitter.push_ordered(8, 8);
+    let played: Vec<u32> = std::iter::from_fn(|| jitter.pop_ready()).collect();
+    assert_eq!(played, [5, 7, 100, 8]);
   }
 }
@@ -1,20 +1,223 @@
+pub mod capture;
+pub mod client;
+#[cfg(feature = "native")]
+pub mod device;
+pub mod dtx;
 pub mod fx;
 pub mod jitter;
+#[cfg(feature = "native")]
+pub mod logging;
+pub mod metrics;
+pub mod mix;
+pub mod morse;
+#[cfg(feature = "native")]
+pub mod net;
+pub mod normalize;
+#[cfg(feature = "nr")]
+pub mod nr;
+#[cfg(feature = "opus")]
+pub mod opus;
+#[cfg(feature = "native")]
+pub mod playback;
+pub mod plc;
+#[cfg(feature = "native")]
+pub mod presets;
+pub mod ptt;
+pub mod ratelimit;
+pub mod resample;
+pub mod seq;
+pub mod server;
+pub mod transmissions;
 
-use std::{sync::LazyLock, time::Duration};
+use std::{net::SocketAddr, sync::LazyLock, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+/// The one audio format squelch speaks, on the wire and internally: mono at
+/// 44.1 kHz. Output devices that run at another rate are resampled to on the
+/// fly, but mics and files aren't, so audio at any other rate would play back
+/// pitch-shifted; convert files first with the `resample` binary.
+pub const SAMPLE_RATE: u32 = 44100;
+/// Channels in the wire format; everything is downmixed to mono.
+pub const CHANNELS: u16 = 1;
+
 pub const TX_BUFFER_SIZE: usize = 256;
-pub const MAX_PACKET_SIZE: usize = 4 * TX_BUFFER_SIZE + 8;
+/// Room for the samples plus the largest header, [`Packet::DirectAudio`]'s
+/// IPv6 address.
+pub const MAX_PACKET_SIZE: usize = 4 * TX_BUFFER_SIZE + 32;
 
 pub type TxBuffer = [f32; TX_BUFFER_SIZE];
 
+/// The largest [`Packet::OpusAudio`] frame, in bytes. Voice frames are a
+/// small fraction of this.
+pub const MAX_OPUS_FRAME: usize = 4 * TX_BUFFER_SIZE;
+
+/// The longest [`Packet::Rejected`] reason, in bytes, that still fits in
+/// [`MAX_PACKET_SIZE`].
+pub const MAX_REASON_LEN: usize = 256;
+
+/// Identifies a client: its address as seen by the server.
+pub type ClientId = SocketAddr;
+
+/// The protocol this build speaks, announced in [`Packet::Hello`]. Peers that
+/// never say hello are assumed to be version 0.
+///
+/// - 0: `Ping`, `Audio` and `DirectAudio`.
+/// - 1: adds `Silence`, `Level`, `Channel` and `Hello`.
+/// - 2: adds `Rejected`.
+/// - 3: adds `ServerInfo`.
+/// - 4: adds `Emergency`.
+/// - 5: adds `SeqAudio`.
+/// - 6: adds `StampedAudio`.
+/// - 7: adds `AcceptOpus` and `OpusAudio`.
+/// - 8: adds `HelloAck`.
+///
+/// New variants must only ever be appended, so older peers still decode the
+/// ones they know, and [`Packet::min_version`] updated.
+pub const PROTOCOL_VERSION: u16 = 8;
+
+/// How many variants [`Packet`] has, to tell a newer peer's packets apart
+/// from corrupt ones.
+const PACKET_VARIANTS: u32 = 15;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(clippy::large_enum_variant)]
 pub enum Packet {
   Ping,
   Audio(#[serde(with = "serde_arrays")] TxBuffer),
+  /// Audio for a single client only. The server mixes it into that client's
+  /// feed and nobody else's.
+  DirectAudio {
+    to: ClientId,
+    #[serde(with = "serde_arrays")]
+    samples: TxBuffer,
+  },
+  /// Sent instead of audio while a DTX talker is silent (see [`dtx`]).
+  Silence,
+  /// The sender's desired transmit level, in percent. The server scales its
+  /// audio by this in the mix, clamped to [`MAX_LEVEL`].
+  Level(u8),
+  /// Moves the sender to another channel. Clients only hear, and are only
+  /// heard by, others on the same channel; everyone starts on channel 0.
+  Channel(u16),
+  /// Announces the sender's [`PROTOCOL_VERSION`], right after its first
+  /// ping, so the server only sends it packets it understands.
+  Hello {
+    version: u16,
+  },
+  /// The server's answer to a ping it won't serve, e.g. because it's full.
+  /// The sender isn't added; it should show `reason` and stop. Keep `reason`
+  /// within [`MAX_REASON_LEN`].
+  Rejected {
+    reason: String,
+  },
+  /// The server's answer to [`Packet::Hello`], and to each ping after it,
+  /// describing how it serves.
+  ServerInfo {
+    /// The server runs the FX on each client's mix, so clients should play
+    /// what they get as-is rather than run their own.
+    fx: bool,
+  },
+  /// Audio for everyone, on every channel. The server relays it only with
+  /// `--allow-emergency`, after an attention tone, and treats it as plain
+  /// channel audio otherwise. Clients that understand it sound the alarm.
+  Emergency {
+    #[serde(with = "serde_arrays")]
+    samples: TxBuffer,
+  },
+  /// [`Packet::Audio`] numbered by its sender, one up per buffer, so the
+  /// receiver can drop buffers that arrive out of order (see [`seq`]). The
+  /// server sends each client's mix like this once it says hello with
+  /// protocol 5.
+  SeqAudio {
+    seq: u32,
+    #[serde(with = "serde_arrays")]
+    samples: TxBuffer,
+  },
+  /// [`Packet::SeqAudio`] that also says when its samples were captured, in
+  /// microseconds on the sender's own clock, so receivers can measure
+  /// jitter and, with synchronised clocks, one-way latency. The server
+  /// stamps each client's mix as it makes it, once it says hello with
+  /// protocol 6; clients stamp their mic audio once the server does.
+  StampedAudio {
+    seq: u32,
+    micros: u64,
+    #[serde(with = "serde_arrays")]
+    samples: TxBuffer,
+  },
+  /// The sender can decode [`Packet::OpusAudio`] and would rather get audio
+  /// that way. A server built with the `opus` feature sends it back, and
+  /// from then on sends the client its mix compressed; the client may then
+  /// send its own audio compressed too.
+  AcceptOpus,
+  /// 10 ms of Opus-compressed audio (see the `opus` module), numbered like
+  /// [`Packet::SeqAudio`]. Only sent to peers that sent
+  /// [`Packet::AcceptOpus`]. At most [`MAX_OPUS_FRAME`] bytes.
+  OpusAudio {
+    seq: u32,
+    frame: Vec<u8>,
+  },
+  /// The server's answer to [`Packet::Hello`], with its own
+  /// [`PROTOCOL_VERSION`], so the client knows what it can send.
+  HelloAck {
+    version: u16,
+  },
+}
+
+impl Packet {
+  /// Decodes a datagram. Packets from a newer protocol than ours are
+  /// `Ok(None)`: they're expected, so callers should skip them quietly
+  /// rather than log every one.
+  pub fn decode(bytes: &[u8]) -> postcard::Result<Option<Packet>> {
+    match postcard::from_bytes::<Packet>(bytes) {
+      Ok(packet) => Ok(Some(packet)),
+      // Postcard leads with the variant index as a varint.
+      Err(_)
+        if postcard::take_from_bytes::<u32>(bytes)
+          .is_ok_and(|(variant, _)| variant >= PACKET_VARIANTS) =>
+      {
+        Ok(None)
+      }
+      Err(err) => Err(err),
+    }
+  }
+
+  /// The oldest protocol version that understands this packet.
+  pub fn min_version(&self) -> u16 {
+    match self {
+      Packet::Ping | Packet::Audio(_) | Packet::DirectAudio { .. } => 0,
+      Packet::Silence
+      | Packet::Level(_)
+      | Packet::Channel(_)
+      | Packet::Hello { .. } => 1,
+      Packet::Rejected { .. } => 2,
+      Packet::ServerInfo { .. } => 3,
+      Packet::Emergency { .. } => 4,
+      Packet::SeqAudio { .. } => 5,
+      Packet::StampedAudio { .. } => 6,
+      Packet::AcceptOpus | Packet::OpusAudio { .. } => 7,
+      Packet::HelloAck { .. } => 8,
+    }
+  }
+
+  /// Where a buffer of numbered audio comes in its sender's feed.
+  pub fn seq(&self) -> Option<u32> {
+    match self {
+      Packet::SeqAudio { seq, .. } | Packet::StampedAudio { seq, .. } => {
+        Some(*seq)
+      }
+      _ => None,
+    }
+  }
+}
+
+/// The loudest transmit level a client may advertise, in percent, so nobody
+/// can shout over the rest of the channel.
+pub const MAX_LEVEL: u8 = 200;
+
+/// The mix gain for an advertised [`Packet::Level`].
+pub fn level_gain(level: u8) -> f32 {
+  level.min(MAX_LEVEL) as f32 / 100.0
 }
 
 pub fn map_would_block<T>(result: std::io::Result<T>) -> std::io::Result<()> {
@@ -27,6 +230,98 @@ pub fn map_would_block<T>(result: std::io::Result<T>) -> std::io::Result<()> {
   }
 }
 
+/// How often clients ping the server while connected, so one that's only
+/// listening isn't taken for gone. Well inside the server's timeout.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
 pub static WAIT_DURATION: LazyLock<Duration> = LazyLock::new(|| {
-  Duration::from_secs_f32(1.0 / (44100.0 / TX_BUFFER_SIZE as f32))
+  Duration::from_secs_f32(TX_BUFFER_SIZE as f32 / SAMPLE_RATE as f32)
 });
+
+#[cfg(test)]
+mod tests {
+  use std::net::{Ipv6Addr, SocketAddrV6};
+
+  use super::*;
+
+  /// Every variant at its largest must fit the receive buffers, or it'll be
+  /// silently truncated.
+  #[test]
+  fn max_packet_size_bounds_every_packet() {
+    let samples = [f32::MAX; TX_BUFFER_SIZE];
+    let to = SocketAddr::V6(SocketAddrV6::new(
+      Ipv6Addr::from(u128::MAX),
+      u16::MAX,
+      u32::MAX,
+      u32::MAX,
+    ));
+    let packets = [
+      Packet::Ping,
+      Packet::Audio(samples),
+      Packet::DirectAudio { to, samples },
+      Packet::Silence,
+      Packet::Level(u8::MAX),
+      Packet::Channel(u16::MAX),
+      Packet::Hello { version: u16::MAX },
+      Packet::Rejected {
+        reason: "x".repeat(MAX_REASON_LEN),
+      },
+      Packet::ServerInfo { fx: true },
+      Packet::Emergency { samples },
+      Packet::SeqAudio {
+        seq: u32::MAX,
+        samples,
+      },
+      Packet::StampedAudio {
+        seq: u32::MAX,
+        micros: u64::MAX,
+        samples,
+      },
+      Packet::AcceptOpus,
+      Packet::OpusAudio {
+        seq: u32::MAX,
+        frame: vec![u8::MAX; MAX_OPUS_FRAME],
+      },
+      Packet::HelloAck { version: u16::MAX },
+    ];
+    assert_eq!(packets.len() as u32, PACKET_VARIANTS);
+
+    for packet in &packets {
+      let len = postcard::to_allocvec(packet).unwrap().len();
+      assert!(
+        len <= MAX_PACKET_SIZE,
+        "{packet:?} encodes to {len} bytes, over MAX_PACKET_SIZE \
+         ({MAX_PACKET_SIZE})",
+      );
+    }
+  }
+
+  /// What a version 0 peer knew of [`Packet`].
+  #[derive(Debug, Deserialize)]
+  #[allow(clippy::large_enum_variant)]
+  enum PacketV0 {
+    Ping,
+    Audio(#[serde(with = "serde_arrays")] TxBuffer),
+  }
+
+  #[test]
+  fn older_and_newer_peers_decode_what_they_know() {
+    // An old peer still reads the packets it knew, as they're never moved.
+    let audio =
+      postcard::to_allocvec(&Packet::Audio([0.5; TX_BUFFER_SIZE])).unwrap();
+    let decoded = postcard::from_bytes::<PacketV0>(&audio).unwrap();
+    assert!(matches!(decoded, PacketV0::Audio(samples) if samples[0] == 0.5));
+    let ping = postcard::to_allocvec(&Packet::Ping).unwrap();
+    assert!(matches!(
+      postcard::from_bytes::<PacketV0>(&ping),
+      Ok(PacketV0::Ping)
+    ));
+
+    // Ours skips a newer peer's packets quietly: the variant after our last,
+    // with whatever it carries.
+    let newer = [PACKET_VARIANTS as u8, 1, 2, 3];
+    assert!(matches!(Packet::decode(&newer), Ok(None)));
+    // But a known one that's cut short is still an error.
+    assert!(Packet::decode(&audio[..10]).is_err());
+  }
+}
@@ -0,0 +1,39 @@
+//! The `-q`/`-v` flags every binary shares, and the log output they pick.
+
+use clap::{ArgAction, Args};
+use tracing::Level;
+
+#[derive(Debug, Clone, Copy, Args)]
+pub struct Verbosity {
+  /// Only print warnings and errors.
+  #[arg(short, long, conflicts_with = "verbose")]
+  pub quiet: bool,
+
+  /// Print more: `-v` for device and connection details, `-vv` for every
+  /// packet.
+  #[arg(short, long, action = ArgAction::Count)]
+  pub verbose: u8,
+}
+
+impl Verbosity {
+  pub fn level(&self) -> Level {
+    match (self.quiet, self.verbose) {
+      (true, _) => Level::WARN,
+      (false, 0) => Level::INFO,
+      (false, 1) => Level::DEBUG,
+      (false, _) => Level::TRACE,
+    }
+  }
+
+  /// Sends log output to stderr at the chosen level, leaving stdout for
+  /// what a command is asked to print (e.g. `--check` or `--calibrate`).
+  /// Call it first thing in `main`.
+  pub fn init(&self) {
+    tracing_subscriber::fmt()
+      .with_max_level(self.level())
+      .with_target(false)
+      .without_time()
+      .with_writer(std::io::stderr)
+      .init();
+  }
+}
@@ -0,0 +1,120 @@
+//! Server counters, exported in the Prometheus text format.
+//!
+//! The counters are plain atomics so updating them stays off the hot path;
+//! the HTTP endpoint is only built with the `metrics` feature.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+  pub clients: AtomicU64,
+  pub packets_received: AtomicU64,
+  pub packets_sent: AtomicU64,
+  pub bytes_received: AtomicU64,
+  pub bytes_sent: AtomicU64,
+  pub decode_errors: AtomicU64,
+  pub mix_ticks: AtomicU64,
+}
+
+impl Metrics {
+  /// Formats every counter in the Prometheus text exposition format.
+  pub fn render(&self) -> String {
+    let metrics: [(&str, &str, &str, &AtomicU64); 7] = [
+      ("clients", "gauge", "Connected clients.", &self.clients),
+      (
+        "packets_received_total",
+        "counter",
+        "Datagrams received.",
+        &self.packets_received,
+      ),
+      (
+        "packets_sent_total",
+        "counter",
+        "Datagrams sent.",
+        &self.packets_sent,
+      ),
+      (
+        "bytes_received_total",
+        "counter",
+        "Bytes received.",
+        &self.bytes_received,
+      ),
+      (
+        "bytes_sent_total",
+        "counter",
+        "Bytes sent.",
+        &self.bytes_sent,
+      ),
+      (
+        "decode_errors_total",
+        "counter",
+        "Datagrams that failed to decode.",
+        &self.decode_errors,
+      ),
+      (
+        "mix_ticks_total",
+        "counter",
+        "Mixer ticks.",
+        &self.mix_ticks,
+      ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+      out += &format!(
+        "# HELP squelch_{name} {help}\n\
+         # TYPE squelch_{name} {kind}\n\
+         squelch_{name} {}\n",
+        value.load(Ordering::Relaxed)
+      );
+    }
+    out
+  }
+
+  /// Serves [`render`](Self::render) over HTTP on `addr` from a background
+  /// thread.
+  #[cfg(feature = "metrics")]
+  pub fn serve(
+    self: std::sync::Arc<Self>,
+    addr: std::net::SocketAddr,
+  ) -> std::io::Result<()> {
+    let server =
+      tiny_http::Server::http(addr).map_err(std::io::Error::other)?;
+    std::thread::spawn(move || {
+      for request in server.incoming_requests() {
+        let content_type = tiny_http::Header::from_bytes(
+          &b"Content-Type"[..],
+          &b"text/plain; version=0.0.4"[..],
+        )
+        .unwrap();
+        let response = tiny_http::Response::from_string(self.render())
+          .with_header(content_type);
+        if let Err(e) = request.respond(response) {
+          tracing::warn!("Failed to serve metrics: {e}");
+        }
+      }
+    });
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn renders_each_counter_in_the_text_format() {
+    let metrics = Metrics::default();
+    metrics.clients.store(3, Ordering::Relaxed);
+    metrics.packets_received.fetch_add(42, Ordering::Relaxed);
+
+    let text = metrics.render();
+    assert!(text.starts_with(
+      "# HELP squelch_clients Connected clients.\n\
+       # TYPE squelch_clients gauge\n\
+       squelch_clients 3\n"
+    ));
+    assert!(text.contains("\nsquelch_packets_received_total 42\n"));
+    assert_eq!(text.lines().count(), 7 * 3);
+  }
+}
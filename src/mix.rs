@@ -0,0 +1,345 @@
+use std::{fmt, str::FromStr};
+
+use crate::{TX_BUFFER_SIZE, TxBuffer};
+
+/// How the mix is scaled down as more clients talk at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attenuation {
+  /// Plain sum; overlapping talkers are likely to clip.
+  None,
+  /// Divide by the square root of the talker count, keeping roughly constant
+  /// loudness for uncorrelated voices.
+  Sqrt,
+  /// Divide by the talker count.
+  Linear,
+}
+
+impl Attenuation {
+  pub fn gain(self, active: usize) -> f32 {
+    let active = active.max(1) as f32;
+    match self {
+      Self::None => 1.0,
+      Self::Sqrt => 1.0 / active.sqrt(),
+      Self::Linear => 1.0 / active,
+    }
+  }
+}
+
+impl FromStr for Attenuation {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(Self::None),
+      "sqrt" => Ok(Self::Sqrt),
+      "linear" => Ok(Self::Linear),
+      _ => Err(format!(
+        "unknown attenuation {s:?} (expected none, sqrt or linear)"
+      )),
+    }
+  }
+}
+
+impl fmt::Display for Attenuation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::None => write!(f, "none"),
+      Self::Sqrt => write!(f, "sqrt"),
+      Self::Linear => write!(f, "linear"),
+    }
+  }
+}
+
+/// Shorthand for common [`MixPolicy`] settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MixMode {
+  /// Plain sum; a lone talker stays at full level but overlaps can clip.
+  Sum,
+  /// Divide by the number of sources, silent or not.
+  Average,
+  /// Divide by the number of sources that actually carry sound this tick.
+  Auto,
+}
+
+impl MixMode {
+  pub fn policy(self, headroom_db: f32) -> MixPolicy {
+    let (attenuation, active_only) = match self {
+      Self::Sum => (Attenuation::None, false),
+      Self::Average => (Attenuation::Linear, false),
+      Self::Auto => (Attenuation::Linear, true),
+    };
+    MixPolicy {
+      attenuation,
+      headroom_db,
+      active_only,
+    }
+  }
+}
+
+impl FromStr for MixMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "sum" => Ok(Self::Sum),
+      "average" => Ok(Self::Average),
+      "auto" => Ok(Self::Auto),
+      _ => Err(format!(
+        "unknown mix mode {s:?} (expected sum, average or auto)"
+      )),
+    }
+  }
+}
+
+impl fmt::Display for MixMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Sum => write!(f, "sum"),
+      Self::Average => write!(f, "average"),
+      Self::Auto => write!(f, "auto"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MixPolicy {
+  pub attenuation: Attenuation,
+  /// Extra attenuation applied to the whole mix before clamping.
+  pub headroom_db: f32,
+  /// Only count sources with a non-silent buffer as talkers when scaling.
+  pub active_only: bool,
+}
+
+impl MixPolicy {
+  /// Sums `sources` into `out`, then scales and clamps once at the end, so
+  /// clipping doesn't depend on the order the sources were added in.
+  ///
+  /// The sum is kept in f64: f32 addition isn't associative, so with several
+  /// loud sources an f32 sum could still differ in its last bits depending on
+  /// the (arbitrary) order the server visits its clients in.
+  pub fn mix<'a>(
+    &self,
+    out: &mut TxBuffer,
+    sources: impl IntoIterator<Item = &'a TxBuffer>,
+  ) {
+    let mut sum = [0f64; TX_BUFFER_SIZE];
+
+    let mut active = 0;
+    for samples in sources {
+      if !self.active_only || samples.iter().any(|s| *s != 0.0) {
+        active += 1;
+      }
+      for (o, s) in sum.iter_mut().zip(samples.iter()) {
+        *o += *s as f64;
+      }
+    }
+
+    let gain = self.attenuation.gain(active) as f64
+      * 10f64.powf(-self.headroom_db as f64 / 20.0);
+    for (o, s) in out.iter_mut().zip(sum) {
+      *o = (s * gain).clamp(-1.0, 1.0) as f32;
+    }
+  }
+}
+
+/// Smooths over a source briefly running dry: instead of dropping out of the
+/// mix abruptly, its last buffer is replayed, fading to silence over a few
+/// ticks.
+#[derive(Debug, Clone)]
+pub struct FadeOut {
+  last: TxBuffer,
+  ticks: usize,
+  left: usize,
+}
+
+impl FadeOut {
+  pub fn new(ticks: usize) -> Self {
+    Self {
+      last: [0.0; TX_BUFFER_SIZE],
+      ticks,
+      left: 0,
+    }
+  }
+
+  /// Call once per mixer tick with the source's next buffer, if it had one.
+  /// Returns what to mix for the source this tick.
+  pub fn next(&mut self, samples: Option<TxBuffer>) -> Option<TxBuffer> {
+    if let Some(samples) = samples {
+      self.last = samples;
+      self.left = self.ticks;
+      return Some(samples);
+    }
+
+    if self.left == 0 {
+      return None;
+    }
+
+    // Ramp smoothly across the buffer from this tick's level to the next.
+    let step = 1.0 / (self.ticks + 1) as f32;
+    let from = self.left as f32 * step;
+    let mut out = self.last;
+    for (i, s) in out.iter_mut().enumerate() {
+      *s *= from - step * (i + 1) as f32 / TX_BUFFER_SIZE as f32;
+    }
+    self.left -= 1;
+
+    Some(out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(attenuation: Attenuation, headroom_db: f32) -> MixPolicy {
+    MixPolicy {
+      attenuation,
+      headroom_db,
+      active_only: false,
+    }
+  }
+
+  #[test]
+  fn clamps_once_after_summing() {
+    let sources = [
+      [0.8; TX_BUFFER_SIZE],
+      [0.8; TX_BUFFER_SIZE],
+      [-0.8; TX_BUFFER_SIZE],
+    ];
+
+    // Clamping as each source is added loses the third one's cancellation.
+    let mut clamped_early = 0f32;
+    for source in &sources {
+      clamped_early = (clamped_early + source[0]).clamp(-1.0, 1.0);
+    }
+    assert!((clamped_early - 0.2).abs() < 1e-6);
+
+    let mut out = [0.0; TX_BUFFER_SIZE];
+    policy(Attenuation::None, 0.0).mix(&mut out, &sources);
+    assert!(out.iter().all(|s| (s - 0.8).abs() < 1e-6));
+  }
+
+  #[test]
+  fn clamps_the_final_mix() {
+    let mut out = [0.0; TX_BUFFER_SIZE];
+    let loud = [[0.9; TX_BUFFER_SIZE], [0.9; TX_BUFFER_SIZE]];
+    policy(Attenuation::None, 0.0).mix(&mut out, &loud);
+    assert!(out.iter().all(|s| *s == 1.0));
+  }
+
+  #[test]
+  fn attenuates_by_talker_count() {
+    assert_eq!(Attenuation::None.gain(4), 1.0);
+    assert_eq!(Attenuation::Sqrt.gain(4), 0.5);
+    assert_eq!(Attenuation::Linear.gain(4), 0.25);
+    // Nobody talking is no different from one talker.
+    assert_eq!(Attenuation::Linear.gain(0), 1.0);
+  }
+
+  #[test]
+  fn headroom_lowers_the_mix() {
+    let mut out = [0.0; TX_BUFFER_SIZE];
+    policy(Attenuation::None, 6.0).mix(&mut out, &[[0.5; TX_BUFFER_SIZE]]);
+    assert!((out[0] - 0.5 * 10f32.powf(-6.0 / 20.0)).abs() < 1e-6);
+  }
+
+  #[test]
+  fn attenuation_parses_and_displays() {
+    for attenuation in
+      [Attenuation::None, Attenuation::Sqrt, Attenuation::Linear]
+    {
+      assert_eq!(attenuation.to_string().parse(), Ok(attenuation));
+    }
+    assert!("loud".parse::<Attenuation>().is_err());
+  }
+
+  #[test]
+  fn mix_does_not_depend_on_talker_order() {
+    let talker = |seed: f32| {
+      let mut buf = [0.0; TX_BUFFER_SIZE];
+      for (i, s) in buf.iter_mut().enumerate() {
+        *s = (seed * (i + 1) as f32).sin() * 0.9;
+      }
+      buf
+    };
+    let talkers = [talker(0.1), talker(0.37), talker(1.3)];
+    let orders = [
+      [0, 1, 2],
+      [0, 2, 1],
+      [1, 0, 2],
+      [1, 2, 0],
+      [2, 0, 1],
+      [2, 1, 0],
+    ];
+
+    for attenuation in
+      [Attenuation::None, Attenuation::Sqrt, Attenuation::Linear]
+    {
+      let mixes: Vec<TxBuffer> = orders
+        .iter()
+        .map(|order| {
+          let mut out = [0.0; TX_BUFFER_SIZE];
+          policy(attenuation, 0.0)
+            .mix(&mut out, order.iter().map(|i| &talkers[*i]));
+          out
+        })
+        .collect();
+      assert!(mixes.iter().all(|mix| *mix == mixes[0]));
+    }
+  }
+
+  /// Mixes `talkers` sources at 0.2 alongside one silent source.
+  fn mix_talkers(mode: MixMode, talkers: usize) -> f32 {
+    let mut sources = vec![[0.2; TX_BUFFER_SIZE]; talkers];
+    sources.push([0.0; TX_BUFFER_SIZE]);
+    let mut out = [0.0; TX_BUFFER_SIZE];
+    mode.policy(0.0).mix(&mut out, &sources);
+    out[0]
+  }
+
+  #[test]
+  fn mix_modes_with_one_two_and_three_talkers() {
+    for talkers in 1..=3 {
+      let n = talkers as f32;
+      let expected = [
+        (MixMode::Sum, 0.2 * n),
+        // The silent source still counts.
+        (MixMode::Average, 0.2 * n / (n + 1.0)),
+        (MixMode::Auto, 0.2),
+      ];
+      for (mode, level) in expected {
+        let mixed = mix_talkers(mode, talkers);
+        assert!(
+          (mixed - level).abs() < 1e-6,
+          "{mode} with {talkers} talkers: {mixed}, expected {level}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn fade_out_covers_an_underrun_then_resumes() {
+    let mut fade = FadeOut::new(3);
+    let loud = [0.8; TX_BUFFER_SIZE];
+    assert_eq!(fade.next(Some(loud)), Some(loud));
+
+    // A late packet: the last buffer comes back, fading smoothly from where
+    // it was.
+    let faded = fade.next(None).unwrap();
+    assert!(faded[0] < 0.8 && faded[0] > 0.5);
+    assert!(faded.windows(2).all(|w| w[1] < w[0]));
+
+    // It turns up, and plays as-is.
+    let quiet = [0.4; TX_BUFFER_SIZE];
+    assert_eq!(fade.next(Some(quiet)), Some(quiet));
+
+    // Running dry for good fades it out over the three ticks, from each
+    // tick's level to the next, and then drops it.
+    let tail: Vec<TxBuffer> = (0..3).map_while(|_| fade.next(None)).collect();
+    assert_eq!(tail.len(), 3);
+    let joined: Vec<f32> = tail.iter().flatten().copied().collect();
+    assert!(joined.windows(2).all(|w| w[1] < w[0]));
+    assert!(joined[joined.len() - 1].abs() < 1e-6);
+    assert_eq!(fade.next(None), None);
+  }
+}
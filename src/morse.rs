@@ -0,0 +1,180 @@
+//! Morse code station identification ("CW ident"), sent as audio tones.
+//!
+//! Timing follows the usual convention: a dah is three dits, elements are
+//! one dit apart, letters three and words seven. At `wpm` words per minute a
+//! dit lasts 1.2 / `wpm` seconds ("PARIS" is 50 dits long).
+
+use crate::{SAMPLE_RATE, TX_BUFFER_SIZE, TxBuffer, fx::gen_tone};
+
+/// Pitch of the ident tone.
+pub const IDENT_TONE_HZ: f32 = 700.0;
+/// Peak level of the ident tone.
+const IDENT_LEVEL: f32 = 0.5;
+/// Rise and fall time of each element, so keying doesn't click (~5 ms).
+const RAMP_SAMPLES: usize = 220;
+
+/// The dits and dahs for `c`, or `None` if it has no Morse code.
+fn code(c: char) -> Option<&'static str> {
+  Some(match c.to_ascii_uppercase() {
+    'A' => ".-",
+    'B' => "-...",
+    'C' => "-.-.",
+    'D' => "-..",
+    'E' => ".",
+    'F' => "..-.",
+    'G' => "--.",
+    'H' => "....",
+    'I' => "..",
+    'J' => ".---",
+    'K' => "-.-",
+    'L' => ".-..",
+    'M' => "--",
+    'N' => "-.",
+    'O' => "---",
+    'P' => ".--.",
+    'Q' => "--.-",
+    'R' => ".-.",
+    'S' => "...",
+    'T' => "-",
+    'U' => "..-",
+    'V' => "...-",
+    'W' => ".--",
+    'X' => "-..-",
+    'Y' => "-.--",
+    'Z' => "--..",
+    '0' => "-----",
+    '1' => ".----",
+    '2' => "..---",
+    '3' => "...--",
+    '4' => "....-",
+    '5' => ".....",
+    '6' => "-....",
+    '7' => "--...",
+    '8' => "---..",
+    '9' => "----.",
+    '/' => "-..-.",
+    '?' => "..--..",
+    '.' => ".-.-.-",
+    ',' => "--..--",
+    '=' => "-...-",
+    _ => return None,
+  })
+}
+
+/// How `text` is keyed, as runs of (key down, length in dits). Characters
+/// without a Morse code are skipped; whitespace separates words.
+pub fn keying(text: &str) -> Vec<(bool, usize)> {
+  let mut runs: Vec<(bool, usize)> = Vec::new();
+  for (w, word) in text.split_whitespace().enumerate() {
+    let mut letters = word.chars().filter_map(code).peekable();
+    if letters.peek().is_none() {
+      continue;
+    }
+    if w > 0 && !runs.is_empty() {
+      runs.push((false, 7));
+    }
+
+    for (l, letter) in letters.enumerate() {
+      if l > 0 {
+        runs.push((false, 3));
+      }
+      for (e, element) in letter.chars().enumerate() {
+        if e > 0 {
+          runs.push((false, 1));
+        }
+        runs.push((true, if element == '-' { 3 } else { 1 }));
+      }
+    }
+  }
+  runs
+}
+
+/// `text` in Morse at `wpm`, as buffers ready to send, ending in silence.
+pub fn ident(text: &str, wpm: f32, freq_hz: f32) -> Vec<TxBuffer> {
+  let dit = (1.2 / wpm * SAMPLE_RATE as f32) as usize;
+  let runs = keying(text);
+  let total = runs.iter().map(|(_, dits)| dits * dit).sum::<usize>();
+
+  let mut samples = gen_tone(freq_hz, total);
+  let mut start = 0;
+  for (on, dits) in runs {
+    let run = &mut samples[start..start + dits * dit];
+    start += run.len();
+    if !on {
+      run.fill(0.0);
+      continue;
+    }
+
+    let ramp = RAMP_SAMPLES.min(run.len() / 2);
+    let len = run.len();
+    for (i, s) in run.iter_mut().enumerate() {
+      let edge = i.min(len - 1 - i);
+      let gain = if edge < ramp {
+        0.5 - 0.5 * (std::f32::consts::PI * edge as f32 / ramp as f32).cos()
+      } else {
+        1.0
+      };
+      *s *= gain * IDENT_LEVEL;
+    }
+  }
+
+  samples
+    .chunks(TX_BUFFER_SIZE)
+    .map(|chunk| {
+      let mut buf = [0f32; TX_BUFFER_SIZE];
+      buf[..chunk.len()].copy_from_slice(chunk);
+      buf
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Reads the keying back out of `buffers`: runs of (key down, length in
+  /// dits), judged by the tone's level a few milliseconds at a time.
+  fn decode(buffers: &[TxBuffer], wpm: f32) -> Vec<(bool, usize)> {
+    let dit = 1.2 / wpm * SAMPLE_RATE as f32;
+    let window = 32;
+    let mut runs: Vec<(bool, usize)> = Vec::new();
+    for chunk in buffers.as_flattened().chunks(window) {
+      let on = chunk.iter().any(|s| s.abs() > 0.05);
+      match runs.last_mut() {
+        Some((last, len)) if *last == on => *len += chunk.len(),
+        _ => runs.push((on, chunk.len())),
+      }
+    }
+    // Neither the quiet start of the first element's ramp nor the padding
+    // after the last element is part of the code.
+    if runs.first().is_some_and(|(on, _)| !on) {
+      runs.remove(0);
+    }
+    if runs.last().is_some_and(|(on, _)| !on) {
+      runs.pop();
+    }
+    runs
+      .into_iter()
+      .map(|(on, len)| (on, (len as f32 / dit).round() as usize))
+      .collect()
+  }
+
+  #[test]
+  fn keying_follows_the_standard_timing() {
+    assert_eq!(keying("e t"), [(true, 1), (false, 7), (true, 3)]);
+    // "PARIS" is the standard word: 50 dits with the gap after it.
+    let paris = keying("PARIS");
+    assert_eq!(paris.iter().map(|(_, dits)| dits).sum::<usize>() + 7, 50);
+    // Characters without a code are skipped.
+    assert_eq!(keying("e~"), keying("e"));
+  }
+
+  #[test]
+  fn ident_audio_decodes_back_to_its_timing() {
+    for wpm in [12.0, 20.0, 30.0] {
+      let audio = ident("AB1CD SOS", wpm, IDENT_TONE_HZ);
+      assert_eq!(decode(&audio, wpm), keying("AB1CD SOS"), "{wpm} wpm");
+      assert!(audio.as_flattened().iter().all(|s| s.abs() <= IDENT_LEVEL));
+    }
+  }
+}
@@ -0,0 +1,81 @@
+use std::{
+  io,
+  net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::info;
+
+/// Binds a UDP socket to `addr`, optionally asking the OS for `buffer_kb`
+/// KiB receive and send buffers so bursts aren't dropped before we read
+/// them.
+///
+/// The buffer sizes have to be set on the raw socket before binding, which
+/// is why this goes through `socket2` rather than `UdpSocket::bind`. The OS
+/// may clamp the request (e.g. to `net.core.rmem_max` on Linux), so the
+/// sizes actually granted are logged.
+pub fn bind_udp(
+  addr: SocketAddr,
+  buffer_kb: Option<usize>,
+) -> io::Result<UdpSocket> {
+  let socket =
+    Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+  if let Some(kb) = buffer_kb {
+    socket.set_recv_buffer_size(kb * 1024)?;
+    socket.set_send_buffer_size(kb * 1024)?;
+    info!(
+      "UDP buffers: requested {} KiB, got {} KiB receive / {} KiB send",
+      kb,
+      socket.recv_buffer_size()? / 1024,
+      socket.send_buffer_size()? / 1024,
+    );
+  }
+
+  socket.bind(&addr.into())?;
+  Ok(socket.into())
+}
+
+/// Any local interface, with a port picked by the OS.
+pub const ANY_SOURCE: SocketAddr =
+  SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Binds a client's sending socket to `bind`, or to [`ANY_SOURCE`] when
+/// `None`, with [`bind_udp`]'s buffer sizing.
+///
+/// Choosing the source interface matters on multi-homed hosts and for
+/// servers that allow-list by source. A fixed source port also helps with
+/// NAT and firewalls: the mapping (and any port-forward or rule written for
+/// it) stays the same across restarts, and so does the client's
+/// [`ClientId`](crate::ClientId).
+pub fn bind_source(
+  bind: Option<SocketAddr>,
+  buffer_kb: Option<usize>,
+) -> io::Result<UdpSocket> {
+  let addr = bind.unwrap_or(ANY_SOURCE);
+  bind_udp(addr, buffer_kb).map_err(|e| {
+    let hint = match e.kind() {
+      io::ErrorKind::AddrNotAvailable => " (not an address of this host)",
+      io::ErrorKind::AddrInUse => " (port already in use)",
+      _ => "",
+    };
+    io::Error::new(e.kind(), format!("couldn't bind to {addr}: {e}{hint}"))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn binds_with_buffers_and_explains_failures() {
+    let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let socket = bind_udp(loopback, Some(256)).unwrap();
+    let taken = socket.local_addr().unwrap();
+    assert_ne!(taken.port(), 0);
+
+    let err = bind_source(Some(taken), None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    assert!(err.to_string().contains("port already in use"), "{err}");
+  }
+}
@@ -0,0 +1,115 @@
+//! Per-transmission loudness normalization on receive.
+//!
+//! The server just sums talkers, so a quiet mic stays quiet. The normalizer
+//! measures each transmission's average speech level as it goes and eases
+//! the gain toward whatever brings it to a target, then starts over for the
+//! next transmission. Unlike a compressor it doesn't follow the syllables:
+//! once a transmission's level is known the gain holds still.
+
+use crate::{SAMPLE_RATE, TxBuffer};
+
+/// RMS below which a buffer is a pause, not speech, and doesn't count
+/// toward the level (~-46 dBFS).
+const SPEECH_THRESHOLD: f32 = 0.005;
+/// The most the normalizer will boost or cut by (±12 dB), so a transmission
+/// that's nearly all noise isn't pulled up to full level.
+const MAX_GAIN: f32 = 4.0;
+const MIN_GAIN: f32 = 0.25;
+/// Time constant of the gain ramp, in seconds.
+const RAMP_SECS: f32 = 0.2;
+
+pub struct Normalizer {
+  target_rms: f32,
+  gain: f32,
+  /// Sum of the mean squares of this transmission's speech buffers.
+  energy: f32,
+  speech: usize,
+  /// How far the gain moves toward its goal each sample.
+  coeff: f32,
+}
+
+impl Normalizer {
+  /// Normalizes each transmission toward `target_rms`, e.g. 0.1 (-20 dBFS).
+  pub fn new(target_rms: f32) -> Self {
+    Self {
+      target_rms,
+      gain: 1.0,
+      energy: 0.0,
+      speech: 0,
+      coeff: 1.0 - (-1.0 / (RAMP_SECS * SAMPLE_RATE as f32)).exp(),
+    }
+  }
+
+  /// Starts a new transmission: forgets the last one's level and goes back
+  /// to unity gain.
+  pub fn reset(&mut self) {
+    self.gain = 1.0;
+    self.energy = 0.0;
+    self.speech = 0;
+  }
+
+  pub fn run(&mut self, samples: &mut TxBuffer) {
+    let mean_square =
+      samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+    if mean_square.sqrt() >= SPEECH_THRESHOLD {
+      self.energy += mean_square;
+      self.speech += 1;
+    }
+
+    let goal = if self.speech == 0 {
+      self.gain
+    } else {
+      let rms = (self.energy / self.speech as f32).sqrt();
+      (self.target_rms / rms).clamp(MIN_GAIN, MAX_GAIN)
+    };
+    for s in samples.iter_mut() {
+      self.gain += (goal - self.gain) * self.coeff;
+      *s *= self.gain;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{TX_BUFFER_SIZE, fx::gen_tone};
+
+  fn rms(samples: &[f32]) -> f32 {
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+  }
+
+  /// Runs two seconds of a tone at `amplitude` through as one transmission
+  /// and returns the output.
+  fn transmit(normalizer: &mut Normalizer, amplitude: f32) -> Vec<f32> {
+    normalizer.reset();
+    let tone = gen_tone(440.0, 2 * SAMPLE_RATE as usize);
+    tone
+      .chunks_exact(TX_BUFFER_SIZE)
+      .flat_map(|chunk| {
+        let mut buf = [0.0; TX_BUFFER_SIZE];
+        for (out, s) in buf.iter_mut().zip(chunk) {
+          *out = s * amplitude;
+        }
+        normalizer.run(&mut buf);
+        buf
+      })
+      .collect()
+  }
+
+  #[test]
+  fn quiet_and_loud_transmissions_end_up_alike() {
+    let mut normalizer = Normalizer::new(0.1);
+    // -6 dB and +6 dB off the target, within the gain limits.
+    let quiet = transmit(&mut normalizer, 0.05 * std::f32::consts::SQRT_2);
+    let loud = transmit(&mut normalizer, 0.2 * std::f32::consts::SQRT_2);
+
+    let last_second = SAMPLE_RATE as usize;
+    for out in [&quiet, &loud] {
+      let level = rms(&out[out.len() - last_second..]);
+      assert!((level - 0.1).abs() < 0.005, "{level}");
+    }
+    // The loud one starts at unity, not with the quiet one's boost.
+    let start = rms(&loud[..TX_BUFFER_SIZE]);
+    assert!((start - 0.2).abs() < 0.02, "{start}");
+  }
+}
@@ -0,0 +1,114 @@
+//! Mic noise reduction with RNNoise (via `nnnoiseless`), to keep fans and
+//! hum off the net.
+//!
+//! RNNoise works on 480-sample frames at 48 kHz, so buffers are linearly
+//! resampled up from 44.1 kHz, denoised a frame at a time, and resampled back.
+//! That costs [`NR_LATENCY`] samples of delay.
+
+use std::collections::VecDeque;
+
+use nnnoiseless::DenoiseState;
+
+use crate::{TX_BUFFER_SIZE, TxBuffer, resample::Linear};
+
+const RATE: f64 = 44100.0;
+const NR_RATE: f64 = 48000.0;
+
+/// Delay added by [`NoiseReducer`], in samples at 44.1 kHz (~10 ms): one
+/// RNNoise frame plus a little slack for resampling.
+pub const NR_LATENCY: usize = 448;
+
+pub struct NoiseReducer {
+  state: Box<DenoiseState<'static>>,
+  up: Linear,
+  down: Linear,
+  /// 48 kHz samples, scaled to 16-bit range, waiting for a full frame.
+  frame: Vec<f32>,
+  denoised: Vec<f32>,
+  resampled: Vec<f32>,
+  /// Denoised 44.1 kHz samples waiting to go out.
+  ready: VecDeque<f32>,
+}
+
+impl NoiseReducer {
+  pub fn new() -> Self {
+    Self {
+      state: DenoiseState::new(),
+      up: Linear::new(RATE, NR_RATE),
+      down: Linear::new(NR_RATE, RATE),
+      frame: Vec::with_capacity(2 * DenoiseState::FRAME_SIZE),
+      denoised: vec![0.0; DenoiseState::FRAME_SIZE],
+      resampled: Vec::with_capacity(DenoiseState::FRAME_SIZE),
+      ready: VecDeque::from(vec![0.0; NR_LATENCY]),
+    }
+  }
+
+  /// Denoises one buffer in place. The output runs [`NR_LATENCY`] samples
+  /// behind the input.
+  pub fn process(&mut self, buf: &mut TxBuffer) {
+    self.up.run(buf, &mut self.frame);
+
+    let size = DenoiseState::FRAME_SIZE;
+    let mut start = 0;
+    while self.frame.len() - start >= size {
+      // RNNoise expects samples in 16-bit range.
+      for s in self.frame[start..start + size].iter_mut() {
+        *s *= 32768.0;
+      }
+      self
+        .state
+        .process_frame(&mut self.denoised, &self.frame[start..start + size]);
+      for s in self.denoised.iter_mut() {
+        *s /= 32768.0;
+      }
+      start += size;
+
+      self.resampled.clear();
+      self.down.run(&self.denoised, &mut self.resampled);
+      self.ready.extend(&self.resampled);
+    }
+    self.frame.drain(..start);
+
+    for s in buf.iter_mut() {
+      // Only short if the latency estimate is ever off; pad with silence.
+      *s = self.ready.pop_front().unwrap_or(0.0);
+    }
+    // Likewise, don't let a surplus grow the delay.
+    while self.ready.len() > NR_LATENCY + TX_BUFFER_SIZE {
+      self.ready.pop_front();
+    }
+  }
+}
+
+impl Default for NoiseReducer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fx::white_noise;
+
+  fn rms(samples: &[f32]) -> f32 {
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+  }
+
+  #[test]
+  fn noise_alone_is_mostly_removed() {
+    let noise: Vec<f32> =
+      white_noise(1, 44_100 * 3).iter().map(|s| s * 0.1).collect();
+    let mut nr = NoiseReducer::new();
+    let mut out = Vec::with_capacity(noise.len());
+    for chunk in noise.chunks_exact(TX_BUFFER_SIZE) {
+      let mut buf: TxBuffer = chunk.try_into().unwrap();
+      nr.process(&mut buf);
+      out.extend(buf);
+    }
+
+    // Once it's had a couple of seconds to settle, at least 12 dB down.
+    let (before, after) = (rms(&noise[88_200..]), rms(&out[88_200..]));
+    assert!(after < before / 4.0, "{before} -> {after}");
+  }
+}
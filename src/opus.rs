@@ -0,0 +1,140 @@
+//! Opus compression for audio on the wire ([`Packet::OpusAudio`]), at a
+//! small fraction of the bandwidth of raw samples.
+//!
+//! Opus doesn't run at 44.1 kHz or take 256-sample frames, so buffers are
+//! linearly resampled up to 48 kHz and encoded 10 ms at a time, and decoded
+//! frames are resampled back down and cut into buffers again. Each side costs
+//! up to a frame of delay.
+
+use std::collections::VecDeque;
+
+use audiopus::{
+  Application, Bitrate, Channels, SampleRate,
+  coder::{Decoder, Encoder},
+  packet::Packet as OpusPacket,
+};
+
+use crate::{
+  MAX_OPUS_FRAME, Packet, TX_BUFFER_SIZE, TxBuffer, resample::Linear,
+  seq::SeqTracker,
+};
+
+const RATE: f64 = 44100.0;
+const OPUS_RATE: f64 = 48000.0;
+/// Samples per Opus frame at 48 kHz (10 ms).
+const FRAME_SIZE: usize = 480;
+/// The most a frame can decode to: Opus allows up to 120 ms per packet.
+const MAX_DECODED: usize = 12 * FRAME_SIZE;
+/// Plenty for voice; raw buffers run at about 1.4 Mbps.
+const BITRATE: i32 = 24_000;
+
+/// Encodes one sender's buffers into numbered [`Packet::OpusAudio`] frames.
+pub struct OpusEncoder {
+  encoder: Encoder,
+  up: Linear,
+  /// 48 kHz samples waiting for a full frame.
+  frame: Vec<f32>,
+  seq: u32,
+}
+
+impl OpusEncoder {
+  pub fn new() -> audiopus::Result<Self> {
+    let mut encoder =
+      Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip)?;
+    encoder.set_bitrate(Bitrate::BitsPerSecond(BITRATE))?;
+    Ok(Self {
+      encoder,
+      up: Linear::new(RATE, OPUS_RATE),
+      frame: Vec::with_capacity(2 * FRAME_SIZE),
+      seq: 0,
+    })
+  }
+
+  /// Adds a buffer, returning the frame it completes, if any. A buffer is
+  /// well under a frame, so it never completes more than one.
+  pub fn encode(&mut self, buf: &TxBuffer) -> audiopus::Result<Option<Packet>> {
+    self.up.run(buf, &mut self.frame);
+    if self.frame.len() < FRAME_SIZE {
+      return Ok(None);
+    }
+    let packet = self.encode_frame();
+    self.frame.drain(..FRAME_SIZE);
+    packet.map(Some)
+  }
+
+  /// Pads out and encodes whatever's left of a partial frame, so the end of
+  /// a transmission isn't held back until the next one.
+  pub fn flush(&mut self) -> audiopus::Result<Option<Packet>> {
+    if self.frame.is_empty() {
+      return Ok(None);
+    }
+    self.frame.resize(FRAME_SIZE, 0.0);
+    let packet = self.encode_frame();
+    self.frame.clear();
+    packet.map(Some)
+  }
+
+  fn encode_frame(&mut self) -> audiopus::Result<Packet> {
+    let mut out = [0u8; MAX_OPUS_FRAME];
+    let len = self
+      .encoder
+      .encode_float(&self.frame[..FRAME_SIZE], &mut out)?;
+    let seq = self.seq;
+    self.seq = self.seq.wrapping_add(1);
+    Ok(Packet::OpusAudio {
+      seq,
+      frame: out[..len].to_vec(),
+    })
+  }
+}
+
+/// Decodes one sender's [`Packet::OpusAudio`] frames back into buffers.
+pub struct OpusDecoder {
+  decoder: Decoder,
+  seqs: SeqTracker,
+  down: Linear,
+  decoded: Vec<f32>,
+  /// 44.1 kHz samples waiting for a full buffer.
+  ready: VecDeque<f32>,
+}
+
+impl OpusDecoder {
+  pub fn new() -> audiopus::Result<Self> {
+    Ok(Self {
+      decoder: Decoder::new(SampleRate::Hz48000, Channels::Mono)?,
+      seqs: SeqTracker::new(),
+      down: Linear::new(OPUS_RATE, RATE),
+      decoded: vec![0.0; MAX_DECODED],
+      ready: VecDeque::new(),
+    })
+  }
+
+  /// Decodes frame `seq`, queueing its audio for [`pop`](Self::pop).
+  /// Frames that arrive out of order are dropped, like late
+  /// [`Packet::SeqAudio`].
+  pub fn decode(&mut self, seq: u32, frame: &[u8]) -> audiopus::Result<()> {
+    if !self.seqs.accept(seq) {
+      return Ok(());
+    }
+    let frame = OpusPacket::try_from(frame)?;
+    let output = self.decoded.as_mut_slice().try_into()?;
+    let len = self.decoder.decode_float(Some(frame), output, false)?;
+
+    let mut resampled = Vec::with_capacity(len);
+    self.down.run(&self.decoded[..len], &mut resampled);
+    self.ready.extend(resampled);
+    Ok(())
+  }
+
+  /// The next whole buffer of decoded audio, if there is one.
+  pub fn pop(&mut self) -> Option<TxBuffer> {
+    if self.ready.len() < TX_BUFFER_SIZE {
+      return None;
+    }
+    let mut buf = [0f32; TX_BUFFER_SIZE];
+    for (s, r) in buf.iter_mut().zip(self.ready.drain(..TX_BUFFER_SIZE)) {
+      *s = r;
+    }
+    Some(buf)
+  }
+}
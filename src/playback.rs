@@ -0,0 +1,519 @@
+use std::{
+  collections::VecDeque,
+  fmt,
+  str::FromStr,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+    mpsc::Receiver,
+  },
+};
+
+use tracing::debug;
+
+use crate::{SAMPLE_RATE, TX_BUFFER_SIZE, TxBuffer, device, resample::Linear};
+
+/// Diagnostics: count how often the speaker callback runs short of data.
+#[derive(Debug, Default)]
+pub struct PlaybackStats {
+  /// Callback had an empty queue (whole block silenced).
+  pub underruns: AtomicU64,
+  /// Callback had some, but not enough, samples.
+  pub partial_fills: AtomicU64,
+  /// Total samples we had to zero-fill across all blocks.
+  pub missing_samples: AtomicU64,
+  pub callbacks: AtomicU64,
+  pub queue_len: AtomicU64,
+}
+
+impl PlaybackStats {
+  /// How much audio is currently queued ahead of the speaker.
+  pub fn queued(&self) -> std::time::Duration {
+    std::time::Duration::from_secs_f64(
+      self.queue_len.load(Ordering::Relaxed) as f64 / SAMPLE_RATE as f64,
+    )
+  }
+}
+
+/// Smoothing factor of the long-term fill average (~1 s at typical block
+/// sizes).
+const DRIFT_ALPHA: f32 = 0.01;
+/// Callbacks to wait after (re)starting before the average is trusted as the
+/// fill level to hold.
+const DRIFT_SETTLE: u32 = 200;
+/// How far the average may wander from that level before we correct.
+const DRIFT_DEADBAND: f32 = (TX_BUFFER_SIZE / 2) as f32;
+
+/// What plays in the part of a block the speaker has no audio for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnderrunFill {
+  /// Zeros, which can click if the audio stopped away from zero.
+  #[default]
+  Silence,
+  /// Repeats the last sample played.
+  Hold,
+  /// Ramps the last sample played down to zero over the rest of the block.
+  Fade,
+}
+
+impl UnderrunFill {
+  /// Fills `out`, which follows `last`, the last sample played.
+  pub fn fill(self, last: f32, out: &mut [f32]) {
+    match self {
+      Self::Silence => out.fill(0.0),
+      Self::Hold => out.fill(last),
+      Self::Fade => {
+        let len = out.len() as f32;
+        for (i, o) in out.iter_mut().enumerate() {
+          *o = last * (1.0 - (i + 1) as f32 / len);
+        }
+      }
+    }
+  }
+}
+
+impl FromStr for UnderrunFill {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "silence" => Ok(Self::Silence),
+      "hold" => Ok(Self::Hold),
+      "fade" => Ok(Self::Fade),
+      _ => Err(format!(
+        "unknown underrun fill {s:?} (expected silence, hold or fade)"
+      )),
+    }
+  }
+}
+
+impl fmt::Display for UnderrunFill {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Silence => write!(f, "silence"),
+      Self::Hold => write!(f, "hold"),
+      Self::Fade => write!(f, "fade"),
+    }
+  }
+}
+
+/// Speaker-side ring buffer fed with chunks from the network thread.
+///
+/// The sender's clock and the speaker's never run at exactly the same rate,
+/// so over a long session the backlog slowly grows or drains. To compensate,
+/// the long-term average fill level is tracked. Once it drifts more than
+/// `DRIFT_DEADBAND` from where it settled, each block plays one sample more
+/// or less than it consumes, linearly resampled across the block, until it's
+/// back in range. That's at most ~0.2% speed change, well under what's
+/// audible.
+pub struct Speaker {
+  rx: Receiver<TxBuffer>,
+  buf: VecDeque<f32>,
+  target_samples: usize,
+  max_samples: usize,
+  filling: bool,
+  stats: Arc<PlaybackStats>,
+  avg_fill: f32,
+  settled: u32,
+  center: Option<f32>,
+  underrun_fill: UnderrunFill,
+  /// The last sample played, for `underrun_fill`.
+  last: f32,
+}
+
+impl Speaker {
+  pub fn new(
+    rx: Receiver<TxBuffer>,
+    jitter_ms: u64,
+    stats: Arc<PlaybackStats>,
+  ) -> Self {
+    // Jitter buffer: pre-buffer ~jitter_ms of audio before (re)starting
+    // playback so the consumer block (which is much larger than a single
+    // network chunk) never skates on an empty queue.
+    let target_samples = (jitter_ms as usize * SAMPLE_RATE as usize) / 1000;
+    // Bound added latency if the sender clock runs slightly fast (drift).
+    let max_samples = target_samples * 4;
+
+    Self {
+      rx,
+      buf: VecDeque::with_capacity(target_samples.max(TX_BUFFER_SIZE)),
+      target_samples,
+      max_samples,
+      // Start in the "refilling" state so we wait for a healthy backlog.
+      filling: true,
+      stats,
+      avg_fill: 0.0,
+      settled: 0,
+      center: None,
+      underrun_fill: UnderrunFill::default(),
+      last: 0.0,
+    }
+  }
+
+  /// Like [`new`](Self::new), but holds the backlog close to `target_ms`:
+  /// anything more than a couple of buffers over it (e.g. from clock drift
+  /// between sender and speaker) is dropped straight away, rather than only
+  /// once it reaches four times the target.
+  pub fn with_target_latency(
+    rx: Receiver<TxBuffer>,
+    target_ms: u64,
+    stats: Arc<PlaybackStats>,
+  ) -> Self {
+    let mut speaker = Self::new(rx, target_ms, stats);
+    speaker.max_samples = speaker.target_samples + 2 * TX_BUFFER_SIZE;
+    speaker
+  }
+
+  /// Sets what plays when the backlog runs out partway through a block.
+  pub fn set_underrun_fill(&mut self, fill: UnderrunFill) {
+    self.underrun_fill = fill;
+  }
+
+  /// Fills one device block with buffered audio.
+  pub fn fill(&mut self, data: &mut [f32]) {
+    self.rx.try_iter().for_each(|samples| {
+      self.buf.extend(samples);
+    });
+
+    self.stats.callbacks.fetch_add(1, Ordering::Relaxed);
+
+    // Drop oldest samples if drift made the backlog grow unbounded.
+    if self.buf.len() > self.max_samples {
+      let drop = self.buf.len() - self.target_samples;
+      self.buf.drain(0..drop);
+    }
+
+    // While (re)filling, emit silence until the backlog is healthy.
+    // This is what stops the per-block zero-fills (faint pops): we
+    // wait for a cushion instead of dribbling out partial blocks.
+    if self.filling {
+      if self.buf.len() >= self.target_samples {
+        self.filling = false;
+      } else {
+        for item in data.iter_mut() {
+          *item = 0.0;
+        }
+        self
+          .stats
+          .queue_len
+          .store(self.buf.len() as u64, Ordering::Relaxed);
+        return;
+      }
+    }
+
+    let want = (data.len() as isize + self.drift_correction()).max(1) as usize;
+    if want != data.len() && self.buf.len() >= want {
+      stretch(&self.buf, want, data);
+      self.last = data.last().copied().unwrap_or(0.0);
+      self.buf.drain(..want);
+      self.track_fill();
+      self
+        .stats
+        .queue_len
+        .store(self.buf.len() as u64, Ordering::Relaxed);
+      return;
+    }
+
+    let take = data.len().min(self.buf.len());
+
+    self
+      .buf
+      .iter()
+      .enumerate()
+      .take(take)
+      .for_each(|(i, s)| data[i] = *s);
+    self.buf.drain(0..take);
+
+    if take > 0 {
+      self.last = data[take - 1];
+    }
+
+    // Couldn't fully satisfy the block: fill the tail, record it, and
+    // drop back into refilling so we rebuild a cushion before resuming
+    // rather than emitting a string of partially-filled blocks.
+    if take < data.len() {
+      if take == 0 {
+        self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+      } else {
+        self.stats.partial_fills.fetch_add(1, Ordering::Relaxed);
+      }
+      self
+        .stats
+        .missing_samples
+        .fetch_add((data.len() - take) as u64, Ordering::Relaxed);
+      self.underrun_fill.fill(self.last, &mut data[take..]);
+      // Refilling plays silence from the next block on.
+      self.last = 0.0;
+      self.filling = true;
+      // The fill level starts over after a refill.
+      self.settled = 0;
+      self.center = None;
+    } else {
+      self.track_fill();
+    }
+
+    self
+      .stats
+      .queue_len
+      .store(self.buf.len() as u64, Ordering::Relaxed);
+  }
+}
+
+impl Speaker {
+  fn track_fill(&mut self) {
+    let fill = self.buf.len() as f32;
+    if self.settled == 0 {
+      self.avg_fill = fill;
+    } else {
+      self.avg_fill += (fill - self.avg_fill) * DRIFT_ALPHA;
+    }
+
+    self.settled = self.settled.saturating_add(1);
+    if self.settled == DRIFT_SETTLE {
+      self.center = Some(self.avg_fill);
+    }
+  }
+
+  /// How many extra samples to consume this block: 1 when the backlog has
+  /// drifted high, -1 when low, otherwise 0.
+  fn drift_correction(&self) -> isize {
+    match self.center {
+      Some(center) if self.avg_fill > center + DRIFT_DEADBAND => 1,
+      Some(center) if self.avg_fill < center - DRIFT_DEADBAND => -1,
+      _ => 0,
+    }
+  }
+}
+
+/// Linearly resamples the first `len` samples of `input` to exactly fill
+/// `out`.
+fn stretch(input: &VecDeque<f32>, len: usize, out: &mut [f32]) {
+  if out.len() < 2 || len < 2 {
+    out.fill(input.front().copied().unwrap_or(0.0));
+    return;
+  }
+
+  let step = (len - 1) as f32 / (out.len() - 1) as f32;
+  for (i, o) in out.iter_mut().enumerate() {
+    let pos = i as f32 * step;
+    let idx = (pos as usize).min(len - 2);
+    let frac = pos - idx as f32;
+    *o = input[idx] * (1.0 - frac) + input[idx + 1] * frac;
+  }
+}
+
+/// Plays the 44.1 kHz [`Speaker`] on a device running at another rate, by
+/// pulling as much as each device block needs and resampling it.
+struct DeviceRate {
+  linear: Linear,
+  /// Device samples per pipeline sample.
+  ratio: f64,
+  chunk: Vec<f32>,
+  resampled: Vec<f32>,
+  /// Resampled audio left over from the last block.
+  ready: VecDeque<f32>,
+}
+
+impl DeviceRate {
+  fn new(rate: u32) -> Self {
+    Self {
+      linear: Linear::new(SAMPLE_RATE as f64, rate as f64),
+      ratio: rate as f64 / SAMPLE_RATE as f64,
+      chunk: Vec::new(),
+      resampled: Vec::new(),
+      ready: VecDeque::new(),
+    }
+  }
+
+  fn fill(&mut self, speaker: &mut Speaker, data: &mut [f32]) {
+    while self.ready.len() < data.len() {
+      let want = data.len() - self.ready.len();
+      self
+        .chunk
+        .resize((want as f64 / self.ratio).ceil() as usize + 1, 0.0);
+      speaker.fill(&mut self.chunk);
+      self.resampled.clear();
+      self.linear.run(&self.chunk, &mut self.resampled);
+      self.ready.extend(&self.resampled);
+    }
+    let len = data.len();
+    for (out, s) in data.iter_mut().zip(self.ready.drain(..len)) {
+      *out = s;
+    }
+  }
+}
+
+/// Left and right gains for a `balance` from -1.0 (left only) to 1.0 (right
+/// only). The centre leaves both channels at full level.
+pub fn balance_gains(balance: f32) -> (f32, f32) {
+  let balance = balance.clamp(-1.0, 1.0);
+  ((1.0 - balance).min(1.0), (1.0 + balance).min(1.0))
+}
+
+/// Interleaves `mono` into `out`, which holds `channels` channels per frame.
+/// A mono device gets the signal as-is; otherwise it goes to the first two
+/// channels, weighted by `balance`, and any further channels are silenced.
+pub fn upmix(mono: &[f32], out: &mut [f32], channels: usize, balance: f32) {
+  if channels <= 1 {
+    out.copy_from_slice(mono);
+    return;
+  }
+
+  let (left, right) = balance_gains(balance);
+  for (frame, s) in out.chunks_exact_mut(channels).zip(mono) {
+    frame[0] = s * left;
+    frame[1] = s * right;
+    frame[2..].fill(0.0);
+  }
+}
+
+/// Builds a playing-ready output stream driven by `speaker`.
+///
+/// Not every output device takes F32 (many Windows devices default to I16),
+/// so the stream is built in the device's native format (see
+/// [`crate::device::build_output_stream`]) and converted from our f32
+/// pipeline in the callback.
+///
+/// `config` may have any number of channels; the mono signal is spread
+/// across them by [`upmix`]. It may also run at any rate, e.g. the device's
+/// own from [`crate::device::output_rate`]; anything but [`SAMPLE_RATE`] is
+/// resampled to in the callback.
+pub fn build_speaker_stream(
+  device: &cpal::Device,
+  config: &cpal::StreamConfig,
+  mut speaker: Speaker,
+  balance: f32,
+  err_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+  let rate = config.sample_rate.0;
+  let mut device_rate = (rate != SAMPLE_RATE).then(|| DeviceRate::new(rate));
+  if device_rate.is_some() {
+    debug!("Resampling playback from {SAMPLE_RATE} Hz to {rate} Hz");
+  }
+  let mut play = move |data: &mut [f32]| match device_rate.as_mut() {
+    Some(device_rate) => device_rate.fill(&mut speaker, data),
+    None => speaker.fill(data),
+  };
+
+  let channels = config.channels as usize;
+  let mut mono: Vec<f32> = Vec::new();
+  let fill = move |data: &mut [f32]| {
+    if channels <= 1 {
+      play(data);
+      return;
+    }
+
+    mono.resize(data.len() / channels, 0.0);
+    play(&mut mono);
+    upmix(&mono, data, channels, balance);
+  };
+  device::build_output_stream(device, config, fill, err_fn)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn balance_pans_between_channels() {
+    assert_eq!(balance_gains(0.0), (1.0, 1.0));
+    assert_eq!(balance_gains(-1.0), (1.0, 0.0));
+    assert_eq!(balance_gains(0.5), (0.5, 1.0));
+    assert_eq!(balance_gains(2.0), (0.0, 1.0));
+  }
+
+  #[test]
+  fn upmix_interleaves_into_the_first_two_channels() {
+    let mono = [0.5, -1.0];
+
+    let mut out = [9.0; 2];
+    upmix(&mono, &mut out, 1, -1.0);
+    assert_eq!(out, mono);
+
+    let mut out = [9.0; 4];
+    upmix(&mono, &mut out, 2, 0.5);
+    assert_eq!(out, [0.25, 0.5, -0.5, -1.0]);
+
+    let mut out = [9.0; 8];
+    upmix(&mono, &mut out, 4, 0.0);
+    assert_eq!(out, [0.5, 0.5, 0.0, 0.0, -1.0, -1.0, 0.0, 0.0]);
+  }
+
+  #[test]
+  fn each_underrun_fill_covers_the_gap_then_goes_quiet() {
+    for fill in [
+      UnderrunFill::Silence,
+      UnderrunFill::Hold,
+      UnderrunFill::Fade,
+    ] {
+      let (tx, rx) = std::sync::mpsc::channel();
+      let stats = Arc::new(PlaybackStats::default());
+      // A jitter buffer under one buffer long, so playback starts with the
+      // first.
+      let mut speaker = Speaker::new(rx, 5, stats.clone());
+      speaker.set_underrun_fill(fill);
+
+      tx.send([0.5; TX_BUFFER_SIZE]).unwrap();
+      let mut block = [9.0; 2 * TX_BUFFER_SIZE];
+      speaker.fill(&mut block);
+      let (played, gap) = block.split_at(TX_BUFFER_SIZE);
+      assert!(played.iter().all(|s| *s == 0.5), "{fill}");
+      match fill {
+        UnderrunFill::Silence => assert!(gap.iter().all(|s| *s == 0.0)),
+        UnderrunFill::Hold => assert!(gap.iter().all(|s| *s == 0.5)),
+        UnderrunFill::Fade => {
+          assert!(gap[0] < 0.5 && gap[0] > 0.49);
+          assert!(gap.windows(2).all(|w| w[1] < w[0]));
+          assert_eq!(gap[gap.len() - 1], 0.0);
+        }
+      }
+      assert_eq!(stats.partial_fills.load(Ordering::Relaxed), 1);
+
+      // Then it's refilling, which is silence whatever the fill.
+      let mut block = [9.0; 2 * TX_BUFFER_SIZE];
+      speaker.fill(&mut block);
+      assert!(block.iter().all(|s| *s == 0.0), "{fill}");
+      let missing = stats.missing_samples.load(Ordering::Relaxed);
+      assert_eq!(missing, TX_BUFFER_SIZE as u64);
+    }
+  }
+
+  /// Plays 20,000 blocks of 512 samples (about 4 minutes) from a sender
+  /// whose clock runs `ratio` times as fast as the speaker's, returning the
+  /// backlog after each block.
+  fn simulate_drift(ratio: f64) -> (Vec<u64>, Arc<PlaybackStats>) {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let stats = Arc::new(PlaybackStats::default());
+    let mut speaker = Speaker::new(rx, 50, stats.clone());
+    let mut block = [0.0; 512];
+    let mut owed = 0.0;
+    let backlog = (0..20_000)
+      .map(|_| {
+        owed += block.len() as f64 * ratio;
+        while owed >= TX_BUFFER_SIZE as f64 {
+          tx.send([0.1; TX_BUFFER_SIZE]).unwrap();
+          owed -= TX_BUFFER_SIZE as f64;
+        }
+        speaker.fill(&mut block);
+        stats.queue_len.load(Ordering::Relaxed)
+      })
+      .collect();
+    (backlog, stats)
+  }
+
+  #[test]
+  fn drift_compensation_holds_the_backlog_steady() {
+    // 0.1% apart either way: uncorrected, the backlog would move by 5,000
+    // samples over the second half alone.
+    for ratio in [1.001, 0.999] {
+      let (backlog, stats) = simulate_drift(ratio);
+      assert_eq!(stats.underruns.load(Ordering::Relaxed), 0, "{ratio}");
+      assert_eq!(stats.partial_fills.load(Ordering::Relaxed), 0, "{ratio}");
+
+      let settled = &backlog[backlog.len() / 2..];
+      let (min, max) = (settled.iter().min(), settled.iter().max());
+      let spread = max.unwrap() - min.unwrap();
+      assert!(spread < 4 * TX_BUFFER_SIZE as u64, "{ratio}: {spread}");
+    }
+  }
+}
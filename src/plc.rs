@@ -0,0 +1,103 @@
+//! Packet loss concealment on receive.
+//!
+//! When a buffer goes missing partway through a transmission, hard silence
+//! clicks. Instead the last buffer that arrived is played again, fading out
+//! over a few buffers. It's played backwards every other time, so each
+//! repeat starts on the sample the one before ended on, without a step.
+
+use crate::{TX_BUFFER_SIZE, TxBuffer};
+
+/// How many buffers in a row are concealed before giving up (~23 ms).
+pub const PLC_BUFFERS: usize = 4;
+
+#[derive(Debug, Clone, Default)]
+pub struct Concealer {
+  last: Option<TxBuffer>,
+  missed: usize,
+}
+
+impl Concealer {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Notes a buffer that did arrive, which the next gap repeats.
+  pub fn played(&mut self, buf: &TxBuffer) {
+    self.last = Some(*buf);
+    self.missed = 0;
+  }
+
+  /// Forgets the last buffer, e.g. at the end of a transmission.
+  pub fn reset(&mut self) {
+    self.last = None;
+    self.missed = 0;
+  }
+
+  /// A stand-in for a missing buffer, or `None` once [`PLC_BUFFERS`] have
+  /// been concealed in a row (or nothing has arrived to repeat).
+  pub fn conceal(&mut self) -> Option<TxBuffer> {
+    let last = self.last.as_ref()?;
+    if self.missed >= PLC_BUFFERS {
+      return None;
+    }
+
+    let mut buf = *last;
+    if self.missed.is_multiple_of(2) {
+      buf.reverse();
+    }
+    // Pick up the fade where the previous stand-in left off.
+    let total = (PLC_BUFFERS * TX_BUFFER_SIZE) as f32;
+    let start = self.missed * TX_BUFFER_SIZE;
+    for (i, s) in buf.iter_mut().enumerate() {
+      *s *= 1.0 - (start + i + 1) as f32 / total;
+    }
+    self.missed += 1;
+    Some(buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn peak(buf: &TxBuffer) -> f32 {
+    buf.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+  }
+
+  #[test]
+  fn concealment_fades_out() {
+    let mut plc = Concealer::new();
+    assert_eq!(plc.conceal(), None);
+
+    plc.played(&[0.5; TX_BUFFER_SIZE]);
+    let stand_ins: Vec<TxBuffer> =
+      std::iter::from_fn(|| plc.conceal()).collect();
+    assert_eq!(stand_ins.len(), PLC_BUFFERS);
+    for pair in stand_ins.windows(2) {
+      assert!(peak(&pair[1]) < peak(&pair[0]));
+      // Each picks up where the last left off.
+      assert!((pair[1][0] - pair[0][TX_BUFFER_SIZE - 1]).abs() < 0.01);
+    }
+    assert_eq!(stand_ins.last().unwrap()[TX_BUFFER_SIZE - 1], 0.0);
+  }
+
+  #[test]
+  fn real_audio_resumes_cleanly() {
+    let mut plc = Concealer::new();
+    plc.played(&[0.5; TX_BUFFER_SIZE]);
+    plc.conceal();
+    plc.conceal();
+
+    // A buffer arriving starts the next gap's fade over from full level.
+    plc.played(&[0.25; TX_BUFFER_SIZE]);
+    let next = plc.conceal().unwrap();
+    assert!((next[0] - 0.25).abs() < 0.001);
+    assert_eq!(
+      std::iter::from_fn(|| plc.conceal()).count(),
+      PLC_BUFFERS - 1
+    );
+
+    plc.reset();
+    assert_eq!(plc.conceal(), None);
+  }
+}
@@ -0,0 +1,124 @@
+//! A directory of saved FX presets, one TOML file per preset, as written by
+//! the client's "Save preset" button and read back by `--preset-file`.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use crate::fx::FxConfig;
+
+/// Where the client keeps presets unless told otherwise.
+pub const DEFAULT_PRESET_DIR: &str = "presets";
+
+/// Longest preset name, so it still makes a sensible file name.
+const MAX_NAME_LEN: usize = 64;
+
+/// Remembers the last preset picked, next to the presets themselves.
+const LAST_FILE: &str = ".last";
+
+pub struct PresetDir {
+  path: PathBuf,
+}
+
+impl PresetDir {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self { path: path.into() }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// The names of the saved presets, sorted. A missing directory just has
+  /// none.
+  pub fn list(&self) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(&self.path) else {
+      return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+      .flatten()
+      .map(|entry| entry.path())
+      .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+      .filter_map(|path| Some(path.file_stem()?.to_str()?.to_owned()))
+      .filter(|name| check_name(name).is_ok())
+      .collect();
+    names.sort();
+    names
+  }
+
+  pub fn exists(&self, name: &str) -> bool {
+    self.file(name).is_file()
+  }
+
+  pub fn load(&self, name: &str) -> Result<FxConfig, String> {
+    check_name(name)?;
+    let path = self.file(name);
+    let config: FxConfig = fs::read_to_string(&path)
+      .map_err(|e| e.to_string())
+      .and_then(|text| toml::from_str(&text).map_err(|e| e.to_string()))
+      .map_err(|e| format!("{}: {e}", path.display()))?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Saves `config` as `name`, creating the directory if need be. Fails if
+  /// a preset of that name exists, unless `overwrite` is set.
+  pub fn save(
+    &self,
+    name: &str,
+    config: &FxConfig,
+    overwrite: bool,
+  ) -> Result<(), String> {
+    check_name(name)?;
+    if !overwrite && self.exists(name) {
+      return Err(format!("a preset named {name:?} already exists"));
+    }
+    config.validate()?;
+
+    let text = toml::to_string(config).map_err(|e| e.to_string())?;
+    fs::create_dir_all(&self.path)
+      .and_then(|()| fs::write(self.file(name), text))
+      .map_err(|e| format!("{}: {e}", self.path.display()))
+  }
+
+  /// The last preset picked, if it's still there.
+  pub fn last(&self) -> Option<String> {
+    let name = fs::read_to_string(self.path.join(LAST_FILE)).ok()?;
+    let name = name.trim();
+    (check_name(name).is_ok() && self.exists(name)).then(|| name.to_owned())
+  }
+
+  pub fn set_last(&self, name: &str) -> Result<(), String> {
+    fs::create_dir_all(&self.path)
+      .and_then(|()| fs::write(self.path.join(LAST_FILE), name))
+      .map_err(|e| format!("{}: {e}", self.path.display()))
+  }
+
+  fn file(&self, name: &str) -> PathBuf {
+    self.path.join(format!("{name}.toml"))
+  }
+}
+
+/// Preset names become file names, so only letters, digits, spaces, `-` and
+/// `_` are allowed, and they can't start with a dot or space.
+pub fn check_name(name: &str) -> Result<(), String> {
+  if name.is_empty() {
+    return Err("the preset needs a name".to_owned());
+  }
+  if name.len() > MAX_NAME_LEN {
+    return Err(format!(
+      "preset names can be at most {MAX_NAME_LEN} characters"
+    ));
+  }
+  if name.starts_with(' ')
+    || !name
+      .chars()
+      .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+  {
+    return Err(format!(
+      "invalid preset name {name:?}: use letters, digits, spaces, - and _"
+    ));
+  }
+  Ok(())
+}
@@ -0,0 +1,249 @@
+use std::time::{Duration, Instant};
+
+/// Debounces a PTT input so chattering presses don't toggle transmission.
+///
+/// A change that arrives within `window` of the last accepted change is held
+/// as pending and only applied once the window has passed (via [`poll`]), so
+/// the final state always wins but rapid toggles in between are dropped.
+///
+/// [`poll`]: Debouncer::poll
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+  window: Duration,
+  state: bool,
+  pending: Option<bool>,
+  last_change: Option<Instant>,
+}
+
+impl Debouncer {
+  pub fn new(window: Duration) -> Self {
+    Self {
+      window,
+      state: false,
+      pending: None,
+      last_change: None,
+    }
+  }
+
+  pub fn state(&self) -> bool {
+    self.state
+  }
+
+  pub fn is_pending(&self) -> bool {
+    self.pending.is_some()
+  }
+
+  /// Feeds a raw input state, returning the debounced state.
+  pub fn set(&mut self, raw: bool, now: Instant) -> bool {
+    if raw == self.state {
+      self.pending = None;
+    } else if self.settled(now) {
+      self.apply(raw, now);
+    } else {
+      self.pending = Some(raw);
+    }
+
+    self.state
+  }
+
+  /// Applies a pending change once the window has passed, returning the
+  /// debounced state.
+  pub fn poll(&mut self, now: Instant) -> bool {
+    if let Some(raw) = self.pending
+      && self.settled(now)
+    {
+      self.apply(raw, now);
+    }
+
+    self.state
+  }
+
+  fn settled(&self, now: Instant) -> bool {
+    self
+      .last_change
+      .is_none_or(|last| now.saturating_duration_since(last) >= self.window)
+  }
+
+  fn apply(&mut self, raw: bool, now: Instant) {
+    self.state = raw;
+    self.pending = None;
+    self.last_change = Some(now);
+  }
+}
+
+/// A radio-style time-out timer (TOT): limits how long a single transmission
+/// may last so a stuck PTT can't hold the channel forever.
+#[derive(Debug, Clone)]
+pub struct TimeoutTimer {
+  limit: Duration,
+  started: Option<Instant>,
+}
+
+impl TimeoutTimer {
+  pub fn new(limit: Duration) -> Self {
+    Self {
+      limit,
+      started: None,
+    }
+  }
+
+  /// Feeds the current PTT state, returning true once the transmission has
+  /// run for the full limit. The timer restarts on the next press.
+  pub fn update(&mut self, transmitting: bool, now: Instant) -> bool {
+    if !transmitting {
+      self.started = None;
+      return false;
+    }
+
+    let started = *self.started.get_or_insert(now);
+    now.saturating_duration_since(started) >= self.limit
+  }
+
+  /// Transmit time left, or `None` when not transmitting.
+  pub fn remaining(&self, now: Instant) -> Option<Duration> {
+    self
+      .started
+      .map(|started| self.limit.saturating_sub(now - started))
+  }
+}
+
+/// A voice-operated switch (VOX): keys up once the mic's level has stayed at
+/// or above a threshold for `attack`, and drops once it has stayed below it
+/// for `hang`, so short pauses between words don't end the transmission.
+#[derive(Debug, Clone)]
+pub struct Vox {
+  threshold: f32,
+  attack: Duration,
+  hang: Duration,
+  state: bool,
+  /// When the level last crossed to the other side of the threshold.
+  crossed: Option<Instant>,
+}
+
+impl Vox {
+  pub fn new(threshold: f32, attack: Duration, hang: Duration) -> Self {
+    Self {
+      threshold,
+      attack,
+      hang,
+      state: false,
+      crossed: None,
+    }
+  }
+
+  pub fn state(&self) -> bool {
+    self.state
+  }
+
+  /// Feeds a block of mic audio, returning whether to transmit.
+  pub fn update(&mut self, samples: &[f32], now: Instant) -> bool {
+    if samples.is_empty() {
+      return self.state;
+    }
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>()
+      / samples.len() as f32)
+      .sqrt();
+    let loud = rms >= self.threshold;
+    if loud == self.state {
+      self.crossed = None;
+      return self.state;
+    }
+
+    let crossed = *self.crossed.get_or_insert(now);
+    let wait = if loud { self.attack } else { self.hang };
+    if now.saturating_duration_since(crossed) >= wait {
+      self.state = loud;
+      self.crossed = None;
+    }
+    self.state
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn debouncer_turns_bouncing_edges_into_one_transition() {
+    let window = Duration::from_millis(20);
+    let mut ptt = Debouncer::new(window);
+    let start = Instant::now();
+    let ms = |ms| start + Duration::from_millis(ms);
+
+    // The first press goes straight through; the bounces after it don't.
+    let mut transitions = 0;
+    let mut last = false;
+    for (i, raw) in [true, false, true, false, true].into_iter().enumerate() {
+      let state = ptt.set(raw, ms(i as u64));
+      transitions += usize::from(state != last);
+      last = state;
+    }
+    assert_eq!(transitions, 1);
+    assert!(ptt.state());
+    assert!(!ptt.is_pending());
+
+    // Bouncing on release drops the key once; the edges after are held
+    // back, and only the state they end on is applied, once the window has
+    // passed since the release.
+    let released = 30;
+    for (i, raw) in [false, true, false, true].into_iter().enumerate() {
+      assert!(!ptt.set(raw, ms(released + i as u64)));
+    }
+    assert!(ptt.is_pending());
+    assert!(!ptt.poll(ms(released + 10)));
+    assert!(ptt.poll(ms(released + 20)));
+    assert!(!ptt.is_pending());
+  }
+
+  #[test]
+  fn vox_keys_after_attack_and_holds_through_short_pauses() {
+    let mut vox =
+      Vox::new(0.1, Duration::from_millis(50), Duration::from_millis(300));
+    let start = Instant::now();
+    let ms = |ms| start + Duration::from_millis(ms);
+    let (loud, quiet) = ([0.5; 64], [0.0; 64]);
+
+    // An empty block changes nothing, keyed or not.
+    assert!(!vox.update(&[], ms(0)));
+    assert!(!vox.update(&loud, ms(0)));
+    assert!(!vox.update(&loud, ms(49)));
+    assert!(vox.update(&loud, ms(50)));
+    assert!(vox.update(&[], ms(60)));
+
+    // A pause shorter than the hang keeps it keyed, and talking again
+    // starts the hang over.
+    assert!(vox.update(&quiet, ms(100)));
+    assert!(vox.update(&quiet, ms(399)));
+    assert!(vox.update(&loud, ms(400)));
+    assert!(vox.update(&quiet, ms(450)));
+    assert!(vox.update(&[], ms(600)));
+    assert!(vox.update(&quiet, ms(749)));
+    assert!(!vox.update(&quiet, ms(750)));
+    assert!(!vox.state());
+  }
+
+  #[test]
+  fn timeout_timer_trips_at_the_limit_and_restarts_per_press() {
+    let limit = Duration::from_secs(60);
+    let mut tot = TimeoutTimer::new(limit);
+    let start = Instant::now();
+    let secs = |secs| start + Duration::from_secs(secs);
+
+    assert!(!tot.update(false, secs(0)));
+    assert_eq!(tot.remaining(secs(0)), None);
+
+    assert!(!tot.update(true, secs(10)));
+    assert_eq!(tot.remaining(secs(30)), Some(Duration::from_secs(40)));
+    assert!(!tot.update(true, secs(69)));
+    assert!(tot.update(true, secs(70)));
+    assert_eq!(tot.remaining(secs(70)), Some(Duration::ZERO));
+
+    // Releasing clears it, and the next press gets the full limit again.
+    assert!(!tot.update(false, secs(71)));
+    assert_eq!(tot.remaining(secs(71)), None);
+    assert!(!tot.update(true, secs(100)));
+    assert_eq!(tot.remaining(secs(100)), Some(limit));
+    assert!(!tot.update(true, secs(159)));
+    assert!(tot.update(true, secs(160)));
+  }
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket that refills at `rate` tokens per second, holding at most
+/// `capacity` tokens.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+  rate: f64,
+  capacity: f64,
+  tokens: f64,
+  last: Instant,
+}
+
+impl TokenBucket {
+  pub fn new(rate: f64, capacity: f64) -> Self {
+    Self {
+      rate,
+      capacity,
+      tokens: capacity,
+      last: Instant::now(),
+    }
+  }
+
+  /// Takes a token if one is available, returning whether it succeeded.
+  pub fn try_take(&mut self) -> bool {
+    self.try_take_at(Instant::now())
+  }
+
+  pub fn try_take_at(&mut self, now: Instant) -> bool {
+    self.refill(now);
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.last = now;
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+  }
+}
+
+/// Spaces events `interval` apart on a steady clock, so packets leave at the
+/// rate the audio is produced rather than in bursts.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+  interval: Duration,
+  next: Option<Instant>,
+}
+
+impl Pacer {
+  pub fn new(interval: Duration) -> Self {
+    Self {
+      interval,
+      next: None,
+    }
+  }
+
+  /// Whether the next event may go out now. Returns `true` at most once per
+  /// interval.
+  pub fn ready(&mut self) -> bool {
+    self.ready_at(Instant::now())
+  }
+
+  pub fn ready_at(&mut self, now: Instant) -> bool {
+    match self.next {
+      Some(next) if now < next => false,
+      // Keep to the schedule, unless we've fallen more than an interval
+      // behind (e.g. after sitting idle), in which case restart from now
+      // rather than bursting to catch up.
+      Some(next) if now - next <= self.interval => {
+        self.next = Some(next + self.interval);
+        true
+      }
+      _ => {
+        self.next = Some(now + self.interval);
+        true
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pacer_spaces_events_an_interval_apart() {
+    let start = Instant::now();
+    let ms = |ms| start + Duration::from_millis(ms);
+    let mut pacer = Pacer::new(Duration::from_millis(10));
+
+    // Polled every millisecond, it fires on the interval and no more often.
+    let fired: Vec<u64> = (0..100).filter(|t| pacer.ready_at(ms(*t))).collect();
+    assert_eq!(fired, [0, 10, 20, 30, 40, 50, 60, 70, 80, 90]);
+
+    // A late poll keeps to the schedule, so the next one isn't pushed back.
+    assert!(pacer.ready_at(ms(103)));
+    assert!(!pacer.ready_at(ms(109)));
+    assert!(pacer.ready_at(ms(110)));
+
+    // After sitting idle, it restarts from now instead of bursting.
+    assert!(pacer.ready_at(ms(200)));
+    assert!(!pacer.ready_at(ms(205)));
+    assert!(pacer.ready_at(ms(210)));
+  }
+}
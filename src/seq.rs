@@ -0,0 +1,135 @@
+//! Ordering and timing for [`Packet::SeqAudio`](crate::Packet::SeqAudio)
+//! and [`Packet::StampedAudio`](crate::Packet::StampedAudio).
+//!
+//! UDP can deliver datagrams out of order. By the time a late buffer shows
+//! up, the ones after it have already played, so it's dropped rather than
+//! played out of place.
+
+use std::time::Instant;
+
+/// How far back a sequence number may jump before it's taken as the sender
+/// starting over (e.g. a restarted server) rather than a late packet.
+pub(crate) const MAX_REORDER: u32 = 1000;
+
+/// Tracks the newest sequence number seen from one sender.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeqTracker {
+  last: Option<u32>,
+}
+
+impl SeqTracker {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Whether a packet numbered `seq` should play: it's newer than anything
+  /// before it, allowing for wraparound. Duplicates and late packets aren't.
+  pub fn accept(&mut self, seq: u32) -> bool {
+    let newer = match self.last {
+      None => true,
+      Some(last) => {
+        let behind = last.wrapping_sub(seq);
+        seq != last && (behind > u32::MAX / 2 || behind > MAX_REORDER)
+      }
+    };
+    if newer {
+      self.last = Some(seq);
+    }
+    newer
+  }
+}
+
+/// Numbers and timestamps one sender's outgoing buffers.
+#[derive(Debug, Clone, Copy)]
+pub struct Stamper {
+  next: u32,
+  epoch: Instant,
+}
+
+impl Stamper {
+  pub fn new() -> Self {
+    Self {
+      next: 0,
+      epoch: Instant::now(),
+    }
+  }
+
+  /// The next sequence number, and `captured` in microseconds since the
+  /// stamper was made. Only the differences between stamps mean anything.
+  pub fn stamp(&mut self, captured: Instant) -> (u32, u64) {
+    let seq = self.next;
+    self.next = self.next.wrapping_add(1);
+    let micros = captured.saturating_duration_since(self.epoch).as_micros();
+    (seq, micros as u64)
+  }
+}
+
+impl Default for Stamper {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// How a sender's stamps line up with when its packets arrive, for
+/// measuring jitter without synchronised clocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+  last: Option<(u64, Instant)>,
+}
+
+impl Timing {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Notes a packet stamped `micros` that arrived at `arrived`, returning
+  /// how far apart it and the previous one were sent and arrived, in
+  /// microseconds.
+  pub fn delta(&mut self, micros: u64, arrived: Instant) -> Option<(i64, i64)> {
+    let last = self.last.replace((micros, arrived));
+    let (last_micros, last_arrived) = last?;
+    let sent = micros as i64 - last_micros as i64;
+    let received = arrived.saturating_duration_since(last_arrived).as_micros();
+    Some((sent, received as i64))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn tracker_drops_late_and_duplicate_packets() {
+    let mut seqs = SeqTracker::new();
+    assert!(seqs.accept(5));
+    assert!(seqs.accept(7));
+    assert!(!seqs.accept(6));
+    assert!(!seqs.accept(7));
+    // Wrapping around is still newer.
+    let mut seqs = SeqTracker::new();
+    assert!(seqs.accept(u32::MAX));
+    assert!(seqs.accept(0));
+    // So is a jump back further than any reordering: the sender restarted.
+    assert!(seqs.accept(1000 + MAX_REORDER));
+    assert!(seqs.accept(0));
+  }
+
+  #[test]
+  fn timing_compares_send_and_arrival_gaps() {
+    let mut stamper = Stamper::new();
+    let epoch = Instant::now();
+    let mut timing = Timing::new();
+    let (seq, first) = stamper.stamp(epoch);
+    assert_eq!(seq, 0);
+    assert_eq!(timing.delta(first, epoch), None);
+
+    let (seq, second) = stamper.stamp(epoch + Duration::from_millis(6));
+    assert_eq!(seq, 1);
+    let arrived = epoch + Duration::from_millis(10);
+    let (sent, received) = timing.delta(second, arrived).unwrap();
+    assert_eq!(received, 10_000);
+    assert!((sent - 6_000).abs() <= 1, "{sent}");
+  }
+}
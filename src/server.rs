@@ -0,0 +1,1539 @@
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  io,
+  net::{IpAddr, SocketAddr, UdpSocket},
+  sync::{
+    Arc,
+    atomic::Ordering,
+    mpsc::{Receiver, channel},
+  },
+  time::{Duration, Instant},
+};
+
+use tracing::{debug, info, trace, warn};
+
+#[cfg(feature = "opus")]
+use crate::opus::{OpusDecoder, OpusEncoder};
+use crate::{
+  ClientId, MAX_PACKET_SIZE, PROTOCOL_VERSION, Packet, SAMPLE_RATE,
+  TX_BUFFER_SIZE, TxBuffer, WAIT_DURATION,
+  fx::{FxConfig, FxUnit, gen_tone},
+  level_gain,
+  metrics::Metrics,
+  mix::{Attenuation, FadeOut, MixPolicy},
+  ratelimit::TokenBucket,
+  seq::{SeqTracker, Stamper},
+};
+#[cfg(feature = "opus")]
+use std::collections::hash_map::Entry;
+
+/// Maximum number of buffered audio chunks to retain per client before
+/// dropping the oldest.
+const MAX_CLIENT_BACKLOG: usize = 8;
+const MAX_CLIENT_BACKLOG_SAMPLES: usize = MAX_CLIENT_BACKLOG * TX_BUFFER_SIZE;
+
+/// How long an address can go without sending anything before the server
+/// forgets about it. Clients ping every
+/// [`KEEPALIVE_INTERVAL`](crate::KEEPALIVE_INTERVAL), so only those that have
+/// really gone are dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many ticks a client that runs dry takes to fade out of the mix.
+const FADE_TICKS: usize = 3;
+
+/// Ticks a talker must be quiet for before its server-side squelch tail
+/// plays; matches the client's squelch timeout.
+const SQUELCH_TICKS: usize = 7;
+
+/// Peak level of the `test_tone`, leaving headroom for client FX.
+const TEST_TONE_LEVEL: f32 = 0.5;
+
+/// The attention tone ahead of each emergency transmission: half a second
+/// (a whole number of cycles, so it ends without a click) at half scale.
+const ATTENTION_TONE_HZ: f32 = 1000.0;
+const ATTENTION_TONE_SAMPLES: usize = SAMPLE_RATE as usize / 2;
+const ATTENTION_TONE_LEVEL: f32 = 0.5;
+
+type Admit = Box<dyn Fn(IpAddr) -> bool + Send>;
+type PacketHook = Box<dyn FnMut(SocketAddr, &Packet) + Send>;
+type MixHook = Box<dyn FnMut(&TxBuffer) + Send>;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+  /// Maximum packets per second accepted from a single client (0 = no
+  /// limit).
+  pub max_pps: f64,
+  /// Number of packets a client may burst above `max_pps`.
+  pub burst: f64,
+  pub policy: MixPolicy,
+  /// Addresses that always receive the mix, without having to ping first.
+  pub peers: Vec<SocketAddr>,
+  /// Most clients that may join by pinging (0 = no limit). Static `peers`
+  /// don't count towards it. Clients over the limit get
+  /// [`Packet::Rejected`].
+  pub max_clients: usize,
+  /// Runs these FX on each listener's finished mix, squelch tail included,
+  /// so everyone hearing a transmission hears the same channel sound.
+  /// Clients are told (see [`Packet::ServerInfo`]) and skip their own.
+  pub fx: Option<FxConfig>,
+  /// Also send each client its own audio, so a lone client hears itself.
+  /// For demos and testing (`client --local-echo`).
+  pub loopback: bool,
+  /// Diagnostic mode: rather than mixing anyone's audio, send every client
+  /// a steady tone at this many Hz, so a lone operator can check their
+  /// receive path, FX and squelch. Clients still join by pinging; the audio
+  /// they send is ignored.
+  pub test_tone: Option<u32>,
+  /// Relay [`Packet::Emergency`] to every client on every channel. Without
+  /// it, emergency audio is mixed like any other, so nobody can shout over
+  /// every channel unless the operator allows it.
+  pub allow_emergency: bool,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    Self {
+      max_pps: 200.0,
+      burst: 32.0,
+      policy: MixPolicy {
+        attenuation: Attenuation::None,
+        headroom_db: 0.0,
+        active_only: false,
+      },
+      peers: Vec::new(),
+      max_clients: 0,
+      fx: None,
+      loopback: false,
+      test_tone: None,
+      allow_emergency: false,
+    }
+  }
+}
+
+/// A squelch server: receives audio from every client and sends each one
+/// the mix of everyone else.
+pub struct Server {
+  socket: UdpSocket,
+  config: ServerConfig,
+  metrics: Arc<Metrics>,
+  admit: Option<Admit>,
+  hooks: Vec<PacketHook>,
+  mix_hook: Option<MixHook>,
+}
+
+impl Server {
+  pub fn new(socket: UdpSocket, config: ServerConfig) -> Self {
+    Self {
+      socket,
+      config,
+      metrics: Arc::new(Metrics::default()),
+      admit: None,
+      hooks: Vec::new(),
+      mix_hook: None,
+    }
+  }
+
+  pub fn metrics(&self) -> Arc<Metrics> {
+    self.metrics.clone()
+  }
+
+  /// Only accept clients whose IP passes `admit`. It's called once per
+  /// client address.
+  pub fn admit(
+    &mut self,
+    admit: impl Fn(IpAddr) -> bool + Send + 'static,
+  ) -> &mut Self {
+    self.admit = Some(Box::new(admit));
+    self
+  }
+
+  /// Calls `hook` with every decoded packet and its source, before the
+  /// server handles it, e.g. for logging or external recording.
+  ///
+  /// Hooks run on the receive thread, so a slow hook delays every client's
+  /// audio. Keep them cheap, or hand the packet off to another thread.
+  pub fn on_packet(
+    &mut self,
+    hook: impl FnMut(SocketAddr, &Packet) + Send + 'static,
+  ) -> &mut Self {
+    self.hooks.push(Box::new(hook));
+    self
+  }
+
+  /// Calls `hook` every mix tick with the mix of every client on channel 0,
+  /// i.e. what a listener there who isn't talking hears (so no whispers),
+  /// e.g. to record the channel. It's called even when nobody is talking,
+  /// so the audio stays in step with wall-clock time.
+  ///
+  /// Runs on the mixer thread; like [`on_packet`](Self::on_packet) hooks,
+  /// keep it cheap.
+  pub fn on_mix(
+    &mut self,
+    hook: impl FnMut(&TxBuffer) + Send + 'static,
+  ) -> &mut Self {
+    self.mix_hook = Some(Box::new(hook));
+    self
+  }
+
+  /// Starts the mixer and serves clients until the socket fails.
+  pub fn run(mut self) -> io::Result<()> {
+    let (tx, rx) = channel::<(SocketAddr, Packet)>();
+
+    let mixer = Mixer {
+      socket: self.socket.try_clone()?,
+      state: MixState::new(
+        &self.config,
+        self.metrics.clone(),
+        self.mix_hook.take(),
+      ),
+      rx,
+    };
+    std::thread::spawn(move || mixer.run());
+
+    let mut gate = Gate::new(&self.config, self.admit.take());
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+      let (size, src) = self.socket.recv_from(&mut buf)?;
+      let Some(packet) = self.receive(&mut gate, src, &buf[..size]) else {
+        continue;
+      };
+
+      if let Packet::Ping = packet {
+        // Answer so clients can tell the server is up even when nobody is
+        // talking.
+        self.socket.send_to(&encode(&Packet::Ping), src)?;
+      }
+      tx.send((src, packet)).unwrap();
+    }
+  }
+
+  /// Like [`run`](Self::run), but on the current tokio runtime: one task
+  /// both receives and runs the mix tick, instead of a thread for each.
+  /// Everything else, from the hooks to the mix itself, is shared with the
+  /// threaded server.
+  #[cfg(feature = "async")]
+  pub async fn run_async(mut self) -> io::Result<()> {
+    self.socket.set_nonblocking(true)?;
+    let socket = tokio::net::UdpSocket::from_std(self.socket.try_clone()?)?;
+
+    let mut state =
+      MixState::new(&self.config, self.metrics.clone(), self.mix_hook.take());
+    let mut gate = Gate::new(&self.config, self.admit.take());
+    let mut tick = tokio::time::interval(*WAIT_DURATION);
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+      tokio::select! {
+        received = socket.recv_from(&mut buf) => {
+          let (size, src) = received?;
+          let Some(packet) = self.receive(&mut gate, src, &buf[..size]) else {
+            continue;
+          };
+
+          if let Packet::Ping = packet {
+            socket.send_to(&encode(&Packet::Ping), src).await?;
+          }
+          for (to, bytes) in state.handle(src, packet) {
+            if let Ok(sent) = socket.send_to(&bytes, to).await {
+              state.count_sent(sent);
+            }
+          }
+        }
+        _ = tick.tick() => {
+          for (to, bytes) in state.tick() {
+            let sent = socket.send_to(&bytes, to).await?;
+            state.count_sent(sent);
+          }
+        }
+      }
+    }
+  }
+
+  /// Counts, admits, rate limits and decodes a datagram from `src`, then
+  /// runs the hooks on it. `None` means it's dropped.
+  fn receive(
+    &mut self,
+    gate: &mut Gate,
+    src: SocketAddr,
+    datagram: &[u8],
+  ) -> Option<Packet> {
+    self
+      .metrics
+      .packets_received
+      .fetch_add(1, Ordering::Relaxed);
+    self
+      .metrics
+      .bytes_received
+      .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+
+    trace!("{} bytes from {src}", datagram.len());
+    if !gate.allow(src) {
+      return None;
+    }
+
+    match Packet::decode(datagram) {
+      // From a newer client; nothing we can do with it.
+      Ok(None) => None,
+      Ok(Some(packet)) => {
+        for hook in self.hooks.iter_mut() {
+          hook(src, &packet);
+        }
+        Some(packet)
+      }
+      Err(err) => {
+        self.metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+        warn!("Error decoding packet from {src}: {err:?}");
+        None
+      }
+    }
+  }
+}
+
+fn encode(packet: &Packet) -> Vec<u8> {
+  postcard::to_allocvec(packet).unwrap()
+}
+
+/// Admission and rate limiting per source address, ahead of decoding.
+struct Gate {
+  max_pps: f64,
+  burst: f64,
+  admit: Option<Admit>,
+  limiters: HashMap<SocketAddr, TokenBucket>,
+  throttled: HashSet<SocketAddr>,
+  admitted: HashMap<SocketAddr, bool>,
+  // When each address last sent anything, so those gone quiet can be
+  // forgotten rather than tracked forever.
+  last_seen: HashMap<SocketAddr, Instant>,
+  last_pruned: Instant,
+}
+
+impl Gate {
+  fn new(config: &ServerConfig, admit: Option<Admit>) -> Self {
+    Self {
+      max_pps: config.max_pps,
+      burst: config.burst,
+      admit,
+      limiters: HashMap::new(),
+      throttled: HashSet::new(),
+      admitted: HashMap::new(),
+      last_seen: HashMap::new(),
+      last_pruned: Instant::now(),
+    }
+  }
+
+  fn allow(&mut self, src: SocketAddr) -> bool {
+    self.allow_at(src, Instant::now())
+  }
+
+  fn allow_at(&mut self, src: SocketAddr, now: Instant) -> bool {
+    self.last_seen.insert(src, now);
+    if now.saturating_duration_since(self.last_pruned) >= CLIENT_TIMEOUT {
+      self.prune(now);
+    }
+
+    // Blocked peers are dropped before they ever reach the mixer, so they
+    // never get added to `client_samples`.
+    let allowed = *self.admitted.entry(src).or_insert_with(|| {
+      let allowed = self.admit.as_ref().is_none_or(|admit| admit(src.ip()));
+      if !allowed {
+        warn!("Rejected connection from {src}");
+      }
+      allowed
+    });
+    if !allowed {
+      return false;
+    }
+
+    // Drop packets from clients sending faster than real-time before they
+    // reach the mixer, so a single peer can't flood it.
+    if self.max_pps > 0.0 {
+      let limiter = self
+        .limiters
+        .entry(src)
+        .or_insert_with(|| TokenBucket::new(self.max_pps, self.burst));
+      if !limiter.try_take_at(now) {
+        if self.throttled.insert(src) {
+          warn!("Throttling {src}: exceeded {} packets/sec", self.max_pps);
+        }
+        return false;
+      } else if self.throttled.remove(&src) {
+        info!("No longer throttling {src}");
+      }
+    }
+
+    true
+  }
+
+  /// Forgets addresses that haven't sent anything for `CLIENT_TIMEOUT`.
+  fn prune(&mut self, now: Instant) {
+    self.last_pruned = now;
+    self
+      .last_seen
+      .retain(|_, seen| now.saturating_duration_since(*seen) < CLIENT_TIMEOUT);
+    let last_seen = &self.last_seen;
+    self.admitted.retain(|src, _| last_seen.contains_key(src));
+    self.limiters.retain(|src, _| last_seen.contains_key(src));
+    self.throttled.retain(|src| last_seen.contains_key(src));
+  }
+}
+
+/// The mixer thread's half of the threaded server.
+struct Mixer {
+  socket: UdpSocket,
+  state: MixState,
+  rx: Receiver<(SocketAddr, Packet)>,
+}
+
+impl Mixer {
+  fn run(mut self) {
+    let mut last_sent = Instant::now();
+    loop {
+      while let Ok((src, packet)) = self.rx.try_recv() {
+        for (to, bytes) in self.state.handle(src, packet) {
+          if let Ok(sent) = self.socket.send_to(&bytes, to) {
+            self.state.count_sent(sent);
+          }
+        }
+      }
+
+      if last_sent.elapsed() > *WAIT_DURATION {
+        for (to, bytes) in self.state.tick() {
+          let sent = self.socket.send_to(&bytes, to).unwrap();
+          self.state.count_sent(sent);
+        }
+
+        last_sent += *WAIT_DURATION;
+        if last_sent.elapsed() > *WAIT_DURATION * 4 {
+          last_sent = Instant::now();
+        }
+      }
+    }
+  }
+}
+
+/// A client's Opus codecs: one for its mix, one for what it sends.
+#[cfg(feature = "opus")]
+struct OpusPeer {
+  encoder: OpusEncoder,
+  decoder: OpusDecoder,
+}
+
+#[cfg(feature = "opus")]
+impl OpusPeer {
+  fn new() -> audiopus::Result<Self> {
+    Ok(Self {
+      encoder: OpusEncoder::new()?,
+      decoder: OpusDecoder::new()?,
+    })
+  }
+}
+
+/// Queues the frame `encoded` for `client`, if it finished one.
+#[cfg(feature = "opus")]
+fn queue_opus(
+  out: &mut Vec<(SocketAddr, Vec<u8>)>,
+  client: SocketAddr,
+  encoded: audiopus::Result<Option<Packet>>,
+) {
+  match encoded {
+    Ok(Some(packet)) => out.push((client, encode(&packet))),
+    Ok(None) => {}
+    Err(e) => warn!("Failed to encode Opus for {client}: {e}"),
+  }
+}
+
+/// A listener's FX, when the server runs them ([`ServerConfig::fx`]).
+struct ListenerFx {
+  unit: FxUnit,
+  /// Ticks since the listener last heard anything, while its squelch is
+  /// open; after `SQUELCH_TICKS` the squelch tail plays.
+  idle: Option<usize>,
+  /// What's left of the squelch tail, a buffer a tick.
+  tail: VecDeque<TxBuffer>,
+}
+
+impl ListenerFx {
+  /// Runs the FX on a tick's `mix`, returning what to send, if anything.
+  /// Each transmission starts from a fresh unit, like a client's, so
+  /// everyone who hears it from the start hears the same static.
+  fn run(&mut self, mut mix: TxBuffer) -> Option<TxBuffer> {
+    if mix.iter().any(|s| *s != 0.0) {
+      if self.idle.is_none() {
+        self.unit.reset();
+        self.tail.clear();
+      }
+      self.idle = Some(0);
+      self.unit.run(&mut mix);
+      return Some(mix);
+    }
+
+    if let Some(idle) = self.idle.as_mut() {
+      *idle += 1;
+      if *idle >= SQUELCH_TICKS {
+        self.idle = None;
+        self.tail.extend(self.unit.squelch());
+      }
+    }
+    self.tail.pop_front()
+  }
+}
+
+/// An emergency transmission in progress.
+struct Emergency {
+  samples: VecDeque<f32>,
+  /// Ticks it's run dry for; after `SQUELCH_TICKS` it's over, and the next
+  /// one gets the attention tone again.
+  idle: usize,
+}
+
+/// Everything the mixer keeps between ticks, independent of how packets
+/// reach it or how the results are sent.
+struct MixState {
+  policy: MixPolicy,
+  metrics: Arc<Metrics>,
+  loopback: bool,
+  max_clients: usize,
+  // Static peers, which are always in `client_samples` and never count
+  // towards `max_clients`.
+  peers: HashSet<SocketAddr>,
+  fx: Option<FxConfig>,
+  listener_fx: HashMap<SocketAddr, ListenerFx>,
+  // One second of the test tone, which loops seamlessly since it's a whole
+  // number of Hz, and where the next tick picks it up.
+  test_tone: Option<Vec<f32>>,
+  tone_pos: usize,
+  allow_emergency: bool,
+  // Emergency traffic by sender, heard on every channel.
+  emergencies: HashMap<SocketAddr, Emergency>,
+  mix_hook: Option<MixHook>,
+  // Each client's audio as one contiguous stream, so every tick takes
+  // exactly `TX_BUFFER_SIZE` samples regardless of packet boundaries.
+  client_samples: HashMap<SocketAddr, VecDeque<f32>>,
+  fades: HashMap<SocketAddr, FadeOut>,
+  // Levels clients have asked to be mixed at; everyone else is at unity.
+  gains: HashMap<SocketAddr, f32>,
+  // Clients that have moved off channel 0.
+  channels: HashMap<SocketAddr, u16>,
+  // Protocol versions clients have announced; the rest are version 0.
+  versions: HashMap<SocketAddr, u16>,
+  // Numbers and stamps each client's feed, and tracks the newest number seen
+  // from each client, for those that speak `SeqAudio` or `StampedAudio`.
+  stampers: HashMap<SocketAddr, Stamper>,
+  received_seqs: HashMap<SocketAddr, SeqTracker>,
+  // Clients that sent `AcceptOpus`: their mix goes out compressed.
+  #[cfg(feature = "opus")]
+  opus: HashMap<SocketAddr, OpusPeer>,
+  // Whispers, queued per sender and recipient so two people whispering to
+  // the same client are mixed rather than played one after the other.
+  direct_chunks: HashMap<(SocketAddr, ClientId), VecDeque<TxBuffer>>,
+  current_chunks: Vec<(SocketAddr, TxBuffer)>,
+  current_direct: Vec<(ClientId, TxBuffer)>,
+  current_emergency: Vec<(SocketAddr, TxBuffer)>,
+  // When each client last sent anything, so those that have gone can be
+  // dropped from the mix.
+  last_seen: HashMap<SocketAddr, Instant>,
+}
+
+impl MixState {
+  fn new(
+    config: &ServerConfig,
+    metrics: Arc<Metrics>,
+    mix_hook: Option<MixHook>,
+  ) -> Self {
+    let mut client_samples: HashMap<SocketAddr, VecDeque<f32>> = HashMap::new();
+    for peer in &config.peers {
+      client_samples.entry(*peer).or_default();
+      info!("Added static peer {peer}");
+    }
+    metrics
+      .clients
+      .store(client_samples.len() as u64, Ordering::Relaxed);
+
+    Self {
+      policy: config.policy,
+      metrics,
+      loopback: config.loopback,
+      max_clients: config.max_clients,
+      peers: config.peers.iter().copied().collect(),
+      fx: config.fx.clone(),
+      listener_fx: HashMap::new(),
+      test_tone: config.test_tone.map(|hz| {
+        info!("Test tone mode: sending everyone {hz} Hz");
+        gen_tone(hz as f32, SAMPLE_RATE as usize)
+          .into_iter()
+          .map(|s| s * TEST_TONE_LEVEL)
+          .collect()
+      }),
+      tone_pos: 0,
+      allow_emergency: config.allow_emergency,
+      emergencies: HashMap::new(),
+      mix_hook,
+      client_samples,
+      fades: HashMap::new(),
+      gains: HashMap::new(),
+      channels: HashMap::new(),
+      versions: HashMap::new(),
+      stampers: HashMap::new(),
+      received_seqs: HashMap::new(),
+      #[cfg(feature = "opus")]
+      opus: HashMap::new(),
+      direct_chunks: HashMap::new(),
+      current_chunks: Vec::new(),
+      current_direct: Vec::new(),
+      current_emergency: Vec::new(),
+      last_seen: HashMap::new(),
+    }
+  }
+
+  /// Takes in a packet from `src`, returning any datagrams to pass straight
+  /// on rather than wait for the next tick.
+  fn handle(
+    &mut self,
+    src: SocketAddr,
+    packet: Packet,
+  ) -> Vec<(SocketAddr, Vec<u8>)> {
+    if !self.client_samples.contains_key(&src) && self.is_full() {
+      // Only a ping gets an answer; anything else from a client that never
+      // got in is dropped.
+      if let Packet::Ping = packet {
+        info!("Rejected {src}: server full");
+        let reason = "server full".to_owned();
+        return vec![(src, encode(&Packet::Rejected { reason }))];
+      }
+      return Vec::new();
+    }
+    self.last_seen.insert(src, Instant::now());
+
+    // The test tone replaces the mix, so there's nothing to do with audio.
+    if self.test_tone.is_some()
+      && matches!(
+        packet,
+        Packet::Audio(_)
+          | Packet::DirectAudio { .. }
+          | Packet::Silence
+          | Packet::Emergency { .. }
+          | Packet::OpusAudio { .. }
+      )
+    {
+      return Vec::new();
+    }
+
+    match packet {
+      Packet::Ping => {
+        self.client_samples.entry(src).or_default();
+        self
+          .metrics
+          .clients
+          .store(self.client_samples.len() as u64, Ordering::Relaxed);
+        info!("Now {} clients", self.client_samples.len());
+        // Keepalives carry the FX flag too, so a client that lost the
+        // answer to its hello still finds out.
+        let info = self.server_info();
+        if self.understands(&src, &info) {
+          return vec![(src, encode(&info))];
+        }
+      }
+      Packet::Audio(samples) => {
+        self.client_samples.entry(src).or_default().extend(samples);
+      }
+      Packet::DirectAudio { to, samples } => {
+        self
+          .direct_chunks
+          .entry((src, to))
+          .or_default()
+          .push_back(samples);
+      }
+      Packet::Level(level) => {
+        self.gains.insert(src, level_gain(level));
+      }
+      Packet::Hello { version } => {
+        if version > PROTOCOL_VERSION {
+          warn!(
+            "{src} speaks protocol {version}, newer than ours \
+             ({PROTOCOL_VERSION}); it'll only get what we understand"
+          );
+        }
+        self.versions.insert(src, version);
+
+        let ack = Packet::HelloAck {
+          version: PROTOCOL_VERSION,
+        };
+        return [ack, self.server_info()]
+          .iter()
+          .filter(|packet| self.understands(&src, packet))
+          .map(|packet| (src, encode(packet)))
+          .collect();
+      }
+      Packet::Channel(channel) => {
+        if self.channel(&src) != channel {
+          // Drop whatever they'd queued on the old channel so it stops
+          // straight away instead of playing out on the new one.
+          self.channels.insert(src, channel);
+          self.client_samples.entry(src).or_default().clear();
+          self.fades.remove(&src);
+          self.listener_fx.remove(&src);
+          debug!("{src} moved to channel {channel}");
+        }
+      }
+      Packet::Silence => {
+        // Pass DTX silence markers straight on to everyone else who can
+        // decode them; there's no audio in them to mix.
+        let marker = encode(&Packet::Silence);
+        let channel = self.channel(&src);
+        return self
+          .client_samples
+          .keys()
+          .filter(|client| **client != src && self.channel(client) == channel)
+          .filter(|client| self.understands(client, &Packet::Silence))
+          .map(|client| (*client, marker.clone()))
+          .collect();
+      }
+      Packet::SeqAudio { seq, samples }
+      | Packet::StampedAudio { seq, samples, .. } => {
+        if !self.received_seqs.entry(src).or_default().accept(seq) {
+          trace!("Dropped out-of-order buffer {seq} from {src}");
+          return Vec::new();
+        }
+        return self.handle(src, Packet::Audio(samples));
+      }
+      Packet::Emergency { samples } if !self.allow_emergency => {
+        debug!("{src} sent emergency audio; mixing it as channel audio");
+        return self.handle(src, Packet::Audio(samples));
+      }
+      Packet::Emergency { samples } => {
+        let emergency = self.emergencies.entry(src).or_insert_with(|| {
+          warn!("Emergency transmission from {src}");
+          Emergency {
+            samples: gen_tone(ATTENTION_TONE_HZ, ATTENTION_TONE_SAMPLES)
+              .into_iter()
+              .map(|s| s * ATTENTION_TONE_LEVEL)
+              .collect(),
+            idle: 0,
+          }
+        });
+        emergency.samples.extend(samples);
+        // Room for the tone on top of the usual backlog.
+        let max = ATTENTION_TONE_SAMPLES + MAX_CLIENT_BACKLOG_SAMPLES;
+        if emergency.samples.len() > max {
+          emergency.samples.drain(..emergency.samples.len() - max);
+        }
+      }
+      #[cfg(feature = "opus")]
+      Packet::AcceptOpus => {
+        if let Entry::Vacant(entry) = self.opus.entry(src) {
+          match OpusPeer::new() {
+            Ok(peer) => {
+              debug!("{src} takes Opus");
+              entry.insert(peer);
+            }
+            Err(e) => {
+              warn!("Failed to set up Opus for {src}: {e}");
+              return Vec::new();
+            }
+          }
+        }
+        return vec![(src, encode(&Packet::AcceptOpus))];
+      }
+      #[cfg(feature = "opus")]
+      Packet::OpusAudio { seq, frame } => {
+        // Only clients we've answered send Opus.
+        let Some(peer) = self.opus.get_mut(&src) else {
+          return Vec::new();
+        };
+        if let Err(e) = peer.decoder.decode(seq, &frame) {
+          debug!("Failed to decode Opus from {src}: {e}");
+          return Vec::new();
+        }
+        let buffers: Vec<TxBuffer> =
+          std::iter::from_fn(|| peer.decoder.pop()).collect();
+        for samples in buffers {
+          self.handle(src, Packet::Audio(samples));
+        }
+      }
+      // Without Opus, `AcceptOpus` goes unanswered, so nobody sends it.
+      #[cfg(not(feature = "opus"))]
+      Packet::AcceptOpus | Packet::OpusAudio { .. } => {}
+      // Only ever sent by the server.
+      Packet::Rejected { .. }
+      | Packet::ServerInfo { .. }
+      | Packet::HelloAck { .. } => {}
+    }
+
+    Vec::new()
+  }
+
+  /// Runs one mix tick, returning each client's mix to send.
+  fn tick(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+    self.metrics.mix_ticks.fetch_add(1, Ordering::Relaxed);
+    self.current_chunks.clear();
+    let now = Instant::now();
+    self.prune(now);
+
+    if self.test_tone.is_some() {
+      return self.tone_tick();
+    }
+
+    for (src, queue) in self.client_samples.iter_mut() {
+      if queue.len() > MAX_CLIENT_BACKLOG_SAMPLES {
+        queue.drain(..queue.len() - MAX_CLIENT_BACKLOG_SAMPLES);
+      }
+
+      let gain = self.gains.get(src).copied().unwrap_or(1.0);
+      let next = (queue.len() >= TX_BUFFER_SIZE).then(|| {
+        let mut samples = [0f32; TX_BUFFER_SIZE];
+        for (s, q) in samples.iter_mut().zip(queue.drain(..TX_BUFFER_SIZE)) {
+          *s = q * gain;
+        }
+        samples
+      });
+
+      // Late packets fade out rather than cutting off abruptly.
+      let fade = self
+        .fades
+        .entry(*src)
+        .or_insert_with(|| FadeOut::new(FADE_TICKS));
+      if let Some(samples) = fade.next(next) {
+        self.current_chunks.push((*src, samples));
+      }
+    }
+
+    self.current_direct.clear();
+    let current_direct = &mut self.current_direct;
+    self.direct_chunks.retain(|(_, to), chunks| {
+      while chunks.len() > MAX_CLIENT_BACKLOG {
+        chunks.pop_front();
+      }
+
+      match chunks.pop_front() {
+        Some(samples) => {
+          current_direct.push((*to, samples));
+          true
+        }
+        None => false,
+      }
+    });
+
+    self.current_emergency.clear();
+    let current_emergency = &mut self.current_emergency;
+    self.emergencies.retain(|src, emergency| {
+      if emergency.samples.len() < TX_BUFFER_SIZE {
+        emergency.idle += 1;
+        return emergency.idle < SQUELCH_TICKS;
+      }
+
+      emergency.idle = 0;
+      let mut samples = [0f32; TX_BUFFER_SIZE];
+      for (s, q) in samples
+        .iter_mut()
+        .zip(emergency.samples.drain(..TX_BUFFER_SIZE))
+      {
+        *s = q;
+      }
+      current_emergency.push((*src, samples));
+      true
+    });
+
+    let mut buf = [0f32; TX_BUFFER_SIZE];
+    if let Some(hook) = self.mix_hook.as_mut() {
+      self.policy.mix(
+        &mut buf,
+        self
+          .current_chunks
+          .iter()
+          .filter(|(src, _)| !self.channels.contains_key(src))
+          .chain(self.current_emergency.iter())
+          .map(|(_, samples)| samples),
+      );
+      hook(&buf);
+    }
+
+    let mut out = Vec::new();
+    for client in self.client_samples.keys() {
+      let channel = self.channel(client);
+      let heard = |src: &SocketAddr| src != client || self.loopback;
+      let emergency = self.current_emergency.iter().any(|(src, _)| heard(src));
+      self.policy.mix(
+        &mut buf,
+        self
+          .current_chunks
+          .iter()
+          .filter(|(src, _)| heard(src) && self.channel(src) == channel)
+          .chain(self.current_direct.iter().filter(|(to, _)| to == client))
+          .chain(self.current_emergency.iter().filter(|(src, _)| heard(src)))
+          .map(|(_, samples)| samples),
+      );
+      if let Some(config) = &self.fx {
+        let fx =
+          self
+            .listener_fx
+            .entry(*client)
+            .or_insert_with(|| ListenerFx {
+              unit: FxUnit::from_config(config),
+              idle: None,
+              tail: VecDeque::new(),
+            });
+        buf = fx.run(buf).unwrap_or([0.0; TX_BUFFER_SIZE]);
+      }
+
+      if buf.iter().all(|a| *a == 0.0) {
+        // Don't hold the end of a transmission back until the next one.
+        #[cfg(feature = "opus")]
+        if let Some(peer) = self.opus.get_mut(client) {
+          queue_opus(&mut out, *client, peer.encoder.flush());
+        }
+        continue;
+      }
+
+      // Older clients still hear it, just without the alarm.
+      let packet = Packet::Emergency { samples: buf };
+      if emergency && self.understands(client, &packet) {
+        out.push((*client, encode(&packet)));
+        continue;
+      }
+      #[cfg(feature = "opus")]
+      if let Some(peer) = self.opus.get_mut(client) {
+        queue_opus(&mut out, *client, peer.encoder.encode(&buf));
+        continue;
+      }
+
+      // Otherwise the newest form of plain audio they understand.
+      let (seq, micros) = self.stampers.entry(*client).or_default().stamp(now);
+      let packet = [
+        Packet::StampedAudio {
+          seq,
+          micros,
+          samples: buf,
+        },
+        Packet::SeqAudio { seq, samples: buf },
+        Packet::Audio(buf),
+      ]
+      .into_iter()
+      .find(|packet| self.understands(client, packet))
+      .unwrap();
+      out.push((*client, encode(&packet)));
+    }
+    out
+  }
+
+  /// Sends every client, on every channel, the next buffer of the test tone.
+  fn tone_tick(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+    let Some(tone) = self.test_tone.as_ref() else {
+      return Vec::new();
+    };
+    let mut buf = [0f32; TX_BUFFER_SIZE];
+    for s in buf.iter_mut() {
+      *s = tone[self.tone_pos];
+      self.tone_pos = (self.tone_pos + 1) % tone.len();
+    }
+    if let Some(hook) = self.mix_hook.as_mut() {
+      hook(&buf);
+    }
+
+    let packet = encode(&Packet::Audio(buf));
+    self
+      .client_samples
+      .keys()
+      .map(|client| (*client, packet.clone()))
+      .collect()
+  }
+
+  /// Drops every trace of clients that haven't sent anything for
+  /// `CLIENT_TIMEOUT`. Static peers never time out.
+  fn prune(&mut self, now: Instant) {
+    let gone: Vec<SocketAddr> = self
+      .last_seen
+      .iter()
+      .filter(|(_, seen)| {
+        now.saturating_duration_since(**seen) >= CLIENT_TIMEOUT
+      })
+      .map(|(src, _)| *src)
+      .filter(|src| !self.peers.contains(src))
+      .collect();
+    if gone.is_empty() {
+      return;
+    }
+
+    for src in &gone {
+      self.last_seen.remove(src);
+      self.client_samples.remove(src);
+      self.fades.remove(src);
+      self.gains.remove(src);
+      self.channels.remove(src);
+      self.versions.remove(src);
+      self.stampers.remove(src);
+      self.received_seqs.remove(src);
+      self.listener_fx.remove(src);
+      self.emergencies.remove(src);
+      #[cfg(feature = "opus")]
+      self.opus.remove(src);
+      info!("Dropped {src}: timed out");
+    }
+    self
+      .direct_chunks
+      .retain(|(from, to), _| !gone.contains(from) && !gone.contains(to));
+    self
+      .metrics
+      .clients
+      .store(self.client_samples.len() as u64, Ordering::Relaxed);
+    info!("Now {} clients", self.client_samples.len());
+  }
+
+  /// How this server serves, for [`Packet::ServerInfo`].
+  fn server_info(&self) -> Packet {
+    Packet::ServerInfo {
+      fx: self.fx.is_some(),
+    }
+  }
+
+  /// Whether `max_clients` clients have already joined by pinging.
+  fn is_full(&self) -> bool {
+    let joined = self.client_samples.len() - self.peers.len();
+    self.max_clients > 0 && joined >= self.max_clients
+  }
+
+  fn understands(&self, client: &SocketAddr, packet: &Packet) -> bool {
+    let version = self.versions.get(client).copied().unwrap_or(0);
+    version >= packet.min_version()
+  }
+
+  fn channel(&self, client: &SocketAddr) -> u16 {
+    self.channels.get(client).copied().unwrap_or(0)
+  }
+
+  fn count_sent(&self, bytes: usize) {
+    self.metrics.packets_sent.fetch_add(1, Ordering::Relaxed);
+    self
+      .metrics
+      .bytes_sent
+      .fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use super::*;
+
+  fn addr(last: u8) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::new(10, 0, 0, last), 1837))
+  }
+
+  fn gate(max_pps: f64, burst: f64) -> Gate {
+    let config = ServerConfig {
+      max_pps,
+      burst,
+      ..Default::default()
+    };
+    Gate::new(&config, None)
+  }
+
+  /// The audio each client was sent by a tick, keyed by client, and whether
+  /// it came as an emergency.
+  fn heard(
+    out: Vec<(SocketAddr, Vec<u8>)>,
+  ) -> HashMap<SocketAddr, (TxBuffer, bool)> {
+    out
+      .into_iter()
+      .filter_map(|(to, bytes)| {
+        let heard = match Packet::decode(&bytes).unwrap()? {
+          Packet::Audio(samples) => (samples, false),
+          Packet::Emergency { samples } => (samples, true),
+          _ => return None,
+        };
+        Some((to, heard))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn gate_drops_a_flood_but_not_other_clients() {
+    let mut gate = gate(10.0, 5.0);
+    let now = Instant::now();
+
+    let passed = (0..50).filter(|_| gate.allow_at(addr(1), now)).count();
+    assert_eq!(passed, 5);
+    assert!(gate.allow_at(addr(2), now));
+
+    // A second later the flooder has earned back max_pps packets.
+    let later = now + Duration::from_secs(1);
+    let passed = (0..50).filter(|_| gate.allow_at(addr(1), later)).count();
+    assert_eq!(passed, 5);
+  }
+
+  #[test]
+  fn mix_state_drops_clients_that_time_out() {
+    let peer = addr(9);
+    let config = ServerConfig {
+      peers: vec![peer],
+      ..Default::default()
+    };
+    let mut state = MixState::new(&config, Arc::default(), None);
+    state.handle(addr(1), Packet::Ping);
+    state.handle(addr(2), Packet::Ping);
+    state.handle(addr(1), Packet::Audio([0.5; TX_BUFFER_SIZE]));
+    state.tick();
+    assert!(state.fades.contains_key(&addr(1)));
+
+    let now = Instant::now();
+    state.prune(now + CLIENT_TIMEOUT / 2);
+    assert_eq!(state.client_samples.len(), 3);
+
+    // Client 2 is still pinging; client 1 has gone.
+    state.last_seen.insert(addr(2), now + CLIENT_TIMEOUT);
+    state.prune(now + CLIENT_TIMEOUT);
+    assert!(!state.client_samples.contains_key(&addr(1)));
+    assert!(!state.fades.contains_key(&addr(1)));
+    assert!(state.client_samples.contains_key(&addr(2)));
+    assert!(state.client_samples.contains_key(&peer));
+  }
+
+  #[test]
+  fn gate_forgets_idle_addresses() {
+    let mut gate = gate(10.0, 5.0);
+    let now = Instant::now();
+    for _ in 0..10 {
+      gate.allow_at(addr(1), now);
+    }
+    assert!(gate.throttled.contains(&addr(1)));
+
+    gate.allow_at(addr(2), now + CLIENT_TIMEOUT);
+    assert!(!gate.admitted.contains_key(&addr(1)));
+    assert!(!gate.limiters.contains_key(&addr(1)));
+    assert!(!gate.throttled.contains(&addr(1)));
+    assert!(gate.admitted.contains_key(&addr(2)));
+  }
+
+  #[test]
+  fn clients_past_the_limit_are_rejected() {
+    let peer = addr(9);
+    let config = ServerConfig {
+      max_clients: 2,
+      peers: vec![peer],
+      ..Default::default()
+    };
+    let mut state = MixState::new(&config, Arc::default(), None);
+    assert!(state.handle(addr(1), Packet::Ping).is_empty());
+    assert!(state.handle(addr(2), Packet::Ping).is_empty());
+
+    // The third is turned away, and nothing else it sends gets in.
+    let out = state.handle(addr(3), Packet::Ping);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].0, addr(3));
+    assert!(matches!(
+      Packet::decode(&out[0].1),
+      Ok(Some(Packet::Rejected { reason })) if reason == "server full"
+    ));
+    assert!(
+      state
+        .handle(addr(3), Packet::Audio([0.5; TX_BUFFER_SIZE]))
+        .is_empty()
+    );
+    assert!(!state.client_samples.contains_key(&addr(3)));
+
+    // Those already in carry on as before, pings included.
+    assert!(state.handle(addr(1), Packet::Ping).is_empty());
+    state.handle(addr(1), Packet::Audio([0.5; TX_BUFFER_SIZE]));
+    let mixes = heard(state.tick());
+    let mut listeners: Vec<&SocketAddr> = mixes.keys().collect();
+    listeners.sort();
+    assert_eq!(listeners, [&addr(2), &peer]);
+  }
+
+  #[test]
+  fn whispers_reach_only_their_target() {
+    let mut state =
+      MixState::new(&ServerConfig::default(), Arc::default(), None);
+    for client in 1..=3 {
+      state.handle(addr(client), Packet::Ping);
+    }
+    state.handle(
+      addr(1),
+      Packet::DirectAudio {
+        to: addr(2),
+        samples: [0.5; TX_BUFFER_SIZE],
+      },
+    );
+
+    let mixes = heard(state.tick());
+    assert_eq!(mixes.len(), 1);
+    assert_eq!(mixes[&addr(2)], ([0.5; TX_BUFFER_SIZE], false));
+
+    // Mixed with whatever the channel is saying.
+    state.handle(addr(3), Packet::Audio([0.25; TX_BUFFER_SIZE]));
+    state.handle(
+      addr(1),
+      Packet::DirectAudio {
+        to: addr(2),
+        samples: [0.5; TX_BUFFER_SIZE],
+      },
+    );
+    let mixes = heard(state.tick());
+    assert_eq!(mixes[&addr(2)].0, [0.75; TX_BUFFER_SIZE]);
+    assert_eq!(mixes[&addr(1)].0, [0.25; TX_BUFFER_SIZE]);
+    assert!(!mixes.contains_key(&addr(3)));
+  }
+
+  #[test]
+  fn emergencies_cross_channels_only_when_allowed() {
+    for allow_emergency in [true, false] {
+      let config = ServerConfig {
+        allow_emergency,
+        ..Default::default()
+      };
+      let mut state = MixState::new(&config, Arc::default(), None);
+      for client in 1..=3 {
+        state.handle(addr(client), Packet::Ping);
+      }
+      state.handle(
+        addr(2),
+        Packet::Hello {
+          version: PROTOCOL_VERSION,
+        },
+      );
+      state.handle(addr(2), Packet::Channel(5));
+      state.handle(
+        addr(1),
+        Packet::Emergency {
+          samples: [0.5; TX_BUFFER_SIZE],
+        },
+      );
+
+      let mixes = heard(state.tick());
+      assert!(!mixes.contains_key(&addr(1)));
+      // Client 3 shares the sender's channel, and hears it either way; as a
+      // version 0 client, without the alarm.
+      assert!(!mixes[&addr(3)].1);
+      if allow_emergency {
+        // It starts with the attention tone.
+        let (samples, alarm) = mixes[&addr(2)];
+        assert!(alarm);
+        assert!(samples.iter().any(|s| *s != 0.5));
+      } else {
+        assert!(!mixes.contains_key(&addr(2)));
+        assert_eq!(mixes[&addr(3)].0, [0.5; TX_BUFFER_SIZE]);
+      }
+    }
+  }
+
+  #[test]
+  fn switching_channels_cuts_off_the_old_one() {
+    let mut state =
+      MixState::new(&ServerConfig::default(), Arc::default(), None);
+    for client in 1..=3 {
+      state.handle(addr(client), Packet::Ping);
+    }
+    state.handle(addr(3), Packet::Channel(2));
+    for _ in 0..4 {
+      state.handle(addr(1), Packet::Audio([0.5; TX_BUFFER_SIZE]));
+      state.handle(addr(3), Packet::Audio([0.25; TX_BUFFER_SIZE]));
+    }
+    let mixes = heard(state.tick());
+    assert_eq!(mixes[&addr(2)].0, [0.5; TX_BUFFER_SIZE]);
+
+    // Client 2 hears channel 2 from the very next tick, though client 1
+    // still has audio queued on channel 0.
+    state.handle(addr(2), Packet::Channel(2));
+    let mixes = heard(state.tick());
+    assert_eq!(mixes[&addr(2)].0, [0.25; TX_BUFFER_SIZE]);
+    assert!(!mixes.contains_key(&addr(3)));
+  }
+
+  #[test]
+  fn advertised_levels_scale_talkers_in_the_mix() {
+    let mut state =
+      MixState::new(&ServerConfig::default(), Arc::default(), None);
+    for client in 1..=3 {
+      state.handle(addr(client), Packet::Ping);
+    }
+    // Half level, unity, and too loud, which is held to `MAX_LEVEL`.
+    state.handle(addr(1), Packet::Level(50));
+    state.handle(addr(3), Packet::Level(u8::MAX));
+    for client in 1..=3 {
+      state.handle(addr(client), Packet::Audio([0.25; TX_BUFFER_SIZE]));
+    }
+
+    // Each hears the other two.
+    let mixes = heard(state.tick());
+    assert_eq!(mixes[&addr(1)].0, [0.25 + 0.5; TX_BUFFER_SIZE]);
+    assert_eq!(mixes[&addr(2)].0, [0.125 + 0.5; TX_BUFFER_SIZE]);
+    assert_eq!(mixes[&addr(3)].0, [0.125 + 0.25; TX_BUFFER_SIZE]);
+  }
+
+  #[test]
+  fn each_version_gets_only_what_it_understands() {
+    let mut state =
+      MixState::new(&ServerConfig::default(), Arc::default(), None);
+    // Protocol 0 (never says hello), 1, 5, ours, and one newer than ours.
+    let versions = [None, Some(1), Some(5), Some(PROTOCOL_VERSION)]
+      .into_iter()
+      .chain([Some(PROTOCOL_VERSION + 1)]);
+    let mut replies = HashMap::new();
+    for (client, version) in (1..).zip(versions) {
+      state.handle(addr(client), Packet::Ping);
+      if let Some(version) = version {
+        let out = state.handle(addr(client), Packet::Hello { version });
+        let kinds: Vec<u16> = out
+          .iter()
+          .map(|(_, bytes)| Packet::decode(bytes).unwrap().unwrap())
+          .map(|packet| packet.min_version())
+          .collect();
+        replies.insert(client, kinds);
+      }
+    }
+    // `ServerInfo` from 3, `HelloAck` from 8.
+    assert!(replies[&2].is_empty());
+    assert_eq!(replies[&3], [3]);
+    assert_eq!(replies[&4], [8, 3]);
+    assert_eq!(replies[&5], [8, 3]);
+
+    // DTX markers skip version 0.
+    let talker = addr(6);
+    state.handle(talker, Packet::Ping);
+    let markers = state.handle(talker, Packet::Silence);
+    let mut marked: Vec<SocketAddr> =
+      markers.into_iter().map(|(to, _)| to).collect();
+    marked.sort();
+    assert_eq!(marked, [addr(2), addr(3), addr(4), addr(5)]);
+
+    // And each gets the mix in the newest form it knows.
+    state.handle(talker, Packet::Audio([0.5; TX_BUFFER_SIZE]));
+    let sent: HashMap<SocketAddr, Packet> = state
+      .tick()
+      .into_iter()
+      .map(|(to, bytes)| (to, Packet::decode(&bytes).unwrap().unwrap()))
+      .collect();
+    assert_eq!(sent.len(), 5);
+    assert!(matches!(sent[&addr(1)], Packet::Audio(_)));
+    assert!(matches!(sent[&addr(2)], Packet::Audio(_)));
+    assert!(matches!(sent[&addr(3)], Packet::SeqAudio { .. }));
+    assert!(matches!(sent[&addr(4)], Packet::StampedAudio { .. }));
+    assert!(matches!(sent[&addr(5)], Packet::StampedAudio { .. }));
+  }
+
+  #[test]
+  fn server_fx_give_every_listener_the_same_audio() {
+    let config = ServerConfig {
+      fx: Some(FxConfig::default()),
+      ..Default::default()
+    };
+    let mut state = MixState::new(&config, Arc::default(), None);
+    for client in 1..=5 {
+      state.handle(addr(client), Packet::Ping);
+    }
+    // Clients that can hear it are told not to run their own, in answer to
+    // their hello and to every ping after it.
+    let told = |out: Vec<(SocketAddr, Vec<u8>)>| {
+      matches!(
+        Packet::decode(&out[0].1),
+        Ok(Some(Packet::ServerInfo { fx: true }))
+      )
+    };
+    assert!(told(state.handle(addr(5), Packet::Hello { version: 3 })));
+    assert!(told(state.handle(addr(5), Packet::Ping)));
+    assert!(state.handle(addr(4), Packet::Ping).is_empty());
+
+    // Two talkers, mixed and then run through the FX once.
+    let ticks = 8;
+    let tones = [440.0, 700.0].map(|hz| {
+      gen_tone(hz, ticks * TX_BUFFER_SIZE)
+        .iter()
+        .map(|s| s * 0.3)
+        .collect::<Vec<f32>>()
+    });
+    let mut expected = FxUnit::from_config(&FxConfig::default());
+    let mut mixes: HashMap<SocketAddr, Vec<f32>> = HashMap::new();
+    // Long enough after the tones for the squelch tail to play out too.
+    for tick in 0..ticks + FADE_TICKS + SQUELCH_TICKS + 10 {
+      let chunks: Vec<TxBuffer> = tones
+        .iter()
+        .filter_map(|tone| tone.chunks_exact(TX_BUFFER_SIZE).nth(tick))
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect();
+      for (talker, chunk) in (1..).zip(&chunks) {
+        state.handle(addr(talker), Packet::Audio(*chunk));
+      }
+      let out = heard(state.tick());
+      if !chunks.is_empty() {
+        let mut mix = [0.0; TX_BUFFER_SIZE];
+        config.policy.mix(&mut mix, &chunks);
+        expected.run(&mut mix);
+        assert_eq!(out[&addr(3)].0, mix, "tick {tick}");
+      }
+      for (to, (samples, _)) in out {
+        mixes.entry(to).or_default().extend(samples);
+      }
+    }
+
+    let reference = &mixes[&addr(3)];
+    // The tones, their fade, and a squelch tail of static after them.
+    let tail = FxConfig::default().squelch_tail;
+    assert_eq!(
+      reference.len(),
+      (ticks + FADE_TICKS + tail) * TX_BUFFER_SIZE
+    );
+    assert!(
+      reference[reference.len() - tail * TX_BUFFER_SIZE..]
+        .iter()
+        .any(|s| *s != 0.0)
+    );
+    assert_eq!(&mixes[&addr(4)], reference);
+    assert_eq!(&mixes[&addr(5)], reference);
+    // Each talker hears only the other, with FX of their own.
+    assert_ne!(&mixes[&addr(1)], reference);
+    assert_ne!(&mixes[&addr(1)], &mixes[&addr(2)]);
+  }
+
+  #[test]
+  fn mix_stays_sample_aligned_over_many_ticks() {
+    let (talker, listener) = (addr(1), addr(2));
+    let mut state =
+      MixState::new(&ServerConfig::default(), Arc::default(), None);
+    state.handle(talker, Packet::Ping);
+    state.handle(listener, Packet::Ping);
+
+    // A ramp, so any sample dropped, repeated or reordered shows.
+    let stream: Vec<f32> = (0..401 * TX_BUFFER_SIZE)
+      .map(|i| 0.1 + i as f32 / 1e6)
+      .collect();
+    let mut packets = stream.chunks_exact(TX_BUFFER_SIZE).map(|chunk| {
+      let buf: TxBuffer = chunk.try_into().unwrap();
+      Packet::Audio(buf)
+    });
+
+    // One buffer of cushion, then the packets arrive in uneven bunches that
+    // average out to one per tick.
+    state.handle(talker, packets.next().unwrap());
+    let mut mixed = Vec::new();
+    for arrivals in [2, 0, 1, 1].into_iter().cycle().take(400) {
+      for packet in packets.by_ref().take(arrivals) {
+        state.handle(talker, packet);
+      }
+      let mixes = heard(state.tick());
+      mixed.extend(mixes[&listener].0);
+    }
+
+    assert_eq!(mixed.len(), 400 * TX_BUFFER_SIZE);
+    assert_eq!(state.client_samples[&talker].len(), TX_BUFFER_SIZE);
+    for (i, (heard, sent)) in mixed.iter().zip(&stream).enumerate() {
+      assert!((heard - sent).abs() < 1e-6, "sample {i}: {heard} vs {sent}");
+    }
+  }
+
+  /// RMS of a 0.5 peak sine.
+  #[cfg(feature = "opus")]
+  const TONE_RMS: f32 = 0.354;
+
+  #[cfg(feature = "opus")]
+  fn rms(samples: &[f32]) -> f32 {
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+  }
+
+  /// A plain client and an Opus one hear each other: `handle` decodes Opus
+  /// talkers to PCM for the mix, and `tick` encodes each Opus listener's mix
+  /// with its own encoder.
+  #[cfg(feature = "opus")]
+  #[test]
+  fn transcodes_between_plain_and_opus_clients() {
+    let (plain, opus) = (addr(1), addr(2));
+    let mut state =
+      MixState::new(&ServerConfig::default(), Arc::default(), None);
+    state.handle(plain, Packet::Ping);
+    state.handle(opus, Packet::Ping);
+    state.handle(
+      opus,
+      Packet::Hello {
+        version: PROTOCOL_VERSION,
+      },
+    );
+    state.handle(opus, Packet::AcceptOpus);
+
+    let tone: Vec<f32> = gen_tone(1000.0, 40 * TX_BUFFER_SIZE)
+      .iter()
+      .map(|s| s * 0.5)
+      .collect();
+    let buffers = tone.chunks_exact(TX_BUFFER_SIZE).map(|chunk| {
+      let buf: TxBuffer = chunk.try_into().unwrap();
+      buf
+    });
+
+    // Plain to Opus.
+    let mut decoder = OpusDecoder::new().unwrap();
+    let mut heard = Vec::new();
+    for buf in buffers.clone() {
+      state.handle(plain, Packet::Audio(buf));
+      for (to, bytes) in state.tick() {
+        assert_eq!(to, opus);
+        let Some(Packet::OpusAudio { seq, frame }) =
+          Packet::decode(&bytes).unwrap()
+        else {
+          panic!("an Opus client was sent something else");
+        };
+        decoder.decode(seq, &frame).unwrap();
+      }
+      heard.extend(std::iter::from_fn(|| decoder.pop()).flatten());
+    }
+    assert!(heard.len() > 30 * TX_BUFFER_SIZE);
+    let level = rms(&heard[10 * TX_BUFFER_SIZE..]);
+    assert!((level - TONE_RMS).abs() < 0.05, "{level}");
+
+    // Let the plain talker fade out.
+    for _ in 0..=FADE_TICKS {
+      state.tick();
+    }
+
+    // Opus to plain.
+    let mut encoder = OpusEncoder::new().unwrap();
+    let mut heard = Vec::new();
+    for buf in buffers {
+      if let Some(packet) = encoder.encode(&buf).unwrap() {
+        state.handle(opus, packet);
+      }
+      for (to, bytes) in state.tick() {
+        assert_eq!(to, plain);
+        match Packet::decode(&bytes).unwrap() {
+          Some(Packet::Audio(samples)) => heard.extend(samples),
+          _ => panic!("a plain client was sent something else"),
+        }
+      }
+    }
+    assert!(heard.len() > 30 * TX_BUFFER_SIZE);
+    let level = rms(&heard[10 * TX_BUFFER_SIZE..]);
+    assert!((level - TONE_RMS).abs() < 0.05, "{level}");
+  }
+
+  #[test]
+  fn test_tone_replaces_the_mix_and_loops_seamlessly() {
+    let config = ServerConfig {
+      test_tone: Some(1000),
+      ..Default::default()
+    };
+    let mut state = MixState::new(&config, Arc::default(), None);
+    state.handle(addr(1), Packet::Ping);
+    state.handle(addr(2), Packet::Ping);
+
+    // A bit past a second, so the one-second tone wraps around.
+    let ticks = SAMPLE_RATE as usize / TX_BUFFER_SIZE + 8;
+    let mut sent = Vec::new();
+    for _ in 0..ticks {
+      // What clients send is ignored.
+      state.handle(addr(1), Packet::Audio([0.9; TX_BUFFER_SIZE]));
+      let out = heard(state.tick());
+      assert_eq!(out.len(), 2);
+      assert_eq!(out[&addr(1)], out[&addr(2)]);
+      assert!(!out[&addr(1)].1);
+      sent.extend(out[&addr(1)].0);
+    }
+
+    let tone = gen_tone(1000.0, SAMPLE_RATE as usize);
+    assert!(
+      sent
+        .iter()
+        .enumerate()
+        .all(|(i, s)| { *s == tone[i % tone.len()] * TEST_TONE_LEVEL })
+    );
+    // No click where it loops: no step is bigger than the tone's steepest.
+    let steepest =
+      std::f32::consts::TAU * 1000.0 / SAMPLE_RATE as f32 * TEST_TONE_LEVEL;
+    assert!(sent.windows(2).all(|w| (w[1] - w[0]).abs() <= steepest));
+  }
+}
@@ -0,0 +1,211 @@
+//! A log of who transmitted when, for sysops to search after a net.
+//!
+//! Transmission boundaries are found the same way the client finds them for
+//! its squelch tail: a client's transmission ends once no audio has come from
+//! it for `gap`. DTX silence markers keep a transmission open.
+
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{ClientId, Packet, WAIT_DURATION};
+
+/// How long a client must be quiet before its transmission is over; matches
+/// the client's squelch timeout.
+pub fn default_gap() -> Duration {
+  WAIT_DURATION.mul_f32(7.0)
+}
+
+#[derive(Debug, Clone)]
+pub struct Transmission {
+  pub client: ClientId,
+  pub start: SystemTime,
+  pub end: SystemTime,
+  /// Loudest sample, from 0.0 to 1.0.
+  pub peak: f32,
+}
+
+impl Transmission {
+  pub fn duration(&self) -> Duration {
+    self.end.duration_since(self.start).unwrap_or_default()
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransmissionLog {
+  gap: Duration,
+  started: SystemTime,
+  open: HashMap<ClientId, Transmission>,
+  done: Vec<Transmission>,
+}
+
+impl TransmissionLog {
+  pub fn new(gap: Duration) -> Self {
+    Self {
+      gap,
+      started: SystemTime::now(),
+      open: HashMap::new(),
+      done: Vec::new(),
+    }
+  }
+
+  /// Notes a packet from `client` that arrived at `now`. Only channel audio
+  /// and DTX markers count towards a transmission; whispers are private.
+  pub fn record(&mut self, client: ClientId, packet: &Packet, now: SystemTime) {
+    // First, so a marker after the gap doesn't reopen a finished one.
+    self.close_idle(now);
+    let peak = match packet {
+      Packet::Audio(samples)
+      | Packet::Emergency { samples }
+      | Packet::SeqAudio { samples, .. }
+      | Packet::StampedAudio { samples, .. } => {
+        samples.iter().fold(0f32, |peak, s| peak.max(s.abs()))
+      }
+      // Compressed audio still counts, though its peak isn't known without
+      // decoding it.
+      Packet::OpusAudio { .. } => 0.0,
+      // A pause, not the end of the transmission, but not a start either.
+      Packet::Silence if self.open.contains_key(&client) => 0.0,
+      _ => return,
+    };
+
+    let transmission = self.open.entry(client).or_insert(Transmission {
+      client,
+      start: now,
+      end: now,
+      peak: 0.0,
+    });
+    transmission.end = now;
+    transmission.peak = transmission.peak.max(peak);
+  }
+
+  /// Ends every transmission that's been quiet for longer than the gap.
+  pub fn close_idle(&mut self, now: SystemTime) {
+    let gap = self.gap;
+    let done = &mut self.done;
+    self.open.retain(|_, transmission| {
+      let idle = now.duration_since(transmission.end).unwrap_or_default();
+      if idle > gap {
+        done.push(transmission.clone());
+      }
+      idle <= gap
+    });
+  }
+
+  /// Ends any open transmissions and returns them all, oldest first.
+  pub fn finish(mut self) -> Vec<Transmission> {
+    self.done.extend(self.open.into_values());
+    self.done.sort_by_key(|transmission| transmission.start);
+    self.done
+  }
+
+  /// When the log was created, e.g. the start of the matching recording.
+  pub fn started(&self) -> SystemTime {
+    self.started
+  }
+}
+
+/// Formats `transmissions` as a JSON array. `origin` is the start of the
+/// recording they go with; each entry's `offset` is seconds from it.
+pub fn to_json(transmissions: &[Transmission], origin: SystemTime) -> String {
+  let secs = |time: SystemTime, from: SystemTime| {
+    time.duration_since(from).unwrap_or_default().as_secs_f64()
+  };
+
+  let mut out = String::from("[\n");
+  for (i, transmission) in transmissions.iter().enumerate() {
+    let _ = write!(
+      out,
+      "  {{\"client\": \"{}\", \"start\": {:.3}, \"end\": {:.3}, \
+       \"offset\": {:.3}, \"duration\": {:.3}, \"peak\": {:.4}}}",
+      transmission.client,
+      secs(transmission.start, UNIX_EPOCH),
+      secs(transmission.end, UNIX_EPOCH),
+      secs(transmission.start, origin),
+      transmission.duration().as_secs_f64(),
+      transmission.peak,
+    );
+    out.push_str(if i + 1 < transmissions.len() {
+      ",\n"
+    } else {
+      "\n"
+    });
+  }
+  out.push(']');
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{Ipv4Addr, SocketAddr};
+
+  use super::*;
+  use crate::TX_BUFFER_SIZE;
+
+  #[test]
+  fn log_matches_the_transmissions_made() {
+    let (alice, bob) = (
+      SocketAddr::from((Ipv4Addr::LOCALHOST, 1)),
+      SocketAddr::from((Ipv4Addr::LOCALHOST, 2)),
+    );
+    let origin = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let at = |ms| origin + Duration::from_millis(ms);
+    let audio = |level| Packet::Audio([level; TX_BUFFER_SIZE]);
+    let mut log = TransmissionLog::new(Duration::from_millis(40));
+
+    // Alice talks for 100 ms with a DTX pause in the middle, and Bob cuts in
+    // for 30 ms, whispering first, which doesn't count.
+    log.record(
+      bob,
+      &Packet::DirectAudio {
+        to: alice,
+        samples: [0.9; TX_BUFFER_SIZE],
+      },
+      at(0),
+    );
+    for ms in (0..=100).step_by(5) {
+      let packet = match ms {
+        40..=60 => Packet::Silence,
+        _ => audio(if ms == 20 { -0.5 } else { 0.25 }),
+      };
+      log.record(alice, &packet, at(ms));
+      if (50..=80).contains(&ms) {
+        log.record(bob, &audio(0.8), at(ms));
+      }
+    }
+    // A silence marker on its own doesn't start a transmission.
+    log.record(bob, &Packet::Silence, at(150));
+    // Alice again, after more than the gap.
+    for ms in [200, 205] {
+      log.record(alice, &audio(0.3), at(ms));
+    }
+
+    let transmissions = log.finish();
+    let summary: Vec<_> = transmissions
+      .iter()
+      .map(|t| {
+        let ms = |time: SystemTime| time.duration_since(origin).unwrap();
+        (t.client, ms(t.start), t.duration(), t.peak)
+      })
+      .collect();
+    let ms = Duration::from_millis;
+    assert_eq!(
+      summary,
+      [
+        (alice, ms(0), ms(100), 0.5),
+        (bob, ms(50), ms(30), 0.8),
+        (alice, ms(200), ms(5), 0.3),
+      ]
+    );
+
+    let json = to_json(&transmissions, origin);
+    assert!(json.starts_with(
+      "[\n  {\"client\": \"127.0.0.1:1\", \"start\": 1700000000.000, \
+       \"end\": 1700000000.100, \"offset\": 0.000, \"duration\": 0.100, \
+       \"peak\": 0.5000},\n"
+    ));
+    assert_eq!(json.lines().count(), 5);
+  }
+}
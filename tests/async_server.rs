@@ -0,0 +1,69 @@
+//! The tokio server, end to end over real sockets.
+#![cfg(feature = "async")]
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{net::UdpSocket, time::timeout};
+
+use squelch::{
+  MAX_PACKET_SIZE, Packet, TX_BUFFER_SIZE, TxBuffer,
+  server::{Server, ServerConfig},
+};
+
+async fn client(server: SocketAddr) -> UdpSocket {
+  let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+  send(&socket, server, &Packet::Ping).await;
+  socket
+}
+
+async fn send(socket: &UdpSocket, to: SocketAddr, packet: &Packet) {
+  let bytes = postcard::to_allocvec(packet).unwrap();
+  socket.send_to(&bytes, to).await.unwrap();
+}
+
+/// The next audio `socket` receives, skipping pings.
+async fn next_audio(socket: &UdpSocket) -> TxBuffer {
+  let mut buf = [0; MAX_PACKET_SIZE];
+  loop {
+    let (size, _) = socket.recv_from(&mut buf).await.unwrap();
+    if let Ok(Some(Packet::Audio(samples))) = Packet::decode(&buf[..size]) {
+      return samples;
+    }
+  }
+}
+
+#[tokio::test]
+async fn mixes_two_talkers_for_a_listener() {
+  let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+  let server = socket.local_addr().unwrap();
+  tokio::spawn(Server::new(socket, ServerConfig::default()).run_async());
+
+  let a = client(server).await;
+  let b = client(server).await;
+  let listener = client(server).await;
+
+  let talk = async {
+    for _ in 0..100 {
+      send(&a, server, &Packet::Audio([0.1; TX_BUFFER_SIZE])).await;
+      send(&b, server, &Packet::Audio([0.2; TX_BUFFER_SIZE])).await;
+      tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+  };
+  // Once both are flowing, the listener hears them summed.
+  let listen = async {
+    loop {
+      let mix = next_audio(&listener).await;
+      if mix.iter().all(|s| (s - 0.3).abs() < 1e-6) {
+        break;
+      }
+    }
+  };
+  let (_, heard) = tokio::join!(talk, timeout(Duration::from_secs(5), listen));
+  heard.expect("the listener never heard both talkers");
+
+  // Each talker hears only the other.
+  let from_b = timeout(Duration::from_secs(1), next_audio(&a))
+    .await
+    .unwrap();
+  assert!(from_b.iter().all(|s| *s <= 0.2 + 1e-6));
+}